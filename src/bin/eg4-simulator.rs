@@ -0,0 +1,53 @@
+use anyhow::Result;
+use clap::Parser;
+use eg4_bridge::prelude::*;
+use eg4_bridge::simulator::Simulator;
+
+/// Pretends to be an inverter and its datalog dongle, for developing and
+/// testing eg4-bridge without real hardware.
+#[derive(Debug, Parser)]
+#[clap(author, version)]
+struct Options {
+    /// Address to listen on
+    #[clap(short = 'l', long = "listen", default_value = "0.0.0.0:8000")]
+    listen: String,
+
+    /// Datalog serial to report
+    #[clap(short = 'd', long = "datalog", default_value = "2222222222")]
+    datalog: String,
+
+    /// Inverter serial to report
+    #[clap(short = 's', long = "serial", default_value = "5555555555")]
+    serial: String,
+
+    /// JSON lines capture (from datalog_file) to seed registers from
+    #[clap(short = 'f', long = "datalog-file")]
+    datalog_file: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let options = Options::parse();
+
+    let simulator = Simulator::new(
+        Serial::from_str(&options.datalog)?,
+        Serial::from_str(&options.serial)?,
+    );
+
+    if let Some(file) = &options.datalog_file {
+        let loaded = simulator.load_datalog_file(file)?;
+        info!("loaded {} captures from {}", loaded, file);
+    }
+
+    let listener = tokio::net::TcpListener::bind(&options.listen).await?;
+    info!(
+        "simulating datalog {} / inverter {} on {}",
+        simulator.datalog(),
+        simulator.serial(),
+        options.listen
+    );
+
+    simulator.start(listener).await
+}
@@ -0,0 +1,428 @@
+use crate::prelude::*;
+use crate::metrics::Metrics;
+
+use {
+    serde::Serialize,
+    std::collections::VecDeque,
+    std::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    std::sync::{Arc, Mutex, Weak},
+    tokio::sync::Notify,
+};
+
+/// What to do with a message for a subscriber whose queue is full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    // make room by dropping the oldest message; for data, where the newest matters most
+    DropOldest,
+    // refuse the new message for that subscriber, still delivering it to
+    // the rest; send() fails if nobody took it, so the sender finds out
+    Reject,
+    // send_wait() waits for room, holding the sender up, so nothing is
+    // lost; send() can't wait, and queues past capacity so control messages
+    // such as Shutdown always get through
+    Wait,
+}
+
+pub mod error {
+    use std::fmt;
+
+    #[derive(Debug)]
+    pub struct SendError<T>(pub T);
+
+    impl<T> fmt::Display for SendError<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "channel closed or full")
+        }
+    }
+    impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum RecvError {
+        Closed,
+    }
+
+    impl fmt::Display for RecvError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "channel closed")
+        }
+    }
+    impl std::error::Error for RecvError {}
+
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum TryRecvError {
+        Empty,
+        Closed,
+    }
+
+    impl fmt::Display for TryRecvError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Empty => write!(f, "channel empty"),
+                Self::Closed => write!(f, "channel closed"),
+            }
+        }
+    }
+    impl std::error::Error for TryRecvError {}
+}
+
+use error::{RecvError, SendError, TryRecvError};
+
+/// How a channel is doing, for /api/stats and /metrics.
+#[derive(Clone, Debug, Serialize)]
+pub struct BusStats {
+    pub capacity: usize,
+    pub overflow: Overflow,
+    pub subscribers: usize,
+    // messages waiting for the furthest behind subscriber
+    pub queued: usize,
+    pub sent: u64,
+    // summed over subscribers, so one message can count more than once
+    pub dropped: u64,
+}
+
+impl BusStats {
+    pub fn write_metrics(&self, channel: &str, metrics: &mut Metrics) {
+        let labels = [("channel", channel)];
+        metrics.gauge("channel_queued", "Messages waiting for the slowest subscriber", &labels, self.queued as f64);
+        metrics.counter("channel_sent", "Messages sent on an internal channel", &labels, self.sent as f64);
+        metrics.counter(
+            "channel_dropped",
+            "Messages dropped or refused because a subscriber's queue was full",
+            &labels,
+            self.dropped as f64,
+        );
+    }
+}
+
+struct Queue<T> {
+    items: Mutex<VecDeque<T>>,
+    notify: Notify,
+    // for send_wait(), when something's taken off or the receiver has gone
+    space: Notify,
+    closed: AtomicBool,
+    receiver_dropped: AtomicBool,
+    // set while we're dropping for this subscriber, so we only warn once
+    overflowing: AtomicBool,
+}
+
+struct Inner<T> {
+    name: &'static str,
+    capacity: usize,
+    overflow: Overflow,
+    subscribers: Mutex<Vec<Weak<Queue<T>>>>,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl<T> Inner<T> {
+    // drops the ones whose Receiver has gone while we're at it
+    fn subscribers(&self) -> Vec<Arc<Queue<T>>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| s.strong_count() > 0);
+        subscribers.iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        for queue in self.subscribers.lock().unwrap().iter().filter_map(Weak::upgrade) {
+            queue.closed.store(true, Ordering::SeqCst);
+            queue.notify.notify_one();
+        }
+    }
+}
+
+/// Like a broadcast::Sender, but every subscriber gets a queue of its own,
+/// so one that falls behind never costs the others anything. What happens
+/// when it fills up is down to the channel's Overflow, and is counted.
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Sender").field("name", &self.inner.name).finish()
+    }
+}
+
+impl<T: Clone> Sender<T> {
+    pub fn new(name: &'static str, capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                name,
+                capacity: capacity.max(1),
+                overflow,
+                subscribers: Mutex::new(Vec::new()),
+                sent: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Queues the message for every subscriber, returning how many got it.
+    /// A full subscriber on a Reject channel is skipped, so it can't hold
+    /// up the others; fails when there are no subscribers, or none took it.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let subscribers = self.inner.subscribers();
+        if subscribers.is_empty() {
+            return Err(SendError(value));
+        }
+        self.inner.sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut delivered = 0;
+
+        for queue in subscribers {
+            let mut items = queue.items.lock().unwrap();
+
+            if items.len() >= self.inner.capacity && self.inner.overflow != Overflow::Wait {
+                self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                if !queue.overflowing.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "{} channel: a subscriber has {} messages waiting, {}",
+                        self.inner.name,
+                        items.len(),
+                        match self.inner.overflow {
+                            Overflow::DropOldest => "dropping the oldest",
+                            _ => "refusing more",
+                        }
+                    );
+                }
+
+                match self.inner.overflow {
+                    Overflow::DropOldest => {
+                        items.pop_front();
+                    }
+                    Overflow::Reject => continue,
+                    Overflow::Wait => unreachable!(),
+                }
+            } else if queue.overflowing.swap(false, Ordering::Relaxed) {
+                info!("{} channel: subscriber caught up", self.inner.name);
+            }
+
+            items.push_back(value.clone());
+            drop(items);
+            queue.notify.notify_one();
+            delivered += 1;
+        }
+
+        if delivered == 0 {
+            Err(SendError(value))
+        } else {
+            Ok(delivered)
+        }
+    }
+
+    /// Like send(), but on a Wait channel waits for room in each full
+    /// queue, so nothing is dropped however slow a subscriber is.
+    pub async fn send_wait(&self, value: T) -> Result<usize, SendError<T>> {
+        if self.inner.overflow != Overflow::Wait {
+            return self.send(value);
+        }
+
+        let subscribers = self.inner.subscribers();
+        if subscribers.is_empty() {
+            return Err(SendError(value));
+        }
+        self.inner.sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut delivered = 0;
+        for queue in subscribers {
+            loop {
+                // registered before looking, so a recv() in between isn't missed
+                let space = queue.space.notified();
+                tokio::pin!(space);
+                space.as_mut().enable();
+
+                {
+                    let mut items = queue.items.lock().unwrap();
+                    if queue.receiver_dropped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if items.len() < self.inner.capacity {
+                        items.push_back(value.clone());
+                        drop(items);
+                        queue.notify.notify_one();
+                        delivered += 1;
+                        break;
+                    }
+                }
+
+                space.await;
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Only messages sent after this are received.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let queue = Arc::new(Queue {
+            items: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            space: Notify::new(),
+            closed: AtomicBool::new(false),
+            receiver_dropped: AtomicBool::new(false),
+            overflowing: AtomicBool::new(false),
+        });
+        self.inner.subscribers.lock().unwrap().push(Arc::downgrade(&queue));
+        Receiver { queue }
+    }
+
+    pub fn stats(&self) -> BusStats {
+        let subscribers = self.inner.subscribers();
+        BusStats {
+            capacity: self.inner.capacity,
+            overflow: self.inner.overflow,
+            subscribers: subscribers.len(),
+            queued: subscribers
+                .iter()
+                .map(|queue| queue.items.lock().unwrap().len())
+                .max()
+                .unwrap_or(0),
+            sent: self.inner.sent.load(Ordering::Relaxed),
+            dropped: self.inner.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> Receiver<T> {
+    // cancel safe: a message is only taken off the queue when it's returned
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => self.queue.notify.notified().await,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let value = self.queue.items.lock().unwrap().pop_front();
+        if let Some(value) = value {
+            self.queue.space.notify_waiters();
+            return Ok(value);
+        }
+
+        if self.queue.closed.load(Ordering::SeqCst) {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.queue.receiver_dropped.store(true, Ordering::SeqCst);
+        self.queue.space.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow() {
+        let sender = Sender::new("test", 2, Overflow::DropOldest);
+        let mut slow = sender.subscribe();
+        let mut fast = sender.subscribe();
+
+        for n in 1..=3 {
+            sender.send(n).unwrap();
+            assert_eq!(fast.recv().await, Ok(n));
+        }
+        assert_eq!(slow.recv().await, Ok(2));
+        assert_eq!(slow.recv().await, Ok(3));
+        assert_eq!(sender.stats().dropped, 1);
+
+        let sender = Sender::new("test", 1, Overflow::Reject);
+        let mut receiver = sender.subscribe();
+        sender.send(1).unwrap();
+        assert!(sender.send(2).is_err());
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        // a full subscriber doesn't stop the others getting it
+        sender.send(2).unwrap();
+        let mut other = sender.subscribe();
+        assert_eq!(sender.send(3).unwrap(), 1);
+        assert_eq!(other.try_recv(), Ok(3));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(sender.stats().dropped, 2);
+
+        drop(receiver);
+        drop(other);
+        assert!(sender.send(3).is_err());
+        assert_eq!(sender.stats().subscribers, 0);
+
+        let mut receiver = sender.subscribe();
+        drop(sender);
+        assert_eq!(receiver.recv().await, Err(RecvError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let sender = Sender::new("test", 2, Overflow::Wait);
+        let mut slow = sender.subscribe();
+
+        let sending = tokio::spawn({
+            let sender = sender.clone();
+            async move {
+                for n in 1..=10 {
+                    sender.send_wait(n).await.unwrap();
+                }
+            }
+        });
+
+        // the sender is held up rather than going past capacity
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(sender.stats().queued, 2);
+        assert!(!sending.is_finished());
+
+        let mut received = Vec::new();
+        for _ in 1..=10 {
+            received.push(slow.recv().await.unwrap());
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        sending.await.unwrap();
+        assert_eq!(received, (1..=10).collect::<Vec<_>>());
+        assert_eq!(sender.stats().dropped, 0);
+
+        // send() can't wait, so goes past capacity rather than losing it
+        sender.send_wait(11).await.unwrap();
+        sender.send_wait(12).await.unwrap();
+        sender.send(13).unwrap();
+        assert_eq!(sender.stats().queued, 3);
+        assert_eq!(sender.stats().dropped, 0);
+        assert_eq!(slow.recv().await, Ok(11));
+        assert_eq!(slow.recv().await, Ok(12));
+        assert_eq!(slow.recv().await, Ok(13));
+
+        // a subscriber going away doesn't leave the sender stuck
+        sender.send_wait(11).await.unwrap();
+        sender.send_wait(12).await.unwrap();
+        let sending = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send_wait(13).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        drop(slow);
+        assert!(sending.await.unwrap().is_ok());
+    }
+}
@@ -1,16 +1,26 @@
 use crate::prelude::*;
+use crate::bus::{self, BusStats, Overflow};
 use crate::eg4::inverter::ChannelData;
 
+use std::collections::BTreeMap;
+
+// per subscriber. sinks get more room, to ride out a slow InfluxDB or
+// database without holding anything up
+const CAPACITY: usize = 2048;
+const SINK_CAPACITY: usize = 16384;
+
 #[derive(Debug, Clone)]
 pub struct Channels {
-    pub from_inverter: broadcast::Sender<ChannelData>,
-    pub to_inverter: broadcast::Sender<ChannelData>,
-    pub from_mqtt: broadcast::Sender<crate::mqtt::ChannelData>,
-    pub to_mqtt: broadcast::Sender<crate::mqtt::ChannelData>,
-    pub to_influx: broadcast::Sender<crate::influx::ChannelData>,
-    pub to_database: broadcast::Sender<database::ChannelData>,
-    pub read_register_cache: broadcast::Sender<register_cache::ChannelData>,
-    pub to_register_cache: broadcast::Sender<register_cache::ChannelData>,
+    pub from_inverter: bus::Sender<ChannelData>,
+    pub to_inverter: bus::Sender<ChannelData>,
+    pub from_mqtt: bus::Sender<crate::mqtt::ChannelData>,
+    pub to_mqtt: bus::Sender<crate::mqtt::ChannelData>,
+    pub to_influx: bus::Sender<crate::influx::ChannelData>,
+    pub to_database: bus::Sender<database::ChannelData>,
+    pub read_register_cache: bus::Sender<register_cache::ChannelData>,
+    pub to_register_cache: bus::Sender<register_cache::ChannelData>,
+    pub to_request_queue: bus::Sender<request_queue::ChannelData>,
+    pub to_coordinator: bus::Sender<coordinator::ChannelData>,
 }
 
 impl Default for Channels {
@@ -22,18 +32,38 @@ impl Default for Channels {
 impl Channels {
     pub fn new() -> Self {
         Self {
-            from_inverter: Self::channel(),
-            to_inverter: Self::channel(),
-            from_mqtt: Self::channel(),
-            to_mqtt: Self::channel(),
-            to_influx: Self::channel(),
-            to_database: Self::channel(),
-            read_register_cache: Self::channel(),
-            to_register_cache: Self::channel(),
+            // data: keep the newest if someone falls behind. the oldest are
+            // lost, with a warning, and counted in channel_dropped
+            from_inverter: bus::Sender::new("from_inverter", CAPACITY, Overflow::DropOldest),
+            to_mqtt: bus::Sender::new("to_mqtt", SINK_CAPACITY, Overflow::DropOldest),
+            // ..except the sinks with a spool, which mustn't lose anything.
+            // they spool what their server won't take, so only fill up if the
+            // disk can't keep up, and then hold the coordinator up until there's room
+            to_influx: bus::Sender::new("to_influx", SINK_CAPACITY, Overflow::Wait),
+            to_database: bus::Sender::new("to_database", SINK_CAPACITY, Overflow::Wait),
+            to_register_cache: bus::Sender::new("to_register_cache", SINK_CAPACITY, Overflow::DropOldest),
+            // requests: better the sender hears it was refused than waits
+            // for a reply that won't come
+            to_inverter: bus::Sender::new("to_inverter", CAPACITY, Overflow::Reject),
+            from_mqtt: bus::Sender::new("from_mqtt", CAPACITY, Overflow::Reject),
+            read_register_cache: bus::Sender::new("read_register_cache", CAPACITY, Overflow::Reject),
+            to_request_queue: bus::Sender::new("to_request_queue", CAPACITY, Overflow::Reject),
+            to_coordinator: bus::Sender::new("to_coordinator", CAPACITY, Overflow::Reject),
         }
     }
 
-    fn channel<T: Clone>() -> broadcast::Sender<T> {
-        broadcast::channel(2048).0
+    pub fn stats(&self) -> BTreeMap<&'static str, BusStats> {
+        BTreeMap::from([
+            ("from_inverter", self.from_inverter.stats()),
+            ("to_inverter", self.to_inverter.stats()),
+            ("from_mqtt", self.from_mqtt.stats()),
+            ("to_mqtt", self.to_mqtt.stats()),
+            ("to_influx", self.to_influx.stats()),
+            ("to_database", self.to_database.stats()),
+            ("read_register_cache", self.read_register_cache.stats()),
+            ("to_register_cache", self.to_register_cache.stats()),
+            ("to_request_queue", self.to_request_queue.stats()),
+            ("to_coordinator", self.to_coordinator.stats()),
+        ])
     }
 }
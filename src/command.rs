@@ -1,5 +1,8 @@
 use crate::prelude::*;
 
+use serde::Serialize;
+use std::collections::BTreeMap;
+
 #[derive(Debug, Clone)]
 pub enum Command {
     ReadInputs(config::Inverter, u16),
@@ -11,6 +14,12 @@ pub enum Command {
     ReadChargePriorityTime(config::Inverter, u16),
     ReadForcedDischargeTime(config::Inverter, u16),
     SetHold(config::Inverter, u16, u16),
+    // a register map shortname and a value in its units, both as given
+    SetSetting(config::Inverter, String, String),
+    // a JSON object of registers or shortnames to values, as given
+    SetHolds(config::Inverter, String),
+    // a flag's name in the register map, and whether to set or clear it
+    SetFlag(config::Inverter, String, bool),
     WriteParam(config::Inverter, u16, u16),
     SetAcChargeTime(config::Inverter, u16, [u8; 4]),
     SetAcFirstTime(config::Inverter, u16, [u8; 4]),
@@ -27,6 +36,74 @@ pub enum Command {
 }
 
 impl Command {
+    /// Reads of every input register block, sized by register_block_size.
+    pub fn read_all_inputs(inverter: &config::Inverter) -> Vec<Command> {
+        let block_size = inverter.register_block_size();
+        (0..=200)
+            .step_by(block_size as usize)
+            .map(|register| Command::ReadInput(inverter.clone(), register, block_size))
+            .collect()
+    }
+
+    /// Reads of every holding register block, sized by register_block_size.
+    pub fn read_all_holds(inverter: &config::Inverter) -> Vec<Command> {
+        let block_size = inverter.register_block_size();
+        (0..=240)
+            .step_by(block_size as usize)
+            .map(|register| Command::ReadHold(inverter.clone(), register, block_size))
+            .collect()
+    }
+
+    pub fn inverter(&self) -> &config::Inverter {
+        use Command::*;
+
+        match self {
+            ReadInputs(inverter, _)
+            | ReadInput(inverter, _, _)
+            | ReadHold(inverter, _, _)
+            | ReadParam(inverter, _)
+            | ReadAcChargeTime(inverter, _)
+            | ReadAcFirstTime(inverter, _)
+            | ReadChargePriorityTime(inverter, _)
+            | ReadForcedDischargeTime(inverter, _)
+            | SetHold(inverter, _, _)
+            | SetSetting(inverter, _, _)
+            | SetHolds(inverter, _)
+            | SetFlag(inverter, _, _)
+            | WriteParam(inverter, _, _)
+            | SetAcChargeTime(inverter, _, _)
+            | SetAcFirstTime(inverter, _, _)
+            | SetChargePriorityTime(inverter, _, _)
+            | SetForcedDischargeTime(inverter, _, _)
+            | ChargeRate(inverter, _)
+            | DischargeRate(inverter, _)
+            | AcCharge(inverter, _)
+            | ChargePriority(inverter, _)
+            | ForcedDischarge(inverter, _)
+            | AcChargeRate(inverter, _)
+            | AcChargeSocLimit(inverter, _)
+            | DischargeCutoffSocLimit(inverter, _) => inverter,
+        }
+    }
+
+    /// Whether this command changes anything on the inverter, and so is
+    /// refused in read-only mode.
+    pub fn is_write(&self) -> bool {
+        use Command::*;
+
+        !matches!(
+            self,
+            ReadInputs(..)
+                | ReadInput(..)
+                | ReadHold(..)
+                | ReadParam(..)
+                | ReadAcChargeTime(..)
+                | ReadAcFirstTime(..)
+                | ReadChargePriorityTime(..)
+                | ReadForcedDischargeTime(..)
+        )
+    }
+
     pub fn to_result_topic(&self) -> String {
         use Command::*;
 
@@ -40,6 +117,9 @@ impl Command {
             ReadChargePriorityTime(inverter, num) => format!("{}/read/charge_priority/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), num),
             ReadForcedDischargeTime(inverter, num) => format!("{}/read/forced_discharge/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), num),
             SetHold(inverter, register, _) => format!("{}/set/hold/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), register),
+            SetSetting(inverter, name, _) => format!("{}/set/setting/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), name),
+            SetHolds(inverter, _) => format!("{}/set/holds", inverter.datalog().map(|s| s.to_string()).unwrap_or_default()),
+            SetFlag(inverter, name, _) => format!("{}/set/flag/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), name),
             WriteParam(inverter, register, _) => format!("{}/set/param/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), register),
             SetAcChargeTime(inverter, num, _) => format!("{}/set/ac_charge/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), num),
             SetAcFirstTime(inverter, num, _) => format!("{}/set/ac_first/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), num),
@@ -58,3 +138,112 @@ impl Command {
         format!("result/{}", rest)
     }
 }
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CommandStatus {
+    #[default]
+    Ok,
+    Fail,
+    // written, but reading back found something else
+    Mismatch,
+}
+
+/// What became of a command, published as JSON on its result/ topic.
+/// register is the first hold register written; requested and actual map
+/// each register written to its value.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CommandResult {
+    pub status: CommandStatus,
+    pub register: Option<u16>,
+    pub requested: Option<BTreeMap<u16, u16>>,
+    pub actual: Option<BTreeMap<u16, u16>>,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
+impl CommandResult {
+    /// A command that succeeded, having written these hold registers.
+    pub fn written(pairs: &[(u16, u16)]) -> Self {
+        Self {
+            register: pairs.iter().map(|(register, _)| *register).min(),
+            requested: (!pairs.is_empty()).then(|| pairs.iter().copied().collect()),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(err: &anyhow::Error) -> Self {
+        Self::default().fail(err)
+    }
+
+    /// Marks this as failed, keeping what was requested.
+    pub fn fail(mut self, err: &anyhow::Error) -> Self {
+        self.status = CommandStatus::Fail;
+        self.error = Some(format!("{:#}", err));
+        self
+    }
+
+    /// Compares what was written with pairs read back from the inverter.
+    pub fn verify(mut self, pairs: &[(u16, u16)]) -> Self {
+        let Some(requested) = &self.requested else {
+            return self;
+        };
+
+        let actual: BTreeMap<u16, u16> = pairs
+            .iter()
+            .filter(|(register, _)| requested.contains_key(register))
+            .copied()
+            .collect();
+
+        let mismatches: Vec<String> = requested
+            .iter()
+            .filter(|(register, wanted)| actual.get(register) != Some(wanted))
+            .map(|(register, wanted)| match actual.get(register) {
+                Some(got) => format!("register {} reads back {} (wanted {})", register, got, wanted),
+                None => format!("register {} wasn't read back (wanted {})", register, wanted),
+            })
+            .collect();
+
+        if !mismatches.is_empty() {
+            self.status = CommandStatus::Mismatch;
+            self.error = Some(mismatches.join(", "));
+        }
+        self.actual = Some(actual);
+        self
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == CommandStatus::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_result() {
+        let result = CommandResult::written(&[(68, 0x1e16), (69, 0x0006)]);
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({ "status": "OK", "register": 68, "requested": { "68": 0x1e16, "69": 6 }, "elapsed_ms": 0 })
+        );
+
+        let verified = result.clone().verify(&[(68, 0x1e16), (69, 0x0006)]);
+        assert!(verified.is_ok());
+        assert_eq!(verified.actual, Some(BTreeMap::from([(68, 0x1e16), (69, 6)])));
+
+        // reads cover more than was written
+        let verified = result.verify(&[(67, 1), (68, 0x1e16), (69, 0x0007)]);
+        assert_eq!(verified.status, CommandStatus::Mismatch);
+        assert_eq!(verified.error.as_deref(), Some("register 69 reads back 7 (wanted 6)"));
+        assert_eq!(verified.actual, Some(BTreeMap::from([(68, 0x1e16), (69, 7)])));
+
+        let failed = CommandResult::failed(&anyhow!("Timeout"));
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap(),
+            serde_json::json!({ "status": "FAIL", "elapsed_ms": 0, "error": "Timeout" })
+        );
+    }
+}
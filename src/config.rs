@@ -16,6 +16,10 @@ pub struct Config {
 
     pub scheduler: Option<Scheduler>,
 
+    pub modbus_server: Option<ModbusServer>,
+
+    pub http_server: Option<HttpServer>,
+
     #[serde(default = "Config::default_loglevel")]
     pub loglevel: String,
 
@@ -29,10 +33,22 @@ pub struct Config {
 
     /// Optional path to output datalog data in JSON format
     pub datalog_file: Option<String>,
+
+    /// Optional register definitions, in doc/eg4_register_map.json's format,
+    /// replacing or adding to the builtin ones
+    pub register_map_file: Option<String>,
+
+    /// Optional directory to keep InfluxDB and database records in while
+    /// they can't be delivered
+    pub spool: Option<Spool>,
+
+    // loaded from the above by Config::new
+    #[serde(skip)]
+    pub register_map: Arc<RegisterMap>,
 }
 
 // Inverter {{{
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Inverter {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
@@ -51,6 +67,13 @@ pub struct Inverter {
     pub register_block_size: Option<u16>,
     pub delay_ms: Option<u64>,
     pub read_only: Option<bool>,
+    pub verify_writes: Option<bool>,
+    pub request_timeout: Option<u64>,
+    pub request_retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub poll: Option<Poll>,
+    pub proxy: Option<Proxy>,
+    pub capture_file: Option<String>,
 }
 impl Inverter {
     pub fn enabled(&self) -> bool {
@@ -100,17 +123,99 @@ impl Inverter {
     pub fn read_only(&self) -> bool {
         self.read_only == Some(true)  // Default to false if not specified
     }
+
+    pub fn verify_writes(&self) -> bool {
+        self.verify_writes == Some(true)  // Default to false if not specified
+    }
+
+    // seconds to wait for each reply
+    pub fn request_timeout(&self) -> u64 {
+        self.request_timeout.unwrap_or(30)
+    }
+
+    pub fn request_retries(&self) -> u32 {
+        self.request_retries.unwrap_or(2)
+    }
+
+    // doubled for each retry
+    pub fn retry_backoff_ms(&self) -> u64 {
+        self.retry_backoff_ms.unwrap_or(1000)
+    }
+
+    pub fn poll(&self) -> Poll {
+        self.poll.clone().unwrap_or_default()
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.clone().filter(|p| p.enabled())
+    }
+
+    pub fn capture_file(&self) -> Option<&str> {
+        self.capture_file.as_deref()
+    }
+} // }}}
+
+// Poll {{{
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct Poll {
+    // seconds between reads of all input/hold register blocks; unset or 0 disables
+    pub inputs_interval: Option<u64>,
+    pub hold_interval: Option<u64>,
+}
+impl Poll {
+    pub fn inputs_interval(&self) -> Option<std::time::Duration> {
+        Self::interval(self.inputs_interval)
+    }
+
+    pub fn hold_interval(&self) -> Option<std::time::Duration> {
+        Self::interval(self.hold_interval)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inputs_interval().is_some() || self.hold_interval().is_some()
+    }
+
+    fn interval(secs: Option<u64>) -> Option<std::time::Duration> {
+        secs.filter(|s| *s > 0).map(std::time::Duration::from_secs)
+    }
+} // }}}
+
+// Proxy {{{
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Proxy {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+
+    #[serde(default = "Config::default_proxy_host")]
+    pub host: String,
+    pub port: u16,
+}
+impl Proxy {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
 } // }}}
 
 // HomeAssistant {{{
 #[serde_as]
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct HomeAssistant {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
 
     #[serde(default = "Config::default_mqtt_homeassistant_prefix")]
     pub prefix: String,
+
+    // where a browser can reach the HTTP API, for the device page's link
+    pub configuration_url: Option<String>,
 }
 
 impl HomeAssistant {
@@ -121,10 +226,14 @@ impl HomeAssistant {
     pub fn prefix(&self) -> &str {
         &self.prefix
     }
+
+    pub fn configuration_url(&self) -> Option<&str> {
+        self.configuration_url.as_deref().map(|url| url.trim_end_matches('/'))
+    }
 } // }}}
 
 // Mqtt {{{
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Mqtt {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
@@ -142,6 +251,11 @@ pub struct Mqtt {
     pub homeassistant: HomeAssistant,
 
     pub publish_individual_input: Option<bool>,
+
+    // 3 (for 3.1.1) or 5
+    pub version: Option<u8>,
+    pub tls: Option<MqttTls>,
+    pub topics: Option<MqttTopics>,
 }
 impl Mqtt {
     pub fn enabled(&self) -> bool {
@@ -175,19 +289,116 @@ impl Mqtt {
     pub fn publish_individual_input(&self) -> bool {
         self.publish_individual_input == Some(true)
     }
+
+    pub fn version(&self) -> u8 {
+        self.version.unwrap_or(3)
+    }
+
+    pub fn tls(&self) -> &Option<MqttTls> {
+        &self.tls
+    }
+
+    pub fn topics(&self) -> MqttTopics {
+        self.topics.clone().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct MqttTls {
+    // PEM files. without ca_file, the system's trusted roots are used
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    // accept any server certificate; only for testing
+    pub insecure: Option<bool>,
+}
+impl MqttTls {
+    pub fn ca_file(&self) -> &Option<String> {
+        &self.ca_file
+    }
+
+    pub fn cert_file(&self) -> &Option<String> {
+        &self.cert_file
+    }
+
+    pub fn key_file(&self) -> &Option<String> {
+        &self.key_file
+    }
+
+    pub fn alpn(&self) -> Vec<String> {
+        self.alpn.clone().unwrap_or_default()
+    }
+
+    pub fn insecure(&self) -> bool {
+        self.insecure == Some(true)
+    }
+}
+
+// QoS and retain for each kind of topic we publish
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct MqttTopics {
+    #[serde(default)]
+    pub inputs: MqttTopic,
+    #[serde(default)]
+    pub hold: MqttTopic,
+    #[serde(default)]
+    pub results: MqttTopic,
+    #[serde(default)]
+    pub discovery: MqttTopic,
+    // <datalog>/status
+    #[serde(default)]
+    pub status: MqttTopic,
+    // what we subscribe to cmd/ topics with
+    pub commands_qos: Option<u8>,
+}
+impl MqttTopics {
+    pub fn commands_qos(&self) -> u8 {
+        self.commands_qos.unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct MqttTopic {
+    pub qos: Option<u8>,
+    pub retain: Option<bool>,
+}
+impl MqttTopic {
+    pub fn qos(&self) -> u8 {
+        self.qos.unwrap_or(1)
+    }
+
+    // unset leaves it to the message; settings are retained, data isn't
+    pub fn retain(&self, default: bool) -> bool {
+        self.retain.unwrap_or(default)
+    }
 } // }}}
 
 // Influx {{{
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Influx {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
 
     pub url: String,
+    // 1, 2 or 3; which write API to use and how to log in
+    pub api_version: Option<u8>,
+
+    // 1.x
     pub username: Option<String>,
     pub password: Option<String>,
-
-    pub database: String,
+    pub database: Option<String>,
+
+    // 2.x and 3.x
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+
+    pub precision: Option<InfluxPrecision>,
+    pub gzip: Option<bool>,
+    pub batch_size: Option<usize>,
+    pub flush_interval: Option<u64>,
+    pub measurements: Option<InfluxMeasurements>,
 }
 impl Influx {
     pub fn enabled(&self) -> bool {
@@ -198,6 +409,10 @@ impl Influx {
         &self.url
     }
 
+    pub fn api_version(&self) -> u8 {
+        self.api_version.unwrap_or(1)
+    }
+
     pub fn username(&self) -> &Option<String> {
         &self.username
     }
@@ -207,12 +422,95 @@ impl Influx {
     }
 
     pub fn database(&self) -> &str {
-        &self.database
+        self.database.as_deref().unwrap_or_default()
+    }
+
+    pub fn org(&self) -> &str {
+        self.org.as_deref().unwrap_or_default()
+    }
+
+    // 3.x calls it a database, so take either
+    pub fn bucket(&self) -> &str {
+        self.bucket.as_deref().or(self.database.as_deref()).unwrap_or_default()
+    }
+
+    pub fn token(&self) -> &Option<String> {
+        &self.token
+    }
+
+    pub fn precision(&self) -> InfluxPrecision {
+        self.precision.unwrap_or(InfluxPrecision::S)
+    }
+
+    pub fn gzip(&self) -> bool {
+        self.gzip.unwrap_or(false)
+    }
+
+    // points per write request
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(100).max(1)
+    }
+
+    // longest a point waits for its batch to fill, in seconds
+    pub fn flush_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.flush_interval.unwrap_or(10).max(1))
+    }
+
+    pub fn measurements(&self) -> InfluxMeasurements {
+        self.measurements.clone().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxPrecision {
+    S,
+    Ms,
+    Us,
+    Ns,
+}
+impl InfluxPrecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::S => "s",
+            Self::Ms => "ms",
+            Self::Us => "us",
+            Self::Ns => "ns",
+        }
+    }
+
+    // our timestamps are in seconds
+    pub fn scale(&self) -> i64 {
+        match self {
+            Self::S => 1,
+            Self::Ms => 1_000,
+            Self::Us => 1_000_000,
+            Self::Ns => 1_000_000_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InfluxMeasurements {
+    #[serde(default = "Config::default_influx_inputs_measurement")]
+    pub inputs: String,
+    #[serde(default = "Config::default_influx_holds_measurement")]
+    pub holds: String,
+    #[serde(default = "Config::default_influx_stats_measurement")]
+    pub stats: String,
+}
+impl Default for InfluxMeasurements {
+    fn default() -> Self {
+        Self {
+            inputs: Config::default_influx_inputs_measurement(),
+            holds: Config::default_influx_holds_measurement(),
+            stats: Config::default_influx_stats_measurement(),
+        }
     }
 } // }}}
 
 // Database {{{
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Database {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
@@ -229,13 +527,34 @@ impl Database {
     }
 } // }}}
 
+// Spool {{{
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Spool {
+    pub directory: String,
+    pub max_size_mb: Option<u64>,
+}
+impl Spool {
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    // per sink. None if max_size_mb is too big to count in bytes, which
+    // validate() rejects
+    pub fn max_size_bytes(&self) -> Option<u64> {
+        self.max_size_mb.unwrap_or(100).checked_mul(1024 * 1024)
+    }
+} // }}}
+
 // Scheduler {{{
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Scheduler {
     #[serde(default = "Config::default_enabled")]
     pub enabled: bool,
 
     pub timesync_cron: Option<String>,
+
+    #[serde(default = "Vec::new")]
+    pub jobs: Vec<Job>,
 }
 impl Scheduler {
     pub fn enabled(&self) -> bool {
@@ -245,16 +564,275 @@ impl Scheduler {
     pub fn timesync_cron(&self) -> &Option<String> {
         &self.timesync_cron
     }
+
+    /// All configured jobs, with `timesync_cron` (if set) turned into a
+    /// timesync job for every inverter.
+    pub fn jobs(&self) -> Vec<Job> {
+        let mut jobs = self.jobs.clone();
+
+        if let Some(cron) = &self.timesync_cron {
+            jobs.push(Job {
+                cron: cron.clone(),
+                datalog: None,
+                action: JobAction::Timesync,
+                command: None,
+                payload: None,
+            });
+        }
+
+        jobs
+    }
+} // }}}
+
+// Job {{{
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobAction {
+    Timesync,
+    ReadInputs,
+    ReadHold,
+    Command,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Job {
+    pub cron: String,
+
+    // limit this job to one inverter; runs against all enabled inverters if unset
+    #[serde(default, deserialize_with = "de_serial")]
+    pub datalog: Option<Serial>,
+
+    pub action: JobAction,
+
+    // for action: command, the part of the MQTT command topic after
+    // cmd/{datalog}/, eg "set/ac_charge", and its payload.
+    pub command: Option<String>,
+    pub payload: Option<String>,
+}
+impl Job {
+    pub fn cron(&self) -> &str {
+        &self.cron
+    }
+
+    pub fn datalog(&self) -> Option<Serial> {
+        self.datalog
+    }
+
+    pub fn action(&self) -> JobAction {
+        self.action
+    }
+
+    pub fn command(&self) -> &Option<String> {
+        &self.command
+    }
+
+    pub fn payload(&self) -> &Option<String> {
+        &self.payload
+    }
+
+    /// Builds the MQTT message this job would have received had it been
+    /// published by hand, so commands are parsed exactly as they are for MQTT.
+    pub fn to_message(&self, inverter: &Inverter) -> Result<mqtt::Message> {
+        let command = match &self.command {
+            Some(command) => command,
+            None => bail!("scheduler job {} has action command but no command", self.cron),
+        };
+
+        Ok(mqtt::Message {
+            topic: format!(
+                "cmd/{}/{}",
+                inverter.datalog().map(|s| s.to_string()).unwrap_or_default(),
+                command
+            ),
+            retain: false,
+            payload: self.payload.clone().unwrap_or_default(),
+        })
+    }
+} // }}}
+
+// ModbusServer {{{
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ModbusServer {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+
+    #[serde(default = "Config::default_modbus_server_host")]
+    pub host: String,
+    #[serde(default = "Config::default_modbus_server_port")]
+    pub port: u16,
+
+    // explicit unit id -> inverter mapping. if empty, unit 1 is the first
+    // enabled inverter, unit 2 the second, and so on.
+    #[serde(default = "Vec::new")]
+    pub units: Vec<ModbusUnit>,
+}
+impl ModbusServer {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn units(&self) -> &Vec<ModbusUnit> {
+        &self.units
+    }
+
+    /// Which of the given (enabled) inverters a Modbus unit id refers to.
+    pub fn inverter_for_unit(&self, unit_id: u8, inverters: &[Inverter]) -> Option<Inverter> {
+        if self.units.is_empty() {
+            return (unit_id as usize)
+                .checked_sub(1)
+                .and_then(|i| inverters.get(i))
+                .cloned();
+        }
+
+        let datalog = self.units.iter().find(|u| u.unit_id == unit_id)?.datalog?;
+        inverters.iter().find(|i| i.datalog() == Some(datalog)).cloned()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ModbusUnit {
+    pub unit_id: u8,
+    #[serde(deserialize_with = "de_serial")]
+    pub datalog: Option<Serial>,
+} // }}}
+
+// HttpServer {{{
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct HttpServer {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+
+    // there's no authentication, so only listen locally unless told otherwise
+    #[serde(default = "Config::default_http_server_host")]
+    pub host: String,
+    #[serde(default = "Config::default_http_server_port")]
+    pub port: u16,
+}
+impl HttpServer {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+} // }}}
+
+// ConfigDiff {{{
+/// What changed between two configs, in terms of which components need
+/// restarting. Inverters are matched up by host, as Inverter::config() does.
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+    pub added_inverters: Vec<Inverter>,
+    pub removed_inverters: Vec<Inverter>,
+    pub changed_inverters: Vec<Inverter>,
+    pub mqtt: bool,
+    pub influx: bool,
+    pub added_databases: Vec<Database>,
+    pub removed_databases: Vec<Database>,
+    // changed sections we can't apply without restarting the bridge
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn new(old: &Config, new: &Config) -> Self {
+        let enabled = |c: &Config| -> Vec<Inverter> {
+            c.inverters.iter().filter(|i| i.enabled()).cloned().collect()
+        };
+        let (old_inverters, new_inverters) = (enabled(old), enabled(new));
+        let find = |inverters: &[Inverter], host: &str| inverters.iter().find(|i| i.host() == host).cloned();
+
+        let mut diff = Self::default();
+
+        for inverter in &new_inverters {
+            match find(&old_inverters, inverter.host()) {
+                None => diff.added_inverters.push(inverter.clone()),
+                Some(old) if old != *inverter => diff.changed_inverters.push(inverter.clone()),
+                Some(_) => {}
+            }
+        }
+        for inverter in &old_inverters {
+            if find(&new_inverters, inverter.host()).is_none() {
+                diff.removed_inverters.push(inverter.clone());
+            }
+        }
+
+        // MQTT subscribes to, and publishes discovery for, each inverter's
+        // datalog, so it needs to know about those coming and going too
+        let datalogs = |inverters: &[Inverter]| inverters.iter().map(|i| i.datalog()).collect::<Vec<_>>();
+        // the register map feeds HA discovery too
+        diff.mqtt = old.mqtt != new.mqtt
+            || old.homeassistant_enabled != new.homeassistant_enabled
+            || old.register_map != new.register_map
+            || datalogs(&old_inverters) != datalogs(&new_inverters);
+
+        diff.influx = old.influx != new.influx;
+
+        let enabled = |c: &Config| -> Vec<Database> {
+            c.databases.iter().filter(|d| d.enabled()).cloned().collect()
+        };
+        let (old_databases, new_databases) = (enabled(old), enabled(new));
+        diff.added_databases = new_databases.iter().filter(|d| !old_databases.contains(d)).cloned().collect();
+        diff.removed_databases = old_databases.iter().filter(|d| !new_databases.contains(d)).cloned().collect();
+
+        if old.scheduler != new.scheduler {
+            diff.restart_required.push("scheduler");
+        }
+        if old.modbus_server != new.modbus_server {
+            diff.restart_required.push("modbus_server");
+        }
+        if old.http_server != new.http_server {
+            diff.restart_required.push("http_server");
+        }
+        if old.datalog_file != new.datalog_file {
+            diff.restart_required.push("datalog_file");
+        }
+        if old.loglevel != new.loglevel {
+            diff.restart_required.push("loglevel");
+        }
+        if old.spool != new.spool {
+            diff.restart_required.push("spool");
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_inverters.is_empty()
+            && self.removed_inverters.is_empty()
+            && self.changed_inverters.is_empty()
+            && !self.mqtt
+            && !self.influx
+            && self.added_databases.is_empty()
+            && self.removed_databases.is_empty()
+            && self.restart_required.is_empty()
+    }
 } // }}}
 
 pub struct ConfigWrapper {
     config: Arc<Mutex<Config>>,
+    // the inverters as the file had them, before any serial or datalog was
+    // learned at runtime; reload() uses these to tell what the user changed
+    loaded: Arc<Mutex<Vec<Inverter>>>,
 }
 
 impl Clone for ConfigWrapper {
     fn clone(&self) -> Self {
         Self {
             config: self.config.clone(),
+            loaded: self.loaded.clone(),
         }
     }
 }
@@ -262,17 +840,50 @@ impl Clone for ConfigWrapper {
 impl ConfigWrapper {
     pub fn new(file: String) -> Result<Self> {
         let config = Config::new(file)?;
-        Ok(Self {
-            config: Arc::new(Mutex::new(config)),
-        })
+        Ok(Self::from_config(config))
     }
 
     pub fn from_config(config: Config) -> Self {
         Self {
+            loaded: Arc::new(Mutex::new(config.inverters.clone())),
             config: Arc::new(Mutex::new(config)),
         }
     }
 
+    /// Re-reads the config file and swaps it in, returning what changed. A
+    /// file that doesn't parse or validate leaves the running config alone.
+    /// Serials and datalogs learned from an inverter are kept, unless the
+    /// file now says something different for them.
+    pub fn reload(&self, file: &str) -> Result<ConfigDiff> {
+        let mut new = Config::new(file.to_string())?;
+        let mut config = self.config.lock().unwrap();
+        let mut loaded = self.loaded.lock().unwrap();
+
+        let file_inverters = new.inverters.clone();
+        for inverter in &mut new.inverters {
+            let host = inverter.host().to_string();
+            let (Some(was), Some(running)) = (
+                loaded.iter().find(|i| i.host() == host),
+                config.inverters.iter().find(|i| i.host() == host),
+            ) else {
+                continue;
+            };
+
+            if inverter.serial == was.serial {
+                inverter.serial = running.serial;
+            }
+            if inverter.datalog == was.datalog {
+                inverter.datalog = running.datalog;
+            }
+        }
+        *loaded = file_inverters;
+
+        let diff = ConfigDiff::new(&config, &new);
+        *config = new;
+
+        Ok(diff)
+    }
+
     pub fn inverters(&self) -> Vec<Inverter> {
         self.config.lock().unwrap().inverters.clone()
     }
@@ -336,6 +947,14 @@ impl ConfigWrapper {
         self.config.lock().unwrap().scheduler.clone()
     }
 
+    pub fn modbus_server(&self) -> Option<ModbusServer> {
+        self.config.lock().unwrap().modbus_server.clone()
+    }
+
+    pub fn http_server(&self) -> Option<HttpServer> {
+        self.config.lock().unwrap().http_server.clone()
+    }
+
     pub fn loglevel(&self) -> String {
         self.config.lock().unwrap().loglevel.clone()
     }
@@ -387,6 +1006,14 @@ impl ConfigWrapper {
     pub fn strict_data_check(&self) -> bool {
         self.config.lock().unwrap().strict_data_check
     }
+
+    pub fn spool(&self) -> Option<Spool> {
+        self.config.lock().unwrap().spool.clone()
+    }
+
+    pub fn register_map(&self) -> Arc<RegisterMap> {
+        self.config.lock().unwrap().register_map.clone()
+    }
 }
 
 impl Config {
@@ -395,7 +1022,8 @@ impl Config {
         let content = std::fs::read_to_string(&file)
             .map_err(|err| anyhow!("config.rs:error reading {}: {}", file, err))?;
 
-        let config: Self = serde_yaml::from_str(&content)?;
+        let mut config: Self = serde_yaml::from_str(&content)?;
+        config.register_map = Arc::new(RegisterMap::load(config.register_map_file.as_deref())?);
         
         // Log configuration details
         info!("Configuration loaded successfully:");
@@ -415,12 +1043,27 @@ impl Config {
             info!("      Register Block Size: {}", inv.register_block_size.unwrap_or(40));
             info!("      Delay MS: {}ms", inv.delay_ms.unwrap_or(1000));
             info!("      Read Only: {}", inv.read_only.unwrap_or(false));
+            let poll = inv.poll();
+            if poll.enabled() {
+                info!("      Poll Inputs: {}", poll.inputs_interval.map(|s| format!("{}s", s)).unwrap_or_else(|| "disabled".to_string()));
+                info!("      Poll Hold: {}", poll.hold_interval.map(|s| format!("{}s", s)).unwrap_or_else(|| "disabled".to_string()));
+            }
+            if let Some(proxy) = inv.proxy() {
+                info!("      Proxy: {}:{}", proxy.host, proxy.port);
+            }
+            if let Some(capture_file) = &inv.capture_file {
+                info!("      Capture File: {}", capture_file);
+            }
         }
 
         info!("  MQTT: {}", if config.mqtt.enabled { "enabled" } else { "disabled" });
         if config.mqtt.enabled {
             info!("    Host: {}", config.mqtt.host);
             info!("    Port: {}", config.mqtt.port);
+            info!("    Version: {}", config.mqtt.version());
+            if let Some(tls) = config.mqtt.tls() {
+                info!("    TLS: {}", if tls.insecure() { "enabled, not verifying the server" } else { "enabled" });
+            }
             info!("    Namespace: {}", config.mqtt.namespace);
             info!("    Home Assistant: {}", if config.mqtt.homeassistant.enabled { "enabled" } else { "disabled" });
         }
@@ -428,7 +1071,13 @@ impl Config {
         info!("  InfluxDB: {}", if config.influx.enabled { "enabled" } else { "disabled" });
         if config.influx.enabled {
             info!("    URL: {}", config.influx.url);
-            info!("    Database: {}", config.influx.database);
+            info!("    API version: {}", config.influx.api_version());
+            if config.influx.api_version() == 1 {
+                info!("    Database: {}", config.influx.database());
+            } else {
+                info!("    Org: {}", config.influx.org());
+                info!("    Bucket: {}", config.influx.bucket());
+            }
         }
 
         info!("  Databases: {} configured, {} enabled",
@@ -447,6 +1096,28 @@ impl Config {
             if let Some(cron) = &scheduler.timesync_cron {
                 info!("    Timesync Cron: {}", cron);
             }
+            for (i, job) in scheduler.jobs.iter().enumerate() {
+                info!("    Job[{}]:", i);
+                info!("      Cron: {}", job.cron);
+                info!("      Action: {:?}", job.action);
+                info!("      Datalog: {}", job.datalog.map(|s| s.to_string()).unwrap_or_else(|| "all".to_string()));
+                if let Some(command) = &job.command {
+                    info!("      Command: {} = {}", command, job.payload.clone().unwrap_or_default());
+                }
+            }
+        }
+
+        info!("  Modbus Server: {}", if config.modbus_server.as_ref().map(|m| m.enabled).unwrap_or(false) { "enabled" } else { "disabled" });
+        if let Some(modbus_server) = config.modbus_server.as_ref().filter(|m| m.enabled) {
+            info!("    Listen: {}:{}", modbus_server.host, modbus_server.port);
+            for unit in &modbus_server.units {
+                info!("    Unit {}: {}", unit.unit_id, unit.datalog.map(|s| s.to_string()).unwrap_or_default());
+            }
+        }
+
+        info!("  HTTP Server: {}", if config.http_server.as_ref().map(|h| h.enabled).unwrap_or(false) { "enabled" } else { "disabled" });
+        if let Some(http_server) = config.http_server.as_ref().filter(|h| h.enabled) {
+            info!("    Listen: {}:{}", http_server.host, http_server.port);
         }
 
         info!("  Global Read Only: {}", config.read_only);
@@ -465,6 +1136,27 @@ impl Config {
             if self.mqtt.host.is_empty() {
                 return Err(anyhow!("config.rs:MQTT host cannot be empty"));
             }
+            if ![3, 5].contains(&self.mqtt.version()) {
+                return Err(anyhow!("config.rs:mqtt.version must be 3 or 5, not {}", self.mqtt.version()));
+            }
+            if let Some(tls) = self.mqtt.tls() {
+                if tls.cert_file().is_some() != tls.key_file().is_some() {
+                    return Err(anyhow!("config.rs:mqtt.tls.cert_file and mqtt.tls.key_file must be given together"));
+                }
+            }
+            let topics = self.mqtt.topics();
+            for (name, qos) in [
+                ("inputs.qos", topics.inputs.qos()),
+                ("hold.qos", topics.hold.qos()),
+                ("results.qos", topics.results.qos()),
+                ("discovery.qos", topics.discovery.qos()),
+                ("status.qos", topics.status.qos()),
+                ("commands_qos", topics.commands_qos()),
+            ] {
+                if qos > 2 {
+                    return Err(anyhow!("config.rs:mqtt.topics.{} must be 0, 1 or 2, not {}", name, qos));
+                }
+            }
         }
 
         // Validate InfluxDB configuration
@@ -472,8 +1164,24 @@ impl Config {
             if let Err(e) = url::Url::parse(&self.influx.url) {
                 return Err(anyhow!("config.rs:Invalid InfluxDB URL: {}", e));
             }
-            if self.influx.database.is_empty() {
-                return Err(anyhow!("config.rs:InfluxDB database name cannot be empty"));
+            match self.influx.api_version() {
+                1 => {
+                    if self.influx.database().is_empty() {
+                        return Err(anyhow!("config.rs:InfluxDB database name cannot be empty"));
+                    }
+                }
+                2 | 3 => {
+                    if self.influx.bucket().is_empty() {
+                        return Err(anyhow!("config.rs:influx.bucket is required with api_version {}", self.influx.api_version()));
+                    }
+                    if self.influx.api_version() == 2 && self.influx.org().is_empty() {
+                        return Err(anyhow!("config.rs:influx.org is required with api_version 2"));
+                    }
+                    if self.influx.token().is_none() {
+                        return Err(anyhow!("config.rs:influx.token is required with api_version {}", self.influx.api_version()));
+                    }
+                }
+                v => return Err(anyhow!("config.rs:influx.api_version must be 1, 2 or 3, not {}", v)),
             }
         }
 
@@ -498,6 +1206,17 @@ impl Config {
                 if inv.read_timeout.unwrap_or(900) == 0 {
                     return Err(anyhow!("config.rs:Invalid read timeout: 0"));
                 }
+                if !(1..=40).contains(&inv.register_block_size()) {
+                    return Err(anyhow!("config.rs:inverter[{}].register_block_size must be between 1 and 40", i));
+                }
+                if inv.request_retries() > 10 {
+                    return Err(anyhow!("config.rs:inverter[{}].request_retries must be at most 10", i));
+                }
+                if let Some(proxy) = inv.proxy() {
+                    if proxy.port == 0 {
+                        return Err(anyhow!("config.rs:inverter[{}].proxy.port must be between 1 and 65535", i));
+                    }
+                }
             }
         }
 
@@ -509,6 +1228,44 @@ impl Config {
                         return Err(anyhow!("config.rs:Scheduler cron expression cannot be empty"));
                     }
                 }
+
+                for job in scheduler.jobs() {
+                    if let Err(e) = cron_parser::parse(job.cron(), &chrono::Utc::now()) {
+                        return Err(anyhow!("config.rs:Invalid scheduler cron expression {}: {:?}", job.cron(), e));
+                    }
+                    if job.action() == JobAction::Command && job.command().is_none() {
+                        return Err(anyhow!("config.rs:Scheduler job {} needs a command", job.cron()));
+                    }
+                }
+            }
+        }
+
+        // Validate Modbus server configuration
+        if let Some(modbus_server) = &self.modbus_server {
+            if modbus_server.enabled {
+                if modbus_server.port == 0 {
+                    return Err(anyhow!("config.rs:modbus_server.port must be between 1 and 65535"));
+                }
+                for unit in &modbus_server.units {
+                    if unit.unit_id == 0 || unit.unit_id > 247 {
+                        return Err(anyhow!("config.rs:modbus_server unit_id {} must be between 1 and 247", unit.unit_id));
+                    }
+                    if unit.datalog.is_none() {
+                        return Err(anyhow!("config.rs:modbus_server unit {} needs a datalog", unit.unit_id));
+                    }
+                }
+            }
+        }
+
+        if let Some(http_server) = &self.http_server {
+            if http_server.enabled && http_server.port == 0 {
+                return Err(anyhow!("config.rs:http_server.port must be between 1 and 65535"));
+            }
+        }
+
+        if let Some(spool) = &self.spool {
+            if spool.max_size_bytes().is_none() {
+                return Err(anyhow!("config.rs:spool.max_size_mb {} is too large", spool.max_size_mb.unwrap_or(100)));
             }
         }
 
@@ -526,6 +1283,7 @@ impl Config {
         HomeAssistant {
             enabled: Self::default_enabled(),
             prefix: Self::default_mqtt_homeassistant_prefix(),
+            configuration_url: None,
         }
     }
 
@@ -533,6 +1291,38 @@ impl Config {
         "homeassistant".to_string()
     }
 
+    fn default_proxy_host() -> String {
+        "0.0.0.0".to_string()
+    }
+
+    fn default_modbus_server_host() -> String {
+        "0.0.0.0".to_string()
+    }
+
+    fn default_modbus_server_port() -> u16 {
+        502
+    }
+
+    fn default_http_server_host() -> String {
+        "127.0.0.1".to_string()
+    }
+
+    fn default_http_server_port() -> u16 {
+        8080
+    }
+
+    fn default_influx_inputs_measurement() -> String {
+        "inputs".to_string()
+    }
+
+    fn default_influx_holds_measurement() -> String {
+        "holds".to_string()
+    }
+
+    fn default_influx_stats_measurement() -> String {
+        "bridge".to_string()
+    }
+
     fn default_enabled() -> bool {
         true
     }
@@ -561,3 +1351,199 @@ where
         Serial::from_str(&s).map(Some).map_err(serde::de::Error::custom)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scheduler_jobs() {
+        let input = json!({
+            "timesync_cron": "0 0 * * *",
+            "jobs": [
+                { "cron": "*/5 * * * *", "action": "read_inputs", "datalog": "TESTDATALO" },
+                { "cron": "0 23 * * *", "action": "command", "command": "set/ac_charge", "payload": "on" },
+            ]
+        });
+        let scheduler: Scheduler = serde_json::from_value(input).unwrap();
+        assert!(scheduler.enabled());
+
+        let jobs = scheduler.jobs();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[0].action(), JobAction::ReadInputs);
+        assert_eq!(jobs[0].datalog(), Some(Serial::from_str("TESTDATALO").unwrap()));
+        assert_eq!(jobs[1].action(), JobAction::Command);
+        assert_eq!(jobs[1].datalog(), None);
+        assert_eq!(jobs[2].action(), JobAction::Timesync);
+        assert_eq!(jobs[2].cron(), "0 0 * * *");
+    }
+
+    #[test]
+    fn inverter_poll() {
+        let poll = Poll::default();
+        assert!(!poll.enabled());
+
+        let input = json!({ "inputs_interval": 30, "hold_interval": 0 });
+        let poll: Poll = serde_json::from_value(input).unwrap();
+        assert!(poll.enabled());
+        assert_eq!(poll.inputs_interval(), Some(std::time::Duration::from_secs(30)));
+        assert_eq!(poll.hold_interval(), None);
+    }
+
+    #[test]
+    fn modbus_server_units() {
+        let inverters: Vec<Inverter> = serde_json::from_value(json!([
+            { "host": "a", "port": 8000, "serial": "5555555555", "datalog": "2222222222" },
+            { "host": "b", "port": 8000, "serial": "5555555556", "datalog": "2222222223" },
+        ]))
+        .unwrap();
+
+        // no units configured: unit ids follow inverter order
+        let modbus_server: ModbusServer = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(modbus_server.port(), 502);
+        assert_eq!(modbus_server.inverter_for_unit(2, &inverters).unwrap().host(), "b");
+        assert!(modbus_server.inverter_for_unit(0, &inverters).is_none());
+        assert!(modbus_server.inverter_for_unit(3, &inverters).is_none());
+
+        let modbus_server: ModbusServer = serde_json::from_value(json!({
+            "port": 5020,
+            "units": [{ "unit_id": 10, "datalog": "2222222223" }],
+        }))
+        .unwrap();
+        assert_eq!(modbus_server.inverter_for_unit(10, &inverters).unwrap().host(), "b");
+        assert!(modbus_server.inverter_for_unit(1, &inverters).is_none());
+    }
+
+    #[test]
+    fn inverter_validation() {
+        let mut config = Config::new("config.yaml.example".to_string()).unwrap();
+        config.inverters[0].request_retries = Some(10);
+        assert!(config.validate().is_ok());
+
+        config.inverters[0].request_retries = Some(11);
+        assert!(config.validate().is_err());
+        config.inverters[0].request_retries = None;
+
+        // block reads step by register_block_size, which panics on 0
+        config.inverters[0].register_block_size = Some(0);
+        assert!(config.validate().is_err());
+        config.inverters[0].register_block_size = Some(41);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn spool_validation() {
+        let mut config = Config::new("config.yaml.example".to_string()).unwrap();
+        config.spool = Some(Spool {
+            directory: "/var/spool/eg4-bridge".to_string(),
+            max_size_mb: Some(1024),
+        });
+        assert!(config.validate().is_ok());
+        assert_eq!(config.spool.as_ref().unwrap().max_size_bytes(), Some(1 << 30));
+
+        config.spool.as_mut().unwrap().max_size_mb = Some(u64::MAX);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            format!("config.rs:spool.max_size_mb {} is too large", u64::MAX)
+        );
+    }
+
+    #[test]
+    fn mqtt_validation() {
+        let mut config = Config::new("config.yaml.example".to_string()).unwrap();
+        config.mqtt.enabled = true;
+        let mut topics = MqttTopics::default();
+        topics.status.qos = Some(2);
+        config.mqtt.topics = Some(topics.clone());
+        assert!(config.validate().is_ok());
+
+        topics.status.qos = Some(3);
+        config.mqtt.topics = Some(topics);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "config.rs:mqtt.topics.status.qos must be 0, 1 or 2, not 3"
+        );
+    }
+
+    #[test]
+    fn http_server_defaults() {
+        let http_server: HttpServer = serde_json::from_value(json!({})).unwrap();
+        assert!(http_server.enabled());
+        assert_eq!(http_server.host(), "127.0.0.1");
+        assert_eq!(http_server.port(), 8080);
+    }
+
+    #[test]
+    fn config_reload_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.yaml").to_str().unwrap().to_owned();
+
+        let mut yaml: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string("config.yaml.example").unwrap()).unwrap();
+        std::fs::write(&file, serde_yaml::to_string(&yaml).unwrap()).unwrap();
+        let config = ConfigWrapper::new(file.clone()).unwrap();
+
+        // unchanged file, nothing to do
+        assert!(config.reload(&file).unwrap().is_empty());
+
+        let extra: serde_yaml::Value = serde_yaml::from_str(
+            "{ host: 192.168.0.11, port: 8000, serial: 5555555556, datalog: 2222222223 }",
+        )
+        .unwrap();
+        yaml["inverters"].as_sequence_mut().unwrap().push(extra);
+        yaml["influx"]["url"] = "http://influx:8086".into();
+        std::fs::write(&file, serde_yaml::to_string(&yaml).unwrap()).unwrap();
+
+        let diff = config.reload(&file).unwrap();
+        assert_eq!(diff.added_inverters.len(), 1);
+        assert_eq!(diff.added_inverters[0].host(), "192.168.0.11");
+        assert!(diff.removed_inverters.is_empty());
+        assert!(diff.changed_inverters.is_empty());
+        assert!(diff.mqtt); // it has a new datalog to subscribe to
+        assert!(diff.influx);
+        assert!(diff.added_databases.is_empty() && diff.removed_databases.is_empty());
+        assert!(diff.restart_required.is_empty());
+        assert_eq!(config.influx().url(), "http://influx:8086");
+
+        // broken file is rejected and the running config is untouched
+        std::fs::write(&file, "inverters: [").unwrap();
+        assert!(config.reload(&file).is_err());
+        assert_eq!(config.enabled_inverters().len(), 2);
+        assert_eq!(config.influx().url(), "http://influx:8086");
+    }
+
+    #[test]
+    fn config_reload_keeps_learned_serials() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.yaml").to_str().unwrap().to_owned();
+
+        let mut yaml: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string("config.yaml.example").unwrap()).unwrap();
+        std::fs::write(&file, serde_yaml::to_string(&yaml).unwrap()).unwrap();
+        let config = ConfigWrapper::new(file.clone()).unwrap();
+
+        let learned_serial = Serial::from_str("5555555557").unwrap();
+        let learned_datalog = Serial::from_str("2222222224").unwrap();
+        config
+            .update_inverter_serial(Serial::from_str("5555555555").unwrap(), learned_serial)
+            .unwrap();
+        config
+            .update_inverter_datalog(Serial::from_str("2222222222").unwrap(), learned_datalog)
+            .unwrap();
+
+        // the file still has the old values, so the learned ones stay
+        assert!(config.reload(&file).unwrap().is_empty());
+        let inverter = config.inverter_with_host("192.168.0.10").unwrap();
+        assert_eq!(inverter.serial(), Some(learned_serial));
+        assert_eq!(inverter.datalog(), Some(learned_datalog));
+
+        // an edited datalog wins over the learned one
+        yaml["inverters"][0]["datalog"] = "2222222225".into();
+        std::fs::write(&file, serde_yaml::to_string(&yaml).unwrap()).unwrap();
+        assert_eq!(config.reload(&file).unwrap().changed_inverters.len(), 1);
+        let inverter = config.inverter_with_host("192.168.0.10").unwrap();
+        assert_eq!(inverter.serial(), Some(learned_serial));
+        assert_eq!(inverter.datalog(), Some(Serial::from_str("2222222225").unwrap()));
+    }
+}
@@ -8,6 +8,7 @@ pub mod set_ac_first_time;
 pub mod set_charge_priority_time;
 pub mod set_forced_discharge_time;
 pub mod set_hold;
+pub mod set_holds;
 pub mod time_register_ops;
 pub mod timesync;
 pub mod update_hold;
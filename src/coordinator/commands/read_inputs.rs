@@ -1,15 +1,14 @@
 use crate::prelude::*;
 
-use eg4::{
-    inverter::WaitForReply,
-    packet::{DeviceFunction, TranslatedData},
-};
+use eg4::packet::{DeviceFunction, TranslatedData};
+use request_queue::Priority;
 
 pub struct ReadInputs {
     channels: Channels,
     inverter: config::Inverter,
     register: u16,
     count: u16,
+    priority: Priority,
 }
 
 impl ReadInputs {
@@ -22,9 +21,16 @@ impl ReadInputs {
             inverter,
             register: register.into(),
             count,
+            priority: Priority::Read,
         }
     }
 
+    /// Reads default to Priority::Read; polling asks for Priority::Background.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub async fn run(&self) -> Result<Packet> {
         let packet = Packet::TranslatedData(TranslatedData {
             datalog: self.inverter.datalog().expect("datalog must be set for read_inputs command"),
@@ -34,18 +40,6 @@ impl ReadInputs {
             values: vec![self.count as u8, 0],
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        let packet = receiver.wait_for_reply(&packet).await?;
-        Ok(packet)
+        RequestQueue::request(&self.channels, packet, self.priority).await
     }
 }
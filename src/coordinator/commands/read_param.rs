@@ -1,11 +1,12 @@
 use crate::prelude::*;
 
-use eg4::inverter::WaitForReply;
+use request_queue::Priority;
 
 pub struct ReadParam {
     channels: Channels,
     inverter: config::Inverter,
     register: u16,
+    priority: Priority,
 }
 
 impl ReadParam {
@@ -17,9 +18,16 @@ impl ReadParam {
             channels,
             inverter,
             register: register.into(),
+            priority: Priority::Read,
         }
     }
 
+    /// Reads default to Priority::Read; polling asks for Priority::Background.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub async fn run(&self) -> Result<Packet> {
         let packet = Packet::ReadParam(eg4::packet::ReadParam {
             datalog: self.inverter.datalog().expect("datalog must be set for read_param command"),
@@ -27,17 +35,6 @@ impl ReadParam {
             values: vec![], // unused
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        receiver.wait_for_reply(&packet).await
+        RequestQueue::request(&self.channels, packet, self.priority).await
     }
 }
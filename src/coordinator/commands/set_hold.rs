@@ -1,9 +1,7 @@
 use crate::prelude::*;
 
-use eg4::{
-    inverter::WaitForReply,
-    packet::{DeviceFunction, TranslatedData},
-};
+use eg4::packet::{DeviceFunction, TranslatedData};
+use request_queue::Priority;
 
 pub struct SetHold {
     channels: Channels,
@@ -40,18 +38,7 @@ impl SetHold {
             values: self.value.to_le_bytes().to_vec(),
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        let packet = receiver.wait_for_reply(&packet).await?;
+        let packet = RequestQueue::request(&self.channels, packet, Priority::Write).await?;
         if packet.value() != self.value {
             bail!(
                 "failed to set register {}, got back value {} (wanted {})",
@@ -0,0 +1,197 @@
+use crate::prelude::*;
+
+use eg4::packet::{DeviceFunction, TranslatedData};
+use request_queue::Priority;
+
+use crate::coordinator::commands::read_hold::ReadHold;
+use crate::register_cache::{RegisterCache, RegisterType};
+
+use std::collections::BTreeMap;
+
+/// Writes several hold registers with WriteMulti, one frame per run of
+/// consecutive registers within a block, reading each back to check it.
+/// If anything fails, every register touched is put back as it was.
+pub struct SetHolds {
+    channels: Channels,
+    inverter: config::Inverter,
+    values: BTreeMap<u16, u16>,
+}
+
+impl SetHolds {
+    pub fn new(channels: Channels, inverter: config::Inverter, values: BTreeMap<u16, u16>) -> Self {
+        Self {
+            channels,
+            inverter,
+            values,
+        }
+    }
+
+    /// Returns the register/value pairs written.
+    pub async fn run(&self) -> Result<Vec<(u16, u16)>> {
+        if self.inverter.read_only() {
+            bail!(
+                "Cannot set holding registers {:?} - inverter {} is in read-only mode",
+                self.values.keys().collect::<Vec<_>>(),
+                self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
+            );
+        }
+
+        let runs = Self::runs(&self.values, self.inverter.register_block_size());
+        let previous = self.previous_values(&runs).await?;
+
+        for (n, run) in runs.iter().enumerate() {
+            // a refused write changed nothing, but one that was accepted and
+            // doesn't read back right might have been partly applied
+            let failed = match self.write(run).await {
+                Err(err) => Some((err, n)),
+                Ok(()) => self.verify(run).await.err().map(|err| (err, n + 1)),
+            };
+
+            if let Some((err, applied)) = failed {
+                match self.rollback(&runs[..applied], &previous).await {
+                    Ok(()) if applied == 0 => bail!("{}; nothing was changed", err),
+                    Ok(()) => bail!("{}; previous values restored", err),
+                    Err(rollback_err) => bail!("{}; restoring previous values failed too: {}", err, rollback_err),
+                }
+            }
+        }
+
+        Ok(self.values.iter().map(|(register, value)| (*register, *value)).collect())
+    }
+
+    /// Consecutive registers, split wherever a block boundary falls between
+    /// them, so each run is one request.
+    pub fn runs(values: &BTreeMap<u16, u16>, block_size: u16) -> Vec<Vec<(u16, u16)>> {
+        let block_size = block_size.max(1);
+        let mut runs: Vec<Vec<(u16, u16)>> = Vec::new();
+
+        for (register, value) in values {
+            match runs.last_mut() {
+                Some(run)
+                    if run.last().map(|(last, _)| last + 1) == Some(*register)
+                        && run[0].0 / block_size == register / block_size =>
+                {
+                    run.push((*register, *value))
+                }
+                _ => runs.push(vec![(*register, *value)]),
+            }
+        }
+
+        runs
+    }
+
+    // what to restore on failure: cached values where we have them, read
+    // from the inverter where we don't
+    async fn previous_values(&self, runs: &[Vec<(u16, u16)>]) -> Result<BTreeMap<u16, u16>> {
+        let datalog = self.inverter.datalog().expect("datalog must be set for set_holds command");
+        let mut previous = BTreeMap::new();
+
+        for run in runs {
+            let mut missing = false;
+            for (register, _) in run {
+                match RegisterCache::get(&self.channels, datalog, RegisterType::Hold, *register).await {
+                    Some(entry) => {
+                        previous.insert(*register, entry.value);
+                    }
+                    None => missing = true,
+                }
+            }
+
+            if missing {
+                previous.extend(self.read(run).await?);
+            }
+        }
+
+        Ok(previous)
+    }
+
+    async fn verify(&self, run: &[(u16, u16)]) -> Result<()> {
+        let actual = self.read(run).await?;
+        for (register, value) in run {
+            let got = actual.iter().find(|(r, _)| r == register).map(|(_, v)| *v);
+            if got != Some(*value) {
+                bail!("register {} reads back {:?} after writing {}", register, got, value);
+            }
+        }
+
+        Ok(())
+    }
+
+    // keeps going past failures, to put back as much as it can
+    async fn rollback(&self, runs: &[Vec<(u16, u16)>], previous: &BTreeMap<u16, u16>) -> Result<()> {
+        let mut errors = Vec::new();
+
+        for run in runs {
+            let restore: Vec<(u16, u16)> = run
+                .iter()
+                .filter_map(|(register, _)| previous.get(register).map(|value| (*register, *value)))
+                .collect();
+            if restore.is_empty() {
+                continue;
+            }
+            warn!("set_holds: restoring {:?}", restore);
+
+            if let Err(err) = self.write(&restore).await {
+                errors.push(err.to_string());
+            } else if let Err(err) = self.verify(&restore).await {
+                errors.push(err.to_string());
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join(", "));
+        }
+
+        Ok(())
+    }
+
+    async fn write(&self, run: &[(u16, u16)]) -> Result<()> {
+        let packet = Packet::TranslatedData(TranslatedData {
+            datalog: self.inverter.datalog().expect("datalog must be set for set_holds command"),
+            device_function: DeviceFunction::WriteMulti,
+            inverter: self.inverter.serial().expect("serial must be set for set_holds command"),
+            register: run[0].0,
+            values: run.iter().flat_map(|(_, value)| value.to_le_bytes()).collect(),
+        });
+
+        // WriteMulti replies with the number of registers written
+        let reply = RequestQueue::request(&self.channels, packet, Priority::Write).await?;
+        if reply.value() as usize != run.len() {
+            bail!(
+                "failed to set registers {}-{}, inverter wrote {} of {}",
+                run[0].0,
+                run[run.len() - 1].0,
+                reply.value(),
+                run.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn read(&self, run: &[(u16, u16)]) -> Result<Vec<(u16, u16)>> {
+        let packet = ReadHold::new(self.channels.clone(), self.inverter.clone(), run[0].0, run.len() as u16)
+            .with_priority(Priority::Write)
+            .run()
+            .await?;
+
+        match packet {
+            Packet::TranslatedData(td) => Ok(td.pairs()),
+            _ => bail!("set_holds.rs:unexpected reply to ReadHold {:?}", packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs() {
+        let values = BTreeMap::from([(21, 1), (38, 2), (39, 3), (40, 4), (41, 5), (68, 6)]);
+        assert_eq!(
+            SetHolds::runs(&values, 40),
+            vec![vec![(21, 1)], vec![(38, 2), (39, 3)], vec![(40, 4), (41, 5)], vec![(68, 6)]]
+        );
+    }
+}
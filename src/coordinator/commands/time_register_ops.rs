@@ -1,9 +1,7 @@
 use crate::prelude::*;
 
-use eg4::{
-    inverter::WaitForReply,
-    packet::{DeviceFunction, TranslatedData},
-};
+use eg4::packet::{DeviceFunction, TranslatedData};
+use request_queue::Priority;
 
 use serde::Serialize;
 
@@ -12,6 +10,7 @@ pub struct ReadTimeRegister {
     inverter: config::Inverter,
     config: ConfigWrapper,
     action: Action,
+    priority: Priority,
 }
 
 #[derive(Debug, Serialize)]
@@ -66,9 +65,16 @@ impl ReadTimeRegister {
             inverter,
             config,
             action,
+            priority: Priority::Read,
         }
     }
 
+    /// Reads default to Priority::Read; reads on connect ask for Priority::Background.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub async fn run(&self) -> Result<()> {
         let packet = Packet::TranslatedData(TranslatedData {
             datalog: self.inverter.datalog().expect("datalog must be set for time_register_ops command"),
@@ -78,18 +84,7 @@ impl ReadTimeRegister {
             values: vec![2, 0],
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        let reply = receiver.wait_for_reply(&packet).await?;
+        let reply = RequestQueue::request(&self.channels, packet, self.priority).await?;
 
         if let Packet::TranslatedData(td) = reply {
             // Only send MQTT message if MQTT is enabled
@@ -142,10 +137,12 @@ impl SetTimeRegister {
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
-        self.set_register(self.action.register()?, &self.values[0..2])
+    /// Returns the register/value pairs written.
+    pub async fn run(&self) -> Result<Vec<(u16, u16)>> {
+        let register = self.action.register()?;
+        self.set_register(register, &self.values[0..2])
             .await?;
-        self.set_register(self.action.register()? + 1, &self.values[2..4])
+        self.set_register(register + 1, &self.values[2..4])
             .await?;
 
         // Only send MQTT message if MQTT is enabled
@@ -166,7 +163,10 @@ impl SetTimeRegister {
             }
         }
 
-        Ok(())
+        Ok(vec![
+            (register, Utils::u16ify(&self.values, 0)),
+            (register + 1, Utils::u16ify(&self.values, 2)),
+        ])
     }
 
     async fn set_register(&self, register: u16, values: &[u8]) -> Result<()> {
@@ -184,18 +184,7 @@ impl SetTimeRegister {
             register,
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        let reply = receiver.wait_for_reply(&packet).await?;
+        let reply = RequestQueue::request(&self.channels, packet, Priority::Write).await?;
         if let Packet::TranslatedData(td) = reply {
             if td.values != values {
                 bail!(
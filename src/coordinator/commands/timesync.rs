@@ -2,10 +2,8 @@ use crate::prelude::*;
 
 use chrono::TimeZone;
 
-use eg4::{
-    inverter::WaitForReply,
-    packet::{DeviceFunction, TranslatedData},
-};
+use eg4::packet::{DeviceFunction, TranslatedData};
+use request_queue::Priority;
 
 pub struct TimeSync {
     channels: Channels,
@@ -33,18 +31,7 @@ impl TimeSync {
             values: vec![3, 0],
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        if let Packet::TranslatedData(td) = receiver.wait_for_reply(&packet).await? {
+        if let Packet::TranslatedData(td) = RequestQueue::request(&self.channels, packet, Priority::Background).await? {
             let year = td.values[0] as u32;
             let month = td.values[1] as u32;
             let day = td.values[2] as u32;
@@ -80,16 +67,7 @@ impl TimeSync {
                (time_diff < -min_limit && time_diff >= -max_limit) {
                 let packet = self.set_time_packet(now);
 
-                if self
-                    .channels
-                    .to_inverter
-                    .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-                    .is_err()
-                {
-                    bail!("send(to_inverter) failed - channel closed?");
-                }
-
-                if let Packet::TranslatedData(_) = receiver.wait_for_reply(&packet).await? {
+                if let Packet::TranslatedData(_) = RequestQueue::request(&self.channels, packet, Priority::Write).await? {
                     debug!("time set ok");
                 } else {
                     warn!("time set didn't get confirmation reply!");
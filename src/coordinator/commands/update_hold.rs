@@ -1,15 +1,13 @@
 use crate::prelude::*;
-use crate::eg4::inverter::WaitForReply;
-use crate::eg4::{
-    packet::{Packet, RegisterBit, DeviceFunction, TranslatedData},
-    inverter::ChannelData,
-};
+use crate::eg4::packet::{Packet, DeviceFunction, TranslatedData};
+use request_queue::Priority;
 
 pub struct UpdateHold {
     channels: Channels,
     inverter: config::Inverter,
     register: u16,
-    bit: RegisterBit,
+    // the bits to set or clear
+    mask: u16,
     enable: bool,
 }
 
@@ -18,22 +16,20 @@ impl UpdateHold {
         channels: Channels,
         inverter: config::Inverter,
         register: u16,
-        bit: RegisterBit,
+        mask: u16,
         enable: bool,
     ) -> Self {
         Self {
             channels,
             inverter,
             register,
-            bit,
+            mask,
             enable,
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        // First read the current value
+    /// Returns the value written.
+    pub async fn run(&self) -> Result<u16> {
         let read_packet = Packet::TranslatedData(TranslatedData {
             datalog: self.inverter.datalog().expect("datalog must be set"),
             device_function: DeviceFunction::ReadHold,
@@ -42,43 +38,80 @@ impl UpdateHold {
             values: vec![1, 0],
         });
 
-        self.channels
-            .to_inverter
-            .send(ChannelData::Packet(read_packet.clone()))
-            .map_err(|e| anyhow!("send(to_inverter) failed: {}", e))?;
-
-        let read_packet = receiver.wait_for_reply(&read_packet).await?;
-        let current_value = read_packet.value();
-        let new_value = if self.enable {
-            current_value | (self.bit.clone() as u16)
-        } else {
-            current_value & !(self.bit.clone() as u16)
-        };
+        // read and written back as one item in the queue, so nothing else
+        // can change the register in between
+        let write_packet =
+            RequestQueue::update_hold(&self.channels, read_packet, self.mask, self.enable, Priority::Write)
+                .await?;
 
-        // Now write the new value
-        let write_packet = Packet::TranslatedData(TranslatedData {
-            datalog: self.inverter.datalog().expect("datalog must be set"),
-            device_function: DeviceFunction::WriteSingle,
-            inverter: self.inverter.serial().expect("serial must be set"),
-            register: self.register,
-            values: new_value.to_le_bytes().to_vec(),
-        });
-
-        self.channels
-            .to_inverter
-            .send(ChannelData::Packet(write_packet.clone()))
-            .map_err(|e| anyhow!("send(to_inverter) failed: {}", e))?;
-
-        let write_packet = receiver.wait_for_reply(&write_packet).await?;
-        if write_packet.value() != new_value {
+        let value = write_packet.value();
+        let wanted = if self.enable { self.mask } else { 0 };
+        if value & self.mask != wanted {
             bail!(
-                "failed to update register {:?}, got back value {} (wanted {})",
+                "failed to update register {:?}, got back value {} (wanted bits {:#06x} {})",
                 self.register,
-                write_packet.value(),
-                new_value
+                value,
+                self.mask,
+                if self.enable { "set" } else { "cleared" }
             );
         }
 
-        Ok(())
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers reads with `current` and echoes writes, returning what was written
+    fn fake_inverter(channels: &Channels, current: u16) -> tokio::task::JoinHandle<Vec<u16>> {
+        let mut receiver = channels.to_inverter.subscribe();
+        let channels = channels.clone();
+
+        tokio::spawn(async move {
+            let mut written = Vec::new();
+            while let Ok(eg4::inverter::ChannelData::Packet(Packet::TranslatedData(mut td))) =
+                receiver.recv().await
+            {
+                match td.device_function {
+                    DeviceFunction::ReadHold => td.values = current.to_le_bytes().to_vec(),
+                    _ => written.push(td.value()),
+                }
+                let _ = channels
+                    .from_inverter
+                    .send(eg4::inverter::ChannelData::Packet(Packet::TranslatedData(td)));
+                if !written.is_empty() {
+                    break;
+                }
+            }
+            written
+        })
+    }
+
+    #[tokio::test]
+    async fn test_masks() {
+        let mut config = config::Config::new("config.yaml.example".to_string()).unwrap();
+        config.inverters[0].delay_ms = Some(0);
+        let config = ConfigWrapper::from_config(config);
+        let inverter = config.inverters()[0].clone();
+        let update = |channels: &Channels, mask, enable| UpdateHold::new(channels.clone(), inverter.clone(), 21, mask, enable);
+
+        // only the masked bits change
+        for (current, mask, enable, wanted) in [
+            (0b1010, 0b0001, true, 0b1011),
+            (0b1010, 0b0010, false, 0b1000),
+            (0b1010, 0b0010, true, 0b1010),
+            (0xFFFF, 1 << 15, false, 0x7FFF),
+        ] {
+            let channels = Channels::new();
+            let queue = RequestQueue::new(config.clone(), channels.clone());
+            tokio::spawn(async move { queue.start().await });
+            tokio::task::yield_now().await;
+
+            let fake = fake_inverter(&channels, current);
+            assert_eq!(update(&channels, mask, enable).run().await.unwrap(), wanted);
+            assert_eq!(fake.await.unwrap(), vec![wanted]);
+        }
     }
 }
@@ -1,10 +1,17 @@
 use crate::prelude::*;
 use crate::coordinator::commands::time_register_ops;
 use crate::coordinator::commands::set_hold::SetHold;
+use crate::coordinator::commands::set_holds::SetHolds;
 use crate::coordinator::commands::write_param::WriteParam;
 use crate::coordinator::commands::time_register_ops::SetTimeRegister;
+use crate::coordinator::commands::update_hold::UpdateHold;
+use crate::eg4::packet::Register;
+use crate::register_cache::RegisterType;
+
+use std::collections::BTreeMap;
 
 /// WriteInverter handles all direct inverter operations.
+/// Writes return the hold register/value pairs they wrote, for reading back.
 /// The read_only check only applies to write operations (set_* functions).
 /// Read operations are always allowed regardless of read_only setting.
 pub struct WriteInverter {
@@ -25,7 +32,7 @@ impl WriteInverter {
     /// Checks if write operations are allowed based on read_only settings.
     /// This check is only used for write operations (set_* functions).
     /// Read operations should not use this check.
-    fn check_read_only(&self) -> Result<()> {
+    pub fn check_read_only(&self) -> Result<()> {
         if self.config.read_only() || self.inverter.read_only.unwrap_or(false) {
             Err(anyhow::anyhow!("Write operations are disabled in read-only mode"))
         } else {
@@ -35,74 +42,76 @@ impl WriteInverter {
 
     /// Write operation: Sets AC charge rate
     /// Blocked by read_only setting
-    pub async fn set_ac_charge_rate(&self, value: u16) -> Result<()> {
+    pub async fn set_ac_charge_rate(&self, value: u16) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
-        self.set_hold(0x0102_u16, value).await
+        self.set_hold(Register::AcChargePowerCmd, value).await
     }
 
     /// Write operation: Sets AC charge SOC limit
     /// Blocked by read_only setting
-    pub async fn set_ac_charge_soc_limit(&self, value: u16) -> Result<()> {
+    pub async fn set_ac_charge_soc_limit(&self, value: u16) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
-        self.set_hold(0x0103_u16, value).await
+        self.set_hold(Register::AcChargeSocLimit, value).await
     }
 
     /// Write operation: Sets AC charge time
     /// Blocked by read_only setting
-    pub async fn set_ac_charge_time(&self, values: [u8; 4]) -> Result<()> {
+    pub async fn set_ac_charge_time(&self, values: [u8; 4]) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
         self.set_time_register(time_register_ops::Action::AcCharge(0), values).await
     }
 
     /// Write operation: Sets AC first time
     /// Blocked by read_only setting
-    pub async fn set_ac_first_time(&self, values: [u8; 4]) -> Result<()> {
+    pub async fn set_ac_first_time(&self, values: [u8; 4]) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
         self.set_time_register(time_register_ops::Action::AcFirst(0), values).await
     }
 
     /// Write operation: Sets charge priority time
     /// Blocked by read_only setting
-    pub async fn set_charge_priority_time(&self, values: [u8; 4]) -> Result<()> {
+    pub async fn set_charge_priority_time(&self, values: [u8; 4]) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
         self.set_time_register(time_register_ops::Action::ChargePriority(0), values).await
     }
 
     /// Write operation: Sets charge rate
     /// Blocked by read_only setting
-    pub async fn set_charge_rate(&self, value: u16) -> Result<()> {
+    pub async fn set_charge_rate(&self, value: u16) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
-        self.set_hold(0x0100_u16, value).await
+        self.set_hold(Register::ChargePowerPercentCmd, value).await
     }
 
     /// Write operation: Sets discharge cutoff SOC limit
     /// Blocked by read_only setting
-    pub async fn set_discharge_cutoff_soc_limit(&self, value: u16) -> Result<()> {
+    pub async fn set_discharge_cutoff_soc_limit(&self, value: u16) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
-        self.set_hold(0x0104_u16, value).await
+        self.set_hold(Register::DischgCutOffSocEod, value).await
     }
 
     /// Write operation: Sets discharge rate
     /// Blocked by read_only setting
-    pub async fn set_discharge_rate(&self, value: u16) -> Result<()> {
+    pub async fn set_discharge_rate(&self, value: u16) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
-        self.set_hold(0x0101_u16, value).await
+        self.set_hold(Register::DischgPowerPercentCmd, value).await
     }
 
     /// Write operation: Sets forced discharge time
     /// Blocked by read_only setting
-    pub async fn set_forced_discharge_time(&self, values: [u8; 4]) -> Result<()> {
+    pub async fn set_forced_discharge_time(&self, values: [u8; 4]) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
         self.set_time_register(time_register_ops::Action::ForcedDischarge(0), values).await
     }
 
     /// Write operation: Sets a holding register value
     /// Blocked by read_only setting
-    pub async fn set_hold<U>(&self, register: U, value: u16) -> Result<()>
+    pub async fn set_hold<U>(&self, register: U, value: u16) -> Result<Vec<(u16, u16)>>
     where
         U: Into<u16>,
     {
         self.check_read_only()?;
+        let register = register.into();
+        self.config.register_map().validate_write(register, value)?;
         SetHold::new(
             self.channels.clone(),
             self.inverter.clone(),
@@ -111,12 +120,70 @@ impl WriteInverter {
         )
         .run()
         .await?;
-        Ok(())
+        Ok(vec![(register, value)])
+    }
+
+    /// Write operation: Sets a hold register by its register map shortname,
+    /// from a value in engineering units
+    /// Blocked by read_only setting
+    pub async fn set_setting(&self, shortname: &str, value: &str) -> Result<Vec<(u16, u16)>> {
+        self.check_read_only()?;
+        let register_map = self.config.register_map();
+        let def = register_map
+            .find(RegisterType::Hold, shortname)
+            .ok_or_else(|| anyhow!("unknown setting {}", shortname))?;
+        let raw = def.encode(value)?;
+        self.set_hold(def.register, raw).await
+    }
+
+    /// Write operation: Sets or clears one bit of a flags register, by the
+    /// flag's name in the register map, leaving the others alone
+    /// Blocked by read_only setting
+    pub async fn set_flag(&self, name: &str, enable: bool) -> Result<Vec<(u16, u16)>> {
+        self.check_read_only()?;
+        let register_map = self.config.register_map();
+        let (def, flag) = register_map
+            .find_flag(RegisterType::Hold, name)
+            .ok_or_else(|| anyhow!("unknown flag {}", name))?;
+        if def.read_only {
+            bail!("register {} ({}) is read-only", def.register, name);
+        }
+
+        let value = UpdateHold::new(self.channels.clone(), self.inverter.clone(), def.register, 1 << flag.bit, enable)
+            .run()
+            .await?;
+        Ok(vec![(def.register, value)])
+    }
+
+    /// Write operation: Sets several hold registers together from a set/holds
+    /// payload, restoring them all if any write fails
+    /// Blocked by read_only setting
+    pub async fn set_holds(&self, payload: &str) -> Result<Vec<(u16, u16)>> {
+        self.check_read_only()?;
+        let values = self.config.register_map().encode_writes(payload)?;
+        SetHolds::new(self.channels.clone(), self.inverter.clone(), values)
+            .run()
+            .await
+    }
+
+    /// Write operation: Sets several raw hold register values as one
+    /// block write, rolled back if any of it fails
+    /// Blocked by read_only setting
+    pub async fn set_hold_values(&self, values: BTreeMap<u16, u16>) -> Result<Vec<(u16, u16)>> {
+        self.check_read_only()?;
+        let register_map = self.config.register_map();
+        for (register, value) in &values {
+            register_map.validate_write(*register, *value)?;
+        }
+        SetHolds::new(self.channels.clone(), self.inverter.clone(), values)
+            .run()
+            .await
     }
 
     /// Write operation: Sets a parameter value
     /// Blocked by read_only setting
-    pub async fn set_param<U>(&self, register: U, value: u16) -> Result<()>
+    /// Params aren't hold registers, so nothing is returned to read back
+    pub async fn set_param<U>(&self, register: U, value: u16) -> Result<Vec<(u16, u16)>>
     where
         U: Into<u16>,
     {
@@ -129,7 +196,7 @@ impl WriteInverter {
         )
         .run()
         .await?;
-        Ok(())
+        Ok(Vec::new())
     }
 
     /// Write operation: Sets a time register value
@@ -138,7 +205,7 @@ impl WriteInverter {
         &self,
         action: time_register_ops::Action,
         values: [u8; 4],
-    ) -> Result<()> {
+    ) -> Result<Vec<(u16, u16)>> {
         self.check_read_only()?;
         SetTimeRegister::new(
             self.channels.clone(),
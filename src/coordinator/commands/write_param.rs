@@ -1,6 +1,6 @@
 use crate::prelude::*;
 
-use eg4::inverter::WaitForReply;
+use request_queue::Priority;
 
 pub struct WriteParam {
     channels: Channels,
@@ -29,18 +29,7 @@ impl WriteParam {
             values: self.value.to_le_bytes().to_vec(),
         });
 
-        let mut receiver = self.channels.from_inverter.subscribe();
-
-        if self
-            .channels
-            .to_inverter
-            .send(eg4::inverter::ChannelData::Packet(packet.clone()))
-            .is_err()
-        {
-            bail!("send(to_inverter) failed - channel closed?");
-        }
-
-        let packet = receiver.wait_for_reply(&packet).await?;
+        let packet = RequestQueue::request(&self.channels, packet, Priority::Write).await?;
         // WriteParam packets seem to reply with 0 on success, very odd
         if packet.value() != 0 {
             bail!("failed to set register {}", self.register);
@@ -3,11 +3,14 @@ pub mod commands;
 use crate::prelude::*;
 use crate::coordinator::commands::time_register_ops::Action;
 use crate::eg4::packet::{Register, RegisterBit};
-use crate::command::Command;
+use crate::eg4::hold_settings::HoldSettings;
+use crate::command::{Command, CommandResult};
+use crate::request_queue::Priority;
 use crate::datalog_writer::DatalogWriter;
+use crate::metrics::Metrics;
 
 use crate::eg4::{
-    packet::{DeviceFunction, TranslatedData, Packet},
+    packet::{DeviceFunction, ReadInput, ReadInputAll, TranslatedData, Packet},
 };
 
 use commands::{
@@ -21,15 +24,31 @@ use crate::eg4::inverter;
 // Sleep durations - keeping only the ones actively used
 const RETRY_DELAY_MS: u64 = 1000;    // 1 second
 
-#[derive(Eq, PartialEq, Debug, Clone)]
+// input blocks older than this aren't combined into a ReadInputAll
+const INPUTS_MAX_AGE_SECS: i64 = 60;
+
+// how often bridge stats go to InfluxDB
+const STATS_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone)]
 pub enum ChannelData {
     Shutdown,
     Packet(crate::eg4::packet::Packet),
+    Command(Box<Command>), // from the scheduler
 }
 
 pub type InputsStore = std::collections::HashMap<Serial, crate::eg4::packet::ReadInputs>;
+pub type HoldsStore = std::collections::HashMap<Serial, Holds>;
 
+// every hold register we've seen from an inverter, to decode HoldSettings
+// from, and what was last sent on from them
 #[derive(Default)]
+pub struct Holds {
+    registers: std::collections::BTreeMap<u16, u16>,
+    settings: HoldSettings,
+}
+
+#[derive(Default, serde::Serialize)]
 pub struct PacketStats {
     packets_received: u64,
     packets_sent: u64,
@@ -101,6 +120,45 @@ impl PacketStats {
         }
     }
 
+    pub fn write_metrics(&self, metrics: &mut Metrics) {
+        for (packet_type, received, sent) in [
+            ("heartbeat", self.heartbeat_packets_received, self.heartbeat_packets_sent),
+            ("translated_data", self.translated_data_packets_received, self.translated_data_packets_sent),
+            ("read_param", self.read_param_packets_received, self.read_param_packets_sent),
+            ("write_param", self.write_param_packets_received, self.write_param_packets_sent),
+        ] {
+            let labels = [("type", packet_type)];
+            metrics.counter("packets_received", "Packets received from inverters", &labels, received as f64);
+            metrics.counter("packets_sent", "Packets sent to inverters", &labels, sent as f64);
+        }
+
+        for (kind, count) in [
+            ("modbus", self.modbus_errors),
+            ("mqtt", self.mqtt_errors),
+            ("influx", self.influx_errors),
+            ("database", self.database_errors),
+            ("register_cache", self.register_cache_errors),
+        ] {
+            metrics.counter("errors", "Errors by kind", &[("kind", kind)], count as f64);
+        }
+
+        metrics.counter("mqtt_messages_sent", "MQTT messages sent", &[], self.mqtt_messages_sent as f64);
+        metrics.counter("influx_writes", "InfluxDB writes", &[], self.influx_writes as f64);
+        metrics.counter("database_writes", "Database writes", &[], self.database_writes as f64);
+        metrics.counter("register_cache_writes", "Register cache writes", &[], self.register_cache_writes as f64);
+        metrics.counter("serial_mismatches", "Inverter serial number mismatches", &[], self.serial_mismatches as f64);
+
+        for (datalog, count) in &self.inverter_disconnections {
+            let datalog = datalog.to_string();
+            metrics.counter(
+                "inverter_disconnections",
+                "Inverter disconnections",
+                &[("datalog", &datalog)],
+                *count as f64,
+            );
+        }
+    }
+
     pub fn increment_serial_mismatches(&mut self) {
         self.serial_mismatches += 1;
     }
@@ -121,6 +179,8 @@ pub struct Coordinator {
     influx: Option<Arc<Influx>>,
     databases: Vec<Arc<Database>>,
     datalog_writer: Option<Arc<DatalogWriter>>,
+    inputs_store: Arc<Mutex<InputsStore>>,
+    holds_store: Arc<Mutex<HoldsStore>>,
     channels: Channels,
     pub stats: Arc<Mutex<PacketStats>>,
 }
@@ -133,6 +193,8 @@ impl Coordinator {
             influx: None,
             databases: Vec::new(),
             datalog_writer: None,
+            inputs_store: Arc::new(Mutex::new(InputsStore::new())),
+            holds_store: Arc::new(Mutex::new(HoldsStore::new())),
             channels,
             stats: Arc::new(Mutex::new(PacketStats::default())),
         }
@@ -147,22 +209,28 @@ impl Coordinator {
         self.start_databases()?;
         self.start_datalog_writer()?;
 
-        if self.config.mqtt().enabled() {
-            tokio::select! {
-                res = self.inverter_receiver() => {
-                    if let Err(e) = res {
-                        error!("Inverter receiver error: {}", e);
-                    }
+        // mqtt_receiver runs even with MQTT disabled, in case a config reload
+        // enables it later; process_message ignores anything until then.
+        tokio::select! {
+            res = self.inverter_receiver() => {
+                if let Err(e) = res {
+                    error!("Inverter receiver error: {}", e);
                 }
-                res = self.mqtt_receiver() => {
-                    if let Err(e) = res {
-                        error!("MQTT receiver error: {}", e);
-                    }
+            }
+            res = self.mqtt_receiver() => {
+                if let Err(e) = res {
+                    error!("MQTT receiver error: {}", e);
                 }
             }
-        } else {
-            if let Err(e) = self.inverter_receiver().await {
-                error!("Inverter receiver error: {}", e);
+            res = self.command_receiver() => {
+                if let Err(e) = res {
+                    error!("Command receiver error: {}", e);
+                }
+            }
+            res = self.stats_sender() => {
+                if let Err(e) = res {
+                    error!("Stats sender error: {}", e);
+                }
             }
         }
 
@@ -173,6 +241,8 @@ impl Coordinator {
         info!("Stopping coordinator...");
         let _ = self.channels.to_inverter.send(crate::eg4::inverter::ChannelData::Shutdown);
         let _ = self.channels.to_mqtt.send(mqtt::ChannelData::Shutdown);
+        let _ = self.channels.to_coordinator.send(ChannelData::Shutdown);
+        let _ = self.channels.to_request_queue.send(request_queue::ChannelData::Shutdown);
         // The datalog writer will be dropped when the Coordinator is dropped
         // since it's wrapped in an Arc
     }
@@ -187,6 +257,33 @@ impl Coordinator {
         Ok(())
     }
 
+    // scheduler -> coordinator
+    async fn command_receiver(&self) -> Result<()> {
+        let mut receiver = self.channels.to_coordinator.subscribe();
+
+        loop {
+            match receiver.recv().await? {
+                ChannelData::Command(command) => {
+                    debug!("running scheduled command {:?}", command);
+                    // each in its own task, so an inverter that isn't answering
+                    // only holds up its own commands; the request queue still
+                    // sends them to each inverter one at a time.
+                    let coordinator = self.clone();
+                    tokio::spawn(async move {
+                        let result = coordinator.run_command(*command, Priority::Background).await;
+                        if let Some(error) = result.error {
+                            warn!("Scheduled command failed: {}", error);
+                        }
+                    });
+                }
+                ChannelData::Shutdown => break,
+                ChannelData::Packet(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
     async fn process_message(&self, message: mqtt::Message) -> Result<()> {
         // If MQTT is disabled, don't process any messages
         if !self.config.mqtt().enabled() {
@@ -194,20 +291,18 @@ impl Coordinator {
         }
 
         for inverter in self.config.inverters_for_message(&message)? {
-            match message.to_command(inverter) {
+            match message.to_command(inverter, &self.config.register_map()) {
                 Ok(command) => {
                     info!("parsed command {:?}", command);
-                    let result = self.process_command(command.clone()).await;
-                    if result.is_err() {
                     let topic_reply = command.to_result_topic();
+                    let result = self.run_command(command, Priority::Read).await;
                     let reply = mqtt::ChannelData::Message(mqtt::Message {
                         topic: topic_reply,
                         retain: false,
-                            payload: "FAIL".to_string(),
+                        payload: serde_json::to_string(&result)?,
                     });
                     if self.channels.to_mqtt.send(reply).is_err() {
                         bail!("send(to_mqtt) failed - channel closed?");
-                        }
                     }
                 }
                 Err(err) => {
@@ -219,6 +314,53 @@ impl Coordinator {
         Ok(())
     }
 
+    /// Runs a command and describes how it went. With verify_writes set on
+    /// the inverter, hold registers written are read back to check they stuck.
+    /// `priority` places any reads in the inverter's request queue; writes
+    /// always go ahead of them.
+    pub async fn run_command(&self, command: Command, priority: Priority) -> CommandResult {
+        let start = std::time::Instant::now();
+        let inverter = command.inverter().clone();
+
+        let mut result = match self.process_command(command, priority).await {
+            Ok(written) if inverter.verify_writes() && !written.is_empty() => {
+                let result = CommandResult::written(&written);
+                match self.read_back(&inverter, &written).await {
+                    Ok(pairs) => result.verify(&pairs),
+                    Err(err) => result.fail(&err.context("reading back failed")),
+                }
+            }
+            Ok(written) => CommandResult::written(&written),
+            Err(err) => CommandResult::failed(&err),
+        };
+
+        result.elapsed_ms = start.elapsed().as_millis() as u64;
+        result
+    }
+
+    // reads back the hold registers written, one request per run of them,
+    // as they were written
+    async fn read_back(&self, inverter: &config::Inverter, written: &[(u16, u16)]) -> Result<Vec<(u16, u16)>> {
+        let written = written.iter().copied().collect();
+        let mut pairs = Vec::new();
+
+        for run in commands::set_holds::SetHolds::runs(&written, inverter.register_block_size()) {
+            let first = run[0].0;
+            let count = run.len() as u16;
+            let packet = commands::read_hold::ReadHold::new(self.channels.clone(), inverter.clone(), first, count)
+                .with_priority(Priority::Write)
+                .run()
+                .await?;
+
+            match packet {
+                Packet::TranslatedData(td) => pairs.extend(td.pairs()),
+                _ => bail!("coordinator/mod.rs:unexpected reply to read back {:?}", packet),
+            }
+        }
+
+        Ok(pairs)
+    }
+
     fn increment_packets_sent(&self, packet: &Packet) {
         if let Ok(mut stats) = self.stats.lock() {
             stats.packets_sent += 1;
@@ -234,8 +376,9 @@ impl Coordinator {
     }
 
     /// Process a command received from MQTT or other sources
-    /// This function routes commands to appropriate read/write handlers
-    async fn process_command(&self, command: Command) -> Result<()> {
+    /// This function routes commands to appropriate read/write handlers,
+    /// returning the hold register/value pairs written, if any
+    pub async fn process_command(&self, command: Command, priority: Priority) -> Result<Vec<(u16, u16)>> {
         let inverter = match &command {
             Command::ChargeRate(inv, _) |
             Command::DischargeRate(inv, _) |
@@ -243,6 +386,9 @@ impl Coordinator {
             Command::AcChargeSocLimit(inv, _) |
             Command::DischargeCutoffSocLimit(inv, _) |
             Command::SetHold(inv, _, _) |
+            Command::SetSetting(inv, _, _) |
+            Command::SetHolds(inv, _) |
+            Command::SetFlag(inv, _, _) |
             Command::WriteParam(inv, _, _) |
             Command::SetAcChargeTime(inv, _, _) |
             Command::SetAcFirstTime(inv, _, _) |
@@ -275,6 +421,9 @@ impl Coordinator {
             Command::AcChargeSocLimit(_, value) => write_inverter.set_ac_charge_soc_limit(value).await,
             Command::DischargeCutoffSocLimit(_, value) => write_inverter.set_discharge_cutoff_soc_limit(value).await,
             Command::SetHold(_, register, value) => write_inverter.set_hold(register, value).await,
+            Command::SetSetting(_, name, value) => write_inverter.set_setting(&name, &value).await,
+            Command::SetHolds(_, payload) => write_inverter.set_holds(&payload).await,
+            Command::SetFlag(_, name, enable) => write_inverter.set_flag(&name, enable).await,
             Command::WriteParam(_, register, value) => write_inverter.set_param(register, value).await,
             Command::SetAcChargeTime(_, _, values) => write_inverter.set_ac_charge_time(values).await,
             Command::SetAcFirstTime(_, _, values) => write_inverter.set_ac_first_time(values).await,
@@ -282,14 +431,14 @@ impl Coordinator {
             Command::SetForcedDischargeTime(_, _, values) => write_inverter.set_forced_discharge_time(values).await,
             
             // Read operations - these are always allowed regardless of read_only mode
-            Command::ReadInputs(_, block) => self.read_input_block(&inverter, block * 40, inverter.register_block_size()).await,
-            Command::ReadInput(_, register, count) => self.read_input_registers(&inverter, register, count).await,
-            Command::ReadHold(_, register, count) => self.read_hold_registers(&inverter, register, count).await,
-            Command::ReadParam(_, register) => self.read_param_register(&inverter, register).await,
-            Command::ReadAcChargeTime(_, num) => self.read_ac_charge_time(&inverter, num).await,
-            Command::ReadAcFirstTime(_, num) => self.read_ac_first_time(&inverter, num).await,
-            Command::ReadChargePriorityTime(_, num) => self.read_charge_priority_time(&inverter, num).await,
-            Command::ReadForcedDischargeTime(_, num) => self.read_forced_discharge_time(&inverter, num).await,
+            Command::ReadInputs(_, block) => self.read_input_block(&inverter, block * 40, inverter.register_block_size(), priority).await.map(|_| Vec::new()),
+            Command::ReadInput(_, register, count) => self.read_input_registers(&inverter, register, count, priority).await.map(|_| Vec::new()),
+            Command::ReadHold(_, register, count) => self.read_hold_registers(&inverter, register, count, priority).await.map(|_| Vec::new()),
+            Command::ReadParam(_, register) => self.read_param_register(&inverter, register, priority).await.map(|_| Vec::new()),
+            Command::ReadAcChargeTime(_, num) => self.read_ac_charge_time(&inverter, num, priority).await.map(|_| Vec::new()),
+            Command::ReadAcFirstTime(_, num) => self.read_ac_first_time(&inverter, num, priority).await.map(|_| Vec::new()),
+            Command::ReadChargePriorityTime(_, num) => self.read_charge_priority_time(&inverter, num, priority).await.map(|_| Vec::new()),
+            Command::ReadForcedDischargeTime(_, num) => self.read_forced_discharge_time(&inverter, num, priority).await.map(|_| Vec::new()),
             
             // Enable/Disable operations - these are blocked by read_only mode
             Command::AcCharge(_, enable) => {
@@ -326,61 +475,54 @@ impl Coordinator {
         inverter: &config::Inverter,
         register: U,
         count: u16,
+        priority: Priority,
     ) -> Result<()>
     where
         U: Into<u16>,
     {
         commands::read_inputs::ReadInputs::new(self.channels.clone(), inverter.clone(), register, count)
+        .with_priority(priority)
         .run()
         .await?;
-
-        // Add delay after read operation
-        tokio::time::sleep(std::time::Duration::from_millis(inverter.delay_ms())).await;
         Ok(())
     }
 
     /// Read specific input registers from the inverter
     /// This operation is always allowed regardless of read_only mode
-    async fn read_input_registers<U>(&self, inverter: &config::Inverter, register: U, count: u16) -> Result<()>
+    async fn read_input_registers<U>(&self, inverter: &config::Inverter, register: U, count: u16, priority: Priority) -> Result<()>
     where
         U: Into<u16>,
     {
         commands::read_inputs::ReadInputs::new(self.channels.clone(), inverter.clone(), register, count)
+        .with_priority(priority)
         .run()
         .await?;
-
-        // Add delay after read operation
-        tokio::time::sleep(std::time::Duration::from_millis(inverter.delay_ms())).await;
         Ok(())
     }
 
     /// Read holding registers from the inverter
     /// This operation is always allowed regardless of read_only mode
-    async fn read_hold_registers<U>(&self, inverter: &config::Inverter, register: U, count: u16) -> Result<()>
+    async fn read_hold_registers<U>(&self, inverter: &config::Inverter, register: U, count: u16, priority: Priority) -> Result<()>
     where
         U: Into<u16>,
     {
         commands::read_hold::ReadHold::new(self.channels.clone(), inverter.clone(), register, count)
+        .with_priority(priority)
         .run()
         .await?;
-
-        // Add delay after read operation
-        tokio::time::sleep(std::time::Duration::from_millis(inverter.delay_ms())).await;
         Ok(())
     }
 
     /// Read a parameter register from the inverter
     /// This operation is always allowed regardless of read_only mode
-    async fn read_param_register<U>(&self, inverter: &config::Inverter, register: U) -> Result<()>
+    async fn read_param_register<U>(&self, inverter: &config::Inverter, register: U, priority: Priority) -> Result<()>
     where
         U: Into<u16>,
     {
         commands::read_param::ReadParam::new(self.channels.clone(), inverter.clone(), register)
+            .with_priority(priority)
             .run()
             .await?;
-
-        // Add delay after read operation
-        tokio::time::sleep(std::time::Duration::from_millis(inverter.delay_ms())).await;
         Ok(())
     }
 
@@ -390,8 +532,9 @@ impl Coordinator {
         &self,
         inverter: &config::Inverter,
         num: u16,
+        priority: Priority,
     ) -> Result<()> {
-        self.read_time_register(inverter, Action::AcCharge(num)).await
+        self.read_time_register(inverter, Action::AcCharge(num), priority).await
     }
 
     /// Read AC first time settings from the inverter
@@ -400,8 +543,9 @@ impl Coordinator {
         &self,
         inverter: &config::Inverter,
         num: u16,
+        priority: Priority,
     ) -> Result<()> {
-        self.read_time_register(inverter, Action::AcFirst(num)).await
+        self.read_time_register(inverter, Action::AcFirst(num), priority).await
     }
 
     /// Read charge priority time settings from the inverter
@@ -410,8 +554,9 @@ impl Coordinator {
         &self,
         inverter: &config::Inverter,
         num: u16,
+        priority: Priority,
     ) -> Result<()> {
-        self.read_time_register(inverter, Action::ChargePriority(num)).await
+        self.read_time_register(inverter, Action::ChargePriority(num), priority).await
     }
 
     /// Read forced discharge time settings from the inverter
@@ -420,8 +565,9 @@ impl Coordinator {
         &self,
         inverter: &config::Inverter,
         num: u16,
+        priority: Priority,
     ) -> Result<()> {
-        self.read_time_register(inverter, Action::ForcedDischarge(num)).await
+        self.read_time_register(inverter, Action::ForcedDischarge(num), priority).await
     }
 
     /// Internal helper to read time register settings
@@ -430,6 +576,7 @@ impl Coordinator {
         &self,
         inverter: &config::Inverter,
         action: commands::time_register_ops::Action,
+        priority: Priority,
     ) -> Result<()> {
         commands::time_register_ops::ReadTimeRegister::new(
             self.channels.clone(),
@@ -437,11 +584,9 @@ impl Coordinator {
             (*self.config).clone(),
             action,
         )
+        .with_priority(priority)
         .run()
         .await?;
-        
-        // Add delay after read operation
-        tokio::time::sleep(std::time::Duration::from_millis(inverter.delay_ms())).await;
         Ok(())
     }
 
@@ -484,7 +629,9 @@ impl Coordinator {
             values,
         )
         .run()
-        .await
+        .await?;
+
+        Ok(())
     }
 
     /// Write a holding register to the inverter
@@ -508,41 +655,37 @@ impl Coordinator {
         register: U,
         bit: crate::eg4::packet::RegisterBit,
         enable: bool,
-    ) -> Result<()>
+    ) -> Result<Vec<(u16, u16)>>
     where
         U: Into<u16>,
     {
-        commands::update_hold::UpdateHold::new(
+        let register = register.into();
+        let value = commands::update_hold::UpdateHold::new(
             self.channels.clone(),
             inverter.clone(),
-            register.into(),
-            bit,
+            register,
+            bit as u16,
             enable,
         )
         .run()
         .await?;
 
-        Ok(())
+        Ok(vec![(register, value)])
     }
 
     async fn process_inverter_packet(&self, packet: Packet, inverter: &config::Inverter) -> Result<()> {
         match &packet {
             Packet::TranslatedData(td) => {
                 let datalog = td.datalog;
-                // Check for Modbus error response
-                if td.values.len() >= 1 {
-                    let first_byte = td.values[0];
-                    if first_byte & 0x80 != 0 {  // Check if MSB is set (error response)
-                        let error_code = first_byte & 0x7F;  // Remove MSB to get error code
-                        if let Some(error) = crate::eg4::packet::ModbusError::from_code(error_code) {
-                            error!("Modbus error from inverter {}: {} (code: {:#04x})", 
-                                inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), error.description(), error_code);
-                            if let Ok(mut stats) = self.stats.lock() {
-                                stats.modbus_errors += 1;
-                            }
-                            return Ok(());  // Return early as this is an error response
-                        }
+                // Check for Modbus error response; whoever sent the request
+                // gets the details from wait_for_reply
+                if let Some(error) = td.exception() {
+                    error!("Modbus error from inverter {} for {:?} of register {}: {}",
+                        inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), td.device_function.request(), td.register, error);
+                    if let Ok(mut stats) = self.stats.lock() {
+                        stats.modbus_errors += 1;
                     }
+                    return Ok(());  // Return early as this is an error response
                 }
 
                 // Log TCP function for debugging
@@ -603,14 +746,13 @@ impl Coordinator {
                 match td.device_function {
                     DeviceFunction::ReadInput => {
                         debug!("Processing ReadInput packet");
-                        let register = td.register();
                         let pairs = td.pairs();
                         
                         // Log all register values
                         debug!("Input Register Values:");
                         for (reg, value) in &pairs {
                             // Cache the register value
-                            if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(*reg, *value)) {
+                            if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(td.datalog, register_cache::RegisterType::Input, *reg, *value)) {
                                 error!("Failed to cache register {}: {}", reg, e);
                                 if let Ok(mut stats) = self.stats.lock() {
                                     stats.register_cache_errors += 1;
@@ -629,12 +771,27 @@ impl Coordinator {
                             }
                         }
 
-                        if let Err(e) = self.publish_input_message(register, pairs, inverter).await {
+                        if let Err(e) = self.publish_input_message(td).await {
                             error!("Failed to publish input message: {}", e);
                             if let Ok(mut stats) = self.stats.lock() {
                                 stats.mqtt_errors += 1;
                             }
                         }
+
+                        match td.read_input() {
+                            // newer dongles send 0-126 in one go; publish_input_message
+                            // has already sent this one to MQTT
+                            Ok(ReadInput::ReadInputAll(input_all)) => {
+                                self.send_input_all(&input_all, td.inverter).await;
+                            }
+                            Ok(block) => {
+                                if let Some(input_all) = self.store_input_block(td.datalog, block) {
+                                    self.publish_input_all(&input_all).await;
+                                    self.send_input_all(&input_all, td.inverter).await;
+                                }
+                            }
+                            Err(e) => debug!("not storing input block: {}", e),
+                        }
                     }
                     DeviceFunction::ReadHold => {
                         debug!("Processing ReadHold packet");
@@ -645,7 +802,7 @@ impl Coordinator {
                         debug!("Hold Register Values:");
                         for (reg, value) in &pairs {
                             // Cache the register value
-                            if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(*reg, *value)) {
+                            if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(td.datalog, register_cache::RegisterType::Hold, *reg, *value)) {
                                 error!("Failed to cache register {}: {}", reg, e);
                                 if let Ok(mut stats) = self.stats.lock() {
                                     stats.register_cache_errors += 1;
@@ -664,23 +821,33 @@ impl Coordinator {
                             }
                         }
                         
+                        let settings = self.store_holds(td.datalog, &pairs);
+
                         if let Err(e) = self.publish_hold_message(register, pairs, inverter).await {
                             error!("Failed to publish hold message: {}", e);
                             if let Ok(mut stats) = self.stats.lock() {
                                 stats.mqtt_errors += 1;
                             }
                         }
+                        if let Some(settings) = settings {
+                            self.publish_hold_all(&settings, td.datalog).await;
+                            self.send_hold_all(&settings, td.datalog).await;
+                        }
                     }
                     DeviceFunction::WriteSingle => {
                         debug!("Processing WriteSingle packet");
                         let register = td.register();
                         let value = td.value();
-                        if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(register, value)) {
+                        if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(td.datalog, register_cache::RegisterType::Hold, register, value)) {
                             error!("Failed to cache register {}: {}", register, e);
                             if let Ok(mut stats) = self.stats.lock() {
                                 stats.register_cache_errors += 1;
                             }
                         }
+                        if let Some(settings) = self.store_holds(td.datalog, &[(register, value)]) {
+                            self.publish_hold_all(&settings, td.datalog).await;
+                            self.send_hold_all(&settings, td.datalog).await;
+                        }
                         if let Err(e) = self.publish_write_confirmation(register, value, inverter).await {
                             error!("Failed to publish write confirmation: {}", e);
                             if let Ok(mut stats) = self.stats.lock() {
@@ -690,22 +857,20 @@ impl Coordinator {
                     }
                     DeviceFunction::WriteMulti => {
                         debug!("Processing WriteMulti packet");
-                        let pairs = td.pairs();
-                        for (register, value) in &pairs {
-                            if let Err(e) = self.channels.to_register_cache.send(register_cache::ChannelData::RegisterData(*register, *value)) {
-                                error!("Failed to cache register {}: {}", register, e);
-                                if let Ok(mut stats) = self.stats.lock() {
-                                    stats.register_cache_errors += 1;
-                                }
-                            }
-                        }
-                        if let Err(e) = self.publish_write_multi_confirmation(pairs, inverter).await {
+                        // the reply only says how many registers were written, not
+                        // their values; those get cached when they're read back
+                        if let Err(e) = self.publish_write_multi_confirmation(td.register(), td.value(), inverter).await {
                             error!("Failed to publish write multi confirmation: {}", e);
                             if let Ok(mut stats) = self.stats.lock() {
                                 stats.mqtt_errors += 1;
                             }
                         }
                     }
+                    // exception replies returned early above
+                    DeviceFunction::ReadHoldError
+                    | DeviceFunction::ReadInputError
+                    | DeviceFunction::WriteSingleError
+                    | DeviceFunction::WriteMultiError => {}
                 }
             }
             Packet::Heartbeat(_) => {
@@ -733,33 +898,10 @@ impl Coordinator {
 
     async fn inverter_receiver(&self) -> Result<()> {
         let mut receiver = self.channels.from_inverter.subscribe();
-        let mut buffer_size = 0;
-        const BUFFER_CLEAR_THRESHOLD: usize = 1024; // 1KB threshold for buffer clearing
-        const MAX_BUFFER_CLEAR_ATTEMPTS: u32 = 3; // Maximum number of buffer clear attempts
 
         while let Ok(message) = receiver.recv().await {
             match message {
                 inverter::ChannelData::Packet(packet) => {
-                    // Track buffer size
-                    buffer_size += std::mem::size_of_val(&packet);
-                    
-                    // Clear buffer if threshold exceeded, but with a limit on attempts
-                    if buffer_size >= BUFFER_CLEAR_THRESHOLD {
-                        debug!("Clearing receiver buffer (size: {} bytes)", buffer_size);
-                        let mut clear_attempts = 0;
-                        while clear_attempts < MAX_BUFFER_CLEAR_ATTEMPTS {
-                            match receiver.try_recv() {
-                                Ok(inverter::ChannelData::Packet(_)) => {
-                                    clear_attempts += 1;
-                                    continue;
-                                }
-                                Ok(_) => break, // Non-packet message, stop clearing
-                                Err(_) => break, // No more messages, stop clearing
-                            }
-                        }
-                        buffer_size = 0;
-                    }
-
                     // Process packet based on type first to avoid unnecessary cloning
                     let packet_clone = packet.clone();
                     if let Packet::TranslatedData(ref td) = packet {
@@ -807,6 +949,8 @@ impl Coordinator {
                         *count += 1;
                     }
                 }
+                // already logged by the inverter; MQTT publishes it on <datalog>/status
+                inverter::ChannelData::ConnectError(..) => {}
                 inverter::ChannelData::Shutdown => {
                     info!("Received shutdown signal");
                     break;
@@ -855,13 +999,13 @@ impl Coordinator {
         // Read all holding register blocks
         for start_register in (0..=240).step_by(block_size as usize) {
             self.increment_packets_sent(&packet);
-            self.read_hold_registers(&inverter, start_register as u16, block_size).await?;
+            self.read_hold_registers(&inverter, start_register as u16, block_size, Priority::Background).await?;
         }
 
         // Read all input register blocks
         for start_register in (0..=200).step_by(block_size as usize) {
             self.increment_packets_sent(&packet);
-            self.read_input_block(&inverter, start_register as u16, block_size).await?;
+            self.read_input_block(&inverter, start_register as u16, block_size, Priority::Background).await?;
         }
 
         // Read time registers
@@ -870,24 +1014,28 @@ impl Coordinator {
             self.read_time_register(
                 &inverter,
                 commands::time_register_ops::Action::AcCharge(*num),
+                Priority::Background,
             ).await?;
 
             self.increment_packets_sent(&packet);
             self.read_time_register(
                 &inverter,
                 commands::time_register_ops::Action::ChargePriority(*num),
+                Priority::Background,
             ).await?;
 
             self.increment_packets_sent(&packet);
             self.read_time_register(
                 &inverter,
                 commands::time_register_ops::Action::ForcedDischarge(*num),
+                Priority::Background,
             ).await?;
 
             self.increment_packets_sent(&packet);
             self.read_time_register(
                 &inverter,
                 commands::time_register_ops::Action::AcFirst(*num),
+                Priority::Background,
             ).await?;
         }
 
@@ -928,15 +1076,15 @@ impl Coordinator {
         bail!("send(to_mqtt) failed after retries - channel closed?");
     }
 
-    async fn publish_input_message(&self, _register: u16, pairs: Vec<(u16, u16)>, inverter: &config::Inverter) -> Result<()> {
+    async fn publish_input_message(&self, td: &TranslatedData) -> Result<()> {
         if !self.config.mqtt().enabled() {
             return Ok(());
         }
 
-        // Publish raw values
-        for (reg, value) in pairs {
-            let topic = format!("{}/inputs/{}", inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), reg);
-            if let Err(e) = self.publish_message(topic, value.to_string(), false).await {
+        // inputs/1..6 per block, plus input/<reg> if publish_individual_input
+        let messages = mqtt::Message::for_input(td.clone(), self.config.mqtt().publish_individual_input())?;
+        for message in messages {
+            if let Err(e) = self.publish_message(message.topic, message.payload, message.retain).await {
                 error!("Failed to publish input message: {}", e);
                 if let Ok(mut stats) = self.stats.lock() {
                     stats.mqtt_errors += 1;
@@ -947,6 +1095,214 @@ impl Coordinator {
         Ok(())
     }
 
+    // adds a decoded input block to the store, returning the combined
+    // ReadInputAll once all six blocks are in and recent enough
+    fn store_input_block(&self, datalog: Serial, block: ReadInput) -> Option<ReadInputAll> {
+        let mut store = self.inputs_store.lock().unwrap();
+        let inputs = store.entry(datalog).or_default();
+
+        match block {
+            ReadInput::ReadInput1(r) => inputs.set_read_input_1(r),
+            ReadInput::ReadInput2(r) => inputs.set_read_input_2(r),
+            ReadInput::ReadInput3(r) => inputs.set_read_input_3(r),
+            ReadInput::ReadInput4(r) => inputs.set_read_input_4(r),
+            ReadInput::ReadInput5(r) => inputs.set_read_input_5(r),
+            ReadInput::ReadInput6(r) => inputs.set_read_input_6(r),
+            ReadInput::ReadInputAll(_) => return None,
+        }
+
+        let input_all = inputs.to_fresh_input_all(chrono::Duration::seconds(INPUTS_MAX_AGE_SECS))?;
+        // start afresh, so the next set is made of all new blocks
+        store.remove(&datalog);
+
+        Some(input_all)
+    }
+
+    async fn publish_input_all(&self, input_all: &ReadInputAll) {
+        if !self.config.mqtt().enabled() {
+            return;
+        }
+
+        let result = match mqtt::Message::for_input_all(input_all, input_all.datalog) {
+            Ok(m) => self.publish_message(m.topic, m.payload, m.retain).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to publish inputs/all: {}", e);
+            if let Ok(mut stats) = self.stats.lock() {
+                stats.mqtt_errors += 1;
+            }
+        }
+    }
+
+    // a complete set of inputs -> InfluxDB, databases and the datalog file
+    async fn send_input_all(&self, input_all: &ReadInputAll, serial: Serial) {
+        if self.config.influx().enabled() {
+            let result = match Self::influx_data(input_all, serial) {
+                Ok(data) => self
+                    .channels
+                    .to_influx
+                    .send_wait(influx::ChannelData::InputData(data))
+                    .await
+                    .map_err(|_| anyhow!("send(to_influx) failed - channel closed?")),
+                Err(e) => Err(e),
+            };
+            if let Ok(mut stats) = self.stats.lock() {
+                match result {
+                    Ok(_) => stats.influx_writes += 1,
+                    Err(e) => {
+                        error!("{}", e);
+                        stats.influx_errors += 1;
+                    }
+                }
+            }
+        }
+
+        if self.config.have_enabled_database() {
+            let channel_data = database::ChannelData::ReadInputAll(Box::new(input_all.clone()));
+            let result = self.channels.to_database.send_wait(channel_data).await;
+            if let Ok(mut stats) = self.stats.lock() {
+                match result {
+                    Ok(_) => stats.database_writes += 1,
+                    Err(_) => {
+                        error!("send(to_database) failed - channel closed?");
+                        stats.database_errors += 1;
+                    }
+                }
+            }
+        }
+
+        if let Some(writer) = &self.datalog_writer {
+            if let Err(e) = writer.write_input_all(serial, input_all.datalog, input_all) {
+                error!("Failed to write to datalog file: {}", e);
+            }
+        }
+    }
+
+    // influx wants time and the tags alongside the fields, and can't store nulls
+    fn influx_data(input_all: &ReadInputAll, serial: Serial) -> Result<serde_json::Value> {
+        let mut data = serde_json::to_value(input_all)?;
+        let fields = data
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("coordinator.rs:ReadInputAll is not a JSON object"))?;
+
+        fields.retain(|_, value| !value.is_null());
+        fields.insert("time".to_string(), input_all.time.0.timestamp().into());
+        fields.insert("datalog".to_string(), input_all.datalog.to_string().into());
+        fields.insert("inverter".to_string(), serial.to_string().into());
+
+        Ok(data)
+    }
+
+    // merges freshly read or written hold registers into the store and
+    // decodes everything we know so far, returning it only if that's changed
+    // since last time, so re-reading the same settings doesn't republish them
+    fn store_holds(&self, datalog: Serial, pairs: &[(u16, u16)]) -> Option<HoldSettings> {
+        let mut store = self.holds_store.lock().unwrap();
+        let holds = store.entry(datalog).or_default();
+        holds.registers.extend(pairs.iter().copied());
+
+        let settings = HoldSettings::from_registers(&self.config.register_map(), &holds.registers);
+        if settings == holds.settings {
+            return None;
+        }
+        holds.settings = settings.clone();
+
+        Some(settings)
+    }
+
+    async fn send_hold_all(&self, settings: &HoldSettings, datalog: Serial) {
+        if !self.config.influx().enabled() {
+            return;
+        }
+
+        let result = match Self::hold_influx_data(settings, datalog) {
+            Ok(data) => self
+                .channels
+                .to_influx
+                .send_wait(influx::ChannelData::HoldData(data))
+                .await
+                .map_err(|_| anyhow!("send(to_influx) failed - channel closed?")),
+            Err(e) => Err(e),
+        };
+        if let Ok(mut stats) = self.stats.lock() {
+            match result {
+                Ok(_) => stats.influx_writes += 1,
+                Err(e) => {
+                    error!("{}", e);
+                    stats.influx_errors += 1;
+                }
+            }
+        }
+    }
+
+    fn hold_influx_data(settings: &HoldSettings, datalog: Serial) -> Result<serde_json::Value> {
+        let mut data = serde_json::to_value(settings)?;
+        let fields = data
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("coordinator.rs:HoldSettings is not a JSON object"))?;
+        // the inverter's clock, as a string, would clash with the timestamp
+        fields.insert("time".to_string(), Utils::utc().timestamp().into());
+        fields.insert("datalog".to_string(), datalog.to_string().into());
+
+        Ok(data)
+    }
+
+    // bridge health, for graphing alongside the inverter's data, and for
+    // Home Assistant's diagnostic entities on the stats topic
+    async fn stats_sender(&self) -> Result<()> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(STATS_INTERVAL_SECS));
+
+        loop {
+            interval.tick().await;
+            if !self.config.influx().enabled() && !self.config.mqtt().enabled() {
+                continue;
+            }
+
+            let mut data = match self.stats.lock() {
+                Ok(stats) => serde_json::to_value(&*stats)?,
+                Err(_) => continue,
+            };
+            if let Some(fields) = data.as_object_mut() {
+                fields.insert("time".to_string(), Utils::utc().timestamp().into());
+            }
+
+            if self.config.mqtt().enabled() {
+                // straight to the channel, so publishing them isn't counted in them
+                let message = mqtt::Message {
+                    topic: "stats".to_string(),
+                    retain: false,
+                    payload: data.to_string(),
+                };
+                let _ = self.channels.to_mqtt.send(mqtt::ChannelData::Message(message));
+            }
+            if self.config.influx().enabled() {
+                let _ = self.channels.to_influx.send_wait(influx::ChannelData::StatsData(data)).await;
+            }
+        }
+    }
+
+    async fn publish_hold_all(&self, settings: &HoldSettings, datalog: Serial) {
+        if !self.config.mqtt().enabled() {
+            return;
+        }
+
+        let result = match mqtt::Message::for_hold_all(settings, datalog) {
+            Ok(m) => self.publish_message(m.topic, m.payload, m.retain).await,
+            Err(e) => Err(e),
+        };
+        if self.config.homeassistant_enabled() {
+            let device_info = home_assistant::DeviceInfo::new(settings);
+            let _ = self.channels.to_mqtt.send(mqtt::ChannelData::DeviceInfo(datalog, device_info));
+        }
+        if let Err(e) = result {
+            error!("Failed to publish hold/all: {}", e);
+            if let Ok(mut stats) = self.stats.lock() {
+                stats.mqtt_errors += 1;
+            }
+        }
+    }
+
     async fn publish_hold_message(&self, _register: u16, pairs: Vec<(u16, u16)>, inverter: &config::Inverter) -> Result<()> {
         if !self.config.mqtt().enabled() {
             return Ok(());
@@ -982,13 +1338,13 @@ impl Coordinator {
         Ok(())
     }
 
-    async fn publish_write_multi_confirmation(&self, pairs: Vec<(u16, u16)>, inverter: &config::Inverter) -> Result<()> {
+    async fn publish_write_multi_confirmation(&self, register: u16, count: u16, inverter: &config::Inverter) -> Result<()> {
         if !self.config.mqtt().enabled() {
             return Ok(());
         }
 
         let topic = format!("{}/write_multi/status", inverter.datalog().map(|s| s.to_string()).unwrap_or_default());
-        if let Err(e) = self.publish_message(topic, format!("OK: {:?}", pairs), false).await {
+        if let Err(e) = self.publish_message(topic, format!("OK: {} registers from {}", count, register), false).await {
             error!("Failed to publish write multi confirmation: {}", e);
             if let Ok(mut stats) = self.stats.lock() {
                 stats.mqtt_errors += 1;
@@ -1020,7 +1376,7 @@ impl Coordinator {
         for db in &self.config.databases() {
             if db.enabled() {
                 info!("Initializing database {}", db.url());
-                let database = Database::new(db.clone(), self.channels.clone());
+                let database = Database::new(db.clone(), self.config.spool(), self.channels.clone());
                 self.databases.push(Arc::new(database));
             }
         }
@@ -1037,3 +1393,168 @@ impl Coordinator {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example config, with nothing to publish to unless `configure`
+    // turns it back on
+    fn coordinator(channels: &Channels, configure: impl FnOnce(&mut Config)) -> Coordinator {
+        let mut config = Config::new("config.yaml.example".to_string()).unwrap();
+        config.mqtt.enabled = false;
+        config.influx.enabled = false;
+        config.databases.clear();
+        configure(&mut config);
+
+        Coordinator::new(Arc::new(ConfigWrapper::from_config(config)), channels.clone())
+    }
+
+    #[tokio::test]
+    async fn test_read_hold_is_cached() -> Result<()> {
+        let channels = Channels::new();
+        let coordinator = coordinator(&channels, |_| {});
+        let inverter = coordinator.config.inverters()[0].clone();
+        let mut to_register_cache = channels.to_register_cache.subscribe();
+
+        let packet = Packet::TranslatedData(TranslatedData {
+            datalog: inverter.datalog().unwrap(),
+            device_function: DeviceFunction::ReadHold,
+            inverter: inverter.serial().unwrap(),
+            register: 12,
+            values: vec![22, 6],
+        });
+        coordinator.process_inverter_packet(packet, &inverter).await?;
+
+        // keyed by datalog and register type, so inverters don't overwrite
+        // each other
+        let register_cache::ChannelData::RegisterData(datalog, register_type, register, value) =
+            to_register_cache.try_recv()?
+        else {
+            unreachable!()
+        };
+        assert_eq!(datalog, inverter.datalog().unwrap());
+        assert_eq!(register_type, register_cache::RegisterType::Hold);
+        assert_eq!((register, value), (12, 1558));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_holds() {
+        let coordinator = coordinator(&Channels::new(), |_| {});
+        let datalog = Serial::from_str("2222222222").unwrap();
+        let other = Serial::from_str("3333333333").unwrap();
+        let holds = [(26, 2530), (28, 5150)];
+
+        assert!(coordinator.store_holds(datalog, &holds).is_some());
+        // the same again isn't worth sending on..
+        assert!(coordinator.store_holds(datalog, &holds).is_none());
+        assert!(coordinator.store_holds(datalog, &holds[1..]).is_none());
+        // ..but is for another inverter
+        assert!(coordinator.store_holds(other, &holds).is_some());
+        // as is a change, with everything else still in it
+        let settings = coordinator.store_holds(datalog, &[(26, 2540)]).unwrap();
+        assert_eq!(settings.get("grid_volt_conn_high"), Some(&serde_json::json!(254.0)));
+        assert_eq!(settings.get("grid_freq_conn_high"), Some(&serde_json::json!(51.5)));
+    }
+
+    // block n of six, as if decoded from zeros, other than the 50Hz grid and
+    // EPS frequencies that ReadInputAll checks for
+    fn input_block(datalog: Serial, n: usize) -> ReadInput {
+        use crate::eg4::packet::{ReadInput2, ReadInput3};
+        use nom_derive::Parse;
+
+        let mut values = vec![0; 80];
+        if n == 1 {
+            values[30..32].copy_from_slice(&5000u16.to_le_bytes());
+            values[46..48].copy_from_slice(&5000u16.to_le_bytes());
+        }
+
+        // these two read a little past their 40 registers, so aren't taken
+        // from a block sized packet
+        let zeros = [0u8; 254];
+        match n {
+            2 => ReadInput::ReadInput2(ReadInput2 { datalog, ..ReadInput2::parse(&zeros).unwrap().1 }),
+            3 => ReadInput::ReadInput3(ReadInput3 { datalog, ..ReadInput3::parse(&zeros).unwrap().1 }),
+            _ => TranslatedData {
+                datalog,
+                device_function: DeviceFunction::ReadInput,
+                inverter: Serial::default(),
+                register: (n as u16 - 1) * 40,
+                values,
+            }
+            .read_input()
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_store_input_block() {
+        let coordinator = coordinator(&Channels::new(), |_| {});
+        let datalog = Serial::from_str("2222222222").unwrap();
+        let other = Serial::from_str("3333333333").unwrap();
+        let store = |datalog, blocks: &[usize]| {
+            blocks.iter().map(|n| coordinator.store_input_block(datalog, input_block(datalog, *n))).last().flatten()
+        };
+
+        // nothing is combined until all six blocks are in, for that datalog
+        assert!(store(datalog, &[1, 2, 3, 4, 5]).is_none());
+        assert!(store(other, &[6]).is_none());
+        let input_all = store(datalog, &[6]).unwrap();
+        assert_eq!(input_all.datalog, datalog);
+        // and the next set starts afresh
+        assert!(!coordinator.inputs_store.lock().unwrap().contains_key(&datalog));
+        assert!(store(datalog, &[6]).is_none());
+
+        // blocks older than INPUTS_MAX_AGE_SECS aren't mixed with new ones..
+        assert!(store(datalog, &[1, 2, 3, 4]).is_none());
+        coordinator
+            .inputs_store
+            .lock()
+            .unwrap()
+            .get_mut(&datalog)
+            .unwrap()
+            .backdate(chrono::Duration::seconds(INPUTS_MAX_AGE_SECS + 1));
+        assert!(store(datalog, &[5]).is_none());
+        // ..until they've all been read again
+        assert!(store(datalog, &[1, 2, 3, 4]).is_none());
+        assert!(store(datalog, &[6]).is_some());
+
+        // a whole ReadInputAll doesn't go through the store
+        let input_all = ReadInput::ReadInputAll(Box::new(input_all));
+        assert!(coordinator.store_input_block(datalog, input_all).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_input_all() -> Result<()> {
+        let channels = Channels::new();
+        let coordinator = coordinator(&channels, |config| {
+            config.mqtt.enabled = true;
+            config.influx.enabled = true;
+        });
+        let inverter = coordinator.config.inverters()[0].clone();
+        let datalog = inverter.datalog().unwrap();
+        let mut to_mqtt = channels.to_mqtt.subscribe();
+        let mut to_influx = channels.to_influx.subscribe();
+
+        let input_all = (1..=6).filter_map(|n| coordinator.store_input_block(datalog, input_block(datalog, n))).next().unwrap();
+        coordinator.publish_input_all(&input_all).await;
+        coordinator.send_input_all(&input_all, inverter.serial().unwrap()).await;
+
+        let mqtt::ChannelData::Message(message) = to_mqtt.try_recv()? else {
+            unreachable!()
+        };
+        assert_eq!(message.topic, format!("{}/inputs/all", datalog));
+
+        // influx gets the tags alongside the fields, and no nulls
+        let influx::ChannelData::InputData(data) = to_influx.try_recv()? else {
+            unreachable!()
+        };
+        assert_eq!(data["datalog"], datalog.to_string());
+        assert_eq!(data["inverter"], inverter.serial().unwrap().to_string());
+        assert!(data.as_object().unwrap().values().all(|value| !value.is_null()));
+
+        Ok(())
+    }
+}
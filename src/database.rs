@@ -2,6 +2,12 @@ use crate::prelude::*;
 use sqlx::{any::AnyConnectOptions, Pool, Any};
 use std::sync::RwLock;
 use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::spool::{SinkError, Spool};
+
+// how often to retry inserting spooled rows while the database is down
+const DRAIN_INTERVAL_SECS: u64 = 30;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ChannelData {
@@ -9,7 +15,7 @@ pub enum ChannelData {
     Shutdown,
 }
 
-pub type Sender = broadcast::Sender<ChannelData>;
+pub type Sender = bus::Sender<ChannelData>;
 
 enum DatabaseType {
     MySQL,
@@ -20,21 +26,30 @@ enum DatabaseType {
 #[derive(Clone, Debug)]
 pub struct Database {
     config: config::Database,
+    spool: Option<config::Spool>,
     channels: Channels,
     pool: Arc<RwLock<Option<Pool<Any>>>>,
+    // set by stop(); Shutdown on the channel goes to every database
+    stop: Arc<watch::Sender<bool>>,
 }
 
 impl Database {
     // databases don't bother with a ConfigWrapper yet as they don't care about any
     // changes once running; there's only enabled/url anyway and we'd use url to key off.
-    pub fn new(config: config::Database, channels: Channels) -> Self {
+    pub fn new(config: config::Database, spool: Option<config::Spool>, channels: Channels) -> Self {
         Self {
             config,
+            spool,
             channels,
             pool: Arc::new(RwLock::new(None)),
+            stop: Arc::new(watch::channel(false).0),
         }
     }
 
+    pub fn config(&self) -> &config::Database {
+        &self.config
+    }
+
     pub async fn start(&self) -> Result<()> {
         // TODO: could log the url but would need to redact password
         info!("initializing database");
@@ -46,8 +61,15 @@ impl Database {
         Ok(())
     }
 
+    /// Stops just this database, once it's finished with the row in hand
+    /// and saved its spool's state.
     pub fn stop(&self) {
-        let _ = self.channels.to_database.send(ChannelData::Shutdown);
+        self.stop.send_replace(true);
+    }
+
+    // until stop(), even if that was before we started looking
+    async fn stopped(stop: &mut watch::Receiver<bool>) {
+        let _ = stop.wait_for(|stop| *stop).await;
     }
 
     fn database(&self) -> Result<DatabaseType> {
@@ -97,9 +119,17 @@ impl Database {
     }
 
     async fn inserter(&self) -> Result<()> {
-        self.connect().await?;
-        info!("database connected");
-        self.migrate().await?;
+        let mut stop = self.stop.subscribe();
+
+        // nothing to lose yet, so no need to wait for a connection to stop
+        tokio::select! {
+            result = async {
+                self.connect().await?;
+                info!("database connected");
+                self.migrate().await
+            } => result?,
+            _ = Self::stopped(&mut stop) => return Ok(()),
+        }
 
         let mut receiver = self.channels.to_database.subscribe();
         let values = match self.database()? {
@@ -149,41 +179,139 @@ impl Database {
             values
         );
 
+        let spool = match &self.spool {
+            Some(config) => Some(Spool::open(config, &self.spool_name()).await?),
+            None => None,
+        };
+        // a drain can take a while if the database doesn't answer; the next
+        // one is a full interval later, leaving time to take in new rows
+        let mut drain = tokio::time::interval(std::time::Duration::from_secs(DRAIN_INTERVAL_SECS));
+        drain.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
         loop {
             use ChannelData::*;
 
-            match receiver.recv().await? {
-                Shutdown => break,
-                ReadInputAll(data) => {
-                    let mut retry_count = 0;
-                    let max_retries = 3;
-                    let mut backoff = 1;
-                    
-                    while retry_count < max_retries {
-                        match self.insert(&query, &data).await {
-                            Ok(_) => break,
-                            Err(err) => {
-                                error!("INSERT failed: {:?} - retrying in {}s", err, backoff);
-                                tokio::time::sleep(std::time::Duration::from_secs(backoff)).await;
-                                retry_count += 1;
-                                backoff *= 2;
+            tokio::select! {
+                message = receiver.recv() => match message? {
+                    Shutdown => break,
+                    ReadInputAll(data) => {
+                        let result = match &spool {
+                            Some(spool) => self.insert_or_spool(&query, spool, &data).await,
+                            None => {
+                                self.insert_with_retries(&query, &data).await;
+                                Ok(())
                             }
+                        };
+                        if let Err(err) = result {
+                            error!("database spool failed: {}", err);
                         }
                     }
-                    
-                    if retry_count == max_retries {
-                        error!("Failed to insert data after {} retries", max_retries);
+                },
+                _ = drain.tick(), if spool.as_ref().is_some_and(|spool| !spool.is_empty()) => {
+                    if let Some(spool) = &spool {
+                        if let Err(err) = self.drain(&query, spool).await {
+                            error!("database spool failed: {}", err);
+                        }
                     }
                 }
+                _ = Self::stopped(&mut stop) => break,
             }
         }
 
+        if let Some(spool) = &spool {
+            spool.flush().await?;
+        }
+
         Ok(())
     }
 
-    async fn insert(&self, query: &str, data: &eg4::packet::ReadInputAll) -> Result<()> {
-        let pool = self.connection().await?;
-        let mut conn = pool.acquire().await?;
+    // anything already spooled goes first, to keep the rows in order, and
+    // this waits behind it for the next drain rather than trying a database
+    // that's down again now. a row the database refuses is dropped here,
+    // rather than spooled to block the rest
+    async fn insert_or_spool(&self, query: &str, spool: &Spool, data: &eg4::packet::ReadInputAll) -> Result<()> {
+        if spool.is_empty() {
+            match self.insert(query, data).await {
+                Ok(()) => return Ok(()),
+                Err(SinkError::Retry(err)) => warn!("INSERT failed, spooling until the database is back: {:?}", err),
+                Err(SinkError::Reject(err)) => {
+                    error!("INSERT rejected, dropping {:?}: {:?}", data, err);
+                    return Ok(());
+                }
+            }
+        }
+
+        spool.push(&[data]).await?;
+        Ok(())
+    }
+
+    // inserts spooled rows oldest first, one at a time
+    async fn drain(&self, query: &str, spool: &Spool) -> Result<()> {
+        spool
+            .drain(1, |rows: Vec<eg4::packet::ReadInputAll>| async move {
+                for data in &rows {
+                    self.insert(query, data).await?;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    // without a spool, all we can do is try a few times
+    async fn insert_with_retries(&self, query: &str, data: &eg4::packet::ReadInputAll) {
+        let mut retry_count = 0;
+        let max_retries = 3;
+        let mut backoff = 1;
+
+        while retry_count < max_retries {
+            match self.insert(query, data).await {
+                Ok(_) => break,
+                Err(SinkError::Reject(err)) => {
+                    error!("INSERT rejected, dropping {:?}: {:?}", data, err);
+                    return;
+                }
+                Err(SinkError::Retry(err)) => {
+                    error!("INSERT failed: {:?} - retrying in {}s", err, backoff);
+                    tokio::time::sleep(std::time::Duration::from_secs(backoff)).await;
+                    retry_count += 1;
+                    backoff *= 2;
+                }
+            }
+        }
+
+        if retry_count == max_retries {
+            error!("Failed to insert data after {} retries", max_retries);
+        }
+    }
+
+    // one spool per database, named for where it is but not how to log in
+    fn spool_name(&self) -> String {
+        let url = self.config.url();
+        let location = url.rsplit('@').next().unwrap_or(url);
+        let location = location.split_once("://").map(|(_, rest)| rest).unwrap_or(location);
+        let scheme = url.split(':').next().unwrap_or("database");
+
+        let name: String = format!("{}-{}", scheme, location)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        format!("database-{}", name)
+    }
+
+    // errors reaching the database are worth retrying; anything else, such
+    // as a constraint or schema error, will fail the same way every time
+    fn classify(err: sqlx::Error) -> SinkError {
+        use sqlx::Error::*;
+
+        match err {
+            Io(_) | Tls(_) | Protocol(_) | PoolTimedOut | PoolClosed | WorkerCrashed => SinkError::Retry(err.into()),
+            _ => SinkError::Reject(err.into()),
+        }
+    }
+
+    async fn insert(&self, query: &str, data: &eg4::packet::ReadInputAll) -> Result<(), SinkError> {
+        let pool = self.connection().await.map_err(SinkError::Retry)?;
+        let mut conn = pool.acquire().await.map_err(Self::classify)?;
 
         sqlx::query(query)
             .bind(data.status as i32)
@@ -276,9 +404,10 @@ impl Database {
             .bind(data.cycle_count as i32)
             .bind(data.vbat_inv)
             .bind(data.datalog.to_string())
-            .bind(chrono::Local::now().naive_local().to_string())
+            .bind(data.time.0.with_timezone(&chrono::Local).naive_local().to_string())
             .execute(&mut *conn)
-            .await?;
+            .await
+            .map_err(Self::classify)?;
 
         Ok(())
     }
@@ -60,7 +60,15 @@ impl DatalogWriter {
         self.write_data(serial, datalog, "input", data)
     }
 
-    fn write_data(&self, serial: Serial, datalog: Serial, register_type: &str, data: &[(u16, u16)]) -> Result<()> {
+    // a complete, decoded set of inputs; these lines have "data" instead of "raw_data"
+    pub fn write_input_all(&self, serial: Serial, datalog: Serial, data: &eg4::packet::ReadInputAll) -> Result<()> {
+        let mut json_data = self.header(serial, datalog, "input_all")?;
+        json_data.insert("data".to_string(), serde_json::to_value(data)?);
+
+        self.write_line(json_data)
+    }
+
+    fn header(&self, serial: Serial, datalog: Serial, register_type: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)?
             .as_secs();
@@ -71,6 +79,12 @@ impl DatalogWriter {
         json_data.insert("datalog".to_string(), serde_json::Value::String(datalog.to_string()));
         json_data.insert("register_type".to_string(), serde_json::Value::String(register_type.to_string()));
 
+        Ok(json_data)
+    }
+
+    fn write_data(&self, serial: Serial, datalog: Serial, register_type: &str, data: &[(u16, u16)]) -> Result<()> {
+        let mut json_data = self.header(serial, datalog, register_type)?;
+
         // Convert register data to hex strings
         let mut raw_data = serde_json::Map::new();
         for (register, value) in data {
@@ -81,6 +95,10 @@ impl DatalogWriter {
         }
         json_data.insert("raw_data".to_string(), serde_json::Value::Object(raw_data));
 
+        self.write_line(json_data)
+    }
+
+    fn write_line(&self, json_data: serde_json::Map<String, serde_json::Value>) -> Result<()> {
         let json_value = serde_json::Value::Object(json_data);
         let json_string = serde_json::to_string(&json_value)?;
         
@@ -0,0 +1,319 @@
+use crate::prelude::*;
+use crate::eg4::inverter::ChannelData;
+use crate::eg4::packet_decoder::PacketDecoder;
+
+use {
+    bytes::BytesMut,
+    serde::{Deserialize, Serialize},
+    std::fs::OpenOptions,
+    std::io::BufRead,
+    std::path::{Path, PathBuf},
+    tokio::sync::mpsc,
+    tokio_util::codec::Decoder,
+};
+
+// records waiting to be written; past this the disk isn't keeping up, and
+// they're dropped rather than holding up the connection
+const QUEUE_SIZE: usize = 1024;
+// how long the channels have to stay empty after a replay before we take it
+// that everything replayed has been dealt with
+const REPLAY_QUIET_MS: u64 = 1000;
+// what every frame from the inverter starts with
+const HEADER_BYTES: [u8; 2] = [161, 26];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx, // bridge -> inverter, one frame per record
+    Rx, // inverter -> bridge, bytes exactly as read from the socket
+}
+
+/// One line of a capture file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub datalog: String,
+    pub direction: Direction,
+    pub hex: String,
+}
+
+impl Record {
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        // slicing by byte below would panic partway into a multi-byte char
+        if !self.hex.is_ascii() {
+            bail!("capture.rs:bad hex {}", self.hex);
+        }
+        if !self.hex.len().is_multiple_of(2) {
+            bail!("capture.rs:odd length hex {}", self.hex);
+        }
+
+        (0..self.hex.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&self.hex[i..i + 2], 16)
+                    .map_err(|err| anyhow!("capture.rs:bad hex {}: {}", self.hex, err))
+            })
+            .collect()
+    }
+}
+
+// CaptureWriter {{{
+/// Writes raw traffic to a JSON lines capture file, for attaching to bug
+/// reports and replaying later with `--replay`. Each connection gets a file
+/// of its own, `<stem>-<datalog>-<time>.jsonl` alongside the configured
+/// path. The writing happens on tokio's blocking pool, so a slow disk
+/// doesn't hold up the socket.
+pub struct CaptureWriter {
+    path: PathBuf,
+    lines: mpsc::Sender<String>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl CaptureWriter {
+    pub fn new(path: &str, datalog: Option<Serial>) -> Result<Self> {
+        let path = Self::timestamped(path, datalog, Utils::utc());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| anyhow!("capture.rs:error opening {}: {}", path.display(), err))?;
+
+        let (lines, mut rx) = mpsc::channel::<String>(QUEUE_SIZE);
+        let task = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                // one write per line so records don't interleave
+                while let Some(line) = rx.blocking_recv() {
+                    if let Err(err) = file.write_all(line.as_bytes()) {
+                        warn!("capture.rs:error writing {}: {}", path.display(), err);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { path, lines, task })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for everything written so far to reach the file.
+    pub async fn finish(self) -> Result<()> {
+        drop(self.lines);
+        self.task.await?;
+        Ok(())
+    }
+
+    // capture.jsonl -> capture-<datalog>-20261016T195146Z.jsonl
+    fn timestamped(path: &str, datalog: Option<Serial>, time: chrono::DateTime<chrono::Utc>) -> PathBuf {
+        let path = Path::new(path);
+        let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_else(|| "capture".into());
+        let extension = path.extension().map(|s| s.to_string_lossy()).unwrap_or_else(|| "jsonl".into());
+
+        let mut name = stem.to_string();
+        if let Some(datalog) = datalog {
+            name.push_str(&format!("-{}", datalog));
+        }
+        name.push_str(&format!("-{}.{}", time.format("%Y%m%dT%H%M%SZ"), extension));
+
+        path.with_file_name(name)
+    }
+
+    pub fn write(&self, datalog: Option<Serial>, direction: Direction, bytes: &[u8]) -> Result<()> {
+        let record = Record {
+            timestamp: Utils::utc(),
+            datalog: datalog.map(|s| s.to_string()).unwrap_or_default(),
+            direction,
+            hex: bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+
+        match self.lines.try_send(line) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => bail!("capture.rs:{} is behind, dropping a record", self.path.display()),
+            Err(mpsc::error::TrySendError::Closed(_)) => bail!("capture.rs:{} writer has stopped", self.path.display()),
+        }
+    }
+} // }}}
+
+// Replay {{{
+/// Reads a capture file back, decoding the inverter's side of it exactly as
+/// the live connection would have.
+pub struct Replay {
+    records: Vec<Record>,
+}
+
+impl Replay {
+    pub fn new(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow!("capture.rs:error reading {}: {}", path, err))?;
+
+        let mut records = Vec::new();
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(Self { records })
+    }
+
+    pub fn records(&self) -> &Vec<Record> {
+        &self.records
+    }
+
+    /// Every packet the inverter sent, in order. Each datalog's bytes are
+    /// buffered separately, as each had its own connection. Frames that
+    /// won't decode are skipped with a warning, so a capture of whatever
+    /// broke decoding can still be replayed.
+    pub fn packets(&self) -> Result<Vec<Packet>> {
+        let mut buffers: std::collections::HashMap<&str, BytesMut> = Default::default();
+        let mut decoder = PacketDecoder::new();
+        let mut packets = Vec::new();
+
+        for record in self.records.iter().filter(|r| r.direction == Direction::Rx) {
+            let buf = buffers.entry(&record.datalog).or_default();
+            buf.extend_from_slice(&record.bytes()?);
+
+            loop {
+                let len = buf.len();
+                match decoder.decode(buf) {
+                    Ok(Some(packet)) => packets.push(packet),
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("replay: skipping a frame from {} that won't decode: {}", record.datalog, err);
+                        // left where it was, so drop it up to the next header
+                        if buf.len() == len {
+                            let skip = buf[1..]
+                                .windows(2)
+                                .position(|w| w == HEADER_BYTES)
+                                .map(|i| i + 1)
+                                .unwrap_or(len);
+                            let _ = buf.split_to(skip);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Feeds the packets to the coordinator as if they'd come from the
+    /// inverter(s), which must be in the config, and returns once they've
+    /// been dealt with and published, so none of the output is cut short.
+    pub async fn run(&self, channels: Channels) -> Result<()> {
+        let packets = self.packets()?;
+        info!("replaying {} packets", packets.len());
+
+        for packet in packets {
+            if channels.from_inverter.send(ChannelData::Packet(packet)).is_err() {
+                bail!("send(from_inverter) failed - channel closed?");
+            }
+
+            // give the coordinator a chance to keep up
+            tokio::task::yield_now().await;
+        }
+
+        Self::drained(&channels).await;
+        info!("replay finished");
+
+        Ok(())
+    }
+
+    // the coordinator, then MQTT, have taken everything and stayed idle for
+    // REPLAY_QUIET_MS, long enough to have finished what they last took
+    async fn drained(channels: &Channels) {
+        let step = std::time::Duration::from_millis(50);
+        let mut quiet = std::time::Duration::ZERO;
+
+        while quiet.as_millis() < REPLAY_QUIET_MS as u128 {
+            tokio::time::sleep(step).await;
+            if channels.from_inverter.stats().queued == 0 && channels.to_mqtt.stats().queued == 0 {
+                quiet += step;
+            } else {
+                quiet = std::time::Duration::ZERO;
+            }
+        }
+    }
+} // }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eg4::packet::{DeviceFunction, TcpFrameFactory, TranslatedData};
+
+    #[tokio::test]
+    async fn test_capture_and_replay() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("capture.jsonl");
+
+        let datalog = Serial::from_str("2222222222")?;
+        let packet = Packet::TranslatedData(TranslatedData {
+            datalog,
+            device_function: DeviceFunction::ReadHold,
+            inverter: Serial::from_str("5555555555")?,
+            register: 12,
+            values: vec![22, 6, 1, 14, 30, 0],
+        });
+        let frame = TcpFrameFactory::build_reply(&packet);
+
+        let writer = CaptureWriter::new(path.to_str().unwrap(), Some(datalog))?;
+        let path = writer.path().to_str().unwrap().to_owned();
+        assert!(path.contains("capture-2222222222-"));
+        writer.write(Some(datalog), Direction::Tx, &TcpFrameFactory::build(&packet))?;
+        // a frame split across two reads, as can happen on a real socket
+        writer.write(Some(datalog), Direction::Rx, &frame[..10])?;
+        writer.write(Some(datalog), Direction::Rx, &frame[10..])?;
+        // one with a bad checksum, which is skipped, then a good one again
+        let mut corrupt = frame.clone();
+        *corrupt.last_mut().unwrap() ^= 0xFF;
+        writer.write(Some(datalog), Direction::Rx, &corrupt)?;
+        writer.write(Some(datalog), Direction::Rx, &frame)?;
+        writer.finish().await?;
+
+        let replay = Replay::new(&path)?;
+        assert_eq!(replay.records().len(), 5);
+        assert_eq!(replay.records()[0].direction, Direction::Tx);
+        assert_eq!(replay.records()[1].datalog, "2222222222");
+        assert_eq!(replay.packets()?, vec![packet.clone(), packet]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamped() {
+        let time = chrono::DateTime::parse_from_rfc3339("2026-10-16T19:51:46Z").unwrap().with_timezone(&chrono::Utc);
+        let datalog = Serial::from_str("2222222222").ok();
+
+        assert_eq!(
+            CaptureWriter::timestamped("/var/log/eg4/capture.jsonl", datalog, time),
+            PathBuf::from("/var/log/eg4/capture-2222222222-20261016T195146Z.jsonl")
+        );
+        assert_eq!(
+            CaptureWriter::timestamped("capture", None, time),
+            PathBuf::from("capture-20261016T195146Z.jsonl")
+        );
+    }
+
+    #[test]
+    fn test_bad_hex() {
+        let record = |hex: &str| Record {
+            timestamp: Utils::utc(),
+            datalog: "2222222222".to_string(),
+            direction: Direction::Rx,
+            hex: hex.to_string(),
+        };
+
+        assert_eq!(record("a11a").bytes().unwrap(), vec![0xa1, 0x1a]);
+        assert!(record("a11").bytes().is_err());
+        assert!(record("zz").bytes().is_err());
+        // even length in bytes, but not on a char boundary
+        assert!(record("aéa").bytes().is_err());
+    }
+}
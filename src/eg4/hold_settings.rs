@@ -0,0 +1,186 @@
+use crate::prelude::*;
+use crate::register_cache::RegisterType;
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// HoldSettings {{{
+/// Hold registers decoded by the register map: every register it knows,
+/// by shortname and in engineering units, plus the serial number and the
+/// inverter's clock, which span several registers. Registers that haven't
+/// been read yet are left out.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct HoldSettings(serde_json::Map<String, Value>);
+
+impl HoldSettings {
+    /// Decodes whichever of the given hold registers the map knows about;
+    /// the rest are ignored.
+    pub fn from_registers(register_map: &RegisterMap, registers: &BTreeMap<u16, u16>) -> Self {
+        let pairs: Vec<(u16, u16)> = registers.iter().map(|(register, value)| (*register, *value)).collect();
+        let mut fields = register_map.decode(RegisterType::Hold, &pairs);
+
+        if let Some(serial) = Self::serial(registers) {
+            fields.insert("serial".to_string(), serial.into());
+        }
+        if let Some(time) = Self::time(registers) {
+            fields.insert("time".to_string(), time.into());
+        }
+
+        Self(fields)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    pub fn fields(&self) -> &serde_json::Map<String, Value> {
+        &self.0
+    }
+
+    // a whole number setting, as the map decodes it
+    fn raw(&self, name: &str) -> Option<u16> {
+        self.get(name)?.as_u64().map(|value| value as u16)
+    }
+
+    /// Register 0 as parse_hold.rs decodes it, for Home Assistant's device
+    /// page. The inverter only gives us codes, so that's what we show.
+    pub fn model_name(&self) -> Option<String> {
+        // all zeros is a blank register, not a model
+        let model = self.raw("model").filter(|model| *model != 0)?;
+
+        Some(format!(
+            "{:#06x} (power rating {}, lithium type {}, lead acid type {}, battery type {})",
+            model,
+            (model >> 8) & 0xF,
+            model >> 12,
+            (model >> 4) & 0xF,
+            model & 0xF
+        ))
+    }
+
+    /// Firmware as the inverter's display shows it, eg FAAB-1F22: four
+    /// ASCII characters from registers 7 and 8, then the slave and control
+    /// CPU versions. Registers 7 and 8 in hex if they aren't ASCII.
+    pub fn firmware(&self) -> Option<String> {
+        let codes = [self.raw("firmware_code")?, self.raw("backup_firmware_code")?];
+        let versions = [self.raw("slave_cpu_version")?, self.raw("control_cpu_version")?];
+        if codes == [0, 0] {
+            return None;
+        }
+
+        let bytes: Vec<u8> = codes.iter().flat_map(|code| code.to_le_bytes()).collect();
+        let code = if bytes.iter().all(u8::is_ascii_alphanumeric) {
+            String::from_utf8_lossy(&bytes).to_string()
+        } else {
+            format!("{:04X}{:04X}", codes[0], codes[1])
+        };
+
+        Some(format!("{}-{:02X}{:02X}", code, versions[0] & 0xFF, versions[1] & 0xFF))
+    }
+
+    // two ASCII characters per register, low byte first
+    fn serial(registers: &BTreeMap<u16, u16>) -> Option<String> {
+        let mut serial = String::new();
+        for register in 2..=6 {
+            let value = registers.get(&register)?;
+            for byte in value.to_le_bytes() {
+                if !byte.is_ascii_alphanumeric() {
+                    return None;
+                }
+                serial.push(byte as char);
+            }
+        }
+
+        Some(serial)
+    }
+
+    // 12 = month (MSB) year (LSB), 13 = hour (MSB) day (LSB), 14 = second (MSB) minute (LSB)
+    fn time(registers: &BTreeMap<u16, u16>) -> Option<String> {
+        let (r12, r13, r14) = (registers.get(&12)?, registers.get(&13)?, registers.get(&14)?);
+
+        Some(format!(
+            "20{:02}-{:02}-{:02} {:02}:{:02}:{:02}",
+            r12 & 0xFF,
+            r12 >> 8,
+            r13 & 0xFF,
+            r13 >> 8,
+            r14 & 0xFF,
+            r14 >> 8
+        ))
+    }
+} // }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_registers() {
+        let registers = BTreeMap::from([
+            (2, u16::from_le_bytes(*b"AB")),
+            (3, u16::from_le_bytes(*b"12")),
+            (4, u16::from_le_bytes(*b"34")),
+            (5, u16::from_le_bytes(*b"56")),
+            (6, u16::from_le_bytes(*b"78")),
+            (12, 0x0A18), // October 2024
+            (13, 0x0D10), // 13:00 on the 16th
+            (14, 0x0502), // 5s past 13:02
+            (21, 1 << 7),
+            (26, 2530),
+            (28, 5150),
+            (64, 100),
+            (68, 0x1E17), // 23:30
+            (119, (-20i16) as u16),
+            (172, (-25i16) as u16),
+        ]);
+
+        let settings = HoldSettings::from_registers(&RegisterMap::builtin(), &registers);
+        assert_eq!(settings.get("serial"), Some(&json!("AB12345678")));
+        assert_eq!(settings.get("time"), Some(&json!("2024-10-16 13:02:05")));
+        assert_eq!(settings.get("function_enable").unwrap()["ac_charge_en"], "ON");
+        assert_eq!(settings.get("function_enable").unwrap()["eps_en"], "OFF");
+        assert_eq!(settings.get("grid_volt_conn_high"), Some(&json!(253.0)));
+        assert_eq!(settings.get("grid_freq_conn_high"), Some(&json!(51.5)));
+        assert_eq!(settings.get("charge_power_percent_cmd"), Some(&json!(100)));
+        assert_eq!(settings.get("ac_charge_start_time_0"), Some(&json!("23:30")));
+        assert_eq!(settings.get("ct_power_offset"), Some(&json!(-20)));
+        assert_eq!(settings.get("auto_test_limit"), Some(&json!(-2.5)));
+
+        // registers we haven't read are left out entirely
+        let json = serde_json::to_value(&settings).unwrap();
+        assert_eq!(json["grid_volt_conn_high"], 253.0);
+        assert!(json.get("grid_volt_conn_low").is_none());
+    }
+
+    #[test]
+    fn test_model_and_firmware() {
+        let map = RegisterMap::builtin();
+        let mut registers = BTreeMap::from([
+            (0, 0x1A25),
+            (7, u16::from_le_bytes(*b"FA")),
+            (8, u16::from_le_bytes(*b"AB")),
+            (9, 0x1F),
+        ]);
+
+        let settings = HoldSettings::from_registers(&map, &registers);
+        assert_eq!(
+            settings.model_name().as_deref(),
+            Some("0x1a25 (power rating 10, lithium type 1, lead acid type 2, battery type 5)")
+        );
+        // no control CPU version yet
+        assert_eq!(settings.firmware(), None);
+
+        registers.insert(10, 0x22);
+        assert_eq!(HoldSettings::from_registers(&map, &registers).firmware().as_deref(), Some("FAAB-1F22"));
+
+        registers.insert(7, 0x0102);
+        assert_eq!(HoldSettings::from_registers(&map, &registers).firmware().as_deref(), Some("01024241-1F22"));
+
+        let blank = HoldSettings::from_registers(&map, &(0..=10).map(|register| (register, 0)).collect());
+        assert_eq!(blank.model_name(), None);
+        assert_eq!(blank.firmware(), None);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::prelude::*;
-use crate::eg4::packet::{Packet, TcpFrameFactory};
+use crate::eg4::packet::{ModbusException, Packet, TcpFrameFactory};
 use crate::eg4::packet_decoder::PacketDecoder;
+use crate::eg4::capture::{CaptureWriter, Direction};
 
 use {
     async_trait::async_trait,
-    serde::{Serialize, Serializer},
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
     tokio::io::{AsyncReadExt, AsyncWriteExt},
+    std::sync::Arc,
     std::time::Duration,
     net2::TcpStreamExt,
 };
@@ -14,12 +16,13 @@ use {
 pub enum ChannelData {
     Connected(Serial),  // strictly speaking, these two only ever go
     Disconnect(Serial), // inverter->coordinator, but eh.
+    ConnectError(Serial, String), // why connecting failed or the connection dropped
     Packet(Packet),     // this one goes both ways through the channel.
     Shutdown,
     Heartbeat(Packet),
 }
-pub type Sender = broadcast::Sender<ChannelData>;
-pub type Receiver = broadcast::Receiver<ChannelData>;
+pub type Sender = bus::Sender<ChannelData>;
+pub type Receiver = bus::Receiver<ChannelData>;
 
 // WaitForReply {{{
 #[async_trait]
@@ -30,17 +33,20 @@ pub trait WaitForReply {
     #[cfg(feature = "mocks")]
     const TIMEOUT: u64 = 0; // fail immediately in tests
 
-    async fn wait_for_reply(&mut self, packet: &Packet) -> Result<Packet>;
+    async fn wait_for_reply(&mut self, packet: &Packet) -> Result<Packet> {
+        self.wait_for_reply_within(packet, Duration::from_secs(Self::TIMEOUT)).await
+    }
+
+    async fn wait_for_reply_within(&mut self, packet: &Packet, timeout: Duration) -> Result<Packet>;
 }
 #[async_trait]
 impl WaitForReply for Receiver {
-    async fn wait_for_reply(&mut self, packet: &Packet) -> Result<Packet> {
-        let start = std::time::Instant::now();
-        let timeout_duration = std::time::Duration::from_secs(Self::TIMEOUT);
+    async fn wait_for_reply_within(&mut self, packet: &Packet, timeout: Duration) -> Result<Packet> {
+        let start = tokio::time::Instant::now();
 
         loop {
-            if start.elapsed() >= timeout_duration {
-                bail!("Timeout waiting for reply to {:?} after {} seconds", packet, Self::TIMEOUT);
+            if start.elapsed() >= timeout {
+                bail!("Timeout waiting for reply to {:?} after {} seconds", packet, timeout.as_secs_f64());
             }
 
             match (packet, self.try_recv()) {
@@ -50,8 +56,16 @@ impl WaitForReply for Receiver {
                 ) => {
                     if td.datalog == reply.datalog
                         && td.register == reply.register
-                        && td.device_function == reply.device_function
+                        && td.device_function == reply.device_function.request()
                     {
+                        if let Some(error) = reply.exception() {
+                            return Err(ModbusException {
+                                device_function: td.device_function,
+                                register: td.register,
+                                error,
+                            }
+                            .into());
+                        }
                         return Ok(Packet::TranslatedData(reply));
                     }
                 }
@@ -72,14 +86,14 @@ impl WaitForReply for Receiver {
                 }
                 (_, Ok(ChannelData::Packet(_))) => {} // Mismatched packet, continue waiting
                 (_, Ok(ChannelData::Heartbeat(_))) => { info!("heartbeat_rx from") } // Heartbeat received, continue waiting
-                (_, Ok(ChannelData::Connected(_))) => {} // Connection status update, continue waiting
+                (_, Ok(ChannelData::Connected(_) | ChannelData::ConnectError(..))) => {} // Connection status update, continue waiting
                 (_, Ok(ChannelData::Disconnect(inverter_datalog))) => {
                     if inverter_datalog == packet.datalog() {
                         bail!("Inverter {} disconnected while waiting for reply", inverter_datalog);
                     }
                 }
                 (_, Ok(ChannelData::Shutdown)) => bail!("Channel shutdown received while waiting for reply"),
-                (_, Err(broadcast::error::TryRecvError::Empty)) => {
+                (_, Err(bus::error::TryRecvError::Empty)) => {
                     // Channel empty, sleep briefly before retrying
                     tokio::time::sleep(std::time::Duration::from_millis(5)).await;
                 }
@@ -116,6 +130,17 @@ impl Serialize for Serial {
     }
 }
 
+impl<'de> Deserialize<'de> for Serial {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl std::str::FromStr for Serial {
     type Err = anyhow::Error;
 
@@ -175,6 +200,9 @@ impl Inverter {
     pub async fn start(&self) -> Result<()> {
         while let Err(e) = self.connect().await {
             error!("inverter {}: {}", self.config().datalog().map(|s| s.to_string()).unwrap_or_default(), e);
+            if let Some(datalog) = self.config().datalog() {
+                let _ = self.channels.from_inverter.send(ChannelData::ConnectError(datalog, e.to_string()));
+            }
             info!(
                 "inverter {}: reconnecting in {}s", 
                 self.config().datalog().map(|s| s.to_string()).unwrap_or_default(), 
@@ -230,9 +258,23 @@ impl Inverter {
 
         info!("inverter {}: connected!", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
 
-        // Start sender and receiver tasks
-        let sender_task = self.sender(writer);
-        let receiver_task = self.receiver(reader);
+        // Start sender, receiver, poller and proxy tasks
+        // raw traffic capture for debugging, if configured
+        let capture = match inverter_config.capture_file() {
+            Some(path) => {
+                let capture = CaptureWriter::new(path, inverter_config.datalog())?;
+                info!("capturing traffic to {}", capture.path().display());
+                Some(Arc::new(capture))
+            }
+            None => None,
+        };
+
+        let sender_task = self.sender(writer, capture.clone());
+        let receiver_task = self.receiver(reader, capture);
+        let poller_task = self.poller();
+        // never returns, so it only ends along with the session, however
+        // that ends; dropping it drops its clients, as the dongle would
+        let proxy_task = self.proxy();
 
         // Send Connected message after tasks are started
         if let Err(e) = self.channels.from_inverter.send(ChannelData::Connected(inverter_config.datalog().expect("datalog must be set"))) {
@@ -241,29 +283,129 @@ impl Inverter {
             info!("{}:sent Connected message", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
         }
 
-        tokio::select! {
+        let error = tokio::select! {
             res = sender_task => {
-                if let Err(e) = res {
+                if let Err(e) = &res {
                     warn!("Sender task error: {} for {}", e, inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                 } else {
                     warn!("Sender task ended for {}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                 }
+                res.err()
             }
             res = receiver_task => {
-                if let Err(e) = res {
+                if let Err(e) = &res {
                     warn!("Receiver task error: {} for {}", e, inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                 } else {
                     warn!("Receiver task ended for {}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                 }
+                res.err()
             }
-        }
+            res = poller_task => {
+                if let Err(e) = &res {
+                    warn!("Poller task error: {} for {}", e, inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
+                }
+                res.err()
+            }
+            _ = proxy_task => None,
+        };
 
         // Ensure we send a disconnect message
-        let _ = self.channels.from_inverter.send(ChannelData::Disconnect(inverter_config.datalog().expect("datalog must be set")));
+        let datalog = inverter_config.datalog().expect("datalog must be set");
+        if let Some(e) = error {
+            let _ = self.channels.from_inverter.send(ChannelData::ConnectError(datalog, e.to_string()));
+        }
+        let _ = self.channels.from_inverter.send(ChannelData::Disconnect(datalog));
         Ok(())
     }
 
-    async fn sender(&self, mut writer: tokio::net::tcp::OwnedWriteHalf) -> Result<()> {
+    // periodically queues reads of every input/hold register block with the
+    // coordinator; replies come back through the usual packet handling.
+    // only runs while connected, and never returns if polling is disabled.
+    async fn poller(&self) -> Result<()> {
+        let poll = self.config().poll();
+
+        futures::try_join!(
+            self.poll_every(poll.inputs_interval(), Command::read_all_inputs),
+            self.poll_every(poll.hold_interval(), Command::read_all_holds),
+        )?;
+
+        Ok(())
+    }
+
+    async fn poll_every(
+        &self,
+        period: Option<Duration>,
+        commands: fn(&config::Inverter) -> Vec<Command>,
+    ) -> Result<()> {
+        let period = match period {
+            Some(period) => period,
+            None => return futures::future::pending().await,
+        };
+
+        // first poll is one period after connecting, so we don't pile on top
+        // of publish_holdings_on_connect
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let inverter_config = self.config();
+            debug!(
+                "inverter {}: polling registers",
+                inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default()
+            );
+
+            // a poll that can't be queued isn't worth dropping the dongle
+            // for; skip the rest of this cycle and try again next tick
+            for command in commands(&inverter_config) {
+                if self
+                    .channels
+                    .to_coordinator
+                    .send(coordinator::ChannelData::Command(Box::new(command)))
+                    .is_err()
+                {
+                    warn!(
+                        "inverter {}: send(to_coordinator) failed - channel closed? skipping this poll",
+                        inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default()
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    // shares this connection with downstream clients, if configured. only
+    // runs while connected; clients are dropped on disconnect. errors are
+    // logged and the proxy restarted, never passed up to the connection -
+    // a proxy problem (port already taken, say) mustn't drop the dongle.
+    async fn proxy(&self) {
+        loop {
+            let inverter_config = self.config();
+            let datalog = inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default();
+
+            let proxy = match inverter_config.proxy() {
+                Some(proxy) => proxy,
+                None => return futures::future::pending().await,
+            };
+
+            if let Err(e) = eg4::proxy::Proxy::new(self.config.clone(), inverter_config, self.channels.clone())
+                .start(proxy)
+                .await
+            {
+                warn!("inverter {}: proxy error: {}", datalog, e);
+            }
+
+            info!("inverter {}: restarting proxy in {}s", datalog, RECONNECT_DELAY_SECS);
+            tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+        }
+    }
+
+    async fn sender(
+        &self,
+        mut writer: tokio::net::tcp::OwnedWriteHalf,
+        capture: Option<Arc<CaptureWriter>>,
+    ) -> Result<()> {
         let mut to_inverter_rx = self.channels.to_inverter.subscribe();
         let inverter_config = self.config();
 
@@ -273,7 +415,7 @@ impl Inverter {
                     info!("Received shutdown signal for {}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                     break;
                 }
-                Ok(ChannelData::Connected(_)) | Ok(ChannelData::Disconnect(_)) => {
+                Ok(ChannelData::Connected(_)) | Ok(ChannelData::Disconnect(_)) | Ok(ChannelData::ConnectError(..)) => {
                     // These messages shouldn't be sent to this channel
                     warn!("Unexpected connection status message in sender channel");
                     continue;
@@ -295,6 +437,12 @@ impl Inverter {
                     }
 
                     debug!("inverter {}: TX {:?}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default(), bytes);
+
+                    if let Some(capture) = &capture {
+                        if let Err(e) = capture.write(inverter_config.datalog(), Direction::Tx, &bytes) {
+                            warn!("Failed to write capture file: {}", e);
+                        }
+                    }
                     
                     // Use timeout for write operations
                     match tokio::time::timeout(
@@ -317,13 +465,9 @@ impl Inverter {
                         warn!("Failed to send heartbeat packet: {}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                     }
                 }
-                Err(broadcast::error::RecvError::Closed) => {
+                Err(bus::error::RecvError::Closed) => {
                     bail!("{}:Channel closed", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
                 }
-                Err(_e) => {
-                    warn!("Error reading from channel: {}", inverter_config.datalog().map(|s| s.to_string()).unwrap_or_default());
-                    continue;
-                }
             }
         }
 
@@ -332,7 +476,11 @@ impl Inverter {
     }
 
     // inverter -> coordinator
-    async fn receiver(&self, mut socket: tokio::net::tcp::OwnedReadHalf) -> Result<()> {
+    async fn receiver(
+        &self,
+        mut socket: tokio::net::tcp::OwnedReadHalf,
+        capture: Option<Arc<CaptureWriter>>,
+    ) -> Result<()> {
         use std::time::Duration;
         use tokio::time::timeout;
         use {bytes::BytesMut, tokio_util::codec::Decoder};
@@ -383,6 +531,16 @@ impl Inverter {
                         Err(_) => bail!("No data received for {} seconds", inverter_config.read_timeout() * READ_TIMEOUT_SECS),
                     };
 
+                    // capture what was read before decoding, so frames the
+                    // decoder chokes on are still in the capture
+                    if let Some(capture) = &capture {
+                        if len > 0 {
+                            if let Err(e) = capture.write(inverter_config.datalog(), Direction::Rx, &buf[buf.len() - len..]) {
+                                warn!("Failed to write capture file: {}", e);
+                            }
+                        }
+                    }
+
                     if len == 0 {
                         // Try to process any remaining data before disconnecting
                         while let Some(packet) = decoder.decode_eof(&mut buf)? {
@@ -1,3 +1,6 @@
+pub mod capture;
+pub mod hold_settings;
 pub mod inverter;
 pub mod packet;
 pub mod packet_decoder;
+pub mod proxy;
@@ -3,7 +3,7 @@ use crate::prelude::*;
 use enum_dispatch::*;
 use nom_derive::{Nom, Parse};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use log::error;
 use std::convert::TryFrom;
 
@@ -26,7 +26,7 @@ pub enum ReadInput {
 }
 
 // {{{ ReadInputAll
-#[derive(PartialEq, Clone, Debug, Serialize, Nom)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize, Nom)]
 #[nom(LittleEndian)]
 pub struct ReadInputAll {
     pub status: u16,
@@ -932,26 +932,58 @@ pub struct ReadInputs {
     read_input_4: Option<ReadInput4>,
     read_input_5: Option<ReadInput5>,
     read_input_6: Option<ReadInput6>,
+    // when each block above arrived, so stale ones aren't combined
+    received_at: [Option<chrono::DateTime<chrono::Utc>>; 6],
 }
 
 impl ReadInputs {
     pub fn set_read_input_1(&mut self, i: ReadInput1) {
         self.read_input_1 = Some(i);
+        self.received_at[0] = Some(Utils::utc());
     }
     pub fn set_read_input_2(&mut self, i: ReadInput2) {
         self.read_input_2 = Some(i);
+        self.received_at[1] = Some(Utils::utc());
     }
     pub fn set_read_input_3(&mut self, i: ReadInput3) {
         self.read_input_3 = Some(i);
+        self.received_at[2] = Some(Utils::utc());
     }
     pub fn set_read_input_4(&mut self, i: ReadInput4) {
         self.read_input_4 = Some(i);
+        self.received_at[3] = Some(Utils::utc());
     }
     pub fn set_read_input_5(&mut self, i: ReadInput5) {
         self.read_input_5 = Some(i);
+        self.received_at[4] = Some(Utils::utc());
     }
     pub fn set_read_input_6(&mut self, i: ReadInput6) {
         self.read_input_6 = Some(i);
+        self.received_at[5] = Some(Utils::utc());
+    }
+
+    // as if every block had arrived `by` earlier
+    #[cfg(test)]
+    pub fn backdate(&mut self, by: chrono::Duration) {
+        for received_at in self.received_at.iter_mut().flatten() {
+            *received_at -= by;
+        }
+    }
+
+    /// Like to_input_all, but only if every block arrived within max_age of
+    /// now, so we never mix a fresh block with one from minutes ago.
+    pub fn to_fresh_input_all(&self, max_age: chrono::Duration) -> Option<ReadInputAll> {
+        let now = Utils::utc();
+        let fresh = self
+            .received_at
+            .iter()
+            .all(|t| matches!(t, Some(t) if now - *t <= max_age));
+
+        if fresh {
+            self.to_input_all()
+        } else {
+            None
+        }
     }
 
     pub fn to_input_all(&self) -> Option<ReadInputAll> {
@@ -1185,10 +1217,27 @@ pub enum DeviceFunction {
     // UpdatePrepare = 33
     // UpdateSendData = 34
     // UpdateReset = 35
-    // ReadHoldError = 131
-    // ReadInputError = 132
-    // WriteSingleError = 134
-    // WriteMultiError = 144
+    ReadHoldError = 131,
+    ReadInputError = 132,
+    WriteSingleError = 134,
+    WriteMultiError = 144,
+}
+
+impl DeviceFunction {
+    /// Whether this is an exception reply rather than a normal one.
+    pub fn is_error(self) -> bool {
+        u8::from(self) & 0x80 != 0
+    }
+
+    /// The function a reply answers, whether it is an exception or not.
+    pub fn request(self) -> Self {
+        Self::try_from(u8::from(self) & 0x7f).unwrap_or(self)
+    }
+
+    /// The exception reply to this function.
+    pub fn error(self) -> Self {
+        Self::try_from(u8::from(self) | 0x80).unwrap_or(self)
+    }
 }
 // }}}
 
@@ -1327,7 +1376,7 @@ pub enum RegisterBit {
 }
 
 // Register21Bits {{{
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Register21Bits {
     pub eps_en: String,
     pub ovf_load_derate_en: String,
@@ -1380,7 +1429,7 @@ impl Register21Bits {
 // }}}
 
 // Register110Bits {{{
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Register110Bits {
     pub ub_pv_grid_off_en: String,
     pub ub_run_without_grid: String,
@@ -1426,9 +1475,24 @@ pub trait PacketCommon {
 pub struct TcpFrameFactory;
 impl TcpFrameFactory {
     pub fn build(data: &Packet) -> Vec<u8> {
-        let data_bytes = data.bytes();
-        let data_length = data_bytes.len() as u8;
-        let frame_length = (18 + data_length) as u16;
+        Self::frame(data, data.protocol(), data.bytes())
+    }
+
+    /// Builds the frame the inverter would have sent us for this packet, for
+    /// handing replies on to proxy clients.
+    pub fn build_reply(data: &Packet) -> Vec<u8> {
+        match data {
+            // replies always use protocol 2, which carries a value length byte
+            Packet::TranslatedData(td) => Self::frame(data, 2, td.encode(PacketSource::Inverter, 2)),
+            Packet::ReadParam(rp) => Self::frame(data, 2, rp.reply_bytes()),
+            Packet::WriteParam(wp) => Self::frame(data, 2, wp.reply_bytes()),
+            Packet::Heartbeat(_) => Self::build(data),
+        }
+    }
+
+    fn frame(data: &Packet, protocol: u16, data_bytes: Vec<u8>) -> Vec<u8> {
+        let data_length = data_bytes.len() as u16;
+        let frame_length = 18 + data_length;
 
         // debug!("data_length={}, frame_length={}", data_length, frame_length);
 
@@ -1436,7 +1500,7 @@ impl TcpFrameFactory {
 
         r[0] = 161;
         r[1] = 26;
-        r[2..4].copy_from_slice(&data.protocol().to_le_bytes());
+        r[2..4].copy_from_slice(&protocol.to_le_bytes());
         r[4..6].copy_from_slice(&(frame_length - 6).to_le_bytes());
         r[6] = 1; // unsure what this is, always seems to be 1
         r[7] = data.tcp_function() as u8;
@@ -1542,6 +1606,14 @@ impl TranslatedData {
             .collect()
     }
 
+    /// The error the inverter gave, if this is an exception reply.
+    pub fn exception(&self) -> Option<ModbusError> {
+        if !self.device_function.is_error() {
+            return None;
+        }
+        ModbusError::from_code(*self.values.first()?)
+    }
+
     pub fn read_input(&self) -> Result<ReadInput> {
         // Validate minimum value size before processing
         if self.values.len() < MIN_INPUT_BLOCK_SIZE {
@@ -1759,6 +1831,16 @@ impl TranslatedData {
     }
 
     fn decode(input: &[u8]) -> Result<Self> {
+        Self::decode_from(PacketSource::Inverter, input)
+    }
+
+    // requests as sent by a client (such as us) to the inverter, which lay out
+    // their values differently to the replies
+    fn decode_request(input: &[u8]) -> Result<Self> {
+        Self::decode_from(PacketSource::Client, input)
+    }
+
+    fn decode_from(source: PacketSource, input: &[u8]) -> Result<Self> {
         let len = input.len();
         if len < MIN_TRANSLATED_DATA_SIZE {
             bail!("TranslatedData::decode packet too short: {} bytes (minimum {})", 
@@ -1792,14 +1874,23 @@ impl TranslatedData {
         let mut value_len = 2;
         let mut value_offset = 14;
 
-        if Self::has_value_length_byte(PacketSource::Inverter, protocol, device_function) {
-            value_len = data[value_offset] as usize;
+        // client WriteMulti requests carry a register count before the values
+        if source == PacketSource::Client && device_function == DeviceFunction::WriteMulti {
+            value_offset += 2;
+        }
+
+        if Self::has_value_length_byte(source, protocol, device_function) {
+            value_len = *data
+                .get(value_offset)
+                .ok_or_else(|| anyhow!("TranslatedData::decode missing value length byte"))?
+                as usize;
             value_offset += 1;
         }
 
-        let values = data[value_offset..].to_vec();
+        let values = data.get(value_offset..).unwrap_or_default().to_vec();
 
-        if values.len() != value_len {
+        // exceptions carry just the exception code, however long the request was
+        if !device_function.is_error() && values.len() != value_len {
             bail!(
                 "TranslatedData::decode mismatch: values.len()={}, value_length_byte={}",
                 values.len(),
@@ -1816,6 +1907,49 @@ impl TranslatedData {
         })
     }
 
+    // the data portion of a frame; requests from a client by default, or
+    // as the inverter would send it for replies.
+    fn encode(&self, source: PacketSource, protocol: u16) -> Vec<u8> {
+        let mut data = vec![0; 16];
+
+        // data[2] (address) is 0 when writing to inverter, 1 when reading from it
+        if source == PacketSource::Inverter {
+            data[2] = 1;
+        }
+        data[3] = self.device_function as u8;
+
+        // experimental: looks like maybe you don't need to fill this in..
+        data[4..14].copy_from_slice(&self.inverter.data());
+        //data[4..14].copy_from_slice(&[0; 10]);
+
+        data[14..16].copy_from_slice(&self.register.to_le_bytes());
+
+        if source == PacketSource::Client && self.device_function == DeviceFunction::WriteMulti {
+            let register_count = self.pairs().len() as u16;
+            data.extend_from_slice(&register_count.to_le_bytes());
+        }
+
+        if Self::has_value_length_byte(source, protocol, self.device_function) {
+            let len = self.values.len() as u8;
+            data.extend_from_slice(&[len]);
+        }
+
+        let mut m = Vec::new();
+        for i in &self.values {
+            m.extend_from_slice(&i.to_le_bytes());
+        }
+        data.append(&mut m);
+
+        // the first two bytes are the data length, excluding checksum which we'll add next
+        let data_length = data.len() as u16;
+        data[0..2].copy_from_slice(&data_length.to_le_bytes());
+
+        // checksum does not include the first two bytes (data length)
+        data.extend_from_slice(&Self::checksum(&data[2..]));
+
+        data
+    }
+
     fn has_value_length_byte(
         source: PacketSource,
         protocol: u16,
@@ -1829,6 +1963,7 @@ impl TranslatedData {
             ReadHold | ReadInput => !p1 && psi,
             WriteSingle => false,
             WriteMulti => !p1 && !psi,
+            ReadHoldError | ReadInputError | WriteSingleError | WriteMultiError => false,
         }
     }
 
@@ -1865,42 +2000,7 @@ impl PacketCommon for TranslatedData {
     }
 
     fn bytes(&self) -> Vec<u8> {
-        let mut data = vec![0; 16];
-
-        // data[2] (address) is 0 when writing to inverter, 1 when reading from it
-        data[3] = self.device_function as u8;
-
-        // experimental: looks like maybe you don't need to fill this in..
-        data[4..14].copy_from_slice(&self.inverter.data());
-        //data[4..14].copy_from_slice(&[0; 10]);
-
-        data[14..16].copy_from_slice(&self.register.to_le_bytes());
-
-        if self.device_function == DeviceFunction::WriteMulti {
-            let register_count = self.pairs().len() as u16;
-            data.extend_from_slice(&register_count.to_le_bytes());
-        }
-
-        if Self::has_value_length_byte(PacketSource::Client, self.protocol(), self.device_function)
-        {
-            let len = self.values.len() as u8;
-            data.extend_from_slice(&[len]);
-        }
-
-        let mut m = Vec::new();
-        for i in &self.values {
-            m.extend_from_slice(&i.to_le_bytes());
-        }
-        data.append(&mut m);
-
-        // the first two bytes are the data length, excluding checksum which we'll add next
-        let data_length = data.len() as u16;
-        data[0..2].copy_from_slice(&data_length.to_le_bytes());
-
-        // checksum does not include the first two bytes (data length)
-        data.extend_from_slice(&Self::checksum(&data[2..]));
-
-        data
+        self.encode(PacketSource::Client, self.protocol())
     }
 
     fn register(&self) -> u16 {
@@ -1973,6 +2073,27 @@ impl ReadParam {
     fn has_value_length_bytes(protocol: u16) -> bool {
         protocol == 2
     }
+
+    // a client request is just the register number, padded
+    fn decode_request(input: &[u8]) -> Result<Self> {
+        if input.len() < 20 {
+            bail!("ReadParam::decode_request packet too short");
+        }
+
+        Ok(Self {
+            datalog: Serial::new(&input[8..18])?,
+            register: u16::from(input[18]),
+            values: Vec::new(),
+        })
+    }
+
+    // the reply as the inverter sends it, with protocol 2 length bytes
+    fn reply_bytes(&self) -> Vec<u8> {
+        let mut data = self.register.to_le_bytes().to_vec();
+        data.extend_from_slice(&(self.values.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.values);
+        data
+    }
 }
 
 impl PacketCommon for ReadParam {
@@ -2069,6 +2190,38 @@ impl WriteParam {
     fn has_value_length_bytes(_protocol: u16) -> bool {
         false
     }
+
+    fn decode_request(input: &[u8]) -> Result<Self> {
+        if input.len() < 22 {
+            bail!("WriteParam::decode_request packet too short");
+        }
+
+        let data = &input[18..];
+        let register = Utils::u16ify(data, 0);
+        let value_len = Utils::u16ify(data, 2) as usize;
+        let values = data[4..].to_vec();
+
+        if values.len() != value_len {
+            bail!(
+                "WriteParam::decode_request mismatch: values.len()={}, value_length_bytes={}",
+                values.len(),
+                value_len
+            );
+        }
+
+        Ok(Self {
+            datalog: Serial::new(&input[8..18])?,
+            register,
+            values,
+        })
+    }
+
+    // the reply as the inverter sends it: a one byte register and the value
+    fn reply_bytes(&self) -> Vec<u8> {
+        let mut data = vec![self.register as u8];
+        data.extend_from_slice(&self.values);
+        data
+    }
 }
 
 impl PacketCommon for WriteParam {
@@ -2119,7 +2272,34 @@ impl PacketCommon for WriteParam {
 
 pub struct Parser;
 impl Parser {
+    /// Parses a frame sent by a client to the inverter, rather than one
+    /// sent by the inverter; only heartbeats look the same both ways.
+    pub fn parse_request(input: &[u8]) -> Result<Packet> {
+        let r = match Self::parse_header(input)? {
+            TcpFunction::Heartbeat => Packet::Heartbeat(Heartbeat::decode(input)?),
+            TcpFunction::TranslatedData => {
+                Packet::TranslatedData(TranslatedData::decode_request(input)?)
+            }
+            TcpFunction::ReadParam => Packet::ReadParam(ReadParam::decode_request(input)?),
+            TcpFunction::WriteParam => Packet::WriteParam(WriteParam::decode_request(input)?),
+        };
+
+        Ok(r)
+    }
+
     pub fn parse(input: &[u8]) -> Result<Packet> {
+        let r = match Self::parse_header(input)? {
+            TcpFunction::Heartbeat => Packet::Heartbeat(Heartbeat::decode(input)?),
+            TcpFunction::TranslatedData => Packet::TranslatedData(TranslatedData::decode(input)?),
+            TcpFunction::ReadParam => Packet::ReadParam(ReadParam::decode(input)?),
+            TcpFunction::WriteParam => Packet::WriteParam(WriteParam::decode(input)?),
+            //_ => bail!("unhandled: tcp_function={} input={:?}", input[7], input),
+        };
+
+        Ok(r)
+    }
+
+    fn parse_header(input: &[u8]) -> Result<TcpFunction> {
         let input_len = input.len() as u8;
         if input_len < 18 {
             bail!("packet less than 18 bytes?");
@@ -2137,15 +2317,7 @@ impl Parser {
             );
         }
 
-        let r = match TcpFunction::try_from(input[7])? {
-            TcpFunction::Heartbeat => Packet::Heartbeat(Heartbeat::decode(input)?),
-            TcpFunction::TranslatedData => Packet::TranslatedData(TranslatedData::decode(input)?),
-            TcpFunction::ReadParam => Packet::ReadParam(ReadParam::decode(input)?),
-            TcpFunction::WriteParam => Packet::WriteParam(WriteParam::decode(input)?),
-            //_ => bail!("unhandled: tcp_function={} input={:?}", input[7], input),
-        };
-
-        Ok(r)
+        Ok(TcpFunction::try_from(input[7])?)
     }
 }
 
@@ -2187,6 +2359,11 @@ impl WarningCodeString {
             .unwrap()
     }
 
+    /// Every warning set in value, not just the first; empty if there are none.
+    pub fn decode(value: u32) -> Vec<&'static str> {
+        (0..=31).filter(|i| value & (1 << i) > 0).map(Self::from_bit).collect()
+    }
+
     fn from_bit(bit: usize) -> &'static str {
         match bit {
             0 => "W000: Battery communication failure",
@@ -2240,6 +2417,11 @@ impl FaultCodeString {
             .unwrap()
     }
 
+    /// Every fault set in value, not just the first; empty if there are none.
+    pub fn decode(value: u32) -> Vec<&'static str> {
+        (0..=31).filter(|i| value & (1 << i) > 0).map(Self::from_bit).collect()
+    }
+
     fn from_bit(bit: usize) -> &'static str {
         match bit {
             0 => "E000: Internal communication fault 1",
@@ -2281,59 +2463,61 @@ impl FaultCodeString {
 
 pub struct BatteryStatusString;
 impl BatteryStatusString {
-    pub fn decode_status_9(value: u16) -> Vec<&'static str> {
-        let mut statuses = Vec::new();
-        
-        // Decode each bit of bat_status_9
-        if value & (1 << 0) != 0 { statuses.push("Cell Balancing Active"); }
-        if value & (1 << 1) != 0 { statuses.push("Charging Enabled"); }
-        if value & (1 << 2) != 0 { statuses.push("Discharging Enabled"); }
-        if value & (1 << 3) != 0 { statuses.push("Heating Active"); }
-        if value & (1 << 4) != 0 { statuses.push("Battery Full"); }
-        if value & (1 << 5) != 0 { statuses.push("Battery Empty"); }
-        if value & (1 << 6) != 0 { statuses.push("SOC Calibration Active"); }
-        if value & (1 << 7) != 0 { statuses.push("BMS Fault Present"); }
-        if value & (1 << 8) != 0 { statuses.push("BMS Warning Present"); }
-        if value & (1 << 9) != 0 { statuses.push("Protection Active"); }
-        if value & (1 << 10) != 0 { statuses.push("Battery Disconnected"); }
-        if value & (1 << 11) != 0 { statuses.push("Battery Sleeping"); }
-        if value & (1 << 12) != 0 { statuses.push("Battery Standby"); }
-        if value & (1 << 13) != 0 { statuses.push("Battery Power Save"); }
-        if value & (1 << 14) != 0 { statuses.push("Battery Self Testing"); }
-        if value & (1 << 15) != 0 { statuses.push("Battery Fault"); }
+    // what each bit of bat_status_9 means, from bit 0 up
+    pub const STATUS_9: [&'static str; 16] = [
+        "Cell Balancing Active",
+        "Charging Enabled",
+        "Discharging Enabled",
+        "Heating Active",
+        "Battery Full",
+        "Battery Empty",
+        "SOC Calibration Active",
+        "BMS Fault Present",
+        "BMS Warning Present",
+        "Protection Active",
+        "Battery Disconnected",
+        "Battery Sleeping",
+        "Battery Standby",
+        "Battery Power Save",
+        "Battery Self Testing",
+        "Battery Fault",
+    ];
+
+    // and of bat_status_inv
+    pub const STATUS_INV: [&'static str; 16] = [
+        "Inverter Battery Active",
+        "Inverter Charging",
+        "Inverter Discharging",
+        "Battery Communication OK",
+        "BMS Protocol Matched",
+        "Battery Type Matched",
+        "Battery Power Limited",
+        "Battery Current Limited",
+        "Battery Voltage Limited",
+        "Battery Temperature Limited",
+        "Battery SOC Limited",
+        "Battery Maintenance Mode",
+        "Battery Force Charging",
+        "Battery Force Discharging",
+        "Battery Calibration Mode",
+        "Battery Protection Active",
+    ];
 
-        if statuses.is_empty() {
-            statuses.push("No Status Flags Set");
-        }
-        
-        statuses
+    pub fn decode_status_9(value: u16) -> Vec<&'static str> {
+        Self::decode(value, &Self::STATUS_9)
     }
 
     pub fn decode_status_inv(value: u16) -> Vec<&'static str> {
-        let mut statuses = Vec::new();
-        
-        // Decode each bit of bat_status_inv
-        if value & (1 << 0) != 0 { statuses.push("Inverter Battery Active"); }
-        if value & (1 << 1) != 0 { statuses.push("Inverter Charging"); }
-        if value & (1 << 2) != 0 { statuses.push("Inverter Discharging"); }
-        if value & (1 << 3) != 0 { statuses.push("Battery Communication OK"); }
-        if value & (1 << 4) != 0 { statuses.push("BMS Protocol Matched"); }
-        if value & (1 << 5) != 0 { statuses.push("Battery Type Matched"); }
-        if value & (1 << 6) != 0 { statuses.push("Battery Power Limited"); }
-        if value & (1 << 7) != 0 { statuses.push("Battery Current Limited"); }
-        if value & (1 << 8) != 0 { statuses.push("Battery Voltage Limited"); }
-        if value & (1 << 9) != 0 { statuses.push("Battery Temperature Limited"); }
-        if value & (1 << 10) != 0 { statuses.push("Battery SOC Limited"); }
-        if value & (1 << 11) != 0 { statuses.push("Battery Maintenance Mode"); }
-        if value & (1 << 12) != 0 { statuses.push("Battery Force Charging"); }
-        if value & (1 << 13) != 0 { statuses.push("Battery Force Discharging"); }
-        if value & (1 << 14) != 0 { statuses.push("Battery Calibration Mode"); }
-        if value & (1 << 15) != 0 { statuses.push("Battery Protection Active"); }
+        Self::decode(value, &Self::STATUS_INV)
+    }
+
+    fn decode(value: u16, names: &[&'static str; 16]) -> Vec<&'static str> {
+        let statuses: Vec<_> = (0..16).filter(|i| value & (1 << i) != 0).map(|i| names[i]).collect();
 
         if statuses.is_empty() {
-            statuses.push("No Status Flags Set");
+            return vec!["No Status Flags Set"];
         }
-        
+
         statuses
     }
 }
@@ -2369,3 +2553,26 @@ impl ModbusError {
         }
     }
 }
+
+impl std::fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {:#04x})", self.description(), *self as u8)
+    }
+}
+
+/// An exception reply to one of our requests, as an error. Asking again
+/// won't help, so these aren't retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModbusException {
+    pub device_function: DeviceFunction,
+    pub register: u16,
+    pub error: ModbusError,
+}
+
+impl std::fmt::Display for ModbusException {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "inverter refused {:?} of register {}: {}", self.device_function, self.register, self.error)
+    }
+}
+
+impl std::error::Error for ModbusException {}
@@ -12,12 +12,19 @@ const HEADER_BYTES: [u8; 2] = [161, 26];
 // Minimum valid packet size: header(2) + protocol(2) + length(2) + unknown(1) + tcp_function(1) + datalog(10) + min_payload(2)
 const MIN_PACKET_SIZE: usize = 20;
 
-pub struct PacketDecoder(());
+pub struct PacketDecoder {
+    // decoding frames a client sent to the inverter, rather than the other way
+    requests: bool,
+}
 
 impl PacketDecoder {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self(())
+        Self { requests: false }
+    }
+
+    pub fn new_for_requests() -> Self {
+        Self { requests: true }
     }
 
     // Verify checksum for TranslatedData packets
@@ -139,7 +146,13 @@ impl Decoder for PacketDecoder {
         trace!("Packet data: {:02x?}", data);
 
         // Parse the packet using the LXP parser
-        match Parser::parse(&data) {
+        let parsed = if self.requests {
+            Parser::parse_request(&data)
+        } else {
+            Parser::parse(&data)
+        };
+
+        match parsed {
             Ok(packet) => {
                 debug!("Successfully parsed packet: {:?}", packet);
                 Ok(Some(packet))
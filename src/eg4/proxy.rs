@@ -0,0 +1,385 @@
+use crate::prelude::*;
+use crate::eg4::inverter::ChannelData;
+use crate::eg4::packet::{DeviceFunction, ModbusError, TcpFrameFactory};
+use crate::request_queue::Priority;
+use crate::eg4::packet_decoder::PacketDecoder;
+
+use {
+    bytes::BytesMut,
+    futures::stream::{FuturesUnordered, StreamExt},
+    std::collections::HashMap,
+    std::sync::{Arc, Mutex},
+    std::time::{Duration, Instant},
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+    tokio::sync::mpsc,
+    tokio_util::codec::Decoder,
+};
+
+// how long we remember who asked for a reply; matches WaitForReply's timeout
+const PENDING_TIMEOUT_SECS: u64 = 30;
+const MAX_BUFFER_SIZE: usize = 65536;
+// replies waiting to go out to one client; past this it isn't keeping up
+const CLIENT_QUEUE_SIZE: usize = 64;
+
+type ClientId = usize;
+
+// Routes {{{
+struct PendingRequest {
+    client: ClientId,
+    device_function: DeviceFunction,
+    register: u16,
+    sent_at: Instant,
+}
+
+/// Remembers which client sent each request upstream, so the inverter's
+/// reply can be handed back to just that client.
+#[derive(Default)]
+pub struct Routes {
+    pending: Vec<PendingRequest>,
+}
+
+impl Routes {
+    pub fn request(&mut self, client: ClientId, packet: &Packet) {
+        if let Packet::TranslatedData(td) = packet {
+            self.pending.push(PendingRequest {
+                client,
+                device_function: td.device_function,
+                register: td.register,
+                sent_at: Instant::now(),
+            });
+        }
+    }
+
+    /// Which client asked for this reply. None means nobody did - it was
+    /// pushed by the inverter, or asked for by the bridge itself - so every
+    /// client gets a copy, as they would if connected to the dongle directly.
+    pub fn reply(&mut self, packet: &Packet) -> Option<ClientId> {
+        let timeout = Duration::from_secs(PENDING_TIMEOUT_SECS);
+        self.pending.retain(|p| p.sent_at.elapsed() < timeout);
+
+        let td = match packet {
+            Packet::TranslatedData(td) => td,
+            _ => return None,
+        };
+
+        let index = self
+            .pending
+            .iter()
+            .position(|p| p.device_function == td.device_function.request() && p.register == td.register)?;
+
+        Some(self.pending.remove(index).client)
+    }
+
+    pub fn forget(&mut self, client: ClientId) {
+        self.pending.retain(|p| p.client != client);
+    }
+} // }}}
+
+/// Accepts downstream clients (installer apps, the vendor cloud relay, ...)
+/// on behalf of a dongle that only allows a few connections, and shares the
+/// bridge's connection with them.
+#[derive(Clone)]
+pub struct Proxy {
+    config: ConfigWrapper,
+    inverter: config::Inverter,
+    channels: Channels,
+    clients: Arc<Mutex<HashMap<ClientId, mpsc::Sender<Vec<u8>>>>>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Proxy {
+    pub fn new(config: ConfigWrapper, inverter: config::Inverter, channels: Channels) -> Self {
+        Self {
+            config,
+            inverter,
+            channels,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            routes: Arc::new(Mutex::new(Routes::default())),
+        }
+    }
+
+    // runs until dropped, which drops every client along with it
+    pub async fn start(&self, proxy: config::Proxy) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind((proxy.host().to_owned(), proxy.port()))
+            .await
+            .map_err(|err| {
+                anyhow!("proxy.rs:can't listen on {}:{}: {}", proxy.host(), proxy.port(), err)
+            })?;
+
+        info!(
+            "inverter {}: proxy listening on {}:{}",
+            self.datalog(),
+            proxy.host(),
+            proxy.port()
+        );
+
+        futures::try_join!(self.acceptor(listener), self.upstream())?;
+
+        Ok(())
+    }
+
+    async fn acceptor(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        let mut next_id: ClientId = 0;
+        let mut clients = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (socket, peer) = accepted?;
+                    next_id += 1;
+                    info!("inverter {}: proxy client {} connected from {}", self.datalog(), next_id, peer);
+
+                    let (tx, rx) = mpsc::channel(CLIENT_QUEUE_SIZE);
+                    self.clients.lock().unwrap().insert(next_id, tx);
+                    clients.push(self.client(next_id, socket, rx));
+                }
+                Some(id) = clients.next(), if !clients.is_empty() => {
+                    info!("inverter {}: proxy client {} disconnected", self.datalog(), id);
+                    self.clients.lock().unwrap().remove(&id);
+                    self.routes.lock().unwrap().forget(id);
+                }
+            }
+        }
+    }
+
+    // inverter -> clients
+    async fn upstream(&self) -> Result<()> {
+        let mut receiver = self.channels.from_inverter.subscribe();
+        let datalog = self.inverter.datalog();
+
+        loop {
+            let packet = match receiver.recv().await {
+                Ok(ChannelData::Packet(packet)) => packet,
+                Ok(ChannelData::Shutdown) => break,
+                Ok(_) => continue,
+                Err(bus::error::RecvError::Closed) => bail!("from_inverter channel closed"),
+            };
+
+            if Some(packet.datalog()) != datalog {
+                continue;
+            }
+
+            // ReadParam/WriteParam are never forwarded upstream, so nobody is
+            // waiting for those
+            if !matches!(packet, Packet::TranslatedData(_) | Packet::Heartbeat(_)) {
+                continue;
+            }
+
+            let frame = TcpFrameFactory::build_reply(&packet);
+            let requester = self.routes.lock().unwrap().reply(&packet);
+            let clients = self.clients.lock().unwrap();
+
+            match requester {
+                Some(id) => {
+                    if let Some(tx) = clients.get(&id) {
+                        self.send_to(id, tx, frame);
+                    }
+                }
+                None => {
+                    for (id, tx) in clients.iter() {
+                        self.send_to(*id, tx, frame.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // a client too slow to take its replies misses some, rather than
+    // holding everyone else up or queueing without limit
+    fn send_to(&self, id: ClientId, tx: &mpsc::Sender<Vec<u8>>, frame: Vec<u8>) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(frame) {
+            warn!("inverter {}: proxy client {} isn't keeping up, dropping a reply", self.datalog(), id);
+        }
+    }
+
+    // client <-> inverter, returns the client id once it has gone
+    async fn client(
+        &self,
+        id: ClientId,
+        socket: tokio::net::TcpStream,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+    ) -> ClientId {
+        if let Err(err) = self.handle_client(id, socket, &mut rx).await {
+            debug!("inverter {}: proxy client {}: {}", self.datalog(), id, err);
+        }
+
+        id
+    }
+
+    async fn handle_client(
+        &self,
+        id: ClientId,
+        socket: tokio::net::TcpStream,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = socket.into_split();
+        let mut buf = BytesMut::with_capacity(MAX_BUFFER_SIZE);
+        let mut decoder = PacketDecoder::new_for_requests();
+
+        loop {
+            if buf.len() >= MAX_BUFFER_SIZE {
+                bail!("buffer overflow");
+            }
+
+            tokio::select! {
+                frame = rx.recv() => match frame {
+                    Some(frame) => writer.write_all(&frame).await?,
+                    None => return Ok(()),
+                },
+                len = reader.read_buf(&mut buf) => {
+                    if len? == 0 {
+                        return Ok(());
+                    }
+
+                    while let Some(packet) = decoder.decode(&mut buf)? {
+                        self.forward(id, packet)?;
+                    }
+                }
+            }
+        }
+    }
+
+    // client -> inverter
+    fn forward(&self, id: ClientId, packet: Packet) -> Result<()> {
+        let mut td = match packet {
+            Packet::TranslatedData(td) => td,
+            // heartbeats are answered by the bridge, and params aren't supported
+            _ => return Ok(()),
+        };
+
+        // clients don't always know the real datalog; the sender insists on it
+        if let Some(datalog) = self.inverter.datalog() {
+            td.datalog = datalog;
+        }
+
+        if matches!(td.device_function, DeviceFunction::WriteSingle | DeviceFunction::WriteMulti)
+            && (self.config.read_only() || self.inverter.read_only())
+        {
+            warn!(
+                "inverter {}: refusing write to register {} from proxy client {} - read-only mode",
+                self.datalog(),
+                td.register,
+                id
+            );
+
+            // answered the way the inverter refuses a write, so the client
+            // isn't left waiting for a reply that never comes
+            td.device_function = td.device_function.error();
+            td.values = vec![ModbusError::IllegalFunction as u8, 0];
+            let frame = TcpFrameFactory::build_reply(&Packet::TranslatedData(td));
+            if let Some(tx) = self.clients.lock().unwrap().get(&id) {
+                self.send_to(id, tx, frame);
+            }
+            return Ok(());
+        }
+
+        let priority = match td.device_function {
+            DeviceFunction::ReadHold | DeviceFunction::ReadInput => Priority::Read,
+            _ => Priority::Write,
+        };
+
+        let packet = Packet::TranslatedData(td);
+        self.routes.lock().unwrap().request(id, &packet);
+
+        // queued with our own requests so they don't collide; the reply
+        // gets back to the client through routes, like any other
+        let channels = self.channels.clone();
+        tokio::spawn(async move {
+            if let Err(err) = RequestQueue::request(&channels, packet, priority).await {
+                warn!("proxy client {}: request failed: {}", id, err);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn datalog(&self) -> String {
+        self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eg4::packet::{Parser, TranslatedData};
+
+    fn translated_data(device_function: DeviceFunction, register: u16, values: Vec<u8>) -> Packet {
+        Packet::TranslatedData(TranslatedData {
+            datalog: Serial::from_str("2222222222").unwrap(),
+            device_function,
+            inverter: Serial::from_str("5555555555").unwrap(),
+            register,
+            values,
+        })
+    }
+
+    #[test]
+    fn test_requests_round_trip() {
+        for packet in [
+            translated_data(DeviceFunction::ReadHold, 0, vec![40, 0]),
+            translated_data(DeviceFunction::WriteSingle, 21, vec![1, 2]),
+            translated_data(DeviceFunction::WriteMulti, 68, vec![1, 2, 3, 4]),
+        ] {
+            let frame = TcpFrameFactory::build(&packet);
+            assert_eq!(Parser::parse_request(&frame).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_replies_round_trip() {
+        for packet in [
+            translated_data(DeviceFunction::ReadInput, 0, (0..=253).collect()),
+            translated_data(DeviceFunction::ReadHold, 40, vec![7; 80]),
+            translated_data(DeviceFunction::WriteSingle, 21, vec![1, 2]),
+        ] {
+            let frame = TcpFrameFactory::build_reply(&packet);
+            assert_eq!(Parser::parse(&frame).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut routes = Routes::default();
+
+        routes.request(1, &translated_data(DeviceFunction::ReadHold, 0, vec![40, 0]));
+        routes.request(2, &translated_data(DeviceFunction::ReadHold, 40, vec![40, 0]));
+
+        // unsolicited push: no owner
+        let push = translated_data(DeviceFunction::ReadInput, 0, vec![0; 80]);
+        assert_eq!(routes.reply(&push), None);
+
+        let reply = translated_data(DeviceFunction::ReadHold, 40, vec![0; 80]);
+        assert_eq!(routes.reply(&reply), Some(2));
+        // each request gets one reply
+        assert_eq!(routes.reply(&reply), None);
+
+        routes.forget(1);
+        let reply = translated_data(DeviceFunction::ReadHold, 0, vec![0; 80]);
+        assert_eq!(routes.reply(&reply), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_write_refused() {
+        let config = ConfigWrapper::from_config(Config::new("config.yaml.example".to_string()).unwrap());
+        let mut inverter = config.inverters()[0].clone();
+        inverter.read_only = Some(true);
+        let proxy = Proxy::new(config, inverter, Channels::new());
+
+        let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE_SIZE);
+        proxy.clients.lock().unwrap().insert(1, tx);
+
+        proxy.forward(1, translated_data(DeviceFunction::WriteSingle, 21, vec![1, 2])).unwrap();
+
+        let reply = Parser::parse(&rx.try_recv().unwrap()).unwrap();
+        match reply {
+            Packet::TranslatedData(td) => {
+                assert_eq!(td.device_function, DeviceFunction::WriteSingle.error());
+                assert_eq!(td.register, 21);
+                assert_eq!(td.exception(), Some(ModbusError::IllegalFunction));
+            }
+            _ => panic!("expected an exception reply, got {:?}", reply),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::prelude::*;
-use crate::eg4::packet::Register;
+use crate::eg4::packet::{BatteryStatusString, Register};
+use crate::eg4::hold_settings::HoldSettings;
+use crate::register_map::{Datatype, Flag, RegisterDef};
 
 use serde::{Serialize, Serializer};
 
@@ -33,23 +35,94 @@ impl Serialize for ValueTemplate {
     }
 } // }}}
 
+// https://www.home-assistant.io/integrations/sensor.mqtt/#availability
 #[derive(Clone, Debug, Serialize)]
 pub struct Availability {
+    availability: Vec<AvailabilityTopic>,
+    // available only while all of them say so
+    availability_mode: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AvailabilityTopic {
     topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<String>,
 }
 
+// https://www.home-assistant.io/integrations/sensor.mqtt/#device
 #[derive(Clone, Debug, Serialize)]
 pub struct Device {
     manufacturer: String,
     name: String,
     identifiers: [String; 1],
-    // model: String, // TODO: provide inverter model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configuration_url: Option<String>,
+    // the parent device's identifier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via_device: Option<String>,
+}
+
+/// What an inverter's hold registers say about it, for its device page.
+/// Empty until registers 0-10 have been read.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceInfo {
+    model: Option<String>,
+    firmware: Option<String>,
 }
 
+impl DeviceInfo {
+    pub fn new(settings: &HoldSettings) -> Self {
+        Self {
+            model: settings.model_name(),
+            firmware: settings.firmware(),
+        }
+    }
+}
+
+// the faults and warnings topics' one line summary, cut to fit in a state
+const CODES_TEMPLATE: &str = "{{ value_json.text[:255] }}";
+
+// flags switch() already has an entity for, under its own name
+const FLAGS_WITH_SWITCHES: &[&str] = &["ac_charge_en", "forced_chg_en", "forced_dischg_en"];
+
+// hold registers that get a number entity, besides the number_percent() ones
+const SETTINGS: &[&str] = &[
+    "start_pv_volt",
+    "grid_volt_conn_low",
+    "grid_volt_conn_high",
+    "grid_freq_conn_low",
+    "grid_freq_conn_high",
+    "grid_volt_limit1_low",
+    "grid_volt_limit1_high",
+    "grid_freq_limit1_low",
+    "grid_freq_limit1_high",
+    "charge_current_limit",
+    "discharge_current_limit",
+    "ac_charge_battery_current",
+    "ct_power_offset",
+    "float_charge_volt",
+    "equalization_volt",
+    "ac_charge_start_volt",
+    "ac_charge_end_volt",
+    "battery_warning_volt",
+    "battery_warning_recovery_volt",
+    "battery_warning_soc",
+    "battery_warning_recovery_soc",
+    "on_grid_eod_volt",
+];
+
 pub struct Config {
     inverter: config::Inverter,
     mqtt_config: config::Mqtt,
     global_config: config::ConfigWrapper,
+    device_info: DeviceInfo,
 }
 
 // https://www.home-assistant.io/integrations/sensor.mqtt/
@@ -81,8 +154,11 @@ pub struct Entity<'a> {
     unit_of_measurement: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     icon: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<&'a str>,
 
     device: Device,
+    #[serde(flatten)]
     availability: Availability,
 }
 
@@ -94,7 +170,10 @@ pub struct Switch {
     command_topic: String,
     value_template: String,
     unique_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'static str>,
     device: Device,
+    #[serde(flatten)]
     availability: Availability,
 }
 
@@ -105,15 +184,40 @@ pub struct Number {
     state_topic: String,
     command_topic: String,
     value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_template: Option<String>,
     unique_id: String,
     device: Device,
+    #[serde(flatten)]
     availability: Availability,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'static str>,
     min: f64,
     max: f64,
     step: f64,
+    // "box" where the limits aren't known, rather than a slider across the whole register
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<&'static str>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     unit_of_measurement: String,
 }
 
+// https://www.home-assistant.io/integrations/select.mqtt/
+#[derive(Debug, Serialize)]
+pub struct Select {
+    name: String,
+    state_topic: String,
+    command_topic: String,
+    value_template: String,
+    command_template: String,
+    unique_id: String,
+    entity_category: &'static str,
+    device: Device,
+    #[serde(flatten)]
+    availability: Availability,
+    options: Vec<String>,
+}
+
 // https://www.home-assistant.io/integrations/text.mqtt/
 #[derive(Debug, Serialize)]
 pub struct Text {
@@ -124,6 +228,7 @@ pub struct Text {
     value_template: String,
     unique_id: String,
     device: Device,
+    #[serde(flatten)]
     availability: Availability,
     pattern: String,
 }
@@ -134,10 +239,20 @@ impl Config {
             inverter: inverter.clone(),
             mqtt_config: mqtt_config.clone(),
             global_config: global_config.clone(),
+            device_info: DeviceInfo::default(),
         }
     }
 
+    pub fn with_device_info(mut self, device_info: DeviceInfo) -> Self {
+        self.device_info = device_info;
+        self
+    }
+
     pub fn sensors(&self) -> Vec<mqtt::Message> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let faults_topic = format!("{}/{}/faults", self.mqtt_config.namespace(), datalog);
+        let warnings_topic = format!("{}/{}/warnings", self.mqtt_config.namespace(), datalog);
+
         let base = Entity {
             key: &String::default(),
             unique_id: &String::default(),
@@ -147,6 +262,7 @@ impl Config {
             state_class: None,
             unit_of_measurement: None,
             icon: None,
+            json_attributes_topic: None,
             value_template: ValueTemplate::Default, // "{{ value_json.$key }}"
             // TODO: might change this to an enum that defaults to InputsAll but can be replaced
             // with a string for a specific topic?
@@ -224,16 +340,14 @@ impl Config {
                 unit_of_measurement: Some("%"),
                 ..base.clone()
             },
+            // every active one, with the list and raw code as attributes
             Entity {
                 key: "fault_code",
                 name: "Fault Code",
                 entity_category: Some("diagnostic"),
-                state_topic: &format!(
-                    "{}/{}/input/fault_code/parsed",
-                    self.mqtt_config.namespace(),
-                    self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
-                ),
-                value_template: ValueTemplate::None,
+                state_topic: &faults_topic,
+                json_attributes_topic: Some(&faults_topic),
+                value_template: ValueTemplate::String(CODES_TEMPLATE.to_string()),
                 icon: Some("mdi:alert"),
                 ..base.clone()
             },
@@ -241,12 +355,9 @@ impl Config {
                 key: "warning_code",
                 name: "Warning Code",
                 entity_category: Some("diagnostic"),
-                state_topic: &format!(
-                    "{}/{}/input/warning_code/parsed",
-                    self.mqtt_config.namespace(),
-                    self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
-                ),
-                value_template: ValueTemplate::None,
+                state_topic: &warnings_topic,
+                json_attributes_topic: Some(&warnings_topic),
+                value_template: ValueTemplate::String(CODES_TEMPLATE.to_string()),
                 icon: Some("mdi:alert-outline"),
                 ..base.clone()
             },
@@ -663,7 +774,11 @@ impl Config {
             return Ok(Vec::new());
         }
 
-        let mut r = vec![
+        // the dongle first, so it's there for the inverter's via_device
+        let mut r = vec![self.connectivity()?];
+        r.append(&mut self.dongle_sensors()?);
+
+        r.append(&mut vec![
             self.switch("ac_charge", "AC Charge")?,
             self.switch("charge_priority", "Charge Priority")?,
             self.switch("forced_discharge", "Forced Discharge")?,
@@ -699,9 +814,13 @@ impl Config {
             self.time_range("forced_discharge/1", "Forced Discharge Timeslot 1")?,
             self.time_range("forced_discharge/2", "Forced Discharge Timeslot 2")?,
             self.time_range("forced_discharge/3", "Forced Discharge Timeslot 3")?,
-        ];
+        ]);
 
+        r.append(&mut self.flag_switches()?);
+        r.append(&mut self.setting_numbers()?);
+        r.append(&mut self.selects()?);
         r.append(&mut self.sensors());
+        r.append(&mut self.bms_binary_sensors()?);
 
         Ok(r)
     }
@@ -733,6 +852,7 @@ impl Config {
                 name
             ),
             unique_id: format!("lxp_{}_{}", self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), name),
+            entity_category: None,
             name: label.to_string(),
             device: self.device(),
             availability: self.availability(),
@@ -745,7 +865,12 @@ impl Config {
         })
     }
 
+    // limits and units come from the register map, where it knows them
     fn number_percent(&self, register: Register, label: &str) -> Result<mqtt::Message> {
+        let register_map = self.global_config.register_map();
+        let def = register_map.get(register_cache::RegisterType::Hold, register as u16);
+        let scale = def.map(|d| d.unit_scale).unwrap_or(1.0);
+
         let config = Number {
             name: label.to_string(),
             state_topic: format!(
@@ -760,14 +885,25 @@ impl Config {
                 self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default(),
                 register as u16,
             ),
-            value_template: "{{ float(value) }}".to_string(),
+            value_template: if scale == 1.0 {
+                "{{ float(value) }}".to_string()
+            } else {
+                format!("{{{{ float(value) * {} }}}}", scale)
+            },
+            command_template: if scale == 1.0 {
+                None
+            } else {
+                Some(format!("{{{{ (float(value) / {}) | round | int }}}}", scale))
+            },
             unique_id: format!("lxp_{}_number_{:?}", self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), register),
             device: self.device(),
             availability: self.availability(),
-            min: 0.0,
-            max: 100.0,
-            step: 1.0,
-            unit_of_measurement: "%".to_string(),
+            entity_category: None,
+            min: def.and_then(|d| d.min_value).unwrap_or(0.0) * scale,
+            max: def.and_then(|d| d.max_value).unwrap_or(100.0) * scale,
+            step: scale,
+            mode: None,
+            unit_of_measurement: def.and_then(|d| d.unit_symbol()).unwrap_or("%").to_string(),
         };
 
         Ok(mqtt::Message {
@@ -808,21 +944,456 @@ impl Config {
         })
     }
 
+    // a switch for each flag in a writable hold register, other than the
+    // ones switch() already covers
+    fn flag_switches(&self) -> Result<Vec<mqtt::Message>> {
+        let register_map = self.global_config.register_map();
+
+        register_map
+            .registers(register_cache::RegisterType::Hold)
+            .values()
+            .filter(|def| !def.read_only)
+            .flat_map(|def| def.flags.iter().map(move |flag| (def, flag)))
+            .filter(|(_, flag)| !FLAGS_WITH_SWITCHES.contains(&flag.name.as_str()))
+            .map(|(def, flag)| self.flag_switch(def, flag))
+            .collect()
+    }
+
+    fn flag_switch(&self, def: &RegisterDef, flag: &Flag) -> Result<mqtt::Message> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let config = Switch {
+            name: if flag.description.is_empty() { flag.name.clone() } else { flag.description.clone() },
+            state_topic: format!("{}/{}/hold/{}", self.mqtt_config.namespace(), datalog, def.register),
+            command_topic: format!("{}/cmd/{}/set/flag/{}", self.mqtt_config.namespace(), datalog, flag.name),
+            value_template: format!("{{{{ 'ON' if (value | int) | bitwise_and({}) else 'OFF' }}}}", 1u16 << flag.bit),
+            unique_id: self.unique_id(&flag.name),
+            entity_category: Some("config"),
+            device: self.device(),
+            availability: self.availability(),
+        };
+
+        Ok(mqtt::Message {
+            topic: self.ha_discovery_topic("switch", &flag.name),
+            retain: true,
+            payload: serde_json::to_string(&config)?,
+        })
+    }
+
+    fn setting_numbers(&self) -> Result<Vec<mqtt::Message>> {
+        let register_map = self.global_config.register_map();
+
+        SETTINGS
+            .iter()
+            .filter_map(|name| register_map.find(register_cache::RegisterType::Hold, name))
+            .filter(|def| !def.read_only)
+            .map(|def| self.setting_number(def))
+            .collect()
+    }
+
+    // in engineering units both ways; set/setting scales and checks it
+    fn setting_number(&self, def: &RegisterDef) -> Result<mqtt::Message> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let shortname = def.shortname.as_deref().unwrap_or_default();
+        let (type_min, type_max) = match def.datatype {
+            Datatype::Uint8 => (0.0, u8::MAX as f64),
+            Datatype::Int16 => (i16::MIN as f64, i16::MAX as f64),
+            Datatype::Uint16 | Datatype::Float => (0.0, u16::MAX as f64),
+        };
+        let scaled = |raw: f64| Utils::round(raw * def.unit_scale, 3);
+
+        let config = Number {
+            name: def.description.clone(),
+            state_topic: format!("{}/{}/hold/{}", self.mqtt_config.namespace(), datalog, def.register),
+            command_topic: format!("{}/cmd/{}/set/setting/{}", self.mqtt_config.namespace(), datalog, shortname),
+            value_template: Self::number_template(def),
+            command_template: None,
+            unique_id: format!("lxp_{}_number_{}", datalog, shortname),
+            device: self.device(),
+            availability: self.availability(),
+            entity_category: Some("config"),
+            min: scaled(def.min_value.unwrap_or(type_min)),
+            max: scaled(def.max_value.unwrap_or(type_max)),
+            step: def.unit_scale,
+            mode: if def.min_value.is_none() || def.max_value.is_none() { Some("box") } else { None },
+            unit_of_measurement: def.unit_symbol().unwrap_or_default().to_string(),
+        };
+
+        Ok(mqtt::Message {
+            topic: self.ha_discovery_topic("number", shortname),
+            retain: true,
+            payload: serde_json::to_string(&config)?,
+        })
+    }
+
+    // hold/<register> is the raw value; this does what RegisterDef::number() does
+    fn number_template(def: &RegisterDef) -> String {
+        let raw = match def.datatype {
+            Datatype::Uint8 => "(value | int) | bitwise_and(255)",
+            Datatype::Int16 => "((value | int) - 65536 if (value | int) > 32767 else (value | int))",
+            Datatype::Uint16 | Datatype::Float => "(value | int)",
+        };
+
+        if def.unit_scale == 1.0 {
+            format!("{{{{ {} }}}}", raw)
+        } else {
+            format!("{{{{ ({} * {}) | round(3) }}}}", raw, def.unit_scale)
+        }
+    }
+
+    // a select for each writable hold register with labelled values
+    fn selects(&self) -> Result<Vec<mqtt::Message>> {
+        let register_map = self.global_config.register_map();
+
+        register_map
+            .registers(register_cache::RegisterType::Hold)
+            .values()
+            .filter(|def| !def.read_only && !def.labels.is_empty() && def.shortname.is_some())
+            .map(|def| self.select(def))
+            .collect()
+    }
+
+    fn select(&self, def: &RegisterDef) -> Result<mqtt::Message> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let shortname = def.shortname.as_deref().unwrap_or_default();
+
+        // Jinja takes JSON string literals as they are
+        let mut to_label = Vec::new();
+        let mut to_value = Vec::new();
+        for (value, label) in &def.labels {
+            let label = serde_json::to_string(label)?;
+            to_label.push(format!("{}: {}", value, label));
+            to_value.push(format!("{}: {}", label, value));
+        }
+
+        let config = Select {
+            // descriptions of these tend to list the values after a colon
+            name: def.description.split(':').next().unwrap_or_default().trim().to_string(),
+            state_topic: format!("{}/{}/hold/{}", self.mqtt_config.namespace(), datalog, def.register),
+            command_topic: format!("{}/cmd/{}/set/hold/{}", self.mqtt_config.namespace(), datalog, def.register),
+            value_template: format!("{{{{ {{{}}}.get(value | int, value) }}}}", to_label.join(", ")),
+            command_template: format!("{{{{ {{{}}}[value] }}}}", to_value.join(", ")),
+            unique_id: format!("lxp_{}_select_{}", datalog, shortname),
+            entity_category: "config",
+            device: self.device(),
+            availability: self.availability(),
+            options: def.labels.values().cloned().collect(),
+        };
+
+        Ok(mqtt::Message {
+            topic: self.ha_discovery_topic("select", shortname),
+            retain: true,
+            payload: serde_json::to_string(&config)?,
+        })
+    }
+
+    // one per bit of the BMS status registers, decoded from inputs/all
+    fn bms_binary_sensors(&self) -> Result<Vec<mqtt::Message>> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let state_topic = format!("{}/{}/inputs/all", self.mqtt_config.namespace(), datalog);
+        let registers = [
+            ("bat_status_9", BatteryStatusString::STATUS_9),
+            ("bat_status_inv", BatteryStatusString::STATUS_INV),
+        ];
+
+        let mut r = Vec::new();
+        for (register, names) in registers {
+            for (bit, name) in names.iter().enumerate() {
+                let key = format!("{}_{}", register, bit);
+                let problem = ["Fault", "Warning", "Protection"].iter().any(|p| name.contains(p));
+                let sensor = Entity {
+                    key: &key,
+                    unique_id: &self.unique_id(&key),
+                    name,
+                    state_topic: &state_topic,
+                    entity_category: Some("diagnostic"),
+                    state_class: None,
+                    device_class: if problem { Some("problem") } else { None },
+                    value_template: ValueTemplate::String(format!(
+                        "{{{{ 'ON' if (value_json.{} | int) | bitwise_and({}) else 'OFF' }}}}",
+                        register,
+                        1u16 << bit
+                    )),
+                    unit_of_measurement: None,
+                    icon: None,
+                    json_attributes_topic: None,
+                    device: self.device(),
+                    availability: self.availability(),
+                };
+
+                r.push(mqtt::Message {
+                    topic: self.ha_discovery_topic("binary_sensor", &key),
+                    retain: true,
+                    payload: serde_json::to_string(&sensor)?,
+                });
+            }
+        }
+
+        Ok(r)
+    }
+
+    // whether the bridge is connected to the dongle, from its status topic
+    fn connectivity(&self) -> Result<mqtt::Message> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let sensor = Entity {
+            key: "connection",
+            unique_id: &self.unique_id("connection"),
+            name: "Connection",
+            state_topic: &format!("{}/{}/status", self.mqtt_config.namespace(), datalog),
+            entity_category: Some("diagnostic"),
+            state_class: None,
+            device_class: Some("connectivity"),
+            value_template: ValueTemplate::String("{{ 'ON' if value_json.status == 'online' else 'OFF' }}".to_string()),
+            unit_of_measurement: None,
+            icon: None,
+            json_attributes_topic: None,
+            device: self.dongle_device(),
+            availability: self.bridge_availability(),
+        };
+
+        Ok(mqtt::Message {
+            topic: self.ha_discovery_topic("binary_sensor", "connection"),
+            retain: true,
+            payload: serde_json::to_string(&sensor)?,
+        })
+    }
+
+    // the bridge's own view of the dongle. These stay available while it's
+    // offline, as that's when they're most interesting.
+    fn dongle_sensors(&self) -> Result<Vec<mqtt::Message>> {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        let stats_topic = format!("{}/stats", self.mqtt_config.namespace());
+        let status_topic = format!("{}/{}/status", self.mqtt_config.namespace(), datalog);
+        let disconnects_template = format!("{{{{ value_json.inverter_disconnections['{}'] | default(0) }}}}", datalog);
+
+        let base = Entity {
+            key: "",
+            unique_id: "",
+            name: "",
+            state_topic: &stats_topic,
+            entity_category: Some("diagnostic"),
+            state_class: None,
+            device_class: None,
+            value_template: ValueTemplate::Default,
+            unit_of_measurement: None,
+            icon: None,
+            json_attributes_topic: None,
+            device: self.dongle_device(),
+            availability: self.bridge_availability(),
+        };
+        let counter = Entity {
+            state_class: Some("total_increasing"),
+            icon: Some("mdi:counter"),
+            ..base.clone()
+        };
+
+        let sensors = [
+            // bridge-wide, the same for every inverter
+            Entity {
+                key: "packets_received",
+                name: "Packets Received",
+                ..counter.clone()
+            },
+            Entity {
+                key: "modbus_errors",
+                name: "Modbus Errors",
+                ..counter.clone()
+            },
+            // and this inverter's
+            Entity {
+                key: "disconnects",
+                name: "Disconnects",
+                value_template: ValueTemplate::String(disconnects_template),
+                ..counter.clone()
+            },
+            Entity {
+                key: "reconnects",
+                name: "Reconnects",
+                state_topic: &status_topic,
+                ..counter.clone()
+            },
+            Entity {
+                key: "last_seen",
+                name: "Last Seen",
+                state_topic: &status_topic,
+                device_class: Some("timestamp"),
+                ..base.clone()
+            },
+            Entity {
+                key: "last_error",
+                name: "Last Connection Error",
+                state_topic: &status_topic,
+                icon: Some("mdi:lan-disconnect"),
+                ..base.clone()
+            },
+        ];
+
+        sensors
+            .into_iter()
+            .map(|sensor| {
+                let mut sensor = Entity {
+                    unique_id: &self.unique_id(sensor.key),
+                    ..sensor
+                };
+                if sensor.value_template.is_default() {
+                    sensor.value_template = ValueTemplate::from_default(sensor.key);
+                }
+
+                Ok(mqtt::Message {
+                    topic: self.ha_discovery_topic("sensor", sensor.key),
+                    retain: true,
+                    payload: serde_json::to_string(&sensor)?,
+                })
+            })
+            .collect()
+    }
+
     fn unique_id(&self, name: &str) -> String {
         format!("lxp_{}_{}", self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default(), name)
     }
 
     fn device(&self) -> Device {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        // keyed by datalog like every unique_id and discovery topic, so
+        // existing entities stay on the device they were created on
         Device {
-            identifiers: [format!("lxp_{}", self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default())],
+            identifiers: [format!("lxp_{}", datalog)],
             manufacturer: "LuxPower".to_owned(),
-            name: format!("lxp_{}", self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()),
+            name: format!("lxp_{}", datalog),
+            model: self.device_info.model.clone(),
+            sw_version: self.device_info.firmware.clone(),
+            serial_number: self.inverter.serial().map(|s| s.to_string()),
+            configuration_url: self
+                .mqtt_config
+                .homeassistant()
+                .configuration_url()
+                .map(|url| format!("{}/api/inverters/{}/settings", url, datalog)),
+            via_device: Some(format!("lxp_dongle_{}", datalog)),
         }
     }
 
+    // the datalog dongle the inverter talks to us through
+    fn dongle_device(&self) -> Device {
+        let datalog = self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default();
+        Device {
+            identifiers: [format!("lxp_dongle_{}", datalog)],
+            manufacturer: "LuxPower".to_owned(),
+            name: format!("lxp_{} dongle", datalog),
+            model: Some("Datalog dongle".to_owned()),
+            sw_version: None,
+            serial_number: Some(datalog),
+            configuration_url: None,
+            via_device: None,
+        }
+    }
+
+    // just the bridge's LWT
+    fn bridge_availability(&self) -> Availability {
+        Availability {
+            availability: vec![AvailabilityTopic {
+                topic: format!("{}/LWT", self.mqtt_config.namespace()),
+                value_template: None,
+            }],
+            availability_mode: "all",
+        }
+    }
+
+    // the bridge's LWT, and whether it's connected to this inverter
     fn availability(&self) -> Availability {
         Availability {
-            topic: format!("{}/LWT", self.mqtt_config.namespace()),
+            availability: vec![
+                AvailabilityTopic {
+                    topic: format!("{}/LWT", self.mqtt_config.namespace()),
+                    value_template: None,
+                },
+                AvailabilityTopic {
+                    topic: format!(
+                        "{}/{}/status",
+                        self.mqtt_config.namespace(),
+                        self.inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
+                    ),
+                    value_template: Some("{{ value_json.status }}".to_string()),
+                },
+            ],
+            availability_mode: "all",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let config = config::ConfigWrapper::from_config(config::Config::new("config.yaml.example".to_string()).unwrap());
+        Config::new(&config.inverters()[0], &config.mqtt(), &config)
+    }
+
+    fn payload(message: &mqtt::Message) -> serde_json::Value {
+        serde_json::from_str(&message.payload).unwrap()
+    }
+
+    #[test]
+    fn test_device() {
+        let device = serde_json::to_value(config().device()).unwrap();
+        assert_eq!(device["identifiers"], serde_json::json!(["lxp_2222222222"]));
+        assert_eq!(device["name"], "lxp_2222222222");
+        assert_eq!(device["serial_number"], "5555555555");
+        assert_eq!(device["via_device"], "lxp_dongle_2222222222");
+        assert!(device.get("model").is_none());
+
+        let registers = std::collections::BTreeMap::from([(0, 0x1A25)]);
+        let settings = HoldSettings::from_registers(&RegisterMap::builtin(), &registers);
+        let device = config().with_device_info(DeviceInfo::new(&settings)).device();
+        assert_eq!(
+            serde_json::to_value(device).unwrap()["model"],
+            "0x1a25 (power rating 10, lithium type 1, lead acid type 2, battery type 5)"
+        );
+    }
+
+    #[test]
+    fn test_number_template() {
+        let register_map = RegisterMap::builtin();
+        let hold = |name| register_map.find(register_cache::RegisterType::Hold, name).unwrap();
+
+        assert_eq!(
+            Config::number_template(hold("grid_volt_conn_high")),
+            "{{ ((value | int) * 0.1) | round(3) }}"
+        );
+        assert_eq!(
+            Config::number_template(hold("ct_power_offset")),
+            "{{ ((value | int) - 65536 if (value | int) > 32767 else (value | int)) }}"
+        );
+        assert_eq!(Config::number_template(hold("charge_power_percent_cmd")), "{{ (value | int) }}");
+    }
+
+    #[test]
+    fn test_select() {
+        let config = config();
+        let register_map = RegisterMap::builtin();
+        let def = register_map.find(register_cache::RegisterType::Hold, "output_priority").unwrap();
+
+        let select = payload(&config.select(def).unwrap());
+        assert_eq!(select["command_topic"], "eg4/cmd/2222222222/set/hold/145");
+        assert_eq!(
+            select["value_template"],
+            r#"{{ {0: "Battery first", 1: "PV first", 2: "AC first"}.get(value | int, value) }}"#
+        );
+        assert_eq!(
+            select["command_template"],
+            r#"{{ {"Battery first": 0, "PV first": 1, "AC first": 2}[value] }}"#
+        );
+        assert_eq!(select["options"], serde_json::json!(["Battery first", "PV first", "AC first"]));
+    }
+
+    #[test]
+    fn test_flag_switch() {
+        let config = config();
+        let register_map = RegisterMap::builtin();
+        let (def, flag) = register_map.find_flag(register_cache::RegisterType::Hold, "ub_micro_grid_en").unwrap();
+
+        let switch = payload(&config.flag_switch(def, flag).unwrap());
+        assert_eq!(switch["state_topic"], "eg4/2222222222/hold/110");
+        assert_eq!(switch["command_topic"], "eg4/cmd/2222222222/set/flag/ub_micro_grid_en");
+        assert_eq!(switch["value_template"], "{{ 'ON' if (value | int) | bitwise_and(4) else 'OFF' }}");
+    }
+}
@@ -0,0 +1,661 @@
+use crate::prelude::*;
+
+use crate::command::CommandResult;
+use crate::request_queue::Priority;
+use crate::spool::Spool;
+use crate::coordinator::commands::write_inverter::WriteInverter;
+use crate::eg4::hold_settings::HoldSettings;
+use crate::eg4::inverter::ChannelData;
+use crate::eg4::packet::{DeviceFunction, ReadInput, ReadInputAll, TranslatedData};
+use crate::metrics::Metrics;
+use crate::register_cache::RegisterType;
+
+use {
+    hyper::service::{make_service_fn, service_fn},
+    hyper::{Body, Method, StatusCode},
+    serde::Serialize,
+    serde_json::json,
+    std::collections::HashMap,
+    std::convert::Infallible,
+    std::sync::{Arc, Mutex},
+};
+
+// a ReadInputAll is decoded from input registers 0-126
+const INPUT_ALL_COUNT: u16 = 127;
+// command payloads are a number, a bool or a time range, never anything big
+const MAX_BODY_SIZE: usize = 4096;
+
+type Response = hyper::Response<Body>;
+
+// Route {{{
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Route {
+    Inverters,
+    Stats,
+    Inputs(Serial),
+    Settings(Serial),
+    Registers(Serial, RegisterType),
+    Metrics,
+    // the rest of the path is an MQTT cmd topic, eg cmd/AB12345678/read/hold/0
+    Command(String),
+    NotFound,
+}
+
+impl Route {
+    pub fn parse(method: &Method, path: &str) -> Self {
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, &parts[..]) {
+            (&Method::GET, ["api", "inverters"]) => Self::Inverters,
+            (&Method::GET, ["api", "stats"]) => Self::Stats,
+            (&Method::GET, ["metrics"]) => Self::Metrics,
+            (&Method::GET, ["api", "inverters", datalog, "inputs"]) => match Serial::from_str(datalog) {
+                Ok(datalog) => Self::Inputs(datalog),
+                Err(_) => Self::NotFound,
+            },
+            (&Method::GET, ["api", "inverters", datalog, "settings"]) => match Serial::from_str(datalog) {
+                Ok(datalog) => Self::Settings(datalog),
+                Err(_) => Self::NotFound,
+            },
+            (&Method::GET, ["api", "inverters", datalog, "registers", register_type]) => {
+                let register_type = match *register_type {
+                    "hold" => RegisterType::Hold,
+                    "input" => RegisterType::Input,
+                    _ => return Self::NotFound,
+                };
+                match Serial::from_str(datalog) {
+                    Ok(datalog) => Self::Registers(datalog, register_type),
+                    Err(_) => Self::NotFound,
+                }
+            }
+            (&Method::POST, ["api", "cmd", _, _, ..]) => Self::Command(parts[1..].join("/")),
+            _ => Self::NotFound,
+        }
+    }
+} // }}}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct InverterStatus {
+    pub connected: bool,
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// what the command did on one inverter; the same JSON as its MQTT result/
+// topic, plus which inverter
+#[derive(Serialize)]
+struct InverterCommandResult {
+    datalog: Option<Serial>,
+    #[serde(flatten)]
+    result: CommandResult,
+}
+
+/// Serves status, cached registers and commands over HTTP/JSON, for scripts
+/// on hosts without an MQTT broker.
+#[derive(Clone)]
+pub struct HttpServer {
+    config: ConfigWrapper,
+    channels: Channels,
+    coordinator: Coordinator,
+    status: Arc<Mutex<HashMap<Serial, InverterStatus>>>,
+}
+
+impl HttpServer {
+    pub fn new(config: ConfigWrapper, channels: Channels, coordinator: Coordinator) -> Self {
+        Self {
+            config,
+            channels,
+            coordinator,
+            status: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let http_server = match self.config.http_server() {
+            Some(http_server) if http_server.enabled() => http_server,
+            _ => {
+                info!("http server disabled, skipping");
+                return Ok(());
+            }
+        };
+
+        let listener = tokio::net::TcpListener::bind((http_server.host().to_owned(), http_server.port()))
+            .await
+            .map_err(|err| {
+                anyhow!(
+                    "http_server.rs:can't listen on {}:{}: {}",
+                    http_server.host(),
+                    http_server.port(),
+                    err
+                )
+            })?;
+
+        info!(
+            "http server listening on {}:{}",
+            http_server.host(),
+            http_server.port()
+        );
+        if !listener.local_addr()?.ip().is_loopback() {
+            warn!("http server: /api/cmd has no authentication, and anyone who can reach this port can write to the inverters");
+        }
+
+        futures::try_join!(self.serve(listener), self.tracker())?;
+
+        Ok(())
+    }
+
+    async fn serve(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        let incoming = hyper::server::conn::AddrIncoming::from_listener(listener)?;
+
+        let server = self.clone();
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(request).await) }
+                }))
+            }
+        });
+
+        hyper::Server::builder(incoming).serve(make_service).await?;
+
+        Ok(())
+    }
+
+    // keeps track of which inverters are connected and when we last heard
+    // from each of them
+    async fn tracker(&self) -> Result<()> {
+        let mut receiver = self.channels.from_inverter.subscribe();
+
+        loop {
+            let (datalog, connected) = match receiver.recv().await {
+                Ok(ChannelData::Connected(datalog)) => (datalog, Some(true)),
+                Ok(ChannelData::Disconnect(datalog)) => (datalog, Some(false)),
+                Ok(ChannelData::ConnectError(..)) => continue,
+                Ok(ChannelData::Packet(packet)) | Ok(ChannelData::Heartbeat(packet)) => {
+                    (packet.datalog(), None)
+                }
+                Ok(ChannelData::Shutdown) => break,
+                Err(bus::error::RecvError::Closed) => bail!("from_inverter channel closed"),
+            };
+
+            let mut status = self.status.lock().unwrap();
+            let status = status.entry(datalog).or_default();
+            match connected {
+                Some(connected) => status.connected = connected,
+                None => status.last_seen = Some(Utils::utc()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&self, request: hyper::Request<Body>) -> Response {
+        let route = Route::parse(request.method(), request.uri().path());
+        debug!("http server: {} {} => {:?}", request.method(), request.uri().path(), route);
+
+        match route {
+            Route::Inverters => self.inverters(),
+            Route::Stats => self.stats().await,
+            Route::Inputs(datalog) => self.inputs(datalog).await,
+            Route::Settings(datalog) => self.settings(datalog).await,
+            Route::Registers(datalog, register_type) => self.registers(datalog, register_type).await,
+            Route::Metrics => self.metrics().await,
+            Route::Command(topic) => match read_body(request).await {
+                Ok(body) => {
+                    self.command(topic, String::from_utf8_lossy(&body).trim().to_string())
+                        .await
+                }
+                Err(response) => response,
+            },
+            Route::NotFound => error_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    fn inverters(&self) -> Response {
+        let status = self.status.lock().unwrap();
+
+        let inverters: Vec<_> = self
+            .config
+            .enabled_inverters()
+            .into_iter()
+            .map(|inverter| {
+                let status = inverter
+                    .datalog()
+                    .and_then(|datalog| status.get(&datalog))
+                    .cloned()
+                    .unwrap_or_default();
+
+                json!({
+                    "datalog": inverter.datalog(),
+                    "serial": inverter.serial(),
+                    "host": inverter.host(),
+                    "connected": status.connected,
+                    "last_seen": status.last_seen,
+                })
+            })
+            .collect();
+
+        json_response(StatusCode::OK, &inverters)
+    }
+
+    async fn stats(&self) -> Response {
+        let mut body = match self.coordinator.stats.lock() {
+            Ok(stats) => serde_json::to_value(&*stats).unwrap_or_default(),
+            Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to lock statistics"),
+        };
+
+        if let serde_json::Value::Object(fields) = &mut body {
+            fields.insert("request_queues".to_string(), json!(RequestQueue::stats(&self.channels).await));
+            fields.insert("channels".to_string(), json!(self.channels.stats()));
+            if let Some(spool) = self.config.spool() {
+                fields.insert("spools".to_string(), json!(Spool::stats(&spool)));
+            }
+        }
+
+        json_response(StatusCode::OK, &body)
+    }
+
+    async fn inputs(&self, datalog: Serial) -> Response {
+        let inverter = match self.config.enabled_inverter_with_datalog(datalog) {
+            Some(inverter) => inverter,
+            None => return error_response(StatusCode::NOT_FOUND, "unknown inverter"),
+        };
+
+        match self.read_input_all(&inverter).await {
+            Ok(Some((inputs, updated_at))) => json_response(
+                StatusCode::OK,
+                &json!({
+                    "datalog": datalog,
+                    "updated_at": updated_at,
+                    "inputs": inputs,
+                }),
+            ),
+            Ok(None) => error_response(
+                StatusCode::NOT_FOUND,
+                "input registers 0-126 have not all been read yet",
+            ),
+            Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        }
+    }
+
+    async fn settings(&self, datalog: Serial) -> Response {
+        if self.config.enabled_inverter_with_datalog(datalog).is_none() {
+            return error_response(StatusCode::NOT_FOUND, "unknown inverter");
+        }
+
+        json_response(StatusCode::OK, &self.read_hold_settings(datalog).await)
+    }
+
+    async fn metrics(&self) -> Response {
+        let mut metrics = Metrics::new();
+
+        if let Ok(stats) = self.coordinator.stats.lock() {
+            stats.write_metrics(&mut metrics);
+        }
+
+        for (datalog, stats) in RequestQueue::stats(&self.channels).await {
+            stats.write_metrics(&datalog, &mut metrics);
+        }
+
+        for (channel, stats) in self.channels.stats() {
+            stats.write_metrics(channel, &mut metrics);
+        }
+
+        if let Some(spool) = self.config.spool() {
+            for (name, stats) in Spool::stats(&spool) {
+                stats.write_metrics(&name, &mut metrics);
+            }
+        }
+
+        for inverter in self.config.enabled_inverters() {
+            if let Err(err) = self.inverter_metrics(&inverter, &mut metrics).await {
+                warn!("http server: metrics for {:?} failed: {}", inverter.datalog(), err);
+            }
+        }
+
+        hyper::Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render()))
+            .unwrap()
+    }
+
+    async fn inverter_metrics(&self, inverter: &config::Inverter, metrics: &mut Metrics) -> Result<()> {
+        let datalog = match inverter.datalog() {
+            Some(datalog) => datalog,
+            None => return Ok(()),
+        };
+
+        let datalog_label = datalog.to_string();
+        let serial_label = inverter.serial().map(|s| s.to_string()).unwrap_or_default();
+        let labels = [("datalog", datalog_label.as_str()), ("inverter", serial_label.as_str())];
+
+        let status = self.status.lock().unwrap().get(&datalog).cloned().unwrap_or_default();
+        metrics.gauge("connected", "Whether the inverter is connected", &labels, status.connected as u8 as f64);
+        if let Some(last_seen) = status.last_seen {
+            metrics.gauge(
+                "last_seen_timestamp_seconds",
+                "When we last heard from the inverter",
+                &labels,
+                last_seen.timestamp() as f64,
+            );
+        }
+
+        // numeric settings, described as the register map has them; the
+        // flags are left to the JSON API
+        let register_map = self.config.register_map();
+        let settings = self.read_hold_settings(datalog).await;
+        for (name, value) in settings.fields() {
+            if let Some(value) = value.as_f64() {
+                let help = match register_map.find(RegisterType::Hold, name) {
+                    Some(def) if !def.description.is_empty() => def.description.clone(),
+                    _ => format!("Hold setting {}", name),
+                };
+                metrics.gauge(&format!("hold_{}", name), &help, &labels, value);
+            }
+        }
+
+        let (inputs, updated_at) = match self.read_input_all(inverter).await? {
+            Some(inputs) => inputs,
+            None => return Ok(()),
+        };
+
+        metrics.gauge(
+            "inputs_updated_timestamp_seconds",
+            "When the oldest of the input registers was read",
+            &labels,
+            updated_at.timestamp() as f64,
+        );
+
+        // every numeric field, by name. lifetime energy totals only go up, so
+        // are counters; everything else, including daily totals, is a gauge.
+        if let serde_json::Value::Object(fields) = serde_json::to_value(&inputs)? {
+            for (name, value) in fields {
+                let value = match value.as_f64() {
+                    Some(value) if name != "time" => value,
+                    _ => continue,
+                };
+
+                let help = format!("ReadInputAll {}", name);
+                if name.starts_with("e_") && name.contains("_all") {
+                    metrics.counter(&name, &help, &labels, value);
+                } else {
+                    metrics.gauge(&name, &help, &labels, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // whichever hold registers are in the cache, decoded
+    async fn read_hold_settings(&self, datalog: Serial) -> HoldSettings {
+        let entries = RegisterCache::get_all(&self.channels, datalog, RegisterType::Hold).await;
+        let registers = entries.iter().map(|(r, e)| (*r, e.value)).collect();
+
+        HoldSettings::from_registers(&self.config.register_map(), &registers)
+    }
+
+    // decoded from the register cache, so it's as fresh as the oldest of the
+    // registers it is made from. None until we've seen all of them.
+    async fn read_input_all(
+        &self,
+        inverter: &config::Inverter,
+    ) -> Result<Option<(Box<ReadInputAll>, chrono::DateTime<chrono::Utc>)>> {
+        let datalog = match inverter.datalog() {
+            Some(datalog) => datalog,
+            None => return Ok(None),
+        };
+
+        let entries = RegisterCache::get_all(&self.channels, datalog, RegisterType::Input).await;
+        let entries: Option<Vec<_>> = (0..INPUT_ALL_COUNT).map(|r| entries.get(&r)).collect();
+        let entries = match entries {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+
+        let td = TranslatedData {
+            datalog,
+            device_function: DeviceFunction::ReadInput,
+            // not used when decoding, so any placeholder will do
+            inverter: inverter.serial().unwrap_or(datalog),
+            register: 0,
+            values: entries.iter().flat_map(|e| e.value.to_le_bytes()).collect(),
+        };
+
+        match td.read_input()? {
+            ReadInput::ReadInputAll(inputs) => {
+                let updated_at = entries.iter().map(|e| e.updated_at).min().unwrap_or_else(Utils::utc);
+                Ok(Some((inputs, updated_at)))
+            }
+            _ => bail!("http_server.rs:unexpected input block"),
+        }
+    }
+
+    async fn registers(&self, datalog: Serial, register_type: RegisterType) -> Response {
+        if self.config.enabled_inverter_with_datalog(datalog).is_none() {
+            return error_response(StatusCode::NOT_FOUND, "unknown inverter");
+        }
+
+        let entries = RegisterCache::get_all(&self.channels, datalog, register_type).await;
+        let register_map = self.config.register_map();
+
+        // each raw entry, plus what the register map makes of it
+        let registers: serde_json::Map<_, _> = entries
+            .iter()
+            .map(|(register, entry)| {
+                let mut value = json!(entry);
+                if let Some(def) = register_map.get(register_type, *register) {
+                    value["decoded"] = def.decode(entry.value).into_iter().collect();
+                }
+                (register.to_string(), value)
+            })
+            .collect();
+
+        json_response(StatusCode::OK, &registers)
+    }
+
+    // topic is parsed exactly as the MQTT cmd/ topic is, and the request
+    // body is its payload
+    async fn command(&self, topic: String, payload: String) -> Response {
+        let message = mqtt::Message {
+            topic,
+            retain: false,
+            payload,
+        };
+
+        let inverters = match self.config.inverters_for_message(&message) {
+            Ok(inverters) if inverters.is_empty() => {
+                return error_response(StatusCode::NOT_FOUND, "unknown inverter")
+            }
+            Ok(inverters) => inverters,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        // all of them up front, so a bad request runs on none of the
+        // inverters rather than just those before the one it failed for
+        let register_map = self.config.register_map();
+        let commands = inverters
+            .into_iter()
+            .map(|inverter| {
+                let datalog = inverter.datalog();
+                message.to_command(inverter, &register_map).map(|command| (datalog, command))
+            })
+            .collect::<Result<Vec<_>>>();
+        let commands = match commands {
+            Ok(commands) => commands,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        let mut status = StatusCode::OK;
+        let mut results = Vec::new();
+
+        for (datalog, command) in commands {
+            info!("http server: running command {:?}", command);
+            let (code, result) = self.run_command(command).await;
+            if code != StatusCode::OK {
+                warn!("http server: command failed: {:?}", result.error);
+                status = code;
+            }
+            results.push(InverterCommandResult { datalog, result });
+        }
+
+        json_response(status, &results)
+    }
+
+    async fn run_command(&self, command: Command) -> (StatusCode, CommandResult) {
+        if command.is_write() {
+            if let Err(err) = WriteInverter::new(self.channels.clone(), command.inverter().clone(), self.config.clone())
+                .check_read_only()
+            {
+                return (StatusCode::FORBIDDEN, CommandResult::failed(&err));
+            }
+        }
+
+        let result = self.coordinator.run_command(command, Priority::Read).await;
+        if result.is_ok() {
+            (StatusCode::OK, result)
+        } else {
+            (StatusCode::BAD_GATEWAY, result)
+        }
+    }
+}
+
+fn json_response<T: Serialize + ?Sized>(status: StatusCode, body: &T) -> Response {
+    match serde_json::to_string(body) {
+        Ok(body) => hyper::Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(err) => hyper::Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+    }
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response {
+    json_response(status, &json!({ "error": error }))
+}
+
+// refuses a body over MAX_BODY_SIZE without reading it all: up front if
+// Content-Length says so, otherwise as soon as the chunks add up to more
+async fn read_body(request: hyper::Request<Body>) -> std::result::Result<Vec<u8>, Response> {
+    use hyper::body::HttpBody;
+
+    let too_large = || error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large");
+
+    let content_length = request
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > MAX_BODY_SIZE as u64) {
+        return Err(too_large());
+    }
+
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| error_response(StatusCode::BAD_REQUEST, &err.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let request = |body: Vec<u8>| hyper::Request::post("/api/cmd/all/read/inputs/1").body(Body::from(body));
+
+        let body = read_body(request(b"100".to_vec()).unwrap()).await.unwrap();
+        assert_eq!(body, b"100");
+
+        // said up front
+        let large = hyper::Request::post("/api/cmd/all/read/inputs/1")
+            .header(hyper::header::CONTENT_LENGTH, "1000000")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(read_body(large).await.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // or found out on the way
+        let large = request(vec![b'1'; MAX_BODY_SIZE + 1]).unwrap();
+        assert_eq!(read_body(large).await.unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_routes() {
+        let datalog = Serial::from_str("2222222222").unwrap();
+
+        assert_eq!(Route::parse(&Method::GET, "/api/inverters"), Route::Inverters);
+        assert_eq!(Route::parse(&Method::GET, "/api/stats/"), Route::Stats);
+        assert_eq!(Route::parse(&Method::GET, "/metrics"), Route::Metrics);
+        assert_eq!(
+            Route::parse(&Method::GET, "/api/inverters/2222222222/inputs"),
+            Route::Inputs(datalog)
+        );
+        assert_eq!(
+            Route::parse(&Method::GET, "/api/inverters/2222222222/settings"),
+            Route::Settings(datalog)
+        );
+        assert_eq!(
+            Route::parse(&Method::GET, "/api/inverters/2222222222/registers/hold"),
+            Route::Registers(datalog, RegisterType::Hold)
+        );
+        assert_eq!(
+            Route::parse(&Method::POST, "/api/cmd/2222222222/set/hold/21"),
+            Route::Command("cmd/2222222222/set/hold/21".to_string())
+        );
+        assert_eq!(
+            Route::parse(&Method::POST, "/api/cmd/all/read/inputs/1"),
+            Route::Command("cmd/all/read/inputs/1".to_string())
+        );
+
+        assert_eq!(Route::parse(&Method::GET, "/api/cmd/all/read/inputs/1"), Route::NotFound);
+        assert_eq!(Route::parse(&Method::POST, "/api/stats"), Route::NotFound);
+        assert_eq!(
+            Route::parse(&Method::GET, "/api/inverters/2222222222/registers/param"),
+            Route::NotFound
+        );
+        assert_eq!(Route::parse(&Method::GET, "/api/inverters/short/inputs"), Route::NotFound);
+    }
+
+    #[test]
+    fn test_commands() {
+        let inverter: config::Inverter = serde_json::from_value(json!({
+            "host": "localhost",
+            "port": 8000,
+            "serial": "5555555555",
+            "datalog": "2222222222",
+        }))
+        .unwrap();
+
+        let command = |topic: &str, payload: &str| {
+            let message = mqtt::Message {
+                topic: topic.to_string(),
+                retain: false,
+                payload: payload.to_string(),
+            };
+            message.to_command(inverter.clone(), &RegisterMap::builtin())
+        };
+
+        assert!(!command("cmd/all/read/hold/21", "2").unwrap().is_write());
+        assert!(!command("cmd/all/read/ac_charge/1", "").unwrap().is_write());
+        assert!(command("cmd/all/set/hold/21", "100").unwrap().is_write());
+        assert!(command("cmd/all/set/ac_charge", "ON").unwrap().is_write());
+        assert!(command("cmd/all/set/hold/21", "").is_err());
+        assert!(command("cmd/all/set/hold/charge_power_percent_cmd", "50").unwrap().is_write());
+        assert!(command("cmd/all/set/hold/nonsense", "50").is_err());
+        assert!(command("cmd/all/set/setting/start_pv_volt", "140.5").unwrap().is_write());
+        assert!(command("cmd/all/set/holds", r#"{"ac_charge_soc_limit":90}"#).unwrap().is_write());
+    }
+}
@@ -1,13 +1,18 @@
 use crate::prelude::*;
 
-use chrono::TimeZone;
-use rinfluxdb::line_protocol::{r#async::Client, LineBuilder};
+use crate::config::InfluxPrecision;
+use crate::spool::{SinkError, Spool};
 
-static INPUTS_MEASUREMENT: &str = "inputs";
+use flate2::{write::GzEncoder, Compression};
+
+// how often to retry sending spooled points while InfluxDB is down
+const DRAIN_INTERVAL_SECS: u64 = 30;
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum ChannelData {
     InputData(serde_json::Value),
+    HoldData(serde_json::Value),
+    StatsData(serde_json::Value),
     Shutdown,
 }
 
@@ -22,113 +27,93 @@ impl Influx {
         Self { config, channels }
     }
 
-    pub async fn start(&self) -> Result<()> {
+    /// Spawns the sender task, returning its handle so whoever stops it
+    /// can wait for it to finish with the spool.
+    pub async fn start(&self) -> Result<Option<tokio::task::JoinHandle<()>>> {
         if !self.config.influx().enabled() {
             info!("influx disabled, skipping");
-            return Ok(());
+            return Ok(None);
         }
 
         info!("initializing influx at {}", self.config.influx().url());
 
-        let client = {
-            let config = self.config.influx();
-            let url = reqwest::Url::parse(config.url())?;
-            let credentials = match (config.username(), config.password()) {
-                (Some(u), Some(p)) => Some((u, p)),
-                _ => None,
-            };
-
-            Client::new(url, credentials)?
-        };
+        let writer = Writer::new(&self.config.influx())?;
+        // subscribed here rather than in the task, so a stop() straight
+        // after this isn't missed
+        let receiver = self.channels.to_influx.subscribe();
 
         // Spawn the sender task instead of awaiting it
         let self_clone = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = self_clone.sender(client).await {
+        let handle = tokio::spawn(async move {
+            if let Err(e) = self_clone.sender(writer, receiver).await {
                 error!("InfluxDB sender task failed: {}", e);
             }
         });
 
         info!("InfluxDB sender task spawned");
 
-        Ok(())
+        Ok(Some(handle))
     }
 
     pub fn stop(&self) {
         let _ = self.channels.to_influx.send(ChannelData::Shutdown);
     }
 
-    async fn sender(&self, client: Client) -> Result<()> {
+    async fn sender(&self, writer: Writer, mut receiver: bus::Receiver<ChannelData>) -> Result<()> {
         use ChannelData::*;
 
-        let mut receiver = self.channels.to_influx.subscribe();
+        let config = self.config.influx();
+        let spool = match self.config.spool() {
+            Some(config) => Some(Spool::open(&config, "influx").await?),
+            None => None,
+        };
+        let mut batch = Vec::new();
+        // the first tick of interval() is immediate; we want a full interval
+        let mut flush =
+            tokio::time::interval_at(tokio::time::Instant::now() + config.flush_interval(), config.flush_interval());
+        // a drain can take a while if the server doesn't answer; the next one
+        // is a full interval later, leaving time to take in new points
+        let mut drain = tokio::time::interval(std::time::Duration::from_secs(DRAIN_INTERVAL_SECS));
+        drain.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         info!("InfluxDB sender started");
 
         loop {
-            info!("InfluxDB sender waiting for data...");
-            let mut line = LineBuilder::new(INPUTS_MEASUREMENT);
-
-            match receiver.recv().await {
-                Ok(Shutdown) => {
-                    info!("InfluxDB sender received shutdown signal");
-                    break;
-                }
-                Ok(InputData(data)) => {
-                    info!("InfluxDB sender received input data");
-                    trace!("InfluxDB processing input data: {:?}", data);
-                    for (key, value) in data.as_object().ok_or_else(|| anyhow!("Invalid data format"))? {
-                        let key = key.to_string();
-                        trace!("Processing field: {} = {:?}", key, value);
-
-                        line = if key == "time" {
-                            let value = value.as_i64().unwrap_or_else(|| {
-                                panic!("cannot represent {value} as i64 for {key}")
-                            });
-                            line.set_timestamp(chrono::Utc.timestamp_opt(value, 0)
-                                .single()
-                                .ok_or_else(|| anyhow!("Invalid timestamp: {}", value))?)
-                        } else if key == "datalog" || key == "inverter" {
-                            let value = value.as_str().unwrap_or_else(|| {
-                                panic!("cannot represent {value} as str for {key}")
-                            });
-                            line.insert_tag(key, value)
-                        } else if value.is_f64() {
-                            let value = value.as_f64().unwrap_or_else(|| {
-                                panic!("cannot represent {value} as f64 for {key}")
-                            });
-                            line.insert_field(key, value)
-                        } else {
-                            // can't be anything other than int
-                            let value = value.as_i64().unwrap_or_else(|| {
-                                panic!("cannot represent {value} as i64 for {key}")
-                            });
-                            line.insert_field(key, value)
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Ok(Shutdown) => {
+                        info!("InfluxDB sender received shutdown signal");
+                        self.flush(&writer, spool.as_ref(), &mut batch).await;
+                        if let Some(spool) = &spool {
+                            if let Err(e) = spool.flush().await {
+                                error!("InfluxDB spool failed: {}", e);
+                            }
                         }
+                        break;
                     }
-
-                    let lines = vec![line.build()];
-                    trace!("Sending to InfluxDB: {:?}", lines);
-
-                    let mut retry_count = 0;
-                    while retry_count < 3 {
-                        match client.send(&self.database(), &lines).await {
-                            Ok(_) => {
-                                info!("Successfully sent data to InfluxDB");
-                                break;
-                            }
-                            Err(err) => {
-                                error!("InfluxDB push failed: {:?} - retrying in 10s (attempt {}/3)", err, retry_count + 1);
-                                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                                retry_count += 1;
-                            }
+                    Ok(data) => {
+                        match Self::line(&config, &data) {
+                            Ok(Some(line)) => batch.push(line),
+                            Ok(None) => {}
+                            Err(e) => error!("InfluxDB can't encode {:?}: {}", data, e),
+                        }
+                        if batch.len() >= config.batch_size() {
+                            self.flush(&writer, spool.as_ref(), &mut batch).await;
                         }
                     }
-                    if retry_count == 3 {
-                        error!("Failed to send data to InfluxDB after 3 attempts");
+                    Err(e) => {
+                        error!("Error receiving from InfluxDB channel: {}", e);
+                        break;
                     }
+                },
+                _ = flush.tick(), if !batch.is_empty() => {
+                    self.flush(&writer, spool.as_ref(), &mut batch).await;
                 }
-                Err(e) => {
-                    error!("Error receiving from InfluxDB channel: {}", e);
+                _ = drain.tick(), if spool.as_ref().is_some_and(|spool| !spool.is_empty()) => {
+                    if let Some(spool) = &spool {
+                        if let Err(e) = self.drain(&writer, spool).await {
+                            error!("InfluxDB spool failed: {}", e);
+                        }
+                    }
                 }
             }
         }
@@ -138,7 +123,303 @@ impl Influx {
         Ok(())
     }
 
-    fn database(&self) -> String {
-        self.config.influx().database().to_string()
+    async fn flush(&self, writer: &Writer, spool: Option<&Spool>, batch: &mut Vec<String>) {
+        let lines = std::mem::take(batch);
+        if lines.is_empty() {
+            return;
+        }
+
+        let result = match spool {
+            Some(spool) => self.send_or_spool(writer, spool, lines).await,
+            None => {
+                self.send_with_retries(writer, &lines).await;
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            error!("InfluxDB spool failed: {}", e);
+        }
+    }
+
+    // anything already spooled goes first, to keep the points in order, and
+    // these wait behind it for the next drain rather than trying a server
+    // that's down again now. a rejected batch is spooled too, so drain() can
+    // pick out the bad points
+    async fn send_or_spool(&self, writer: &Writer, spool: &Spool, lines: Vec<String>) -> Result<()> {
+        if !spool.is_empty() {
+            spool.push(&lines).await?;
+            return Ok(());
+        }
+
+        match writer.write(&lines).await {
+            Ok(()) => Ok(()),
+            Err(SinkError::Retry(err)) => {
+                warn!("InfluxDB push failed, spooling until it's back: {}", err);
+                spool.push(&lines).await?;
+                Ok(())
+            }
+            Err(SinkError::Reject(err)) => {
+                warn!("InfluxDB rejected {} points: {}", lines.len(), err);
+                spool.push(&lines).await?;
+                self.drain(writer, spool).await
+            }
+        }
+    }
+
+    // sends spooled points oldest first, a batch at a time
+    async fn drain(&self, writer: &Writer, spool: &Spool) -> Result<()> {
+        spool
+            .drain(self.config.influx().batch_size(), |lines: Vec<String>| async move {
+                writer.write(&lines).await
+            })
+            .await
+    }
+
+    // without a spool, all we can do is try a few times
+    async fn send_with_retries(&self, writer: &Writer, lines: &[String]) {
+        let mut retry_count = 0;
+        while retry_count < 3 {
+            match writer.write(lines).await {
+                Ok(_) => {
+                    info!("Successfully sent {} points to InfluxDB", lines.len());
+                    break;
+                }
+                Err(SinkError::Reject(err)) => {
+                    error!("InfluxDB rejected {} points: {}", lines.len(), err);
+                    return;
+                }
+                Err(SinkError::Retry(err)) => {
+                    error!("InfluxDB push failed: {:?} - retrying in 10s (attempt {}/3)", err, retry_count + 1);
+                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                    retry_count += 1;
+                }
+            }
+        }
+        if retry_count == 3 {
+            error!("Failed to send data to InfluxDB after 3 attempts");
+        }
+    }
+
+    fn line(config: &config::Influx, data: &ChannelData) -> Result<Option<String>> {
+        let measurements = config.measurements();
+        let (measurement, data) = match data {
+            ChannelData::InputData(data) => (measurements.inputs, data),
+            ChannelData::HoldData(data) => (measurements.holds, data),
+            ChannelData::StatsData(data) => (measurements.stats, data),
+            ChannelData::Shutdown => return Ok(None),
+        };
+
+        encode(&measurement, data, config.precision())
+    }
+}
+
+/// One point in line protocol. datalog and inverter become tags and time
+/// the timestamp; numbers and bools are fields, with nested objects
+/// flattened into `parent_child`. Strings and nulls are left out. None if
+/// that leaves no fields, which Influx would reject.
+pub fn encode(measurement: &str, data: &serde_json::Value, precision: InfluxPrecision) -> Result<Option<String>> {
+    let object = data.as_object().ok_or_else(|| anyhow!("influx.rs:point is not a JSON object"))?;
+
+    let mut line = escape(measurement, &[',', ' ']);
+    for tag in ["datalog", "inverter"] {
+        if let Some(value) = object.get(tag).and_then(|v| v.as_str()) {
+            line.push_str(&format!(",{}={}", tag, escape(value, &[',', '=', ' '])));
+        }
+    }
+
+    let mut fields = Vec::new();
+    for (key, value) in object {
+        if key != "time" && key != "datalog" && key != "inverter" {
+            add_fields(&mut fields, key, value);
+        }
+    }
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    line.push(' ');
+    line.push_str(&fields.join(","));
+
+    if let Some(time) = object.get("time") {
+        let time = time.as_i64().ok_or_else(|| anyhow!("influx.rs:time {} is not a unix timestamp", time))?;
+        line.push_str(&format!(" {}", time * precision.scale()));
+    }
+
+    Ok(Some(line))
+}
+
+fn add_fields(fields: &mut Vec<String>, key: &str, value: &serde_json::Value) {
+    use serde_json::Value;
+
+    let value = match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) if !n.is_f64() => format!("{}i", i),
+            (_, Some(f)) if f.is_finite() => f.to_string(),
+            _ => return,
+        },
+        Value::Object(object) => {
+            for (k, v) in object {
+                add_fields(fields, &format!("{}_{}", key, k), v);
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    fields.push(format!("{}={}", escape(key, &[',', '=', ' ']), value));
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Sends batches of line protocol to the write API for the configured
+/// InfluxDB version. 3.x takes 2.x's API, with the bucket naming a database.
+struct Writer {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    auth: Auth,
+    gzip: bool,
+}
+
+enum Auth {
+    None,
+    Basic(String, Option<String>),
+    Token(String),
+}
+
+impl Writer {
+    fn new(config: &config::Influx) -> Result<Self> {
+        let mut base = reqwest::Url::parse(config.url())?;
+        // so join() adds to the path rather than replacing its last part
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+
+        let (mut url, auth) = match config.api_version() {
+            1 => {
+                let mut url = base.join("write")?;
+                url.query_pairs_mut().append_pair("db", config.database());
+                let auth = match config.username() {
+                    Some(username) => Auth::Basic(username.clone(), config.password().clone()),
+                    None => Auth::None,
+                };
+                (url, auth)
+            }
+            _ => {
+                let mut url = base.join("api/v2/write")?;
+                if !config.org().is_empty() {
+                    url.query_pairs_mut().append_pair("org", config.org());
+                }
+                url.query_pairs_mut().append_pair("bucket", config.bucket());
+                let auth = match config.token() {
+                    Some(token) => Auth::Token(token.clone()),
+                    None => Auth::None,
+                };
+                (url, auth)
+            }
+        };
+        url.query_pairs_mut().append_pair("precision", config.precision().as_str());
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()?,
+            url,
+            auth,
+            gzip: config.gzip(),
+        })
+    }
+
+    /// Connection failures, timeouts, 5xx and 429 are worth retrying;
+    /// any other 4xx means Influx won't ever take these lines.
+    async fn write(&self, lines: &[String]) -> Result<(), SinkError> {
+        let mut body = lines.join("\n").into_bytes();
+        trace!("Sending to InfluxDB: {:?}", lines);
+
+        let mut request = self.client.post(self.url.clone()).header("Content-Type", "text/plain; charset=utf-8");
+        if self.gzip {
+            body = Self::gzip(&body).map_err(SinkError::Reject)?;
+            request = request.header("Content-Encoding", "gzip");
+        }
+        request = match &self.auth {
+            Auth::None => request,
+            Auth::Basic(username, password) => request.basic_auth(username, password.as_ref()),
+            Auth::Token(token) => request.header("Authorization", format!("Token {}", token)),
+        };
+
+        let response = request.body(body).send().await.map_err(|err| SinkError::Retry(err.into()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let err = anyhow!("InfluxDB write failed with {}: {}", status, response.text().await.unwrap_or_default());
+            return Err(Self::classify(status, err));
+        }
+
+        Ok(())
+    }
+
+    fn classify(status: reqwest::StatusCode, err: Error) -> SinkError {
+        use reqwest::StatusCode;
+
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT {
+            SinkError::Retry(err)
+        } else {
+            SinkError::Reject(err)
+        }
+    }
+
+    fn gzip(body: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_encode() {
+        let data = json!({
+            "time": 1700000000,
+            "datalog": "BJ43500414",
+            "inverter": "4372830102",
+            "soc": 85,
+            "v_bat": 52.5,
+            "function_enable": {"ac_charge_enable": true},
+            "serial": "skipped",
+            "p_pv": null,
+        });
+        assert_eq!(
+            encode("inputs", &data, InfluxPrecision::Ms).unwrap().unwrap(),
+            "inputs,datalog=BJ43500414,inverter=4372830102 function_enable_ac_charge_enable=true,soc=85i,v_bat=52.5 1700000000000"
+        );
+
+        assert_eq!(
+            encode("my bridge", &json!({"mqtt,errors": 1}), InfluxPrecision::S).unwrap().unwrap(),
+            "my\\ bridge mqtt\\,errors=1i"
+        );
+        assert_eq!(encode("inputs", &json!({"datalog": "BJ43500414"}), InfluxPrecision::S).unwrap(), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        use reqwest::StatusCode;
+
+        for status in [StatusCode::SERVICE_UNAVAILABLE, StatusCode::TOO_MANY_REQUESTS] {
+            assert!(matches!(Writer::classify(status, anyhow!("")), SinkError::Retry(_)));
+        }
+        for status in [StatusCode::BAD_REQUEST, StatusCode::UNAUTHORIZED, StatusCode::UNPROCESSABLE_ENTITY] {
+            assert!(matches!(Writer::classify(status, anyhow!("")), SinkError::Reject(_)));
+        }
     }
 }
@@ -1,3 +1,4 @@
+pub mod bus;
 pub mod channels;
 pub mod command;
 pub mod config;
@@ -5,12 +6,20 @@ pub mod coordinator;
 pub mod database;
 pub mod datalog_writer;
 pub mod home_assistant;
+pub mod http_server;
 pub mod influx;
+pub mod metrics;
+pub mod modbus_server;
 pub mod mqtt;
 pub mod options;
 pub mod prelude;
 pub mod register_cache;
+pub mod register_map;
+pub mod request_queue;
 pub mod scheduler;
+pub mod simulator;
+pub mod spool;
+pub mod supervisor;
 pub mod unixtime;
 pub mod utils;
 pub mod eg4;
@@ -25,15 +34,12 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct Components {
     coordinator: Coordinator,
-    mqtt: Mqtt,
-    influx: Influx,
-    inverters: Vec<Inverter>,
-    databases: Vec<Database>,
+    supervisor: Supervisor,
     channels: Channels,
 }
 
 impl Components {
-    fn stop(mut self) {
+    fn stop(self) {
         // First send shutdown signals to all components
         info!("Sending shutdown signals...");
         let _ = self.channels.from_inverter.send(eg4::inverter::ChannelData::Shutdown);
@@ -53,14 +59,7 @@ impl Components {
 
         // Now stop all components
         info!("Stopping components...");
-        for inverter in self.inverters {
-            inverter.stop();
-        }
-        for database in self.databases {
-            database.stop();
-        }
-        self.mqtt.stop();
-        self.influx.stop();
+        self.supervisor.stop();
         self.coordinator.stop();
     }
 }
@@ -69,7 +68,7 @@ pub async fn app() -> Result<()> {
     let options = Options::new();
     info!("Starting eg4-bridge {} with config file: {}", CARGO_PKG_VERSION, options.config_file);
 
-    let config = ConfigWrapper::new(options.config_file).unwrap_or_else(|err| {
+    let config = ConfigWrapper::new(options.config_file.clone()).unwrap_or_else(|err| {
         // no logging available yet, so eprintln! will have to do
         eprintln!("Error: {:?}", err);
         std::process::exit(255);
@@ -101,40 +100,23 @@ pub async fn app() -> Result<()> {
     
     info!("  Creating Coordinator...");
     let config = Arc::new(config);
+    
+    info!("  Creating RequestQueue...");
+    let request_queue = RequestQueue::new((*config).clone(), channels.clone());
     let coordinator = Coordinator::new(config.clone(), channels.clone());
     
     info!("  Creating Scheduler...");
     let scheduler = Scheduler::new((*config).clone(), channels.clone());
     
-    info!("  Creating MQTT client...");
-    let mqtt = Mqtt::new((*config).clone(), channels.clone());
-    
-    info!("  Creating InfluxDB client...");
-    let influx = Influx::new((*config).clone(), channels.clone());
-
-    info!("  Creating Inverters...");
-    let inverters: Vec<_> = config
-        .enabled_inverters()
-        .into_iter()
-        .map(|inverter| Inverter::new((*config).clone(), &inverter, channels.clone()))
-        .collect();
-    info!("    Created {} inverter instances", inverters.len());
-
-    info!("  Creating Databases...");
-    let databases: Vec<_> = config
-        .enabled_databases()
-        .into_iter()
-        .map(|database| Database::new(database, channels.clone()))
-        .collect();
-    info!("    Created {} database instances", databases.len());
+    // owns the inverters, MQTT, InfluxDB and databases, so it can restart
+    // them individually when the config file changes
+    info!("  Creating Supervisor...");
+    let supervisor = Supervisor::new(options.config_file.clone(), (*config).clone(), channels.clone());
 
     // Store components that need to be stopped
     let components = Components {
         coordinator: coordinator.clone(),
-        mqtt: mqtt.clone(),
-        influx: influx.clone(),
-        inverters: inverters.clone(),
-        databases: databases.clone(),
+        supervisor: supervisor.clone(),
         channels: channels.clone(),
     };
 
@@ -154,16 +136,12 @@ pub async fn app() -> Result<()> {
     
     // Start databases first
     info!("Starting databases...");
-    if let Err(e) = start_databases(databases.clone()).await {
-        error!("Failed to start databases: {}", e);
-        components.stop();
-        return Err(e);
-    }
+    supervisor.start_databases();
     info!("Databases started successfully");
 
     // Start InfluxDB before inverters
     info!("Starting InfluxDB...");
-    if let Err(e) = influx.start().await {
+    if let Err(e) = supervisor.start_influx().await {
         error!("Failed to start InfluxDB: {}", e);
         components.stop();
         return Err(e);
@@ -189,22 +167,59 @@ pub async fn app() -> Result<()> {
         }
     });
 
-    // Start inverters
-    info!("Starting inverters...");
-    if let Err(e) = start_inverters(inverters.clone()).await {
-        error!("Failed to start inverters: {}", e);
-        components.stop();
-        return Err(e);
+    // Start RequestQueue before inverters, so nothing is sent without it
+    info!("Starting RequestQueue...");
+    let _request_queue_handle = tokio::spawn(async move {
+        if let Err(e) = request_queue.start().await {
+            error!("RequestQueue error: {}", e);
+        }
+    });
+
+    // Start the Modbus server, if configured, alongside the register cache it serves
+    info!("Starting Modbus server...");
+    let modbus_server = ModbusServer::new((*config).clone(), channels.clone());
+    let _modbus_server_handle = tokio::spawn(async move {
+        if let Err(e) = modbus_server.start().await {
+            error!("Modbus server error: {}", e);
+        }
+    });
+
+    // Start the HTTP API, if configured
+    info!("Starting HTTP server...");
+    let http_server = HttpServer::new((*config).clone(), channels.clone(), coordinator.clone());
+    let _http_server_handle = tokio::spawn(async move {
+        if let Err(e) = http_server.start().await {
+            error!("HTTP server error: {}", e);
+        }
+    });
+
+    // Start inverters, unless we're replaying a capture in their place
+    let replay = match &options.replay_file {
+        Some(file) => match eg4::capture::Replay::new(file) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error!("Failed to read replay file: {}", e);
+                components.stop();
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    if replay.is_none() {
+        info!("Starting inverters...");
+        supervisor.start_inverters();
+        info!("Inverters started successfully");
     }
-    info!("Inverters started successfully");
 
     // Start remaining components
-    info!("Starting remaining components (scheduler, MQTT)...");
+    info!("Starting remaining components (scheduler, MQTT, config watcher)...");
+    supervisor.start_mqtt();
     let app_result = tokio::select! {
         res = async {
             futures::try_join!(
                 scheduler.start(),
-                mqtt.start(),
+                supervisor.watch(),
             )
         } => {
             if let Err(e) = res {
@@ -212,6 +227,17 @@ pub async fn app() -> Result<()> {
             }
             Ok(())
         }
+        res = async {
+            match &replay {
+                Some(replay) => replay.run(channels.clone()).await,
+                None => futures::future::pending().await,
+            }
+        } => {
+            if let Err(e) = res {
+                error!("Replay error: {}", e);
+            }
+            Ok(())
+        }
         _ = shutdown_rx => {
             info!("Initiating shutdown sequence");
             Ok(())
@@ -226,27 +252,6 @@ pub async fn app() -> Result<()> {
     app_result
 }
 
-async fn start_databases(databases: Vec<Database>) -> Result<()> {
-    let futures = databases.iter().map(|d| d.start());
-    futures::future::join_all(futures).await;
-    Ok(())
-}
-
-async fn start_inverters(inverters: Vec<Inverter>) -> Result<()> {
-    for inverter in &inverters {
-        let config = inverter.config();
-        info!(
-            "Starting inverter - Serial: {}, Datalog: {}, Host: {}",
-            config.serial().map(|s| s.to_string()).unwrap_or_default(),
-            config.datalog().map(|s| s.to_string()).unwrap_or_default(),
-            config.host(),
-        );
-    }
-    let futures = inverters.iter().map(|i| i.start());
-    futures::future::join_all(futures).await;
-    Ok(())
-}
-
 pub async fn run(config: Config) -> Result<()> {
     info!("Starting up...");
 
@@ -263,6 +268,9 @@ pub async fn run(config: Config) -> Result<()> {
     info!("  Creating Register Cache...");
     let register_cache = register_cache::RegisterCache::new(channels.clone());
 
+    info!("  Creating Request Queue...");
+    let request_queue = request_queue::RequestQueue::new((*config).clone(), channels.clone());
+
     info!("  Starting Register Cache...");
     let register_cache_handle = tokio::spawn(async move {
         if let Err(e) = register_cache.start().await {
@@ -270,6 +278,13 @@ pub async fn run(config: Config) -> Result<()> {
         }
     });
 
+    info!("  Starting Request Queue...");
+    let request_queue_handle = tokio::spawn(async move {
+        if let Err(e) = request_queue.start().await {
+            error!("request_queue error: {}", e);
+        }
+    });
+
     info!("  Starting Scheduler...");
     let scheduler_handle = tokio::spawn(async move {
         if let Err(e) = scheduler.start().await {
@@ -284,7 +299,7 @@ pub async fn run(config: Config) -> Result<()> {
         }
     });
 
-    futures::try_join!(register_cache_handle, scheduler_handle, coordinator_handle)?;
+    futures::try_join!(register_cache_handle, request_queue_handle, scheduler_handle, coordinator_handle)?;
 
     Ok(())
 }
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// every metric we export starts with this
+const PREFIX: &str = "eg4";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gauge => "gauge",
+            Self::Counter => "counter",
+        }
+    }
+}
+
+struct Family {
+    metric_type: MetricType,
+    help: String,
+    samples: Vec<(String, f64)>,
+}
+
+/// Collects samples and renders them in the Prometheus text exposition
+/// format. Samples of the same metric are grouped together however they were
+/// added, as the format requires.
+#[derive(Default)]
+pub struct Metrics {
+    families: BTreeMap<String, Family>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.add(MetricType::Gauge, name, help, labels, value);
+    }
+
+    /// name should not include the _total suffix, it's added for you.
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.add(MetricType::Counter, &format!("{}_total", name), help, labels, value);
+    }
+
+    fn add(&mut self, metric_type: MetricType, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        // Prometheus can't do anything useful with these
+        if !value.is_finite() {
+            return;
+        }
+
+        let family = self
+            .families
+            .entry(format!("{}_{}", PREFIX, name))
+            .or_insert_with(|| Family {
+                metric_type,
+                help: help.to_string(),
+                samples: Vec::new(),
+            });
+
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, Self::escape(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        family.samples.push((labels, value));
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, family) in &self.families {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.metric_type.as_str());
+            for (labels, value) in &family.samples {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+
+        out
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::new();
+        metrics.gauge("soc", "State of charge", &[("datalog", "1111111111")], 55.0);
+        metrics.counter("e_pv_all", "PV energy", &[("datalog", "1111111111")], 1234.5);
+        metrics.gauge("soc", "State of charge", &[("datalog", "2222222222")], 60.0);
+        metrics.gauge("v_pv_1", "PV voltage", &[], f64::NAN);
+        metrics.counter("serial_mismatches", "Mismatches", &[("note", "a \"b\"")], 0.0);
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP eg4_e_pv_all_total PV energy\n\
+             # TYPE eg4_e_pv_all_total counter\n\
+             eg4_e_pv_all_total{datalog=\"1111111111\"} 1234.5\n\
+             # HELP eg4_serial_mismatches_total Mismatches\n\
+             # TYPE eg4_serial_mismatches_total counter\n\
+             eg4_serial_mismatches_total{note=\"a \\\"b\\\"\"} 0\n\
+             # HELP eg4_soc State of charge\n\
+             # TYPE eg4_soc gauge\n\
+             eg4_soc{datalog=\"1111111111\"} 55\n\
+             eg4_soc{datalog=\"2222222222\"} 60\n"
+        );
+    }
+}
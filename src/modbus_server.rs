@@ -0,0 +1,401 @@
+use crate::prelude::*;
+
+use crate::coordinator::commands::write_inverter::WriteInverter;
+use crate::register_cache::RegisterType;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Modbus function codes we understand
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+// most registers a single read/write may ask for, per the Modbus spec
+const MAX_READ_COUNT: u16 = 125;
+const MAX_WRITE_COUNT: u16 = 123;
+
+// Exception {{{
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    GatewayPathUnavailable = 0x0A,
+    GatewayTargetFailedToRespond = 0x0B,
+} // }}}
+
+// Request {{{
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Request {
+    ReadHoldingRegisters(u16, u16),
+    ReadInputRegisters(u16, u16),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleRegisters(u16, Vec<u16>),
+}
+
+impl Request {
+    /// Parses a PDU (function code onwards, without the MBAP header).
+    pub fn parse(pdu: &[u8]) -> Result<Self, Exception> {
+        let function = *pdu.first().ok_or(Exception::IllegalFunction)?;
+        let data = &pdu[1..];
+
+        let word = |i: usize| -> Result<u16, Exception> {
+            data.get(i..i + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or(Exception::IllegalDataValue)
+        };
+
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let (start, count) = (word(0)?, word(2)?);
+                if count == 0 || count > MAX_READ_COUNT {
+                    return Err(Exception::IllegalDataValue);
+                }
+                if start.checked_add(count - 1).is_none() {
+                    return Err(Exception::IllegalDataAddress);
+                }
+                if function == READ_HOLDING_REGISTERS {
+                    Ok(Self::ReadHoldingRegisters(start, count))
+                } else {
+                    Ok(Self::ReadInputRegisters(start, count))
+                }
+            }
+            WRITE_SINGLE_REGISTER => Ok(Self::WriteSingleRegister(word(0)?, word(2)?)),
+            WRITE_MULTIPLE_REGISTERS => {
+                let (start, count) = (word(0)?, word(2)?);
+                let byte_count = *data.get(4).ok_or(Exception::IllegalDataValue)? as usize;
+                if count == 0 || count > MAX_WRITE_COUNT || byte_count != count as usize * 2 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                if start.checked_add(count - 1).is_none() {
+                    return Err(Exception::IllegalDataAddress);
+                }
+                let values = (0..count as usize)
+                    .map(|i| word(5 + i * 2))
+                    .collect::<Result<Vec<u16>, Exception>>()?;
+                Ok(Self::WriteMultipleRegisters(start, values))
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    pub fn function(&self) -> u8 {
+        match self {
+            Self::ReadHoldingRegisters(_, _) => READ_HOLDING_REGISTERS,
+            Self::ReadInputRegisters(_, _) => READ_INPUT_REGISTERS,
+            Self::WriteSingleRegister(_, _) => WRITE_SINGLE_REGISTER,
+            Self::WriteMultipleRegisters(_, _) => WRITE_MULTIPLE_REGISTERS,
+        }
+    }
+} // }}}
+
+// Response {{{
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Response {
+    Registers(u8, Vec<u16>),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleRegisters(u16, u16),
+    Exception(u8, Exception),
+}
+
+impl Response {
+    /// Encodes the response PDU (function code onwards).
+    pub fn to_pdu(&self) -> Vec<u8> {
+        let mut pdu = Vec::new();
+
+        match self {
+            Self::Registers(function, values) => {
+                pdu.push(*function);
+                pdu.push((values.len() * 2) as u8);
+                for value in values {
+                    pdu.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            Self::WriteSingleRegister(register, value) => {
+                pdu.push(WRITE_SINGLE_REGISTER);
+                pdu.extend_from_slice(&register.to_be_bytes());
+                pdu.extend_from_slice(&value.to_be_bytes());
+            }
+            Self::WriteMultipleRegisters(start, count) => {
+                pdu.push(WRITE_MULTIPLE_REGISTERS);
+                pdu.extend_from_slice(&start.to_be_bytes());
+                pdu.extend_from_slice(&count.to_be_bytes());
+            }
+            Self::Exception(function, exception) => {
+                pdu.push(function | 0x80);
+                pdu.push(*exception as u8);
+            }
+        }
+
+        pdu
+    }
+} // }}}
+
+/// Serves the register cache to Modbus TCP clients, and forwards their
+/// writes to the inverter, so third-party tools can share the one dongle
+/// connection the bridge owns.
+#[derive(Clone)]
+pub struct ModbusServer {
+    config: ConfigWrapper,
+    channels: Channels,
+}
+
+impl ModbusServer {
+    pub fn new(config: ConfigWrapper, channels: Channels) -> Self {
+        Self { config, channels }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let modbus_server = match self.config.modbus_server() {
+            Some(modbus_server) if modbus_server.enabled() => modbus_server,
+            _ => {
+                info!("modbus server disabled, skipping");
+                return Ok(());
+            }
+        };
+
+        let listen = (modbus_server.host().to_owned(), modbus_server.port());
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .map_err(|err| {
+                anyhow!(
+                    "modbus_server.rs:can't listen on {}:{}: {}",
+                    modbus_server.host(),
+                    modbus_server.port(),
+                    err
+                )
+            })?;
+
+        info!(
+            "modbus server listening on {}:{}",
+            modbus_server.host(),
+            modbus_server.port()
+        );
+
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            debug!("modbus server: connection from {}", peer);
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_connection(socket).await {
+                    debug!("modbus server: {} disconnected: {}", peer, err);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, mut socket: tokio::net::TcpStream) -> Result<()> {
+        loop {
+            // MBAP header: transaction id, protocol id, length, unit id
+            let mut header = [0u8; 7];
+            socket.read_exact(&mut header).await?;
+
+            let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+            let protocol_id = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]);
+            let unit_id = header[6];
+
+            if protocol_id != 0 || !(2..=254).contains(&length) {
+                bail!("invalid MBAP header {:?}", header);
+            }
+
+            let mut pdu = vec![0u8; length as usize - 1];
+            socket.read_exact(&mut pdu).await?;
+
+            let response = match Request::parse(&pdu) {
+                Ok(request) => self.handle_request(unit_id, request).await,
+                Err(exception) => Response::Exception(pdu[0], exception),
+            };
+
+            let pdu = response.to_pdu();
+            let mut frame = Vec::with_capacity(7 + pdu.len());
+            frame.extend_from_slice(&transaction_id.to_be_bytes());
+            frame.extend_from_slice(&0u16.to_be_bytes());
+            frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            frame.push(unit_id);
+            frame.extend_from_slice(&pdu);
+
+            socket.write_all(&frame).await?;
+        }
+    }
+
+    async fn handle_request(&self, unit_id: u8, request: Request) -> Response {
+        let function = request.function();
+
+        let inverter = match self.inverter_for_unit(unit_id) {
+            Some(inverter) => inverter,
+            None => return Response::Exception(function, Exception::GatewayPathUnavailable),
+        };
+        let datalog = match inverter.datalog() {
+            Some(datalog) => datalog,
+            None => return Response::Exception(function, Exception::GatewayPathUnavailable),
+        };
+
+        match request {
+            Request::ReadHoldingRegisters(start, count) => {
+                self.read_registers(function, datalog, RegisterType::Hold, start, count)
+                    .await
+            }
+            Request::ReadInputRegisters(start, count) => {
+                self.read_registers(function, datalog, RegisterType::Input, start, count)
+                    .await
+            }
+            Request::WriteSingleRegister(register, value) => {
+                match self.write_inverter(inverter).set_hold(register, value).await {
+                    Ok(_) => Response::WriteSingleRegister(register, value),
+                    Err(err) => {
+                        warn!("modbus server: write of register {} failed: {}", register, err);
+                        Response::Exception(function, Exception::ServerDeviceFailure)
+                    }
+                }
+            }
+            Request::WriteMultipleRegisters(start, values) => {
+                let count = values.len() as u16;
+                let values = (0..count).map(|i| start + i).zip(values).collect();
+                match self.write_inverter(inverter).set_hold_values(values).await {
+                    Ok(_) => Response::WriteMultipleRegisters(start, count),
+                    Err(err) => {
+                        warn!("modbus server: write of registers {}..{} failed: {}", start, start + (count - 1), err);
+                        Response::Exception(function, Exception::ServerDeviceFailure)
+                    }
+                }
+            }
+        }
+    }
+
+    // registers we've never seen a value for are reported as the inverter
+    // not responding, rather than made up; poll them first.
+    async fn read_registers(
+        &self,
+        function: u8,
+        datalog: Serial,
+        register_type: RegisterType,
+        start: u16,
+        count: u16,
+    ) -> Response {
+        let mut values = Vec::with_capacity(count as usize);
+
+        // start + count is one past 65535 for a read of the last register
+        for register in (0..count).map(|i| start + i) {
+            match RegisterCache::get(&self.channels, datalog, register_type, register).await {
+                Some(entry) => values.push(entry.value),
+                None => {
+                    return Response::Exception(function, Exception::GatewayTargetFailedToRespond)
+                }
+            }
+        }
+
+        Response::Registers(function, values)
+    }
+
+    fn inverter_for_unit(&self, unit_id: u8) -> Option<config::Inverter> {
+        self.config
+            .modbus_server()?
+            .inverter_for_unit(unit_id, &self.config.enabled_inverters())
+    }
+
+    fn write_inverter(&self, inverter: config::Inverter) -> WriteInverter {
+        WriteInverter::new(self.channels.clone(), inverter, self.config.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(
+            Request::parse(&[0x03, 0x00, 0x15, 0x00, 0x02]),
+            Ok(Request::ReadHoldingRegisters(21, 2))
+        );
+        assert_eq!(
+            Request::parse(&[0x04, 0x00, 0x00, 0x00, 0x28]),
+            Ok(Request::ReadInputRegisters(0, 40))
+        );
+        assert_eq!(
+            Request::parse(&[0x06, 0x00, 0x40, 0x00, 0x64]),
+            Ok(Request::WriteSingleRegister(64, 100))
+        );
+        assert_eq!(
+            Request::parse(&[0x10, 0x00, 0x44, 0x00, 0x02, 0x04, 0x00, 0x01, 0x00, 0x02]),
+            Ok(Request::WriteMultipleRegisters(68, vec![1, 2]))
+        );
+
+        // the last register can be read and written, but not past it
+        assert_eq!(
+            Request::parse(&[0x04, 0xFF, 0xFE, 0x00, 0x02]),
+            Ok(Request::ReadInputRegisters(65534, 2))
+        );
+        assert_eq!(
+            Request::parse(&[0x04, 0xFF, 0xFF, 0x00, 0x02]),
+            Err(Exception::IllegalDataAddress)
+        );
+        assert_eq!(
+            Request::parse(&[0x10, 0xFF, 0xFF, 0x00, 0x01, 0x02, 0x00, 0x07]),
+            Ok(Request::WriteMultipleRegisters(65535, vec![7]))
+        );
+
+        assert_eq!(Request::parse(&[0x05, 0x00]), Err(Exception::IllegalFunction));
+        assert_eq!(
+            Request::parse(&[0x03, 0x00, 0x00, 0x00, 0x00]),
+            Err(Exception::IllegalDataValue)
+        );
+        assert_eq!(
+            Request::parse(&[0x10, 0x00, 0x44, 0x00, 0x02, 0x02, 0x00, 0x01]),
+            Err(Exception::IllegalDataValue)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_last_registers() -> Result<()> {
+        let config = ConfigWrapper::from_config(config::Config::new("config.yaml.example".to_string())?);
+        let channels = Channels::new();
+        let cache = RegisterCache::new(channels.clone());
+        let handle = tokio::spawn(async move { cache.start().await });
+        let datalog = Serial::from_str("2222222222")?;
+
+        // let the cache subscribe before we send anything
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        for (register, value) in [(65534, 1), (65535, 2)] {
+            channels
+                .to_register_cache
+                .send(register_cache::ChannelData::RegisterData(datalog, RegisterType::Input, register, value))?;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let server = ModbusServer::new(config, channels.clone());
+        assert_eq!(
+            server
+                .read_registers(READ_INPUT_REGISTERS, datalog, RegisterType::Input, 65534, 2)
+                .await,
+            Response::Registers(READ_INPUT_REGISTERS, vec![1, 2])
+        );
+
+        channels.to_register_cache.send(register_cache::ChannelData::Shutdown)?;
+        channels.read_register_cache.send(register_cache::ChannelData::Shutdown)?;
+        handle.await??;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_responses() {
+        assert_eq!(
+            Response::Registers(READ_HOLDING_REGISTERS, vec![0x1234, 5]).to_pdu(),
+            vec![0x03, 0x04, 0x12, 0x34, 0x00, 0x05]
+        );
+        assert_eq!(
+            Response::WriteMultipleRegisters(68, 2).to_pdu(),
+            vec![0x10, 0x00, 0x44, 0x00, 0x02]
+        );
+        assert_eq!(
+            Response::Exception(READ_INPUT_REGISTERS, Exception::GatewayTargetFailedToRespond)
+                .to_pdu(),
+            vec![0x84, 0x0B]
+        );
+    }
+}
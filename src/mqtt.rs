@@ -1,7 +1,16 @@
 use crate::prelude::*;
 use crate::eg4::packet::BatteryStatusString;
+use crate::home_assistant::DeviceInfo;
 
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, Publish, QoS};
+use rumqttc::tokio_rustls::rustls;
+use rumqttc::{QoS, TlsConfiguration, Transport};
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// how often <datalog>/status is refreshed with a new last_seen
+const STATUS_INTERVAL_SECS: u64 = 60;
 
 // Message {{{
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -74,6 +83,33 @@ impl Message {
         })
     }
 
+    // retained, like the individual hold/<reg> topics, as settings rarely change
+    pub fn for_hold_all(
+        settings: &crate::eg4::hold_settings::HoldSettings,
+        datalog: crate::eg4::inverter::Serial,
+    ) -> Result<Message> {
+        Ok(mqtt::Message {
+            topic: format!("{}/hold/all", datalog),
+            retain: true,
+            payload: serde_json::to_string(settings)?,
+        })
+    }
+
+    // every active fault or warning, not just the first, and a one line summary
+    fn for_codes(topic: String, code: u32, active: Vec<&'static str>) -> Result<Message> {
+        let text = if active.is_empty() { "OK".to_string() } else { active.join(", ") };
+
+        Ok(mqtt::Message {
+            topic,
+            retain: false,
+            payload: serde_json::to_string(&serde_json::json!({
+                "code": code,
+                "active": active,
+                "text": text,
+            }))?,
+        })
+    }
+
     pub fn for_input(
         td: crate::eg4::packet::TranslatedData,
         publish_individual: bool,
@@ -82,13 +118,13 @@ impl Message {
 
         let mut r = Vec::new();
 
-        if publish_individual {
-            let mut fault_code_registers_seen = false;
-            let mut fault_code = 0;
-            let mut warning_code_registers_seen = false;
-            let mut warning_code = 0;
+        let mut fault_code_registers_seen = false;
+        let mut fault_code = 0;
+        let mut warning_code_registers_seen = false;
+        let mut warning_code = 0;
 
-            for (register, value) in td.pairs() {
+        for (register, value) in td.pairs() {
+            if publish_individual {
                 r.push(mqtt::Message {
                     topic: format!("{}/input/{}", td.datalog, register),
                     retain: false,
@@ -102,41 +138,55 @@ impl Message {
                         payload: crate::eg4::packet::StatusString::from_value(value).to_owned(),
                     });
                 }
+            }
 
-                if register == 60 {
-                    fault_code |= value as u32;
-                    fault_code_registers_seen = true;
-                }
-                if register == 61 {
-                    fault_code |= (value as u32) << 16;
-                    fault_code_registers_seen = true;
-                }
+            if register == 60 {
+                fault_code |= value as u32;
+                fault_code_registers_seen = true;
+            }
+            if register == 61 {
+                fault_code |= (value as u32) << 16;
+                fault_code_registers_seen = true;
+            }
 
-                if register == 62 {
-                    warning_code |= value as u32;
-                    warning_code_registers_seen = true;
-                }
-                if register == 63 {
-                    warning_code |= (value as u32) << 16;
-                    warning_code_registers_seen = true;
-                }
+            if register == 62 {
+                warning_code |= value as u32;
+                warning_code_registers_seen = true;
             }
+            if register == 63 {
+                warning_code |= (value as u32) << 16;
+                warning_code_registers_seen = true;
+            }
+        }
 
-            if warning_code_registers_seen {
+        if warning_code_registers_seen {
+            if publish_individual {
                 r.push(mqtt::Message {
                     topic: format!("{}/input/warning_code/parsed", td.datalog),
                     retain: false,
                     payload: crate::eg4::packet::WarningCodeString::from_value(warning_code).to_owned(),
                 });
             }
+            r.push(Self::for_codes(
+                format!("{}/warnings", td.datalog),
+                warning_code,
+                crate::eg4::packet::WarningCodeString::decode(warning_code),
+            )?);
+        }
 
-            if fault_code_registers_seen {
+        if fault_code_registers_seen {
+            if publish_individual {
                 r.push(mqtt::Message {
                     topic: format!("{}/input/fault_code/parsed", td.datalog),
                     retain: false,
                     payload: crate::eg4::packet::FaultCodeString::from_value(fault_code).to_owned(),
                 });
             }
+            r.push(Self::for_codes(
+                format!("{}/faults", td.datalog),
+                fault_code,
+                crate::eg4::packet::FaultCodeString::decode(fault_code),
+            )?);
         }
 
         match td.read_input() {
@@ -231,9 +281,15 @@ impl Message {
         Ok(r)
     }
 
-    pub fn to_command(&self, inverter: config::Inverter) -> Result<Command> {
+    // registers in read/ and set/ topics can be given by number or by
+    // their shortname in the register map
+    pub fn to_command(&self, inverter: config::Inverter, register_map: &RegisterMap) -> Result<Command> {
+        use crate::register_cache::RegisterType;
         use Command::*;
 
+        let input = |register| register_map.register_number(RegisterType::Input, register);
+        let hold = |register| register_map.register_number(RegisterType::Hold, register);
+
         let (_datalog, parts) = self.split_cmd_topic()?;
 
         let r = match parts[..] {
@@ -244,17 +300,21 @@ impl Message {
             ["read", "inputs", "5"] => ReadInputs(inverter, 5),
             ["read", "inputs", "6"] => ReadInputs(inverter, 6),
             ["read", "input", register] => {
-                ReadInput(inverter, register.parse()?, self.payload_int_or_1()?)
+                ReadInput(inverter, input(register)?, self.payload_int_or_1()?)
             }
             ["read", "hold", register] => {
-                ReadHold(inverter, register.parse()?, self.payload_int_or_1()?)
+                ReadHold(inverter, hold(register)?, self.payload_int_or_1()?)
             }
             ["read", "param", register] => ReadParam(inverter, register.parse()?),
             ["read", "ac_charge", num] => ReadAcChargeTime(inverter, num.parse()?),
             ["read", "ac_first", num] => ReadAcFirstTime(inverter, num.parse()?),
             ["read", "charge_priority", num] => ReadChargePriorityTime(inverter, num.parse()?),
             ["read", "forced_discharge", num] => ReadForcedDischargeTime(inverter, num.parse()?),
-            ["set", "hold", register] => SetHold(inverter, register.parse()?, self.payload_int()?),
+            ["set", "hold", register] => SetHold(inverter, hold(register)?, self.payload_int()?),
+            // converted and checked when run, so mistakes get a result/ reply
+            ["set", "setting", name] => SetSetting(inverter, name.to_string(), self.payload.clone()),
+            ["set", "holds"] => SetHolds(inverter, self.payload.clone()),
+            ["set", "flag", name] => SetFlag(inverter, name.to_string(), self.payload_bool()),
             ["set", "param", register] => {
                 WriteParam(inverter, register.parse()?, self.payload_int()?)
             }
@@ -356,16 +416,119 @@ impl Message {
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub enum ChannelData {
     Message(Message),
+    // from the latest hold registers; discovery is sent again when it changes
+    DeviceInfo(Serial, DeviceInfo),
     Shutdown,
 }
 
-pub type Sender = broadcast::Sender<ChannelData>;
+pub type Sender = bus::Sender<ChannelData>;
+
+// published, retained, on <datalog>/status
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct InverterStatus {
+    // online or offline
+    pub status: &'static str,
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    // whether it's connected since we started watching
+    #[serde(skip)]
+    connected_before: bool,
+}
+
+impl InverterStatus {
+    fn offline() -> Self {
+        Self {
+            status: "offline",
+            ..Default::default()
+        }
+    }
+
+    pub fn online(&self) -> bool {
+        self.status == "online"
+    }
+
+    fn seen(&mut self) {
+        self.status = "online";
+        self.last_seen = Some(Utils::utc());
+        self.connected_before = true;
+    }
+}
+
+// each inverter's status, from what comes in on from_inverter
+#[derive(Debug, Default)]
+struct Statuses(HashMap<Serial, InverterStatus>);
+
+impl Statuses {
+    fn get(&mut self, datalog: Serial) -> &InverterStatus {
+        self.0.entry(datalog).or_insert_with(InverterStatus::offline)
+    }
+
+    // which inverter it's about, and whether that's worth publishing
+    // straight away rather than on the next tick
+    fn update(&mut self, message: crate::eg4::inverter::ChannelData) -> Option<(Serial, bool)> {
+        use crate::eg4::inverter::ChannelData;
+
+        match message {
+            ChannelData::Connected(datalog) => {
+                let status = self.0.entry(datalog).or_insert_with(InverterStatus::offline);
+                if status.connected_before {
+                    status.reconnects += 1;
+                }
+                status.seen();
+                Some((datalog, true))
+            }
+            ChannelData::Disconnect(datalog) => {
+                self.0.entry(datalog).or_insert_with(InverterStatus::offline).status = "offline";
+                Some((datalog, true))
+            }
+            ChannelData::ConnectError(datalog, error) => {
+                let status = self.0.entry(datalog).or_insert_with(InverterStatus::offline);
+                status.status = "offline";
+                status.last_error = Some(error);
+                Some((datalog, true))
+            }
+            // we might have started after it connected
+            ChannelData::Packet(packet) | ChannelData::Heartbeat(packet) => {
+                let status = self.0.entry(packet.datalog()).or_insert_with(InverterStatus::offline);
+                let was_online = status.online();
+                status.seen();
+                Some((packet.datalog(), !was_online))
+            }
+            ChannelData::Shutdown => None,
+        }
+    }
+
+    // marks offline any inverter that's been quiet for longer than its
+    // read_timeout, after which the connection would have been dropped
+    fn expire(&mut self, inverters: &[config::Inverter], now: chrono::DateTime<chrono::Utc>) -> Vec<Serial> {
+        let mut expired = Vec::new();
+
+        for inverter in inverters {
+            let datalog = match inverter.datalog() {
+                Some(datalog) => datalog,
+                None => continue,
+            };
+            let read_timeout = chrono::Duration::seconds(inverter.read_timeout() as i64);
+            if let Some(status) = self.0.get_mut(&datalog) {
+                if status.online() && status.last_seen.is_some_and(|seen| now - seen > read_timeout) {
+                    status.status = "offline";
+                    expired.push(datalog);
+                }
+            }
+        }
+
+        expired
+    }
+}
 
 #[derive(Clone)]
 pub struct Mqtt {
     config: ConfigWrapper,
     shutdown: bool,
     channels: Channels,
+    // shared by clones, so a restarted client picks up where the last left off
+    statuses: Arc<Mutex<Statuses>>,
 }
 
 impl Mqtt {
@@ -374,6 +537,7 @@ impl Mqtt {
             config,
             channels,
             shutdown: false,
+            statuses: Arc::new(Mutex::new(Statuses::default())),
         }
     }
 
@@ -385,32 +549,20 @@ impl Mqtt {
             return Ok(());
         }
 
-        let mut options = MqttOptions::new("lxp-bridge", c.mqtt().host(), c.mqtt().port());
-
-        let will = LastWill {
-            topic: self.lwt_topic(),
-            message: bytes::Bytes::from("offline"),
-            qos: QoS::AtLeastOnce,
-            retain: true,
-        };
-        options.set_last_will(will);
-
-        options.set_keep_alive(std::time::Duration::from_secs(60));
-        if let (Some(u), Some(p)) = (c.mqtt().username(), c.mqtt().password()) {
-            options.set_credentials(u, p);
-        }
-
         info!(
-            "initializing mqtt at {}:{}",
+            "initializing mqtt v{} at {}:{}{}",
+            c.mqtt().version(),
             c.mqtt().host(),
-            c.mqtt().port()
+            c.mqtt().port(),
+            if c.mqtt().tls().is_some() { " with TLS" } else { "" }
         );
 
-        let (client, eventloop) = AsyncClient::new(options, 10);
+        let (client, eventloop) = self.connect()?;
 
         futures::try_join!(
             self.setup(client.clone()),
             self.receiver(eventloop),
+            self.status(client.clone()),
             self.sender(client)
         )?;
 
@@ -423,7 +575,97 @@ impl Mqtt {
         let _ = self.channels.from_mqtt.send(ChannelData::Shutdown);
     }
 
-    async fn setup(&self, client: AsyncClient) -> Result<()> {
+    // rumqttc has separate clients for 3.1.1 and 5, set up the same way
+    fn connect(&self) -> Result<(Client, EventLoop)> {
+        let c = self.config.mqtt();
+        let transport = match c.tls() {
+            Some(tls) => Self::tls_transport(tls)?,
+            None => Transport::Tcp,
+        };
+        let keep_alive = std::time::Duration::from_secs(60);
+
+        if c.version() == 5 {
+            use rumqttc::v5::{mqttbytes::v5::LastWill, AsyncClient, MqttOptions};
+
+            let mut options = MqttOptions::new("lxp-bridge", c.host(), c.port());
+            options.set_last_will(LastWill::new(self.lwt_topic(), "offline", v5_qos(QoS::AtLeastOnce), true, None));
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+            if let (Some(u), Some(p)) = (c.username(), c.password()) {
+                options.set_credentials(u, p);
+            }
+
+            let (client, eventloop) = AsyncClient::new(options, 10);
+            Ok((Client::V5(client), EventLoop::V5(Box::new(eventloop))))
+        } else {
+            use rumqttc::{AsyncClient, LastWill, MqttOptions};
+
+            let mut options = MqttOptions::new("lxp-bridge", c.host(), c.port());
+            options.set_last_will(LastWill::new(self.lwt_topic(), "offline", QoS::AtLeastOnce, true));
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+            if let (Some(u), Some(p)) = (c.username(), c.password()) {
+                options.set_credentials(u, p);
+            }
+
+            let (client, eventloop) = AsyncClient::new(options, 10);
+            Ok((Client::V4(client), EventLoop::V4(Box::new(eventloop))))
+        }
+    }
+
+    fn tls_transport(tls: &config::MqttTls) -> Result<Transport> {
+        let builder = rustls::ClientConfig::builder();
+
+        let builder = if tls.insecure() {
+            warn!("mqtt: not verifying the broker's certificate (tls.insecure)");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(danger::NoVerification::new()))
+        } else {
+            let mut roots = rustls::RootCertStore::empty();
+            match tls.ca_file() {
+                Some(ca_file) => {
+                    for cert in Self::read_certs(ca_file)? {
+                        roots.add(cert)?;
+                    }
+                }
+                None => {
+                    for cert in rustls_native_certs::load_native_certs()? {
+                        // one odd system certificate shouldn't stop us connecting
+                        let _ = roots.add(cert);
+                    }
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let mut config = match (tls.cert_file(), tls.key_file()) {
+            (Some(cert_file), Some(key_file)) => {
+                let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(key_file)?))?
+                    .ok_or_else(|| anyhow!("mqtt.rs:no private key in {}", key_file))?;
+                builder.with_client_auth_cert(Self::read_certs(cert_file)?, key)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = tls.alpn().into_iter().map(String::into_bytes).collect();
+
+        Ok(Transport::Tls(TlsConfiguration::Rustls(Arc::new(config))))
+    }
+
+    fn read_certs(file: &str) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+        let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(file)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            bail!("mqtt.rs:no certificates in {}", file);
+        }
+
+        Ok(certs)
+    }
+
+    async fn setup(&self, client: Client) -> Result<()> {
+        let topics = self.config.mqtt().topics();
+        let commands_qos = qos(topics.commands_qos());
+
         client
             .publish(self.lwt_topic(), QoS::AtLeastOnce, true, "online")
             .await?;
@@ -431,7 +673,7 @@ impl Mqtt {
         client
             .subscribe(
                 format!("{}/cmd/all/#", self.config.mqtt().namespace()),
-                QoS::AtMostOnce,
+                commands_qos,
             )
             .await?;
 
@@ -443,18 +685,34 @@ impl Mqtt {
                         self.config.mqtt().namespace(),
                         inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
                     ),
-                    QoS::AtMostOnce,
+                    commands_qos,
                 )
                 .await?;
 
-            if self.config.homeassistant_enabled() && self.config.mqtt().homeassistant().enabled() {
-                let ha = home_assistant::Config::new(&inverter, &self.config.mqtt(), &self.config);
-                for msg in ha.all()?.into_iter() {
-                    let _ = client
-                        .publish(&msg.topic, QoS::AtLeastOnce, msg.retain, msg.payload)
-                        .await;
-                }
-            }
+            // model and firmware aren't known until the hold registers are
+            // read; sender() publishes discovery again once they are
+            self.publish_discovery(&client, &inverter, DeviceInfo::default()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish_discovery(&self, client: &Client, inverter: &config::Inverter, device_info: DeviceInfo) -> Result<()> {
+        if !self.config.homeassistant_enabled() || !self.config.mqtt().homeassistant().enabled() {
+            return Ok(());
+        }
+
+        let topics = self.config.mqtt().topics();
+        let ha = home_assistant::Config::new(inverter, &self.config.mqtt(), &self.config).with_device_info(device_info);
+        for msg in ha.all()?.into_iter() {
+            let _ = client
+                .publish(
+                    msg.topic,
+                    qos(topics.discovery.qos()),
+                    topics.discovery.retain(msg.retain),
+                    msg.payload,
+                )
+                .await;
         }
 
         Ok(())
@@ -471,7 +729,7 @@ impl Mqtt {
                 tokio::time::timeout(std::time::Duration::from_secs(1), eventloop.poll()).await
             {
                 match event {
-                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                    Ok(Some(publish)) => {
                         self.handle_message(publish)?;
                     }
                     Err(e) => {
@@ -480,7 +738,7 @@ impl Mqtt {
                         info!("reconnecting in 5s");
                         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                     }
-                    _ => {} // keepalives etc
+                    Ok(None) => {} // keepalives etc
                 }
             }
         }
@@ -490,16 +748,12 @@ impl Mqtt {
         Ok(())
     }
 
-    fn handle_message(&self, publish: Publish) -> Result<()> {
+    fn handle_message(&self, publish: Message) -> Result<()> {
         // remove the namespace, including the first /
         // doing it this way means we don't break if namespace happens to contain a /
         let topic = publish.topic[self.config.mqtt().namespace().len() + 1..].to_owned();
 
-        let message = Message {
-            topic,
-            retain: publish.retain,
-            payload: String::from_utf8(publish.payload.to_vec())?,
-        };
+        let message = Message { topic, ..publish };
         debug!("RX: {:?}", message);
         if self
             .channels
@@ -514,19 +768,38 @@ impl Mqtt {
     }
 
     // coordinator -> mqtt
-    async fn sender(&self, client: AsyncClient) -> Result<()> {
+    async fn sender(&self, client: Client) -> Result<()> {
         use ChannelData::*;
 
         let mut receiver = self.channels.to_mqtt.subscribe();
+        let topics = self.config.mqtt().topics();
+        let mut devices: HashMap<Serial, home_assistant::DeviceInfo> = HashMap::new();
 
         loop {
             match receiver.recv().await? {
                 Shutdown => break,
+                DeviceInfo(datalog, device_info) => {
+                    let known = devices.entry(datalog).or_default();
+                    if *known == device_info {
+                        continue;
+                    }
+                    *known = device_info.clone();
+
+                    let inverter = self.config.enabled_inverters().into_iter().find(|i| i.datalog() == Some(datalog));
+                    if let Some(inverter) = inverter {
+                        info!("{}: sending Home Assistant discovery again with model and firmware", datalog);
+                        self.publish_discovery(&client, &inverter, device_info).await?;
+                    }
+                }
                 Message(message) => {
+                    let (qos, retain) = match Self::topic_settings(&topics, &message.topic) {
+                        Some(settings) => (qos(settings.qos()), settings.retain(message.retain)),
+                        None => (QoS::AtLeastOnce, message.retain),
+                    };
                     let topic = format!("{}/{}", self.config.mqtt().namespace(), message.topic);
                     info!("publishing: {} = {}", topic, message.payload);
                     let _ = client
-                        .publish(&topic, QoS::AtLeastOnce, message.retain, message.payload)
+                        .publish(topic.clone(), qos, retain, message.payload)
                         .await
                         .map_err(|err| error!("publish {} failed: {:?} .. skipping", topic, err));
                 }
@@ -538,7 +811,380 @@ impl Mqtt {
         Ok(())
     }
 
+    // inverter -> <datalog>/status, as its connection comes and goes. sent
+    // straight away when it goes on or offline, otherwise every minute
+    async fn status(&self, client: Client) -> Result<()> {
+        let mut receiver = self.channels.from_inverter.subscribe();
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(STATUS_INTERVAL_SECS));
+
+        // what we knew before a restart, or offline until we hear otherwise
+        // rather than whatever was retained from last time
+        for inverter in self.config.enabled_inverters() {
+            if let Some(datalog) = inverter.datalog() {
+                self.publish_status(&client, datalog).await;
+            }
+        }
+        let mut changed: Vec<Serial> = Vec::new();
+
+        loop {
+            tokio::select! {
+                message = receiver.recv() => {
+                    let message = match message {
+                        Ok(crate::eg4::inverter::ChannelData::Shutdown) => break,
+                        Ok(message) => message,
+                        Err(bus::error::RecvError::Closed) => bail!("from_inverter channel closed"),
+                    };
+
+                    let update = self.statuses.lock().unwrap().update(message);
+                    if let Some((datalog, now)) = update {
+                        if now {
+                            self.publish_status(&client, datalog).await;
+                            changed.retain(|d| *d != datalog);
+                        } else if !changed.contains(&datalog) {
+                            changed.push(datalog);
+                        }
+                    }
+                }
+                _ = interval.tick() => {
+                    // from_inverter drops the oldest when we fall behind, so
+                    // a Disconnect can go missing; this catches it
+                    let expired = self.statuses.lock().unwrap().expire(&self.config.enabled_inverters(), Utils::utc());
+                    for datalog in expired {
+                        if !changed.contains(&datalog) {
+                            changed.push(datalog);
+                        }
+                    }
+                    for datalog in changed.drain(..) {
+                        self.publish_status(&client, datalog).await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish_status(&self, client: &Client, datalog: Serial) {
+        let status = self.statuses.lock().unwrap().get(datalog).clone();
+        let payload = match serde_json::to_string(&status) {
+            Ok(payload) => payload,
+            Err(err) => return error!("can't serialize {:?}: {}", status, err),
+        };
+
+        let topic = format!("{}/status", datalog);
+        let (qos, retain) = match Self::topic_settings(&self.config.mqtt().topics(), &topic) {
+            Some(settings) => (qos(settings.qos()), settings.retain(true)),
+            None => (QoS::AtLeastOnce, true),
+        };
+        let topic = format!("{}/{}", self.config.mqtt().namespace(), topic);
+
+        debug!("publishing: {} = {}", topic, payload);
+        let _ = client
+            .publish(topic.clone(), qos, retain, payload)
+            .await
+            .map_err(|err| error!("publish {} failed: {:?} .. skipping", topic, err));
+    }
+
+    // which of mqtt.topics a topic (without the namespace) comes under, if any
+    fn topic_settings<'a>(topics: &'a config::MqttTopics, topic: &str) -> Option<&'a config::MqttTopic> {
+        let parts: Vec<&str> = topic.split('/').collect();
+
+        match parts[..] {
+            ["result", ..] => Some(&topics.results),
+            [_, "status"] => Some(&topics.status),
+            [_, "inputs" | "input" | "faults" | "warnings", ..] => Some(&topics.inputs),
+            [_, "hold" | "param" | "ac_charge" | "ac_first" | "charge_priority" | "forced_discharge", ..] => {
+                Some(&topics.hold)
+            }
+            _ => None,
+        }
+    }
+
     fn lwt_topic(&self) -> String {
         format!("{}/LWT", self.config.mqtt().namespace())
     }
 }
+
+// Config::validate() refuses anything else
+fn qos(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => unreachable!("mqtt qos {} should have been refused by config validation", qos),
+    }
+}
+
+fn v5_qos(qos: QoS) -> rumqttc::v5::mqttbytes::QoS {
+    use rumqttc::v5::mqttbytes::QoS as V5;
+
+    match qos {
+        QoS::AtMostOnce => V5::AtMostOnce,
+        QoS::AtLeastOnce => V5::AtLeastOnce,
+        QoS::ExactlyOnce => V5::ExactlyOnce,
+    }
+}
+
+#[derive(Clone)]
+enum Client {
+    V4(rumqttc::AsyncClient),
+    V5(rumqttc::v5::AsyncClient),
+}
+
+impl Client {
+    async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) -> Result<()> {
+        match self {
+            Self::V4(client) => client.publish(topic, qos, retain, payload).await?,
+            Self::V5(client) => client.publish(topic, v5_qos(qos), retain, payload.into()).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: String, qos: QoS) -> Result<()> {
+        match self {
+            Self::V4(client) => client.subscribe(topic, qos).await?,
+            Self::V5(client) => client.subscribe(topic, v5_qos(qos)).await?,
+        }
+
+        Ok(())
+    }
+}
+
+enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<rumqttc::v5::EventLoop>),
+}
+
+impl EventLoop {
+    // the next message received, or None for anything else the broker sent.
+    // a message we can't read is skipped, not an error - that would have us
+    // reconnect, and the broker would send it again if it was retained
+    async fn poll(&mut self) -> Result<Option<Message>> {
+        use rumqttc::{v5, Event, Incoming};
+
+        let message = match self {
+            Self::V4(eventloop) => match eventloop.poll().await? {
+                Event::Incoming(Incoming::Publish(publish)) => {
+                    Self::message(publish.topic.as_bytes(), publish.retain, &publish.payload)
+                }
+                _ => None,
+            },
+            Self::V5(eventloop) => match eventloop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::Publish(publish)) => {
+                    Self::message(&publish.topic, publish.retain, &publish.payload)
+                }
+                _ => None,
+            },
+        };
+
+        Ok(message)
+    }
+
+    fn message(topic: &[u8], retain: bool, payload: &[u8]) -> Option<Message> {
+        let topic = String::from_utf8_lossy(topic);
+        match std::str::from_utf8(payload) {
+            Ok(payload) => Some(Message {
+                topic: topic.into_owned(),
+                retain,
+                payload: payload.to_string(),
+            }),
+            Err(err) => {
+                warn!("mqtt: ignoring message on {}, payload isn't UTF-8: {}", topic, err);
+                None
+            }
+        }
+    }
+}
+
+mod danger {
+    use rumqttc::tokio_rustls::rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    };
+
+    // for tls.insecure: takes any certificate for any name, but still checks
+    // the handshake is signed by the certificate we were given
+    #[derive(Debug)]
+    pub struct NoVerification(WebPkiSupportedAlgorithms);
+
+    impl NoVerification {
+        pub fn new() -> Self {
+            Self(ring::default_provider().signature_verification_algorithms)
+        }
+    }
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &self.0)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &self.0)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.supported_schemes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_settings() {
+        let topics = config::MqttTopics {
+            inputs: config::MqttTopic { qos: Some(0), retain: None },
+            hold: config::MqttTopic { qos: None, retain: Some(false) },
+            ..Default::default()
+        };
+        let settings = |topic| Mqtt::topic_settings(&topics, topic);
+
+        assert_eq!(settings("BJ43500414/inputs/all").map(|s| s.qos()), Some(0));
+        assert_eq!(settings("BJ43500414/input/0/parsed").map(|s| s.qos()), Some(0));
+        assert_eq!(settings("BJ43500414/hold/21/bits").map(|s| s.retain(true)), Some(false));
+        assert_eq!(settings("BJ43500414/ac_charge/1"), Some(&topics.hold));
+        assert_eq!(settings("result/BJ43500414/set/hold/21"), Some(&topics.results));
+        assert_eq!(settings("result/BJ43500414/set/hold/21").map(|s| s.retain(false)), Some(false));
+        assert_eq!(settings("BJ43500414/faults"), Some(&topics.inputs));
+        assert_eq!(settings("BJ43500414/write/status"), None);
+        assert_eq!(settings("BJ43500414/status"), Some(&topics.status));
+    }
+
+    #[test]
+    fn test_to_command_flags_and_settings() {
+        let register_map = RegisterMap::builtin();
+        let inverter: config::Inverter = serde_json::from_value(serde_json::json!(
+            { "host": "a", "port": 8000, "serial": "5555555555", "datalog": "2222222222" }
+        ))
+        .unwrap();
+        let command = |topic: &str, payload: &str| {
+            let message = Message { topic: topic.to_string(), retain: false, payload: payload.to_string() };
+            message.to_command(inverter.clone(), &register_map).unwrap()
+        };
+
+        for (payload, enable) in [("ON", true), ("true", true), ("1", true), ("OFF", false), ("0", false), ("", false)] {
+            assert!(
+                matches!(command("cmd/2222222222/set/flag/eps_en", payload), Command::SetFlag(_, ref name, e) if name == "eps_en" && e == enable),
+                "{:?}",
+                payload
+            );
+        }
+        // converted when run, so the value is passed through as it came
+        assert!(matches!(
+            command("cmd/2222222222/set/setting/start_pv_volt", "140.5"),
+            Command::SetSetting(_, ref name, ref value) if name == "start_pv_volt" && value == "140.5"
+        ));
+        assert!(matches!(command("cmd/2222222222/set/hold/function_enable", "129"), Command::SetHold(_, 21, 129)));
+    }
+
+    #[test]
+    fn test_statuses() {
+        use crate::eg4::inverter::ChannelData;
+
+        let inverter: config::Inverter = serde_json::from_value(serde_json::json!(
+            { "host": "a", "port": 8000, "serial": "5555555555", "datalog": "2222222222", "read_timeout": 300 }
+        ))
+        .unwrap();
+        let datalog = inverter.datalog().unwrap();
+        let heartbeat = ChannelData::Heartbeat(Packet::Heartbeat(crate::eg4::packet::Heartbeat { datalog }));
+        let mut statuses = Statuses::default();
+
+        assert_eq!(statuses.get(datalog).status, "offline");
+
+        // heard from before we saw it connect
+        assert_eq!(statuses.update(heartbeat.clone()), Some((datalog, true)));
+        assert!(statuses.get(datalog).online());
+        assert_eq!(statuses.update(heartbeat.clone()), Some((datalog, false)));
+
+        assert_eq!(statuses.update(ChannelData::Disconnect(datalog)), Some((datalog, true)));
+        assert_eq!(statuses.get(datalog).status, "offline");
+        statuses.update(ChannelData::ConnectError(datalog, "refused".to_string()));
+        assert_eq!(statuses.get(datalog).last_error.as_deref(), Some("refused"));
+
+        statuses.update(ChannelData::Connected(datalog));
+        assert!(statuses.get(datalog).online());
+        assert_eq!(statuses.get(datalog).reconnects, 1);
+        statuses.update(ChannelData::Connected(datalog));
+        assert_eq!(statuses.get(datalog).reconnects, 2);
+
+        // a Disconnect that never arrived is caught once read_timeout is up
+        let seen = statuses.get(datalog).last_seen.unwrap();
+        let inverters = [inverter];
+        assert!(statuses.expire(&inverters, seen + chrono::Duration::seconds(300)).is_empty());
+        assert!(statuses.get(datalog).online());
+        assert_eq!(statuses.expire(&inverters, seen + chrono::Duration::seconds(301)), vec![datalog]);
+        assert_eq!(statuses.get(datalog).status, "offline");
+        // and only reported the once
+        assert!(statuses.expire(&inverters, seen + chrono::Duration::seconds(400)).is_empty());
+    }
+
+    #[test]
+    fn test_for_input_faults() {
+        use crate::eg4::packet::{DeviceFunction, TranslatedData};
+
+        // input 60-63, with faults E000 and E002 and no warnings
+        let mut values = vec![0; 8];
+        values[0] = 0b101;
+        let td = TranslatedData {
+            datalog: "BJ43500414".parse().unwrap(),
+            device_function: DeviceFunction::ReadInput,
+            inverter: "4372830102".parse().unwrap(),
+            register: 60,
+            values,
+        };
+
+        let messages = Message::for_input(td, false).unwrap();
+        let payload = |topic: &str| {
+            let message = messages.iter().find(|m| m.topic == topic).unwrap();
+            serde_json::from_str::<serde_json::Value>(&message.payload).unwrap()
+        };
+
+        let faults = payload("BJ43500414/faults");
+        assert_eq!(faults["code"], 5);
+        assert_eq!(faults["active"].as_array().unwrap().len(), 2);
+        assert_eq!(faults["text"], "E000: Internal communication fault 1, E002: BatOnMosFail");
+        assert_eq!(payload("BJ43500414/warnings")["text"], "OK");
+        assert!(!messages.iter().any(|m| m.topic.contains("/input/")));
+    }
+
+    #[test]
+    fn test_received_message() {
+        assert_eq!(
+            EventLoop::message(b"lxp/cmd/all/read/inputs/1", false, b"1"),
+            Some(Message {
+                topic: "lxp/cmd/all/read/inputs/1".to_string(),
+                retain: false,
+                payload: "1".to_string(),
+            })
+        );
+        // not UTF-8, skipped rather than failing the poll
+        assert_eq!(EventLoop::message(b"lxp/cmd/all/read/inputs/1", true, &[0xff, 0xfe]), None);
+    }
+}
@@ -6,6 +6,11 @@ pub struct Options {
     /// Config file to read
     #[clap(short = 'c', long = "config", default_value = "config.yaml")]
     pub config_file: String,
+
+    /// Replay a capture file through the coordinator instead of connecting
+    /// to the inverters
+    #[clap(long = "replay")]
+    pub replay_file: Option<String>,
 }
 
 impl Options {
@@ -13,22 +13,28 @@ pub use {
 };
 
 pub use crate::{
+    bus,
     channels::Channels,
     command::Command,
     config::{self, Config, ConfigWrapper},
     coordinator::{self, Coordinator},
     database::{self, Database},
     home_assistant,
+    http_server::HttpServer,
     influx::{self, Influx},
     eg4::{
         self,
         inverter::{Inverter, Serial},
         packet::{Packet, PacketCommon},
     },
+    modbus_server::ModbusServer,
     mqtt::{self, Mqtt},
     options::Options,
     register_cache::{self, RegisterCache},
+    register_map::{self, RegisterMap},
+    request_queue::{self, RequestQueue},
     scheduler::Scheduler,
+    supervisor::Supervisor,
     unixtime::UnixTime,
     utils::Utils,
 };
@@ -1,24 +1,53 @@
 use crate::prelude::*;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
 
-// this just needs to be bigger than the max register we'll see
-const REGISTER_COUNT: usize = 512;
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum RegisterType {
+    Hold,
+    Input,
+}
+
+// a cached register value along with when we last heard it from the inverter
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize)]
+pub struct Entry {
+    pub value: u16,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Entry {
+    pub fn age(&self) -> chrono::Duration {
+        Utils::utc() - self.updated_at
+    }
+
+    pub fn is_stale(&self, max_age: chrono::Duration) -> bool {
+        self.age() > max_age
+    }
+}
+
+type Reply = Arc<Mutex<Option<oneshot::Sender<Option<Entry>>>>>;
+type ReplyAll = Arc<Mutex<Option<oneshot::Sender<BTreeMap<u16, Entry>>>>>;
 
 #[derive(Clone, Debug)]
 pub enum ChannelData {
-    ReadRegister(u16, Arc<Mutex<Option<oneshot::Sender<u16>>>>),
-    RegisterData(u16, u16),
+    ReadRegister(Serial, RegisterType, u16, Reply),
+    ReadRegisters(Serial, RegisterType, ReplyAll),
+    RegisterData(Serial, RegisterType, u16, u16),
     Shutdown,
 }
 
+// keyed by (datalog, register type, register) so multiple inverters, and
+// hold/input registers with the same number, never overwrite each other.
+type Store = HashMap<(Serial, RegisterType, u16), Entry>;
+
 pub struct RegisterCache {
     channels: Channels,
-    register_data: Arc<Mutex<[u16; REGISTER_COUNT]>>,
+    register_data: Arc<Mutex<Store>>,
 }
 
 impl RegisterCache {
     pub fn new(channels: Channels) -> Self {
-        let register_data = Arc::new(Mutex::new([0; REGISTER_COUNT]));
+        let register_data = Arc::new(Mutex::new(Store::new()));
 
         Self {
             channels,
@@ -34,12 +63,32 @@ impl RegisterCache {
 
     // external helper method to simplify access to the cache, use like so:
     //
-    //   RegisterCache::get(&self.channels, 1);
+    //   RegisterCache::get(&self.channels, datalog, RegisterType::Hold, 21);
     //
-    pub async fn get(channels: &Channels, register: u16) -> u16 {
+    // returns None if we've never seen a value for this register.
+    pub async fn get(
+        channels: &Channels,
+        datalog: Serial,
+        register_type: RegisterType,
+        register: u16,
+    ) -> Option<Entry> {
         let (tx, rx) = oneshot::channel();
         let tx = Arc::new(Mutex::new(Some(tx)));
-        let channel_data = ChannelData::ReadRegister(register, tx);
+        let channel_data = ChannelData::ReadRegister(datalog, register_type, register, tx);
+        let _ = channels.read_register_cache.send(channel_data);
+        rx.await
+            .expect("unexpected error reading from register cache")
+    }
+
+    // every register of one type we've seen from an inverter, in order.
+    pub async fn get_all(
+        channels: &Channels,
+        datalog: Serial,
+        register_type: RegisterType,
+    ) -> BTreeMap<u16, Entry> {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let channel_data = ChannelData::ReadRegisters(datalog, register_type, tx);
         let _ = channels.read_register_cache.send(channel_data);
         rx.await
             .expect("unexpected error reading from register cache")
@@ -52,11 +101,31 @@ impl RegisterCache {
 
         while let Ok(data) = receiver.recv().await {
             match data {
-                ChannelData::ReadRegister(register, tx) => {
-                    let value = self.register_data.lock().unwrap()[register as usize];
+                ChannelData::ReadRegister(datalog, register_type, register, tx) => {
+                    let entry = self
+                        .register_data
+                        .lock()
+                        .unwrap()
+                        .get(&(datalog, register_type, register))
+                        .copied();
                     if let Ok(mut tx) = tx.lock() {
                         if let Some(tx) = tx.take() {
-                            let _ = tx.send(value);
+                            let _ = tx.send(entry);
+                        }
+                    }
+                }
+                ChannelData::ReadRegisters(datalog, register_type, tx) => {
+                    let entries = self
+                        .register_data
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|((d, t, _), _)| *d == datalog && *t == register_type)
+                        .map(|((_, _, register), entry)| (*register, *entry))
+                        .collect();
+                    if let Ok(mut tx) = tx.lock() {
+                        if let Some(tx) = tx.take() {
+                            let _ = tx.send(entries);
                         }
                     }
                 }
@@ -75,8 +144,15 @@ impl RegisterCache {
 
         while let Ok(data) = receiver.recv().await {
             match data {
-                ChannelData::RegisterData(register, value) => {
-                    self.register_data.lock().unwrap()[register as usize] = value;
+                ChannelData::RegisterData(datalog, register_type, register, value) => {
+                    let entry = Entry {
+                        value,
+                        updated_at: Utils::utc(),
+                    };
+                    self.register_data
+                        .lock()
+                        .unwrap()
+                        .insert((datalog, register_type, register), entry);
                 }
                 ChannelData::Shutdown => break,
                 _ => (),
@@ -86,3 +162,52 @@ impl RegisterCache {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registers_are_separate_per_datalog_and_type() -> Result<()> {
+        let channels = Channels::new();
+        let cache = RegisterCache::new(channels.clone());
+        let handle = tokio::spawn(async move { cache.start().await });
+
+        let datalog_1 = Serial::from_str("1111111111")?;
+        let datalog_2 = Serial::from_str("2222222222")?;
+
+        // let the cache subscribe before we send anything
+        tokio::task::yield_now().await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        channels
+            .to_register_cache
+            .send(ChannelData::RegisterData(datalog_1, RegisterType::Hold, 21, 100))?;
+        channels
+            .to_register_cache
+            .send(ChannelData::RegisterData(datalog_1, RegisterType::Input, 21, 200))?;
+        channels
+            .to_register_cache
+            .send(ChannelData::RegisterData(datalog_2, RegisterType::Hold, 21, 300))?;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let get = |datalog, register_type| RegisterCache::get(&channels, datalog, register_type, 21);
+        assert_eq!(get(datalog_1, RegisterType::Hold).await.map(|e| e.value), Some(100));
+        assert_eq!(get(datalog_1, RegisterType::Input).await.map(|e| e.value), Some(200));
+        assert_eq!(get(datalog_2, RegisterType::Hold).await.map(|e| e.value), Some(300));
+        assert_eq!(get(datalog_2, RegisterType::Input).await, None);
+
+        let entry = get(datalog_1, RegisterType::Hold).await.unwrap();
+        assert!(!entry.is_stale(chrono::Duration::seconds(60)));
+
+        let holds = RegisterCache::get_all(&channels, datalog_1, RegisterType::Hold).await;
+        assert_eq!(holds.keys().collect::<Vec<_>>(), vec![&21]);
+        assert_eq!(holds[&21].value, 100);
+
+        channels.to_register_cache.send(ChannelData::Shutdown)?;
+        channels.read_register_cache.send(ChannelData::Shutdown)?;
+        handle.await??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,722 @@
+use crate::prelude::*;
+use crate::register_cache::RegisterType;
+
+use {
+    serde::Deserialize,
+    serde_json::{json, Value},
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+// the map we ship; users can add to or replace entries with register_map_file.
+// doc/eg4_registers.json is the published file it was corrected from, whose
+// input registers are mislabelled as hold and repeated.
+const BUILTIN: &str = include_str!("../doc/eg4_register_map.json");
+
+// File format {{{
+// This mirrors doc/eg4_registers.json, warts and all - booleans and scales
+// are sometimes strings, and value_map is either packed sub-values or labels.
+#[derive(Deserialize)]
+struct File {
+    registers: Vec<Block>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    register_type: String,
+    register_map: Vec<RawRegister>,
+}
+
+#[derive(Deserialize)]
+struct RawRegister {
+    register_number: u16,
+    shortname: Option<String>,
+    #[serde(default)]
+    description: String,
+    datatype: Option<String>,
+    display_as: Option<String>,
+    unit: Option<String>,
+    unit_scale: Option<StringOr<f64>>,
+    min_value: Option<f64>,
+    max_value: Option<f64>,
+    read_only: Option<StringOr<bool>>,
+    value_map: Option<RawValueMap>,
+    #[serde(default)]
+    flags: Vec<Flag>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOr<T> {
+    Value(T),
+    String(String),
+}
+
+impl<T: FromStr> StringOr<T> {
+    fn parse(self, register: u16) -> Result<T> {
+        match self {
+            Self::Value(value) => Ok(value),
+            Self::String(s) => s
+                .parse()
+                .map_err(|_| anyhow!("register_map.rs:register {}: can't parse {:?}", register, s)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValueMap {
+    Fields(Vec<RawField>),
+    Labels(BTreeMap<String, String>),
+}
+
+#[derive(Deserialize)]
+struct RawField {
+    shortname: String,
+    #[serde(default)]
+    description: String,
+    unit: Option<String>,
+    value_unit: String,
+    value_size: u16,
+    value_location: u16,
+} // }}}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Datatype {
+    Uint8,
+    Uint16,
+    Int16,
+    // a uint16 multiplied by unit_scale
+    Float,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisplayAs {
+    Number,
+    Flags,
+    // hour in the low byte, minute in the high byte
+    Time,
+    // two ASCII characters, low byte first
+    Ascii,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Flag {
+    pub bit: u8,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A value packed into part of a register, such as SOC and SOH sharing
+/// input 5. Location and size are in bits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub shortname: String,
+    pub description: String,
+    pub unit: Option<String>,
+    pub location: u16,
+    pub size: u16,
+}
+
+impl Field {
+    pub fn value(&self, raw: u16) -> u16 {
+        let mask = ((1u32 << self.size) - 1) as u16;
+        (raw >> self.location) & mask
+    }
+}
+
+// RegisterDef {{{
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterDef {
+    pub register: u16,
+    // None for registers only made up of fields
+    pub shortname: Option<String>,
+    pub description: String,
+    pub datatype: Datatype,
+    pub display_as: DisplayAs,
+    pub unit: Option<String>,
+    pub unit_scale: f64,
+    // in raw register units, as the protocol documents them
+    pub min_value: Option<f64>,
+    pub max_value: Option<f64>,
+    pub read_only: bool,
+    pub fields: Vec<Field>,
+    pub labels: BTreeMap<u16, String>,
+    pub flags: Vec<Flag>,
+}
+
+impl RegisterDef {
+    fn from_raw(raw: RawRegister) -> Result<Option<Self>> {
+        let register = raw.register_number;
+
+        let datatype = match raw.datatype.as_deref() {
+            None | Some("uint16") => Datatype::Uint16,
+            Some("uint8") => Datatype::Uint8,
+            Some("int16") => Datatype::Int16,
+            Some("float") => Datatype::Float,
+            Some(other) => bail!("register {}: unknown datatype {}", register, other),
+        };
+        let display_as = match raw.display_as.as_deref() {
+            None | Some("number") => DisplayAs::Number,
+            Some("flags") => DisplayAs::Flags,
+            Some("time") => DisplayAs::Time,
+            Some("ascii") => DisplayAs::Ascii,
+            Some(other) => bail!("register {}: unknown display_as {}", register, other),
+        };
+
+        let mut fields = Vec::new();
+        let mut labels = BTreeMap::new();
+        match raw.value_map {
+            Some(RawValueMap::Fields(raw_fields)) => {
+                for f in raw_fields {
+                    let scale = match f.value_unit.as_str() {
+                        "bit" => 1,
+                        "byte" => 8,
+                        other => bail!("register {}: unknown value_unit {}", register, other),
+                    };
+                    let location = f.value_location.saturating_mul(scale);
+                    let mut size = f.value_size.saturating_mul(scale);
+                    if size == 0 || location >= 16 {
+                        warn!("register map: register {}: {} isn't within its 16 bits, skipping it", register, f.shortname);
+                        continue;
+                    }
+                    // the published file gives parallelnum 9 bits from bit 8
+                    if location + size > 16 {
+                        warn!(
+                            "register map: register {}: {} runs past bit 15, using its {} bits from bit {}",
+                            register,
+                            f.shortname,
+                            16 - location,
+                            location
+                        );
+                        size = 16 - location;
+                    }
+                    fields.push(Field {
+                        shortname: f.shortname,
+                        description: f.description,
+                        unit: f.unit,
+                        location,
+                        size,
+                    });
+                }
+            }
+            Some(RawValueMap::Labels(raw_labels)) => {
+                for (value, label) in raw_labels {
+                    let value = value
+                        .parse()
+                        .map_err(|_| anyhow!("register_map.rs:register {}: bad label value {}", register, value))?;
+                    labels.insert(value, label);
+                }
+            }
+            None => {}
+        }
+
+        if let Some(flag) = raw.flags.iter().find(|flag| flag.bit > 15) {
+            bail!("register {}: flag {} is bit {}, past the 16 in a register", register, flag.name, flag.bit);
+        }
+
+        // some documented registers have no name yet; nothing can refer to them
+        if raw.shortname.is_none() && fields.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            register,
+            shortname: raw.shortname,
+            description: raw.description,
+            datatype,
+            display_as,
+            unit: raw.unit,
+            unit_scale: match raw.unit_scale {
+                Some(scale) => scale.parse(register)?,
+                None => 1.0,
+            },
+            min_value: raw.min_value,
+            max_value: raw.max_value,
+            // hold registers are mostly settings, so writable unless we're told
+            read_only: match raw.read_only {
+                Some(read_only) => read_only.parse(register)?,
+                None => false,
+            },
+            fields,
+            labels,
+            flags: raw.flags,
+        }))
+    }
+
+    /// The raw value in engineering units: scaled, and signed if need be.
+    pub fn number(&self, raw: u16) -> f64 {
+        let value = match self.datatype {
+            Datatype::Uint8 => (raw & 0xFF) as f64,
+            Datatype::Int16 => raw as i16 as f64,
+            Datatype::Uint16 | Datatype::Float => raw as f64,
+        };
+
+        Utils::round(value * self.unit_scale, 3)
+    }
+
+    pub fn value(&self, raw: u16) -> Value {
+        match self.display_as {
+            // ON or OFF for each flag, as on the hold/<reg>/bits topics
+            DisplayAs::Flags if !self.flags.is_empty() => self
+                .flags
+                .iter()
+                .map(|flag| {
+                    let on = raw & (1 << flag.bit) != 0;
+                    (flag.name.clone(), json!(if on { "ON" } else { "OFF" }))
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            DisplayAs::Time => json!(format!("{:02}:{:02}", raw & 0xFF, raw >> 8)),
+            DisplayAs::Ascii => {
+                let bytes = raw.to_le_bytes();
+                json!(String::from_utf8_lossy(&bytes).trim_end_matches('\0'))
+            }
+            DisplayAs::Number | DisplayAs::Flags => {
+                let number = self.number(raw);
+                if self.unit_scale == 1.0 {
+                    json!(number as i64)
+                } else {
+                    json!(number)
+                }
+            }
+        }
+    }
+
+    /// Every named value in this register - usually just the one, but packed
+    /// registers have one per field.
+    pub fn decode(&self, raw: u16) -> Vec<(String, Value)> {
+        let mut r: Vec<_> = self.shortname.iter().map(|name| (name.clone(), self.value(raw))).collect();
+
+        for field in &self.fields {
+            r.push((field.shortname.clone(), json!(field.value(raw))));
+        }
+
+        r
+    }
+
+    /// Checks a raw value is one the inverter should accept here.
+    pub fn validate(&self, raw: u16) -> Result<()> {
+        let name = self.shortname.as_deref().unwrap_or("");
+
+        if self.read_only {
+            bail!("register {} ({}) is read-only", self.register, name);
+        }
+
+        if self.display_as == DisplayAs::Time && (raw & 0xFF > 23 || raw >> 8 > 59) {
+            bail!("register {} ({}): {} is not a valid time", self.register, name, raw);
+        }
+
+        let value = match self.datatype {
+            Datatype::Int16 => raw as i16 as f64,
+            _ => raw as f64,
+        };
+        let too_low = self.min_value.map(|min| value < min).unwrap_or(false);
+        let too_high = self.max_value.map(|max| value > max).unwrap_or(false);
+        if too_low || too_high {
+            // in engineering units, as whoever sent it will know them
+            let unit = self.unit_symbol().map(|u| format!(" {}", u)).unwrap_or_default();
+            let scaled = |v: f64| Utils::round(v * self.unit_scale, 3).to_string();
+            bail!(
+                "register {} ({}): {}{} is outside {}..{}{}",
+                self.register,
+                name,
+                self.number(raw),
+                unit,
+                self.min_value.map(scaled).unwrap_or_default(),
+                self.max_value.map(scaled).unwrap_or_default(),
+                unit,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The reverse of value(): a raw register value from one in engineering
+    /// units, such as "52.5" volts or "23:30", checked with validate().
+    pub fn encode(&self, value: &str) -> Result<u16> {
+        let name = self.shortname.as_deref().unwrap_or("");
+        let value = value.trim();
+
+        let raw = match self.display_as {
+            DisplayAs::Time => {
+                let (hour, minute) = value
+                    .split_once(':')
+                    .and_then(|(h, m)| Some((h.parse::<u16>().ok()?, m.parse::<u16>().ok()?)))
+                    .ok_or_else(|| anyhow!("{}: {:?} is not a time like 23:30", name, value))?;
+                if hour > 23 || minute > 59 {
+                    bail!("{}: {:?} is not a valid time", name, value);
+                }
+                hour | minute << 8
+            }
+            DisplayAs::Ascii => bail!("{}: can't be set", name),
+            DisplayAs::Number | DisplayAs::Flags => {
+                let number: f64 = value
+                    .parse()
+                    .map_err(|_| anyhow!("register_map.rs:{}: {:?} is not a number", name, value))?;
+                let steps = number / self.unit_scale;
+                let raw = steps.round();
+                if (steps - raw).abs() > 1e-6 {
+                    bail!("{}: {} is not a multiple of {}", name, number, self.unit_scale);
+                }
+
+                let (min, max) = match self.datatype {
+                    Datatype::Uint8 => (0.0, u8::MAX as f64),
+                    Datatype::Int16 => (i16::MIN as f64, i16::MAX as f64),
+                    Datatype::Uint16 | Datatype::Float => (0.0, u16::MAX as f64),
+                };
+                if raw < min || raw > max {
+                    bail!("{}: {} doesn't fit in the register", name, number);
+                }
+
+                match self.datatype {
+                    Datatype::Int16 => raw as i16 as u16,
+                    _ => raw as u16,
+                }
+            }
+        };
+
+        self.validate(raw)?;
+
+        Ok(raw)
+    }
+
+    /// The unit as Home Assistant and Prometheus users expect to see it.
+    pub fn unit_symbol(&self) -> Option<&'static str> {
+        let symbol = match self.unit.as_deref()? {
+            "percent" => "%",
+            "volt" => "V",
+            "ampere" => "A",
+            "watt" => "W",
+            "kilowatt" => "kW",
+            "voltampere" => "VA",
+            "hertz" => "Hz",
+            "kilowatthour" => "kWh",
+            "amperehour" => "Ah",
+            "celsius" => "°C",
+            "millisecond" => "ms",
+            "second" => "s",
+            "minute" => "min",
+            "hour" => "h",
+            "day" => "d",
+            _ => return None,
+        };
+
+        Some(symbol)
+    }
+} // }}}
+
+// RegisterMap {{{
+/// What we know about each input and hold register: names, units, scaling
+/// and limits, loaded from doc/eg4_register_map.json plus an optional override
+/// file in the same format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterMap {
+    hold: BTreeMap<u16, RegisterDef>,
+    input: BTreeMap<u16, RegisterDef>,
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl RegisterMap {
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN).expect("doc/eg4_register_map.json is invalid")
+    }
+
+    /// The builtin map, with any registers in file replacing ours.
+    pub fn load(file: Option<&str>) -> Result<Self> {
+        let mut map = Self::builtin();
+
+        if let Some(file) = file {
+            let content = std::fs::read_to_string(file)
+                .map_err(|err| anyhow!("register_map.rs:error reading {}: {}", file, err))?;
+            let overrides = Self::from_json(&content).map_err(|err| anyhow!("register_map.rs:{}: {}", file, err))?;
+            map.merge(overrides);
+        }
+
+        Ok(map)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let file: File = serde_json::from_str(json)?;
+        let mut map = Self {
+            hold: BTreeMap::new(),
+            input: BTreeMap::new(),
+        };
+
+        for block in file.registers {
+            let registers = match block.register_type.as_str() {
+                "hold" => &mut map.hold,
+                "input" => &mut map.input,
+                other => bail!("unknown register_type {}", other),
+            };
+            let mut repeated = BTreeSet::new();
+            for raw in block.register_map {
+                if let Some(def) = RegisterDef::from_raw(raw)? {
+                    if let Some(old) = registers.insert(def.register, def) {
+                        repeated.insert(old.register);
+                    }
+                }
+            }
+
+            // doc/eg4_registers.json lists its input registers as hold, three
+            // times over; as an override they'd replace our hold registers
+            if !repeated.is_empty() {
+                warn!(
+                    "register map: {} {} register(s) are listed more than once and only the last is kept - \
+                     input registers listed as {:?}, as in doc/eg4_registers.json?",
+                    repeated.len(),
+                    block.register_type,
+                    block.register_type
+                );
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        self.hold.extend(other.hold);
+        self.input.extend(other.input);
+    }
+
+    pub fn registers(&self, register_type: RegisterType) -> &BTreeMap<u16, RegisterDef> {
+        match register_type {
+            RegisterType::Hold => &self.hold,
+            RegisterType::Input => &self.input,
+        }
+    }
+
+    pub fn get(&self, register_type: RegisterType, register: u16) -> Option<&RegisterDef> {
+        self.registers(register_type).get(&register)
+    }
+
+    pub fn find(&self, register_type: RegisterType, shortname: &str) -> Option<&RegisterDef> {
+        self.registers(register_type)
+            .values()
+            .find(|def| def.shortname.as_deref() == Some(shortname))
+    }
+
+    /// The register a named flag lives in, and the flag.
+    pub fn find_flag(&self, register_type: RegisterType, name: &str) -> Option<(&RegisterDef, &Flag)> {
+        self.registers(register_type)
+            .values()
+            .find_map(|def| def.flags.iter().find(|flag| flag.name == name).map(|flag| (def, flag)))
+    }
+
+    /// Accepts either a register number or a shortname, as in MQTT topics.
+    pub fn register_number(&self, register_type: RegisterType, name: &str) -> Result<u16> {
+        if let Ok(register) = name.parse() {
+            return Ok(register);
+        }
+
+        match self.find(register_type, name) {
+            Some(def) => Ok(def.register),
+            None => bail!("unknown {:?} register {}", register_type, name),
+        }
+    }
+
+    /// Named, scaled values for whichever of these registers we know about.
+    pub fn decode(&self, register_type: RegisterType, pairs: &[(u16, u16)]) -> serde_json::Map<String, Value> {
+        let mut r = serde_json::Map::new();
+
+        for (register, value) in pairs {
+            if let Some(def) = self.get(register_type, *register) {
+                r.extend(def.decode(*value));
+            }
+        }
+
+        r
+    }
+
+    /// Registers we don't know about can still be written; those we do are
+    /// checked against read_only and min/max.
+    pub fn validate_write(&self, register: u16, value: u16) -> Result<()> {
+        match self.get(RegisterType::Hold, register) {
+            Some(def) => def.validate(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Raw hold register values from a set/holds payload, a JSON object of
+    /// register numbers to raw values, or shortnames to values in their units.
+    pub fn encode_writes(&self, payload: &str) -> Result<BTreeMap<u16, u16>> {
+        let writes: serde_json::Map<String, Value> =
+            serde_json::from_str(payload).map_err(|err| anyhow!("register_map.rs:set/holds payload: {}", err))?;
+
+        let mut r = BTreeMap::new();
+        for (key, value) in &writes {
+            // strings as given, anything else as JSON prints it
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+
+            let (register, raw) = match key.parse::<u16>() {
+                Ok(register) => {
+                    let raw = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("register {}: {:?} is not a raw register value", register, value))?;
+                    self.validate_write(register, raw)?;
+                    (register, raw)
+                }
+                Err(_) => {
+                    let def = self
+                        .find(RegisterType::Hold, key)
+                        .ok_or_else(|| anyhow!("unknown setting {}", key))?;
+                    (def.register, def.encode(&value)?)
+                }
+            };
+
+            if r.insert(register, raw).is_some() {
+                bail!("register {} is given more than once", register);
+            }
+        }
+
+        if r.is_empty() {
+            bail!("set/holds payload has nothing to write");
+        }
+
+        Ok(r)
+    }
+} // }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let map = RegisterMap::builtin();
+
+        let def = map.find(RegisterType::Hold, "charge_power_percent_cmd").unwrap();
+        assert_eq!(def.register, 64);
+        assert_eq!(def.unit_symbol(), Some("%"));
+        assert!(map.validate_write(64, 100).is_ok());
+        assert!(map.validate_write(64, 101).is_err());
+        // firmware code
+        assert!(map.validate_write(7, 1).is_err());
+        // 23:30 is fine, 24:00 isn't
+        assert!(map.validate_write(68, 0x1E17).is_ok());
+        assert!(map.validate_write(68, 0x0018).is_err());
+        // unknown registers are left alone
+        assert!(map.validate_write(1000, 1).is_ok());
+
+        let decoded = map.decode(RegisterType::Hold, &[(26, 2530), (68, 0x1E17), (119, (-20i16) as u16)]);
+        assert_eq!(decoded["grid_volt_conn_high"], json!(253.0));
+        assert_eq!(decoded["ac_charge_start_time_0"], json!("23:30"));
+        assert_eq!(decoded["ct_power_offset"], json!(-20));
+        let decoded = map.decode(RegisterType::Hold, &[(21, 1 << 7 | 1)]);
+        assert_eq!(decoded["function_enable"]["ac_charge_en"], json!("ON"));
+        assert_eq!(decoded["function_enable"]["eps_en"], json!("ON"));
+        assert_eq!(decoded["function_enable"]["forced_chg_en"], json!("OFF"));
+
+        // SOC and SOH share input 5
+        let decoded = map.decode(RegisterType::Input, &[(4, 532), (5, 0x6450)]);
+        assert_eq!(decoded["vbat"], json!(53.2));
+        assert_eq!(decoded["SOC"], json!(80));
+        assert_eq!(decoded["SOH"], json!(100));
+
+        assert_eq!(map.register_number(RegisterType::Hold, "21").unwrap(), 21);
+        assert_eq!(map.register_number(RegisterType::Hold, "function_enable").unwrap(), 21);
+        assert!(map.register_number(RegisterType::Hold, "nonsense").is_err());
+
+        let (def, flag) = map.find_flag(RegisterType::Hold, "eps_en").unwrap();
+        assert_eq!((def.register, flag.bit), (21, 0));
+        let (def, flag) = map.find_flag(RegisterType::Hold, "ub_micro_grid_en").unwrap();
+        assert_eq!((def.register, flag.bit), (110, 2));
+        assert!(map.find_flag(RegisterType::Hold, "nonsense").is_none());
+    }
+
+    #[test]
+    fn test_encode() {
+        let map = RegisterMap::builtin();
+        let hold = |name| map.find(RegisterType::Hold, name).unwrap();
+
+        assert_eq!(hold("start_pv_volt").encode("140.5").unwrap(), 1405);
+        assert_eq!(hold("ac_charge_start_time_0").encode("23:30").unwrap(), 0x1E17);
+        assert_eq!(hold("ct_power_offset").encode("-20").unwrap(), (-20i16) as u16);
+        assert_eq!(hold("charge_power_percent_cmd").encode(" 50 ").unwrap(), 50);
+
+        let err = |name, value| hold(name).encode(value).unwrap_err().to_string();
+        assert_eq!(
+            err("start_pv_volt", "600"),
+            "register 22 (start_pv_volt): 600 V is outside 90..500 V"
+        );
+        assert_eq!(err("start_pv_volt", "140.55"), "start_pv_volt: 140.55 is not a multiple of 0.1");
+        assert_eq!(err("firmware_code", "1"), "register 7 (firmware_code) is read-only");
+        assert_eq!(err("ac_charge_start_time_0", "24:00"), "ac_charge_start_time_0: \"24:00\" is not a valid time");
+        assert!(hold("charge_power_percent_cmd").encode("lots").is_err());
+        assert!(hold("charge_power_percent_cmd").encode("-1").is_err());
+    }
+
+    #[test]
+    fn test_encode_writes() {
+        let map = RegisterMap::builtin();
+
+        let writes = map
+            .encode_writes(r#"{"ac_charge_start_time_0":"23:30","69":"6","ac_charge_soc_limit":90}"#)
+            .unwrap();
+        assert_eq!(writes, BTreeMap::from([(67, 90), (68, 0x1E17), (69, 6)]));
+
+        let err = |payload| map.encode_writes(payload).unwrap_err().to_string();
+        assert_eq!(err(r#"{"67":90,"ac_charge_soc_limit":80}"#), "register 67 is given more than once");
+        assert_eq!(err(r#"{"nonsense":1}"#), "unknown setting nonsense");
+        assert_eq!(err(r#"{"64":"lots"}"#), "register 64: \"lots\" is not a raw register value");
+        assert_eq!(err("{}"), "set/holds payload has nothing to write");
+        assert!(map.encode_writes("[1,2]").is_err());
+        assert!(map.encode_writes(r#"{"64":101}"#).is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut map = RegisterMap::builtin();
+        let overrides = RegisterMap::from_json(
+            r#"{"registers": [{"register_type": "hold", "register_map": [
+                {"register_number": 64, "shortname": "charge_rate", "datatype": "float",
+                 "unit": "percent", "unit_scale": 0.1, "min_value": 0, "max_value": 1000,
+                 "read_only": false},
+                {"register_number": 250, "shortname": "new_setting", "read_only": "false"}
+            ]}]}"#,
+        )
+        .unwrap();
+        map.merge(overrides);
+
+        assert!(map.find(RegisterType::Hold, "charge_power_percent_cmd").is_none());
+        assert_eq!(map.register_number(RegisterType::Hold, "charge_rate").unwrap(), 64);
+        assert!(map.validate_write(64, 1000).is_ok());
+        assert_eq!(map.decode(RegisterType::Hold, &[(64, 505)])["charge_rate"], json!(50.5));
+        assert_eq!(map.register_number(RegisterType::Hold, "new_setting").unwrap(), 250);
+        // everything else is still there
+        assert_eq!(map.register_number(RegisterType::Hold, "ac_charge_soc_limit").unwrap(), 67);
+
+        // the published file loads, with the 9-bit parallelnum it gives register 113
+        // cut to the 8 bits that fit
+        let published = RegisterMap::from_json(include_str!("../doc/eg4_registers.json")).unwrap();
+        assert_eq!(published.register_number(RegisterType::Hold, "vpv1").unwrap(), 1);
+        let parallelnum = published.get(RegisterType::Hold, 113).unwrap();
+        let parallelnum = parallelnum.fields.iter().find(|f| f.shortname == "parallelnum").unwrap();
+        assert_eq!((parallelnum.location, parallelnum.size), (8, 8));
+
+        // fields outside the register are skipped rather than failing the map
+        let map = RegisterMap::from_json(r#"{"registers": [{"register_type": "hold", "register_map": [
+            {"register_number": 1, "value_map": [
+                {"shortname": "a", "value_unit": "bit", "value_size": 4, "value_location": 0},
+                {"shortname": "b", "value_unit": "byte", "value_size": 1, "value_location": 2}]}]}]}"#)
+        .unwrap();
+        let fields: Vec<_> = map.get(RegisterType::Hold, 1).unwrap().fields.iter().map(|f| &f.shortname).collect();
+        assert_eq!(fields, ["a"]);
+
+        assert!(RegisterMap::from_json(r#"{"registers": [{"register_type": "hold", "register_map": [
+            {"register_number": 1, "shortname": "x", "datatype": "complex"}]}]}"#)
+        .is_err());
+        assert!(RegisterMap::from_json(r#"{"registers": [{"register_type": "hold", "register_map": [
+            {"register_number": 1, "shortname": "x", "flags": [{"bit": 16, "name": "y"}]}]}]}"#)
+        .is_err());
+    }
+}
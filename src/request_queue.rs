@@ -0,0 +1,638 @@
+use crate::prelude::*;
+use crate::eg4::inverter::WaitForReply;
+use crate::eg4::packet::{DeviceFunction, ModbusException};
+use crate::metrics::Metrics;
+
+use {
+    serde::Serialize,
+    std::cmp::Ordering,
+    std::collections::{BTreeMap, BinaryHeap, HashMap},
+    std::sync::{Arc, Mutex},
+    std::time::Duration,
+    tokio::sync::Notify,
+    tokio::time::Instant,
+};
+
+// requests waiting for one inverter; past this it isn't answering, and
+// taking more would only pile them up
+const MAX_DEPTH: usize = 256;
+// longest we wait between retries, however big retry_backoff_ms is
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Which request goes next when several are waiting for the same inverter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    // polling, reads on connect and scheduled jobs
+    Background,
+    // reads asked for over MQTT, HTTP or by proxy/Modbus clients
+    Read,
+    // anything that changes the inverter
+    Write,
+}
+
+type Reply = Arc<Mutex<Option<oneshot::Sender<Result<Packet>>>>>;
+type ReplyStats = Arc<Mutex<Option<oneshot::Sender<BTreeMap<String, QueueStats>>>>>;
+
+#[derive(Clone, Debug)]
+pub enum ChannelData {
+    Request(Packet, Priority, Reply),
+    // a hold register read, then written back with the mask bits set (true)
+    // or cleared (false); resolves to the write's reply
+    UpdateHold(Packet, u16, bool, Priority, Reply),
+    Stats(ReplyStats),
+    Shutdown,
+}
+
+/// How one inverter's queue is doing, for /api/stats and /metrics.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct QueueStats {
+    // waiting to be sent, not counting the one in flight
+    pub depth: usize,
+    pub in_flight: bool,
+    pub completed: u64,
+    pub failed: u64,
+    pub retries: u64,
+    // from being queued to the reply, so including time spent waiting
+    pub last_latency_ms: u64,
+    pub max_latency_ms: u64,
+    pub total_latency_ms: u64,
+}
+
+impl QueueStats {
+    pub fn write_metrics(&self, datalog: &str, metrics: &mut Metrics) {
+        let labels = [("datalog", datalog)];
+        metrics.gauge("request_queue_depth", "Requests waiting to be sent to the inverter", &labels, self.depth as f64);
+        metrics.counter("requests_completed", "Requests the inverter replied to", &labels, self.completed as f64);
+        metrics.counter("requests_failed", "Requests that failed after any retries", &labels, self.failed as f64);
+        metrics.counter("request_retries", "Requests sent again after a timeout", &labels, self.retries as f64);
+        metrics.gauge(
+            "request_latency_last_seconds",
+            "Time from queueing the last request to its reply",
+            &labels,
+            self.last_latency_ms as f64 / 1000.0,
+        );
+        metrics.counter(
+            "request_latency_seconds",
+            "Time from queueing to reply, summed over all requests",
+            &labels,
+            self.total_latency_ms as f64 / 1000.0,
+        );
+    }
+}
+
+// what the worker does for one queued item; an UpdateHold's read and write
+// go out back to back, so nothing else can change the register in between
+#[derive(Clone, Debug)]
+enum Job {
+    Request(Packet),
+    UpdateHold(Packet, u16, bool),
+}
+
+impl Job {
+    fn packet(&self) -> &Packet {
+        match self {
+            Self::Request(packet) | Self::UpdateHold(packet, _, _) => packet,
+        }
+    }
+}
+
+struct Queued {
+    priority: Priority,
+    // first come first served within a priority
+    seq: u64,
+    job: Job,
+    queued_at: Instant,
+    reply: Reply,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Queued {}
+
+#[derive(Default)]
+struct Queue {
+    pending: Mutex<BinaryHeap<Queued>>,
+    notify: Notify,
+    stats: Mutex<QueueStats>,
+}
+
+/// Sends requests to each inverter one at a time, so replies can't be
+/// confused or lost when MQTT, the scheduler and polling all want something
+/// at once. Timeouts, retries and the gap between requests come from the
+/// inverter's config.
+#[derive(Clone)]
+pub struct RequestQueue {
+    config: ConfigWrapper,
+    channels: Channels,
+    queues: Arc<Mutex<HashMap<Serial, Arc<Queue>>>>,
+    seq: Arc<Mutex<u64>>,
+}
+
+impl RequestQueue {
+    pub fn new(config: ConfigWrapper, channels: Channels) -> Self {
+        Self {
+            config,
+            channels,
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            seq: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    // external helper, use like so:
+    //
+    //   RequestQueue::request(&self.channels, packet, Priority::Read).await?
+    //
+    // resolves to the inverter's reply once it's been sent and answered.
+    pub async fn request(channels: &Channels, packet: Packet, priority: Priority) -> Result<Packet> {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        if channels
+            .to_request_queue
+            .send(ChannelData::Request(packet, priority, tx))
+            .is_err()
+        {
+            bail!("send(to_request_queue) failed - request queue not running?");
+        }
+        rx.await
+            .map_err(|_| anyhow!("request_queue.rs:request dropped without a reply"))?
+    }
+
+    // reads the hold register in `read`, then writes it back with `mask` set
+    // or cleared, as one item so no other request can get in between:
+    //
+    //   RequestQueue::update_hold(&self.channels, read, mask, true, Priority::Write).await?
+    //
+    // resolves to the inverter's reply to the write.
+    pub async fn update_hold(
+        channels: &Channels,
+        read: Packet,
+        mask: u16,
+        enable: bool,
+        priority: Priority,
+    ) -> Result<Packet> {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        if channels
+            .to_request_queue
+            .send(ChannelData::UpdateHold(read, mask, enable, priority, tx))
+            .is_err()
+        {
+            bail!("send(to_request_queue) failed - request queue not running?");
+        }
+        rx.await
+            .map_err(|_| anyhow!("request_queue.rs:update_hold dropped without a reply"))?
+    }
+
+    pub async fn stats(channels: &Channels) -> BTreeMap<String, QueueStats> {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        if channels.to_request_queue.send(ChannelData::Stats(tx)).is_err() {
+            return BTreeMap::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let mut receiver = self.channels.to_request_queue.subscribe();
+
+        debug!("request_queue starting");
+
+        loop {
+            match receiver.recv().await? {
+                ChannelData::Request(packet, priority, reply) => {
+                    self.enqueue(Job::Request(packet), priority, reply)
+                }
+                ChannelData::UpdateHold(packet, mask, enable, priority, reply) => {
+                    self.enqueue(Job::UpdateHold(packet, mask, enable), priority, reply)
+                }
+                ChannelData::Stats(reply) => {
+                    if let Some(reply) = reply.lock().unwrap().take() {
+                        let _ = reply.send(self.snapshot());
+                    }
+                }
+                ChannelData::Shutdown => break,
+            }
+        }
+
+        info!("request_queue exiting");
+        Ok(())
+    }
+
+    fn enqueue(&self, job: Job, priority: Priority, reply: Reply) {
+        let queue = self.queue(job.packet().datalog());
+
+        let seq = {
+            let mut seq = self.seq.lock().unwrap();
+            *seq += 1;
+            *seq
+        };
+
+        let mut pending = queue.pending.lock().unwrap();
+        if pending.len() >= MAX_DEPTH {
+            queue.stats.lock().unwrap().failed += 1;
+            if let Some(reply) = reply.lock().unwrap().take() {
+                let _ = reply.send(Err(anyhow!(
+                    "request_queue.rs:{} requests already waiting for {}",
+                    pending.len(),
+                    job.packet().datalog()
+                )));
+            }
+            return;
+        }
+        pending.push(Queued {
+            priority,
+            seq,
+            job,
+            queued_at: Instant::now(),
+            reply,
+        });
+        queue.stats.lock().unwrap().depth = pending.len();
+        queue.notify.notify_one();
+    }
+
+    // each inverter gets its own queue and worker the first time it's used
+    fn queue(&self, datalog: Serial) -> Arc<Queue> {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get(&datalog) {
+            return queue.clone();
+        }
+
+        let queue = Arc::new(Queue::default());
+        queues.insert(datalog, queue.clone());
+        tokio::spawn(self.clone().worker(datalog, queue.clone()));
+        queue
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, QueueStats> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(datalog, queue)| (datalog.to_string(), queue.stats.lock().unwrap().clone()))
+            .collect()
+    }
+
+    async fn worker(self, datalog: Serial, queue: Arc<Queue>) {
+        let mut last_reply: Option<Instant> = None;
+
+        loop {
+            let request = loop {
+                if let Some(request) = queue.pending.lock().unwrap().pop() {
+                    break request;
+                }
+                queue.notify.notified().await;
+            };
+
+            {
+                let mut stats = queue.stats.lock().unwrap();
+                stats.depth = queue.pending.lock().unwrap().len();
+                stats.in_flight = true;
+            }
+
+            let result = match self.config.enabled_inverter_with_datalog(datalog) {
+                Some(inverter) => {
+                    // give the inverter delay_ms to itself between requests
+                    let delay = Duration::from_millis(inverter.delay_ms());
+                    if let Some(elapsed) = last_reply.map(|last| last.elapsed()) {
+                        if elapsed < delay {
+                            tokio::time::sleep(delay - elapsed).await;
+                        }
+                    }
+
+                    let result = self.run(&inverter, &request.job, &queue).await;
+                    last_reply = Some(Instant::now());
+                    result
+                }
+                None => Err(anyhow!("request_queue.rs:no enabled inverter with datalog {}", datalog)),
+            };
+
+            {
+                let latency = request.queued_at.elapsed().as_millis() as u64;
+                let mut stats = queue.stats.lock().unwrap();
+                stats.in_flight = false;
+                if result.is_ok() {
+                    stats.completed += 1;
+                } else {
+                    stats.failed += 1;
+                }
+                stats.last_latency_ms = latency;
+                stats.max_latency_ms = stats.max_latency_ms.max(latency);
+                stats.total_latency_ms += latency;
+            }
+
+            let reply = request.reply.lock().unwrap().take();
+            if let Some(reply) = reply {
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    async fn run(&self, inverter: &config::Inverter, job: &Job, queue: &Queue) -> Result<Packet> {
+        let (read, mask, enable) = match job {
+            Job::Request(packet) => return self.send(inverter, packet, queue).await,
+            Job::UpdateHold(read, mask, enable) => (read, *mask, *enable),
+        };
+
+        let current = self.send(inverter, read, queue).await?.value();
+        let value = if enable { current | mask } else { current & !mask };
+
+        let mut write = match read {
+            Packet::TranslatedData(td) => td.clone(),
+            _ => bail!("request_queue.rs:update_hold needs a TranslatedData read"),
+        };
+        write.device_function = DeviceFunction::WriteSingle;
+        write.values = value.to_le_bytes().to_vec();
+
+        tokio::time::sleep(Duration::from_millis(inverter.delay_ms())).await;
+        self.send(inverter, &Packet::TranslatedData(write), queue).await
+    }
+
+    async fn send(&self, inverter: &config::Inverter, packet: &Packet, queue: &Queue) -> Result<Packet> {
+        let mut attempt = 0;
+
+        loop {
+            // subscribe before sending, so a quick reply can't be missed
+            let mut receiver = self.channels.from_inverter.subscribe();
+
+            if self
+                .channels
+                .to_inverter
+                .send(eg4::inverter::ChannelData::Packet(packet.clone()))
+                .is_err()
+            {
+                bail!("send(to_inverter) failed - channel closed?");
+            }
+
+            let err = match receiver
+                .wait_for_reply_within(packet, Duration::from_secs(inverter.request_timeout()))
+                .await
+            {
+                Ok(reply) => return Ok(reply),
+                Err(err) => err,
+            };
+
+            if attempt >= inverter.request_retries() || err.is::<ModbusException>() {
+                return Err(err);
+            }
+
+            let backoff = backoff(inverter.retry_backoff_ms(), attempt);
+            warn!(
+                "inverter {}: {}, retrying in {:?}",
+                packet.datalog(),
+                err,
+                backoff
+            );
+            queue.stats.lock().unwrap().retries += 1;
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+// retry_backoff_ms, doubled for each attempt so far
+fn backoff(retry_backoff_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(retry_backoff_ms)
+        .checked_mul(2u32.saturating_pow(attempt))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eg4::packet::TranslatedData;
+
+    fn queued(priority: Priority, seq: u64) -> Queued {
+        Queued {
+            priority,
+            seq,
+            job: Job::Request(Packet::Heartbeat(eg4::packet::Heartbeat {
+                datalog: Serial::default(),
+            })),
+            queued_at: Instant::now(),
+            reply: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        let mut heap = BinaryHeap::new();
+        heap.push(queued(Priority::Background, 1));
+        heap.push(queued(Priority::Read, 2));
+        heap.push(queued(Priority::Background, 3));
+        heap.push(queued(Priority::Write, 4));
+        heap.push(queued(Priority::Read, 5));
+
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop()).map(|q| (q.priority, q.seq)).collect();
+        assert_eq!(
+            order,
+            vec![
+                (Priority::Write, 4),
+                (Priority::Read, 2),
+                (Priority::Read, 5),
+                (Priority::Background, 1),
+                (Priority::Background, 3),
+            ]
+        );
+    }
+
+    // config.yaml.example's first inverter, with quick timeouts
+    fn config(delay_ms: u64) -> (ConfigWrapper, config::Inverter) {
+        let mut config = Config::new("config.yaml.example".to_string()).unwrap();
+        let inverter = &mut config.inverters[0];
+        inverter.delay_ms = Some(delay_ms);
+        inverter.request_timeout = Some(1);
+        inverter.request_retries = Some(2);
+        inverter.retry_backoff_ms = Some(100);
+
+        let config = ConfigWrapper::from_config(config);
+        let inverter = config.inverters()[0].clone();
+        (config, inverter)
+    }
+
+    async fn start(config: &ConfigWrapper) -> Channels {
+        let channels = Channels::new();
+        let queue = RequestQueue::new(config.clone(), channels.clone());
+        tokio::spawn(async move { queue.start().await });
+        // let it subscribe
+        tokio::task::yield_now().await;
+        channels
+    }
+
+    type Sent = Arc<Mutex<Vec<(Instant, TranslatedData)>>>;
+
+    // answers what the inverter is sent with whatever `answer` makes of it,
+    // or not at all for None, noting what was sent when
+    fn fake_inverter<F>(channels: &Channels, mut answer: F) -> Sent
+    where
+        F: FnMut(&TranslatedData) -> Option<TranslatedData> + Send + 'static,
+    {
+        let mut receiver = channels.to_inverter.subscribe();
+        let channels = channels.clone();
+        let sent = Sent::default();
+
+        tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                while let Ok(eg4::inverter::ChannelData::Packet(Packet::TranslatedData(td))) = receiver.recv().await {
+                    sent.lock().unwrap().push((Instant::now(), td.clone()));
+                    if let Some(reply) = answer(&td) {
+                        let reply = eg4::inverter::ChannelData::Packet(Packet::TranslatedData(reply));
+                        let _ = channels.from_inverter.send(reply);
+                    }
+                }
+            }
+        });
+
+        sent
+    }
+
+    fn read_hold(inverter: &config::Inverter, register: u16) -> Packet {
+        Packet::TranslatedData(TranslatedData {
+            datalog: inverter.datalog().unwrap(),
+            device_function: DeviceFunction::ReadHold,
+            inverter: inverter.serial().unwrap(),
+            register,
+            values: vec![1, 0],
+        })
+    }
+
+    async fn stats(channels: &Channels, inverter: &config::Inverter) -> QueueStats {
+        RequestQueue::stats(channels).await[&inverter.datalog().unwrap().to_string()].clone()
+    }
+
+    fn gaps(sent: &Sent) -> Vec<Duration> {
+        let sent = sent.lock().unwrap();
+        sent.windows(2).map(|pair| pair[1].0 - pair[0].0).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_after_timeout() {
+        let (config, inverter) = config(0);
+        let channels = start(&config).await;
+        let mut attempts = 0;
+        let sent = fake_inverter(&channels, move |td| {
+            attempts += 1;
+            (attempts > 1).then(|| td.clone())
+        });
+
+        assert!(RequestQueue::request(&channels, read_hold(&inverter, 0), Priority::Read).await.is_ok());
+
+        // sent again after the 1s timeout and 100ms backoff
+        let gaps = gaps(&sent);
+        assert_eq!(gaps.len(), 1);
+        assert!(gaps[0] >= Duration::from_millis(1100));
+        let stats = stats(&channels, &inverter).await;
+        assert_eq!((stats.completed, stats.failed, stats.retries), (1, 0, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_run_out() {
+        let (config, inverter) = config(0);
+        let channels = start(&config).await;
+        let sent = fake_inverter(&channels, |_| None);
+
+        assert!(RequestQueue::request(&channels, read_hold(&inverter, 0), Priority::Read).await.is_err());
+
+        // request_retries of 2, with the backoff doubling each time
+        let gaps = gaps(&sent);
+        assert_eq!(gaps.len(), 2);
+        assert!(gaps[0] >= Duration::from_millis(1100) && gaps[0] < Duration::from_millis(1200));
+        assert!(gaps[1] >= Duration::from_millis(1200));
+        let stats = stats(&channels, &inverter).await;
+        assert_eq!((stats.completed, stats.failed, stats.retries), (0, 1, 2));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(100, 0), Duration::from_millis(100));
+        assert_eq!(backoff(100, 3), Duration::from_millis(800));
+        // capped rather than overflowing the Duration
+        assert_eq!(backoff(u64::MAX, 10), MAX_BACKOFF);
+        assert_eq!(backoff(1000, 31), MAX_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retry_on_exception() {
+        let (config, inverter) = config(0);
+        let channels = start(&config).await;
+        let sent = fake_inverter(&channels, |td| {
+            let mut reply = td.clone();
+            reply.device_function = td.device_function.error();
+            reply.values = vec![eg4::packet::ModbusError::IllegalAddress as u8, 0];
+            Some(reply)
+        });
+
+        let err = RequestQueue::request(&channels, read_hold(&inverter, 0), Priority::Read).await.unwrap_err();
+        assert!(err.is::<ModbusException>());
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_update_hold() {
+        let (config, inverter) = config(500);
+        let channels = start(&config).await;
+        let sent = fake_inverter(&channels, |td| {
+            let mut reply = td.clone();
+            if td.device_function == DeviceFunction::ReadHold && td.register == 21 {
+                reply.values = 0b1010u16.to_le_bytes().to_vec();
+            }
+            Some(reply)
+        });
+
+        for (mask, enable, wanted) in [(0b0001, true, 0b1011), (0b0010, false, 0b1000)] {
+            sent.lock().unwrap().clear();
+
+            // the poll is queued first, but can't get between the read and the write
+            let (poll, update) = tokio::join!(
+                RequestQueue::request(&channels, read_hold(&inverter, 40), Priority::Background),
+                RequestQueue::update_hold(&channels, read_hold(&inverter, 21), mask, enable, Priority::Write),
+            );
+            assert!(poll.is_ok());
+            assert_eq!(update.unwrap().value(), wanted);
+
+            let order: Vec<_> = sent.lock().unwrap().iter().map(|(_, td)| (td.device_function, td.register)).collect();
+            assert_eq!(
+                order,
+                vec![
+                    (DeviceFunction::ReadHold, 21),
+                    (DeviceFunction::WriteSingle, 21),
+                    (DeviceFunction::ReadHold, 40),
+                ]
+            );
+            // delay_ms between each
+            assert!(gaps(&sent).iter().all(|gap| *gap >= Duration::from_millis(500)));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_depth() {
+        let (config, inverter) = config(0);
+        let channels = start(&config).await;
+        fake_inverter(&channels, |_| None);
+
+        for _ in 0..=MAX_DEPTH {
+            let request = ChannelData::Request(read_hold(&inverter, 0), Priority::Background, Reply::default());
+            channels.to_request_queue.send(request).unwrap();
+        }
+
+        // the inverter isn't answering, so there's no room for more
+        let err = RequestQueue::request(&channels, read_hold(&inverter, 0), Priority::Write).await.unwrap_err();
+        assert!(err.to_string().contains("requests already waiting"));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::prelude::*;
 
+use crate::config::JobAction;
+use crate::coordinator::commands::timesync::TimeSync;
 
 pub struct Scheduler {
     config: ConfigWrapper,
@@ -12,16 +14,91 @@ impl Scheduler {
     }
 
     pub async fn start(&self) -> Result<()> {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        let jobs = match self.config.scheduler() {
+            Some(scheduler) if scheduler.enabled() => scheduler.jobs(),
+            _ => Vec::new(),
+        };
 
+        if jobs.is_empty() {
+            info!("scheduler has no jobs, skipping");
+            // app() treats us finishing as the end of the program, so stay
+            // around even if there's nothing to do.
+            futures::future::pending::<()>().await;
+        }
+
+        info!("scheduler starting with {} jobs", jobs.len());
+
+        let futures = jobs.into_iter().map(|job| self.job_loop(job));
+        futures::future::try_join_all(futures).await?;
+
+        Ok(())
+    }
+
+    async fn job_loop(&self, job: config::Job) -> Result<()> {
         loop {
-            interval.tick().await;
+            let now = Utils::localtime();
+            let next = cron_parser::parse(job.cron(), &now)
+                .map_err(|err| anyhow!("scheduler: bad cron expression {}: {:?}", job.cron(), err))?;
 
-            for inverter in self.config.enabled_inverters() {
-                crate::coordinator::commands::timesync::TimeSync::new(self.channels.clone(), inverter)
-                    .run()
-                    .await?;
+            debug!("scheduler: next {:?} job at {}", job.action(), next);
+
+            // to_std() only fails for negative durations, which would mean run now
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            for inverter in self.inverters_for_job(&job) {
+                if let Err(err) = self.run_job(&job, inverter.clone()).await {
+                    warn!(
+                        "scheduler: {:?} job for {} failed: {}",
+                        job.action(),
+                        inverter.datalog().map(|s| s.to_string()).unwrap_or_default(),
+                        err
+                    );
+                }
             }
         }
     }
+
+    fn inverters_for_job(&self, job: &config::Job) -> Vec<config::Inverter> {
+        match job.datalog() {
+            Some(datalog) => self.config.enabled_inverter_with_datalog(datalog).into_iter().collect(),
+            None => self.config.enabled_inverters(),
+        }
+    }
+
+    async fn run_job(&self, job: &config::Job, inverter: config::Inverter) -> Result<()> {
+        match job.action() {
+            JobAction::Timesync => TimeSync::new(self.channels.clone(), inverter).run().await,
+            JobAction::ReadInputs => {
+                for command in Command::read_all_inputs(&inverter) {
+                    self.send_command(command)?;
+                }
+                Ok(())
+            }
+            JobAction::ReadHold => {
+                for command in Command::read_all_holds(&inverter) {
+                    self.send_command(command)?;
+                }
+                Ok(())
+            }
+            JobAction::Command => {
+                let message = job.to_message(&inverter)?;
+                self.send_command(message.to_command(inverter, &self.config.register_map())?)
+            }
+        }
+    }
+
+    // commands are run by the coordinator, one at a time, the same as those from MQTT
+    fn send_command(&self, command: Command) -> Result<()> {
+        if self
+            .channels
+            .to_coordinator
+            .send(coordinator::ChannelData::Command(Box::new(command)))
+            .is_err()
+        {
+            bail!("send(to_coordinator) failed - channel closed?");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,416 @@
+use crate::prelude::*;
+use crate::eg4::packet::{DeviceFunction, ModbusError, ReadParam, TcpFrameFactory, TranslatedData, WriteParam};
+use crate::eg4::packet_decoder::PacketDecoder;
+
+use {
+    bytes::BytesMut,
+    std::collections::HashMap,
+    std::io::BufRead,
+    std::sync::{Arc, Mutex},
+    std::time::Duration,
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+    tokio_util::codec::Decoder,
+};
+
+// input blocks pushed unprompted, as the dongle does every few minutes
+const PUSH_REGISTERS: [u16; 3] = [0, 40, 80];
+const PUSH_COUNT: u16 = 40;
+const MAX_BUFFER_SIZE: usize = 65536;
+// writes at or above this are refused, as a real inverter does
+const HOLD_REGISTERS: u16 = 256;
+
+#[derive(Default)]
+struct Registers {
+    holds: HashMap<u16, u16>,
+    inputs: HashMap<u16, u16>,
+    params: HashMap<u16, u16>,
+}
+
+/// A fake inverter and datalog dongle, answering requests from an in-memory
+/// register map. Used by the eg4-simulator binary for offline development.
+#[derive(Clone)]
+pub struct Simulator {
+    datalog: Serial,
+    serial: Serial,
+    registers: Arc<Mutex<Registers>>,
+    clients: broadcast::Sender<Packet>,
+}
+
+impl Simulator {
+    pub fn new(datalog: Serial, serial: Serial) -> Self {
+        Self {
+            datalog,
+            serial,
+            registers: Arc::new(Mutex::new(Registers::default())),
+            clients: broadcast::channel(128).0,
+        }
+    }
+
+    pub fn datalog(&self) -> Serial {
+        self.datalog
+    }
+
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    pub fn set_hold(&self, register: u16, value: u16) {
+        self.registers.lock().unwrap().holds.insert(register, value);
+    }
+
+    pub fn hold(&self, register: u16) -> Option<u16> {
+        self.registers.lock().unwrap().holds.get(&register).copied()
+    }
+
+    pub fn set_input(&self, register: u16, value: u16) {
+        self.registers.lock().unwrap().inputs.insert(register, value);
+    }
+
+    pub fn input(&self, register: u16) -> Option<u16> {
+        self.registers.lock().unwrap().inputs.get(&register).copied()
+    }
+
+    /// Seeds registers from a JSON lines capture, as written by `datalog_file`
+    /// (doc/datalog.json is one). Returns how many lines were loaded.
+    pub fn load_datalog_file(&self, path: &str) -> Result<usize> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow!("simulator.rs:error reading {}: {}", path, err))?;
+
+        let mut loaded = 0;
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.load_datalog_line(&line)?;
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+
+    fn load_datalog_line(&self, line: &str) -> Result<()> {
+        let json: serde_json::Value = serde_json::from_str(line)?;
+
+        // decoded inputs, the same registers are in the "input" lines too
+        if json["register_type"] == "input_all" {
+            return Ok(());
+        }
+
+        let raw_data = json["raw_data"]
+            .as_object()
+            .ok_or_else(|| anyhow!("simulator.rs:datalog line has no raw_data"))?;
+
+        let mut registers = self.registers.lock().unwrap();
+        let map = match json["register_type"].as_str() {
+            Some("hold") => &mut registers.holds,
+            Some("input") => &mut registers.inputs,
+            other => bail!("simulator.rs:unknown register_type {:?}", other),
+        };
+
+        for (register, value) in raw_data {
+            let register = register.parse::<u16>()?;
+            let value = value
+                .as_str()
+                .and_then(|v| u16::from_str_radix(v.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| anyhow!("simulator.rs:bad value for register {}", register))?;
+            map.insert(register, value);
+        }
+
+        Ok(())
+    }
+
+    pub async fn start(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        futures::try_join!(self.acceptor(listener), self.pusher())?;
+
+        Ok(())
+    }
+
+    async fn acceptor(&self, listener: tokio::net::TcpListener) -> Result<()> {
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            info!("simulator: connection from {}", peer);
+
+            let simulator = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = simulator.handle_connection(socket).await {
+                    info!("simulator: {} disconnected: {}", peer, err);
+                }
+            });
+        }
+    }
+
+    // sends input blocks to every client every minute, like a real dongle
+    async fn pusher(&self) -> Result<()> {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+
+            for register in PUSH_REGISTERS {
+                let _ = self.clients.send(self.read_reply(DeviceFunction::ReadInput, register, PUSH_COUNT));
+            }
+        }
+    }
+
+    async fn handle_connection(&self, socket: tokio::net::TcpStream) -> Result<()> {
+        let (mut reader, mut writer) = socket.into_split();
+        let mut buf = BytesMut::with_capacity(MAX_BUFFER_SIZE);
+        let mut decoder = PacketDecoder::new_for_requests();
+        let mut pushes = self.clients.subscribe();
+
+        loop {
+            if buf.len() >= MAX_BUFFER_SIZE {
+                bail!("buffer overflow");
+            }
+
+            tokio::select! {
+                push = pushes.recv() => {
+                    if let Ok(packet) = push {
+                        writer.write_all(&TcpFrameFactory::build_reply(&packet)).await?;
+                    }
+                }
+                len = reader.read_buf(&mut buf) => {
+                    if len? == 0 {
+                        bail!("connection closed");
+                    }
+
+                    while let Some(packet) = decoder.decode(&mut buf)? {
+                        debug!("simulator: RX {:?}", packet);
+                        if let Some(reply) = self.handle(packet) {
+                            debug!("simulator: TX {:?}", reply);
+                            writer.write_all(&TcpFrameFactory::build_reply(&reply)).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a request to the register map, returning the inverter's reply.
+    pub fn handle(&self, packet: Packet) -> Option<Packet> {
+        match packet {
+            // we never send heartbeats, so anything arriving is unprompted
+            Packet::Heartbeat(_) => None,
+            Packet::TranslatedData(td) => Some(self.handle_translated_data(td)),
+            Packet::ReadParam(rp) => {
+                let value = self.registers.lock().unwrap().params.get(&rp.register).copied();
+                Some(Packet::ReadParam(ReadParam {
+                    datalog: self.datalog,
+                    register: rp.register,
+                    values: value.unwrap_or_default().to_le_bytes().to_vec(),
+                }))
+            }
+            Packet::WriteParam(wp) => {
+                let value = Utils::u16ify(&wp.values, 0);
+                self.registers.lock().unwrap().params.insert(wp.register, value);
+                Some(Packet::WriteParam(WriteParam {
+                    datalog: self.datalog,
+                    register: wp.register,
+                    values: value.to_le_bytes().to_vec(),
+                }))
+            }
+        }
+    }
+
+    fn handle_translated_data(&self, td: TranslatedData) -> Packet {
+        match td.device_function {
+            DeviceFunction::ReadHold | DeviceFunction::ReadInput => {
+                let count = Utils::u16ify(&td.values, 0);
+                self.read_reply(td.device_function, td.register, count)
+            }
+            DeviceFunction::WriteSingle | DeviceFunction::WriteMulti => {
+                let pairs = td.pairs();
+                if pairs.iter().any(|(register, _)| *register >= HOLD_REGISTERS) {
+                    return self.exception(td.device_function, td.register, ModbusError::IllegalAddress);
+                }
+                {
+                    let mut registers = self.registers.lock().unwrap();
+                    for (register, value) in &pairs {
+                        registers.holds.insert(*register, *value);
+                    }
+                }
+
+                // WriteSingle echoes the value, WriteMulti the register count
+                let values = if td.device_function == DeviceFunction::WriteSingle {
+                    td.values
+                } else {
+                    (pairs.len() as u16).to_le_bytes().to_vec()
+                };
+
+                self.translated_data(td.device_function, td.register, values)
+            }
+            _ => self.exception(td.device_function, td.register, ModbusError::IllegalFunction),
+        }
+    }
+
+    fn exception(&self, device_function: DeviceFunction, register: u16, error: ModbusError) -> Packet {
+        self.translated_data(device_function.error(), register, vec![error as u8, 0])
+    }
+
+    fn read_reply(&self, device_function: DeviceFunction, register: u16, count: u16) -> Packet {
+        let registers = self.registers.lock().unwrap();
+        let map = match device_function {
+            DeviceFunction::ReadInput => &registers.inputs,
+            _ => &registers.holds,
+        };
+
+        let values = (register..register.saturating_add(count))
+            .flat_map(|r| map.get(&r).copied().unwrap_or_default().to_le_bytes())
+            .collect();
+
+        self.translated_data(device_function, register, values)
+    }
+
+    fn translated_data(&self, device_function: DeviceFunction, register: u16, values: Vec<u8>) -> Packet {
+        Packet::TranslatedData(TranslatedData {
+            datalog: self.datalog,
+            device_function,
+            inverter: self.serial,
+            register,
+            values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulator() -> Simulator {
+        Simulator::new(
+            Serial::from_str("2222222222").unwrap(),
+            Serial::from_str("5555555555").unwrap(),
+        )
+    }
+
+    fn request(simulator: &Simulator, device_function: DeviceFunction, register: u16, values: Vec<u8>) -> TranslatedData {
+        let packet = Packet::TranslatedData(TranslatedData {
+            datalog: simulator.datalog(),
+            device_function,
+            inverter: simulator.serial(),
+            register,
+            values,
+        });
+
+        match simulator.handle(packet) {
+            Some(Packet::TranslatedData(td)) => td,
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reads_and_writes() {
+        let simulator = simulator();
+        simulator.set_hold(21, 0x1234);
+
+        let reply = request(&simulator, DeviceFunction::ReadHold, 20, vec![2, 0]);
+        assert_eq!(reply.pairs(), vec![(20, 0), (21, 0x1234)]);
+
+        let reply = request(&simulator, DeviceFunction::WriteSingle, 64, vec![100, 0]);
+        assert_eq!(reply.pairs(), vec![(64, 100)]);
+
+        let reply = request(&simulator, DeviceFunction::WriteMulti, 66, vec![1, 0, 2, 0]);
+        assert_eq!(reply.value(), 2);
+        assert_eq!(simulator.hold(64), Some(100));
+        assert_eq!(simulator.hold(67), Some(2));
+    }
+
+    #[test]
+    fn test_write_exception() {
+        let simulator = simulator();
+
+        let reply = request(&simulator, DeviceFunction::WriteSingle, 300, vec![1, 0]);
+        assert_eq!(reply.device_function, DeviceFunction::WriteSingleError);
+        assert_eq!(reply.device_function.request(), DeviceFunction::WriteSingle);
+        assert_eq!(reply.exception(), Some(ModbusError::IllegalAddress));
+        assert_eq!(simulator.hold(300), None);
+
+        // it survives the trip through a frame
+        let packet = Packet::TranslatedData(reply);
+        let frame = TcpFrameFactory::build_reply(&packet);
+        let mut decoder = PacketDecoder::new();
+        let decoded = decoder.decode(&mut BytesMut::from(&frame[..])).unwrap();
+        assert_eq!(decoded, Some(packet));
+    }
+
+    #[test]
+    fn test_load_datalog_file() -> Result<()> {
+        let simulator = simulator();
+        let loaded = simulator.load_datalog_file("doc/datalog.json")?;
+        assert!(loaded > 0);
+
+        // first line of doc/datalog.json is a hold capture with "21":"0x7AD5"
+        assert_eq!(simulator.hold(21), Some(0x7AD5));
+        assert!(simulator.input(1).is_some());
+
+        // decoded lines written alongside the raw ones are skipped
+        simulator.load_datalog_line(r#"{"datalog":"2222222222","register_type":"input_all","data":{"soc":50}}"#)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inverter_connection() -> Result<()> {
+        use crate::eg4::inverter::ChannelData;
+
+        let simulator = simulator();
+        simulator.set_input(1, 0x1234);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = simulator.clone();
+        tokio::spawn(async move { server.start(listener).await });
+
+        let mut config = Config::new("config.yaml.example".to_string())?;
+        let inverter = &mut config.inverters[0];
+        inverter.host = "127.0.0.1".to_string();
+        inverter.port = port;
+        inverter.serial = Some(simulator.serial());
+        inverter.datalog = Some(simulator.datalog());
+        let inverter = inverter.clone();
+
+        let channels = Channels::new();
+        let mut from_inverter = channels.from_inverter.subscribe();
+        let client = Inverter::new(ConfigWrapper::from_config(config), &inverter, channels.clone());
+        tokio::spawn(async move { client.start().await });
+
+        let request = Packet::TranslatedData(TranslatedData {
+            datalog: simulator.datalog(),
+            device_function: DeviceFunction::ReadInput,
+            inverter: simulator.serial(),
+            register: 1,
+            values: vec![1, 0],
+        });
+
+        let reply = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match from_inverter.recv().await? {
+                    // the inverter starts listening just after saying it's
+                    // connected, so keep asking until it does
+                    ChannelData::Connected(_) | ChannelData::Packet(Packet::Heartbeat(_)) => {}
+                    // ours, not one of the blocks the simulator pushes
+                    ChannelData::Packet(Packet::TranslatedData(td)) if td.register == 1 => {
+                        break Ok::<_, Error>(td)
+                    }
+                    ChannelData::Packet(Packet::TranslatedData(_)) => {}
+                    other => bail!("unexpected {:?}", other),
+                }
+            }
+        });
+        tokio::pin!(reply);
+
+        let reply = loop {
+            let _ = channels.to_inverter.send(ChannelData::Packet(request.clone()));
+            if let Ok(reply) = tokio::time::timeout(Duration::from_millis(100), &mut reply).await {
+                break reply??;
+            }
+        };
+
+        assert_eq!(reply.device_function, DeviceFunction::ReadInput);
+        assert_eq!(reply.datalog, simulator.datalog());
+        assert_eq!(reply.pairs(), vec![(1, 0x1234)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+use crate::prelude::*;
+use crate::metrics::Metrics;
+
+use {
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::collections::BTreeMap,
+    std::fmt::Debug,
+    std::future::Future,
+    std::fs::{self, File, OpenOptions},
+    std::io::{BufRead, BufReader, Seek, SeekFrom},
+    std::path::{Path, PathBuf},
+    std::sync::{Arc, Mutex},
+    std::time::{Duration, Instant},
+};
+
+// how often the state file is rewritten as records come and go
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// kept next to the records, so we pick up where we left off after a restart
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SpoolStats {
+    // where the next record to deliver starts
+    #[serde(default)]
+    offset: u64,
+    pub records: u64,
+    pub bytes: u64,
+    // refused because the spool was full
+    pub dropped: u64,
+    // given up on because the sink will never take them
+    #[serde(default)]
+    pub rejected: u64,
+}
+
+impl SpoolStats {
+    pub fn write_metrics(&self, spool: &str, metrics: &mut Metrics) {
+        let labels = [("spool", spool)];
+        metrics.gauge("spool_records", "Records waiting on disk for a sink to come back", &labels, self.records as f64);
+        metrics.gauge("spool_bytes", "Size of the spool file", &labels, self.bytes as f64);
+        metrics.counter("spool_dropped", "Records lost because the spool was full", &labels, self.dropped as f64);
+        metrics.counter("spool_rejected", "Records the sink refused for good", &labels, self.rejected as f64);
+    }
+}
+
+/// Why a sink didn't take records, which decides what becomes of them.
+#[derive(Debug)]
+pub enum SinkError {
+    // down, timing out or overloaded; keep them and try again later
+    Retry(anyhow::Error),
+    // it will never take them, say a bad point or a constraint; sending
+    // them again would only hold up everything spooled behind
+    Reject(anyhow::Error),
+}
+
+impl std::fmt::Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Retry(err) | Self::Reject(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Write-ahead spool for a sink that's down: records are appended to
+/// `<directory>/<name>.jsonl` and delivered from the front, oldest first,
+/// once it's back. Records aren't changed on the way through, so they keep
+/// their original timestamps.
+///
+/// The file work happens on tokio's blocking pool, so a slow disk doesn't
+/// hold up other tasks.
+#[derive(Clone)]
+pub struct Spool {
+    files: Arc<Files>,
+}
+
+impl Spool {
+    pub async fn open(config: &config::Spool, name: &str) -> Result<Self> {
+        let (config, name) = (config.clone(), name.to_string());
+        let files = tokio::task::spawn_blocking(move || Files::open(&config, &name)).await??;
+
+        Ok(Self { files: Arc::new(files) })
+    }
+
+    /// What's in each spool in a directory, for /api/stats and /metrics,
+    /// without needing the sinks that own them.
+    pub fn stats(config: &config::Spool) -> BTreeMap<String, SpoolStats> {
+        let entries = match fs::read_dir(config.directory()) {
+            Ok(entries) => entries,
+            Err(_) => return BTreeMap::new(),
+        };
+
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "state" {
+                    return None;
+                }
+                let state = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+                Some((path.file_stem()?.to_string_lossy().to_string(), state))
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.state.lock().unwrap().stats.records == 0
+    }
+
+    /// Adds records to the back. Returns how many were dropped because the
+    /// spool is full.
+    pub async fn push<T: Serialize>(&self, records: &[T]) -> Result<u64> {
+        let lines = records
+            .iter()
+            .map(|record| serde_json::to_string(record).map(|line| line + "\n"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || files.push(&lines)).await?
+    }
+
+    /// Up to `max` of the oldest records, and how many bytes of the file
+    /// they take, to pass to pop() once they've been delivered. Lines that
+    /// don't parse - say, cut short by a crash - are skipped.
+    pub async fn peek<T: DeserializeOwned + Send + 'static>(&self, max: usize) -> Result<(Vec<T>, u64)> {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || files.peek(max)).await?
+    }
+
+    /// Removes `records` from the front, `len` bytes of the file.
+    pub async fn pop(&self, records: u64, len: u64) -> Result<()> {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || files.pop(records, len)).await?
+    }
+
+    /// Sends records to a sink oldest first, up to `max` at a time, until
+    /// they're gone or it can't take any more for now. A rejected batch is
+    /// sent again a record at a time, so only the records the sink will
+    /// never take are logged and given up on.
+    pub async fn drain<T, F, Fut>(&self, max: usize, mut send: F) -> Result<()>
+    where
+        T: DeserializeOwned + Clone + Debug + Send + 'static,
+        F: FnMut(Vec<T>) -> Fut,
+        Fut: Future<Output = std::result::Result<(), SinkError>>,
+    {
+        // records left to send one by one after a rejected batch
+        let mut singles = 0;
+
+        loop {
+            let batch = match singles {
+                0 => max,
+                _ => {
+                    singles -= 1;
+                    1
+                }
+            };
+            let (records, len) = self.peek::<T>(batch).await?;
+            if records.is_empty() {
+                return Ok(());
+            }
+
+            match send(records.clone()).await {
+                Ok(()) => self.pop(records.len() as u64, len).await?,
+                Err(SinkError::Retry(err)) => {
+                    debug!("spool {}: sink still unavailable: {}", self.files.name, err);
+                    return Ok(());
+                }
+                Err(SinkError::Reject(err)) if records.len() > 1 => {
+                    warn!("spool {}: batch rejected, sending it a record at a time: {}", self.files.name, err);
+                    singles = records.len();
+                }
+                Err(SinkError::Reject(err)) => {
+                    error!("spool {}: giving up on {:?}: {}", self.files.name, records[0], err);
+                    self.files.state.lock().unwrap().stats.rejected += 1;
+                    self.pop(1, len).await?;
+                }
+            }
+
+            if self.is_empty() {
+                info!("spool {}: drained", self.files.name);
+            }
+        }
+    }
+
+    /// Writes out the state if a save has been put off; call before
+    /// shutting down.
+    pub async fn flush(&self) -> Result<()> {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut state = files.state.lock().unwrap();
+            if state.dirty {
+                files.save(&mut state)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+struct State {
+    stats: SpoolStats,
+    // changes since the state file was last written
+    dirty: bool,
+    saved: Instant,
+}
+
+// the blocking half of Spool
+struct Files {
+    name: String,
+    path: PathBuf,
+    state_path: PathBuf,
+    max_bytes: u64,
+    state: Mutex<State>,
+}
+
+impl Files {
+    fn open(config: &config::Spool, name: &str) -> Result<Self> {
+        let directory = Path::new(config.directory());
+        fs::create_dir_all(directory)?;
+
+        let path = directory.join(format!("{}.jsonl", name));
+        let state_path = directory.join(format!("{}.state", name));
+
+        let mut stats = match fs::read_to_string(&state_path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+            Err(_) => SpoolStats::default(),
+        };
+        stats.bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        // saves are put off, so the offset can be behind (and records
+        // delivered twice) but not ahead, short of the file being replaced
+        if stats.offset > stats.bytes {
+            stats.offset = 0;
+        }
+        stats.records = Self::count(&path, stats.offset)?;
+
+        if stats.records > 0 {
+            info!("spool {}: {} records waiting from last time", name, stats.records);
+        }
+
+        let files = Self {
+            name: name.to_string(),
+            path,
+            state_path,
+            max_bytes: config
+                .max_size_bytes()
+                .ok_or_else(|| anyhow!("spool.rs:spool.max_size_mb is too large"))?,
+            state: Mutex::new(State {
+                stats,
+                dirty: false,
+                saved: Instant::now(),
+            }),
+        };
+        files.save(&mut files.state.lock().unwrap())?;
+
+        Ok(files)
+    }
+
+    fn push(&self, lines: &[String]) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut dropped = 0;
+
+        for line in lines {
+            let len = line.len() as u64;
+            if state.stats.bytes + len > self.max_bytes && state.stats.offset > 0 {
+                self.compact(&mut state)?;
+                file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            }
+            if state.stats.bytes + len > self.max_bytes {
+                state.stats.dropped += 1;
+                if state.stats.dropped % 100 == 1 {
+                    warn!(
+                        "spool {}: full at {} bytes, {} records dropped",
+                        self.name, state.stats.bytes, state.stats.dropped
+                    );
+                }
+                dropped += 1;
+                continue;
+            }
+
+            file.write_all(line.as_bytes())?;
+            state.stats.bytes += len;
+            state.stats.records += 1;
+        }
+        file.flush()?;
+
+        self.save_later(&mut state)?;
+        Ok(dropped)
+    }
+
+    fn peek<T: DeserializeOwned>(&self, max: usize) -> Result<(Vec<T>, u64)> {
+        let (offset, records) = {
+            let state = self.state.lock().unwrap();
+            (state.stats.offset, state.stats.records)
+        };
+        if records == 0 {
+            return Ok((Vec::new(), 0));
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+
+        let mut batch = Vec::new();
+        let mut len = 0;
+        let mut skipped = 0;
+        while batch.len() < max {
+            let mut line = String::new();
+            let n = reader.read_line(&mut line)? as u64;
+            if n == 0 {
+                break;
+            }
+
+            match serde_json::from_str(line.trim_end()) {
+                Ok(record) => batch.push(record),
+                Err(err) => {
+                    warn!("spool {}: skipping unreadable record at {}: {}", self.name, offset + len, err);
+                    skipped += 1;
+                }
+            }
+            len += n;
+        }
+
+        // nothing readable; drop what we skipped and look again
+        if batch.is_empty() && skipped > 0 {
+            self.pop(skipped, len)?;
+            return self.peek(max);
+        }
+        if skipped > 0 {
+            let mut state = self.state.lock().unwrap();
+            state.stats.records = state.stats.records.saturating_sub(skipped);
+        }
+
+        Ok((batch, len))
+    }
+
+    fn pop(&self, records: u64, len: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.stats.offset += len;
+        state.stats.records = state.stats.records.saturating_sub(records);
+
+        // start the file again once it's all been delivered. the offset
+        // has to be on disk before the file changes under it
+        if state.stats.records == 0 || state.stats.offset >= state.stats.bytes {
+            state.stats.offset = 0;
+            state.stats.bytes = 0;
+            state.stats.records = 0;
+            self.save(&mut state)?;
+            File::create(&self.path)?;
+            return Ok(());
+        }
+
+        self.save_later(&mut state)
+    }
+
+    // drops the part of the file that's already been delivered
+    fn compact(&self, state: &mut State) -> Result<()> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(state.stats.offset))?;
+
+        let tmp = self.path.with_extension("jsonl.tmp");
+        std::io::copy(&mut file, &mut File::create(&tmp)?)?;
+
+        state.stats.bytes -= state.stats.offset;
+        state.stats.offset = 0;
+        self.save(state)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    // the offset only moves forward between these, so a crash before the
+    // next one just delivers a few records again
+    fn save_later(&self, state: &mut State) -> Result<()> {
+        state.dirty = true;
+        if state.saved.elapsed() >= SAVE_INTERVAL {
+            self.save(state)?;
+        }
+        Ok(())
+    }
+
+    // to a temporary file first, so a crash leaves the old state or the
+    // new one, never half of each
+    fn save(&self, state: &mut State) -> Result<()> {
+        let tmp = self.state_path.with_extension("state.tmp");
+        fs::write(&tmp, serde_json::to_string(&state.stats)?)?;
+        fs::rename(&tmp, &self.state_path)?;
+
+        state.dirty = false;
+        state.saved = Instant::now();
+        Ok(())
+    }
+
+    fn count(path: &Path, offset: u64) -> Result<u64> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(0),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(BufReader::new(file).lines().count() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(directory: &tempfile::TempDir) -> config::Spool {
+        config::Spool {
+            directory: directory.path().to_string_lossy().to_string(),
+            max_size_mb: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+
+        let spool = Spool::open(&config, "test").await.unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(spool.push(&[1, 2, 3]).await.unwrap(), 0);
+
+        let (first, len) = spool.peek::<u32>(1).await.unwrap();
+        assert_eq!(first, vec![1]);
+        spool.pop(1, len).await.unwrap();
+        spool.flush().await.unwrap();
+
+        // picks up where it left off
+        let spool = Spool::open(&config, "test").await.unwrap();
+        assert_eq!(Spool::stats(&config)["test"].records, 2);
+        let (rest, len) = spool.peek::<u32>(10).await.unwrap();
+        assert_eq!(rest, vec![2, 3]);
+        spool.pop(2, len).await.unwrap();
+        assert!(spool.is_empty());
+        assert!(spool.peek::<u32>(10).await.unwrap().0.is_empty());
+        assert_eq!(fs::metadata(&spool.files.path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unsaved_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+
+        let spool = Spool::open(&config, "test").await.unwrap();
+        spool.push(&[1, 2, 3]).await.unwrap();
+        let (first, len) = spool.peek::<u32>(1).await.unwrap();
+        spool.pop(1, len).await.unwrap();
+        assert_eq!(first, vec![1]);
+
+        // went down before the state was saved: the record is delivered
+        // again rather than anything being lost
+        let spool = Spool::open(&config, "test").await.unwrap();
+        assert_eq!(spool.peek::<u32>(10).await.unwrap().0, vec![1, 2, 3]);
+        assert!(!Path::new(config.directory()).join("test.state.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_drain_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let spool = Spool::open(&config, "test").await.unwrap();
+        spool.push(&[1, 2, 3, 4]).await.unwrap();
+
+        let delivered = Mutex::new(Vec::new());
+        let sink = |fail: Option<SinkError>| {
+            let delivered = &delivered;
+            let mut fail = fail;
+            move |records: Vec<u32>| {
+                let result = match fail.take() {
+                    Some(err) => Err(err),
+                    None if records.contains(&2) => Err(SinkError::Reject(anyhow!("bad record"))),
+                    None => {
+                        delivered.lock().unwrap().extend(records);
+                        Ok(())
+                    }
+                };
+                async move { result }
+            }
+        };
+
+        // down: everything stays put
+        spool.drain(10, sink(Some(SinkError::Retry(anyhow!("down"))))).await.unwrap();
+        assert_eq!(spool.peek::<u32>(10).await.unwrap().0, vec![1, 2, 3, 4]);
+
+        // one record is never taken; the ones behind it still get through
+        spool.drain(10, sink(None)).await.unwrap();
+        assert_eq!(*delivered.lock().unwrap(), vec![1, 3, 4]);
+        assert!(spool.is_empty());
+        assert_eq!(spool.files.state.lock().unwrap().stats.rejected, 1);
+    }
+}
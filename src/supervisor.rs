@@ -0,0 +1,264 @@
+use crate::prelude::*;
+
+use {
+    std::collections::HashMap,
+    std::sync::{Arc, Mutex},
+    std::time::{Duration, SystemTime},
+    tokio::signal::unix::{signal, SignalKind},
+    tokio::task::JoinHandle,
+};
+
+// how often we look at the config file's modification time
+const WATCH_INTERVAL_SECS: u64 = 5;
+
+#[derive(Default)]
+struct Tasks {
+    // keyed by host, as Inverter::config() looks them up that way
+    inverters: HashMap<String, JoinHandle<()>>,
+    mqtt: Option<JoinHandle<()>>,
+    influx: Option<JoinHandle<()>>,
+    databases: Vec<(Database, JoinHandle<()>)>,
+}
+
+/// Runs the parts of the bridge that are driven by the config file -
+/// inverters, MQTT, InfluxDB and databases - and when the file changes,
+/// restarts only those affected, leaving every other connection alone.
+#[derive(Clone)]
+pub struct Supervisor {
+    file: String,
+    config: ConfigWrapper,
+    channels: Channels,
+    influx: Influx,
+    // kept across restarts, so inverter statuses aren't lost with the client
+    mqtt: Mqtt,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl Supervisor {
+    pub fn new(file: String, config: ConfigWrapper, channels: Channels) -> Self {
+        let influx = Influx::new(config.clone(), channels.clone());
+        let mqtt = Mqtt::new(config.clone(), channels.clone());
+
+        Self {
+            file,
+            config,
+            channels,
+            influx,
+            mqtt,
+            tasks: Arc::new(Mutex::new(Tasks::default())),
+        }
+    }
+
+    pub fn start_databases(&self) {
+        for database in self.config.enabled_databases() {
+            self.start_database(database);
+        }
+    }
+
+    pub async fn start_influx(&self) -> Result<()> {
+        let handle = self.influx.start().await?;
+        self.tasks.lock().unwrap().influx = handle;
+        Ok(())
+    }
+
+    // waits for the sender to flush and let go of the spool, so two of them
+    // never write to it at once
+    async fn stop_influx(&self) {
+        self.influx.stop();
+
+        let handle = self.tasks.lock().unwrap().influx.take();
+        if let Some(handle) = handle {
+            if let Err(e) = handle.await {
+                error!("InfluxDB sender task failed: {}", e);
+            }
+        }
+    }
+
+    pub fn start_inverters(&self) {
+        for inverter in self.config.enabled_inverters() {
+            self.start_inverter(&inverter);
+        }
+    }
+
+    pub fn start_mqtt(&self) {
+        let mqtt = self.mqtt.clone();
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = mqtt.start().await {
+                error!("MQTT error: {}", e);
+            }
+        });
+
+        self.tasks.lock().unwrap().mqtt = Some(handle);
+    }
+
+    pub fn stop(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+
+        // these are broadcast to every instance, so once each is enough
+        let _ = self.channels.to_inverter.send(eg4::inverter::ChannelData::Shutdown);
+        let _ = self.channels.to_database.send(database::ChannelData::Shutdown);
+        self.influx.stop();
+
+        for (_, handle) in tasks.inverters.drain() {
+            handle.abort();
+        }
+        for (_, handle) in tasks.databases.drain(..) {
+            handle.abort();
+        }
+        if let Some(handle) = tasks.mqtt.take() {
+            handle.abort();
+        }
+    }
+
+    /// Reloads the config on SIGHUP, or when the file's modification time
+    /// changes. Never returns unless signal handling can't be set up.
+    pub async fn watch(&self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(Duration::from_secs(WATCH_INTERVAL_SECS));
+        let mut modified = self.modified();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("received SIGHUP, reloading {}", self.file);
+                }
+                _ = interval.tick() => {
+                    let now = self.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    info!("{} has changed, reloading", self.file);
+                }
+            }
+
+            modified = self.modified();
+
+            if let Err(err) = self.reload().await {
+                error!("config reload failed, carrying on with the old config: {}", err);
+            }
+        }
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        let diff = self.config.reload(&self.file)?;
+
+        if diff.is_empty() {
+            info!("config reloaded, nothing to restart");
+            return Ok(());
+        }
+
+        for inverter in diff.removed_inverters.iter().chain(&diff.changed_inverters) {
+            self.stop_inverter(inverter);
+        }
+        for inverter in diff.added_inverters.iter().chain(&diff.changed_inverters) {
+            self.start_inverter(inverter);
+        }
+
+        if diff.mqtt {
+            info!("restarting MQTT");
+            // not Mqtt::stop(), that would shut down the coordinator's side too
+            if let Some(handle) = self.tasks.lock().unwrap().mqtt.take() {
+                handle.abort();
+            }
+            self.start_mqtt();
+        }
+
+        if diff.influx {
+            info!("restarting InfluxDB");
+            self.stop_influx().await;
+            self.start_influx().await?;
+        }
+
+        for database in &diff.removed_databases {
+            self.stop_database(database).await;
+        }
+        for database in diff.added_databases {
+            self.start_database(database);
+        }
+
+        for section in &diff.restart_required {
+            warn!("config: changes to {} need a restart to take effect", section);
+        }
+
+        Ok(())
+    }
+
+    fn start_inverter(&self, inverter: &config::Inverter) {
+        info!(
+            "Starting inverter - Serial: {}, Datalog: {}, Host: {}",
+            inverter.serial().map(|s| s.to_string()).unwrap_or_default(),
+            inverter.datalog().map(|s| s.to_string()).unwrap_or_default(),
+            inverter.host(),
+        );
+
+        let instance = Inverter::new(self.config.clone(), inverter, self.channels.clone());
+        let handle = tokio::spawn(async move {
+            if let Err(e) = instance.start().await {
+                error!("Inverter error: {}", e);
+            }
+        });
+
+        self.tasks
+            .lock()
+            .unwrap()
+            .inverters
+            .insert(inverter.host().to_string(), handle);
+    }
+
+    fn stop_inverter(&self, inverter: &config::Inverter) {
+        let task = self.tasks.lock().unwrap().inverters.remove(inverter.host());
+
+        if let Some(handle) = task {
+            info!(
+                "Stopping inverter {}",
+                inverter.datalog().map(|s| s.to_string()).unwrap_or_default()
+            );
+            handle.abort();
+
+            // aborting skips the goodbye the connection would normally send
+            if let Some(datalog) = inverter.datalog() {
+                let _ = self
+                    .channels
+                    .from_inverter
+                    .send(eg4::inverter::ChannelData::Disconnect(datalog));
+            }
+        }
+    }
+
+    fn start_database(&self, config: config::Database) {
+        let database = Database::new(config, self.config.spool(), self.channels.clone());
+        let handle = tokio::spawn({
+            let database = database.clone();
+            async move {
+                if let Err(e) = database.start().await {
+                    error!("Database error: {}", e);
+                }
+            }
+        });
+
+        self.tasks.lock().unwrap().databases.push((database, handle));
+    }
+
+    // like stop_influx(), waits for it to finish with the row in hand and
+    // save its spool, rather than cutting it off partway through
+    async fn stop_database(&self, config: &config::Database) {
+        let stopping: Vec<_> = {
+            let mut tasks = self.tasks.lock().unwrap();
+            let (stopping, keep) = tasks.databases.drain(..).partition(|(database, _)| database.config() == config);
+            tasks.databases = keep;
+            stopping
+        };
+
+        for (database, handle) in stopping {
+            database.stop();
+            if let Err(e) = handle.await {
+                error!("Database task failed: {}", e);
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.file).and_then(|m| m.modified()).ok()
+    }
+}
@@ -4,7 +4,7 @@
 
 use crate::utils::Utils;
 
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct UnixTime(pub chrono::DateTime<chrono::Utc>);
@@ -25,3 +25,19 @@ impl Serialize for UnixTime {
         serializer.serialize_i64(self.0.timestamp())
     }
 }
+
+impl<'de> Deserialize<'de> for UnixTime {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use chrono::TimeZone;
+
+        let secs = i64::deserialize(deserializer)?;
+        chrono::Utc
+            .timestamp_opt(secs, 0)
+            .single()
+            .map(Self)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp {}", secs)))
+    }
+}